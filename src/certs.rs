@@ -3,6 +3,7 @@ use sha2::{Digest, Sha256};
 use std::io::{Read, Write};
 
 use keys;
+use util;
 use RsaVerifierImpl;
 use RsaSignerImpl;
 
@@ -20,6 +21,10 @@ pub enum Cert {
 
 pub type HoursSinceEpoch = u32;
 
+/// The expiration date we give the certs we make unless told otherwise. I think this is
+/// 2050-01-01.
+pub const DEFAULT_EXPIRATION_DATE: HoursSinceEpoch = 701288;
+
 #[derive(Debug)]
 pub struct Ed25519Cert {
     cert_type: Ed25519CertType,
@@ -86,11 +91,11 @@ impl Ed25519Cert {
         cert_type: Ed25519CertType,
         certified_key: [u8; 32],
         signing_key_bytes: &[u8; 32],
+        expiration_date: HoursSinceEpoch,
     ) -> Ed25519Cert {
         Ed25519Cert {
             cert_type: cert_type,
-            // I think this is 2050-01-01, but maybe this should be dynamic
-            expiration_date: 701288,
+            expiration_date: expiration_date,
             certified_key_type: Ed25519CertifiedKeyType::Ed25519Key,
             certified_key: certified_key,
             extensions: vec![Ed25519CertExtension::new(signing_key_bytes)],
@@ -140,6 +145,19 @@ impl Ed25519Cert {
         &self.signature
     }
 
+    pub fn get_cert_type(&self) -> &Ed25519CertType {
+        &self.cert_type
+    }
+
+    pub fn get_expiration_date(&self) -> HoursSinceEpoch {
+        self.expiration_date
+    }
+
+    /// Whether the cert has expired as of `now` (seconds since the epoch).
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.expiration_date as u64 * 3600
+    }
+
     // TODO: this doesn't make sense for non-Ed25519 keys (which brings up the question of why this
     // is called an Ed25519 Certificate, but ok), so maybe return a Result or something?
     // (Although see below - apparently we can't be sure that something marked as an Ed25519 key
@@ -158,15 +176,27 @@ impl Ed25519Cert {
             return false;
         }
         */
-        hash == self.certified_key
+        util::ct_eq(hash, &self.certified_key)
     }
 
     pub fn get_extensions(&self) -> &[Ed25519CertExtension] {
         &self.extensions
     }
+
+    /// The key in the signed-with-ed25519-key extension, if the cert has one. cert-spec.txt
+    /// section 2.2.1 says this is the key that signed the cert, so if it's present it has to be
+    /// the key the signature actually verifies with.
+    pub fn get_signed_with_key(&self) -> Option<&[u8]> {
+        for extension in &self.extensions {
+            if let Ed25519CertExtensionType::SignedWithEd25519Key = extension.ext_type {
+                return Some(&extension.ext_data);
+            }
+        }
+        None
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Ed25519CertType {
     // Ed25519 signing key signed by an identity key
     SigningKey,
@@ -252,6 +282,11 @@ impl Ed25519CertExtension {
             return Err("failed to read extension flags");
         }
         let ext_flags = Ed25519CertExtensionFlags::from_u8(one_byte_buf[0]);
+        if let Ed25519CertExtensionType::SignedWithEd25519Key = ext_type {
+            if length != 32 {
+                return Err("signed-with-ed25519-key extension has the wrong length");
+            }
+        }
         let mut ext_data: Vec<u8> = Vec::with_capacity(length);
         ext_data.resize(length, 0);
         if let Err(_) = reader.read_exact(ext_data.as_mut_slice()) {
@@ -462,17 +497,98 @@ impl X509Cert {
         &self.der
     }
 
+    /// Walks the DER just far enough to find the TBSCertificate fields we're interested in:
+    /// Certificate is a SEQUENCE starting with the TBSCertificate SEQUENCE, which is an optional
+    /// [0] version, serialNumber INTEGER, signature AlgorithmIdentifier, issuer Name, Validity,
+    /// and subject Name (RFC 5280 section 4.1).
+    fn get_tbs_fields(&self) -> Result<TbsCertificateFields, &'static str> {
+        let (tag, certificate, _) = der_read_tlv(&self.der)?;
+        if tag != DER_SEQUENCE {
+            return Err("x509 cert is not a SEQUENCE");
+        }
+        let (tag, tbs_certificate, _) = der_read_tlv(certificate)?;
+        if tag != DER_SEQUENCE {
+            return Err("x509 TBSCertificate is not a SEQUENCE");
+        }
+        let (mut tag, mut serial_number, mut rest) = der_read_tlv(tbs_certificate)?;
+        if tag == DER_EXPLICIT_0 {
+            // That was the version, so the serial number is next.
+            let (serial_tag, serial, after_serial) = der_read_tlv(rest)?;
+            tag = serial_tag;
+            serial_number = serial;
+            rest = after_serial;
+        }
+        if tag != DER_INTEGER {
+            return Err("x509 serialNumber is not an INTEGER");
+        }
+        let (_, _, rest) = der_read_tlv(rest)?; // signature
+        let (tag, issuer, rest) = der_read_tlv(rest)?;
+        if tag != DER_SEQUENCE {
+            return Err("x509 issuer is not a SEQUENCE");
+        }
+        let (tag, validity, rest) = der_read_tlv(rest)?;
+        if tag != DER_SEQUENCE {
+            return Err("x509 Validity is not a SEQUENCE");
+        }
+        let (tag, subject, _) = der_read_tlv(rest)?;
+        if tag != DER_SEQUENCE {
+            return Err("x509 subject is not a SEQUENCE");
+        }
+        Ok(TbsCertificateFields {
+            serial_number: serial_number,
+            issuer: issuer,
+            validity: validity,
+            subject: subject,
+        })
+    }
+
+    /// Returns the notBefore and notAfter times of the certificate, in seconds since the epoch.
+    pub fn get_validity(&self) -> Result<(u64, u64), &'static str> {
+        let validity = self.get_tbs_fields()?.validity;
+        let (not_before_tag, not_before, rest) = der_read_tlv(validity)?;
+        let (not_after_tag, not_after, _) = der_read_tlv(rest)?;
+        Ok((
+            der_time_to_epoch(not_before_tag, not_before)?,
+            der_time_to_epoch(not_after_tag, not_after)?,
+        ))
+    }
+
+    /// Returns the notBefore time of the certificate, in seconds since the epoch.
+    pub fn get_not_before(&self) -> Result<u64, &'static str> {
+        Ok(self.get_validity()?.0)
+    }
+
+    /// Returns the notAfter time of the certificate, in seconds since the epoch.
+    pub fn get_not_after(&self) -> Result<u64, &'static str> {
+        Ok(self.get_validity()?.1)
+    }
+
+    /// Returns the serial number of the certificate, as the big-endian bytes of the DER INTEGER
+    /// (so possibly with a leading zero byte).
+    pub fn get_serial_number(&self) -> Result<&[u8], &'static str> {
+        Ok(self.get_tbs_fields()?.serial_number)
+    }
+
+    /// Returns the (first) commonName of the certificate's subject.
+    pub fn get_subject_cn(&self) -> Result<String, &'static str> {
+        der_name_get_cn(self.get_tbs_fields()?.subject)
+    }
+
+    /// Returns the (first) commonName of the certificate's issuer.
+    pub fn get_issuer_cn(&self) -> Result<String, &'static str> {
+        der_name_get_cn(self.get_tbs_fields()?.issuer)
+    }
+
     pub fn sign_ed25519_key(
         &self,
         ed25519_identity_key: &keys::Ed25519Key,
         rsa_signer: &RsaSignerImpl,
+        expiration_date: HoursSinceEpoch,
     ) -> Ed25519Identity {
         // The payload to be signed is:
         // "Tor TLS RSA/Ed25519 cross-certificate" || Ed25519 public key (32 bytes) ||
         // expiration date (hours since epoch, 4 bytes)
         let mut buf: Vec<u8> = Vec::new();
-        // I think this represents 2050-01-01
-        let expiration_date = 701288;
         buf.extend(CROSS_SIGN_PREFIX.iter());
         buf.extend(&ed25519_identity_key.get_public_key_bytes());
         buf.write_u32::<NetworkEndian>(expiration_date).unwrap();
@@ -485,3 +601,234 @@ impl X509Cert {
         )
     }
 }
+
+/// The contents of the parts of an X509 TBSCertificate that `X509Cert` exposes.
+struct TbsCertificateFields<'a> {
+    serial_number: &'a [u8],
+    issuer: &'a [u8],
+    validity: &'a [u8],
+    subject: &'a [u8],
+}
+
+const DER_INTEGER: u8 = 0x02;
+const DER_OID: u8 = 0x06;
+const DER_UTF8_STRING: u8 = 0x0c;
+const DER_PRINTABLE_STRING: u8 = 0x13;
+const DER_IA5_STRING: u8 = 0x16;
+const DER_SEQUENCE: u8 = 0x30;
+const DER_SET: u8 = 0x31;
+const DER_EXPLICIT_0: u8 = 0xa0;
+/// The contents of the OID 2.5.4.3 (id-at-commonName).
+const OID_COMMON_NAME: &'static [u8] = &[0x55, 0x04, 0x03];
+const DER_UTC_TIME: u8 = 0x17;
+const DER_GENERALIZED_TIME: u8 = 0x18;
+
+/// Splits one DER TLV off the front of `data`, returning its tag, its contents, and whatever
+/// follows it.
+fn der_read_tlv(data: &[u8]) -> Result<(u8, &[u8], &[u8]), &'static str> {
+    if data.len() < 2 {
+        return Err("truncated DER");
+    }
+    let tag = data[0];
+    let (length, header_len) = if data[1] < 0x80 {
+        (data[1] as usize, 2)
+    } else {
+        let length_len = (data[1] & 0x7f) as usize;
+        if length_len == 0 || length_len > 4 || data.len() < 2 + length_len {
+            return Err("invalid DER length");
+        }
+        let mut length = 0;
+        for b in &data[2..2 + length_len] {
+            length = (length << 8) | *b as usize;
+        }
+        (length, 2 + length_len)
+    };
+    if data.len() - header_len < length {
+        return Err("truncated DER");
+    }
+    let (contents, rest) = data[header_len..].split_at(length);
+    Ok((tag, contents, rest))
+}
+
+/// Finds the first commonName in the contents of a DER Name, which is a SEQUENCE of
+/// RelativeDistinguishedName SETs of AttributeTypeAndValue SEQUENCEs (an OID and a value).
+fn der_name_get_cn(name: &[u8]) -> Result<String, &'static str> {
+    let mut rdns = name;
+    while !rdns.is_empty() {
+        let (tag, rdn, rest) = der_read_tlv(rdns)?;
+        if tag != DER_SET {
+            return Err("x509 RelativeDistinguishedName is not a SET");
+        }
+        rdns = rest;
+        let mut attributes = rdn;
+        while !attributes.is_empty() {
+            let (tag, attribute, rest) = der_read_tlv(attributes)?;
+            if tag != DER_SEQUENCE {
+                return Err("x509 AttributeTypeAndValue is not a SEQUENCE");
+            }
+            attributes = rest;
+            let (tag, oid, value) = der_read_tlv(attribute)?;
+            if tag != DER_OID || oid != OID_COMMON_NAME {
+                continue;
+            }
+            let (tag, value, _) = der_read_tlv(value)?;
+            return match tag {
+                DER_UTF8_STRING | DER_PRINTABLE_STRING | DER_IA5_STRING => {
+                    match String::from_utf8(value.to_vec()) {
+                        Ok(cn) => Ok(cn),
+                        Err(_) => Err("invalid x509 commonName"),
+                    }
+                }
+                _ => Err("unsupported x509 commonName string type"),
+            };
+        }
+    }
+    Err("x509 name has no commonName")
+}
+
+/// Converts a DER UTCTime ("YYMMDDHHMMSSZ", where years before 50 are in the 2000s) or
+/// GeneralizedTime ("YYYYMMDDHHMMSSZ") to seconds since the epoch.
+fn der_time_to_epoch(tag: u8, time: &[u8]) -> Result<u64, &'static str> {
+    let time = match String::from_utf8(time.to_vec()) {
+        Ok(time) => time,
+        Err(_) => return Err("invalid x509 time"),
+    };
+    if !time.ends_with('Z') || !time[..time.len() - 1].bytes().all(|b| b >= b'0' && b <= b'9') {
+        return Err("invalid x509 time");
+    }
+    let full_time = match (tag, time.len()) {
+        (DER_UTC_TIME, 13) => {
+            let century = if &time[0..2] < "50" { "20" } else { "19" };
+            format!("{}{}", century, time)
+        }
+        (DER_GENERALIZED_TIME, 15) => time,
+        _ => return Err("invalid x509 time"),
+    };
+    let timestamp = format!(
+        "{}-{}-{} {}:{}:{}",
+        &full_time[0..4],
+        &full_time[4..6],
+        &full_time[6..8],
+        &full_time[8..10],
+        &full_time[10..12],
+        &full_time[12..14]
+    );
+    match util::parse_utc_timestamp(&timestamp) {
+        Some(seconds) => Ok(seconds),
+        None => Err("invalid x509 time"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_signed_with_key() {
+        let signer = keys::Ed25519Key::new();
+        let signed = keys::Ed25519Key::new();
+        let cert = signer.sign_ed25519_key(
+            &signed,
+            Ed25519CertType::SigningKey,
+            DEFAULT_EXPIRATION_DATE,
+        );
+        assert_eq!(cert.get_signed_with_key(), Some(&signer.get_public_key_bytes()[..]));
+        let mut bytes = Vec::new();
+        cert.write_to(&mut bytes);
+        let read = Ed25519Cert::read_new(&mut &bytes[..]).unwrap();
+        assert_eq!(read.get_signed_with_key(), Some(&signer.get_public_key_bytes()[..]));
+        // Without any extensions there's no signer named. (Version, type, expiration date,
+        // certified key type and key, no extensions, and a signature.)
+        let mut bytes = vec![1, 4, 0, 0, 0, 1, 1];
+        bytes.extend(&[7; 32]);
+        bytes.push(0);
+        bytes.extend(&[0; 64][..]);
+        let read = Ed25519Cert::read_new(&mut &bytes[..]).unwrap();
+        assert_eq!(read.get_signed_with_key(), None);
+    }
+
+    #[test]
+    fn test_signed_with_key_extension_length() {
+        // Length, type 4 (signed-with-ed25519-key), no flags, and the key.
+        let mut bytes = vec![0, 32, 4, 0];
+        bytes.extend(&[7; 32]);
+        let extension = Ed25519CertExtension::read_new(&mut &bytes[..]).unwrap();
+        assert_eq!(extension.ext_data, vec![7; 32]);
+        let mut bytes = vec![0, 31, 4, 0];
+        bytes.extend(&[7; 31]);
+        assert!(Ed25519CertExtension::read_new(&mut &bytes[..]).is_err());
+        let mut bytes = vec![0, 33, 4, 0];
+        bytes.extend(&[7; 33]);
+        assert!(Ed25519CertExtension::read_new(&mut &bytes[..]).is_err());
+        // Other extensions can be any length.
+        let bytes = [0, 3, 9, 0, 1, 2, 3];
+        let extension = Ed25519CertExtension::read_new(&mut &bytes[..]).unwrap();
+        assert_eq!(extension.ext_data, vec![1, 2, 3]);
+    }
+
+    /// "Signs" by hashing the signer's cert together with the data, so that (unlike a mock that
+    /// accepts anything) verification fails if either the data or the cert is different.
+    struct HashRsa {
+        der: Vec<u8>,
+    }
+
+    fn hash_signature(cert: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.input(cert);
+        hasher.input(data);
+        hasher.result().to_vec()
+    }
+
+    impl RsaSignerImpl for HashRsa {
+        fn sign_data(&self, data: &[u8]) -> Vec<u8> {
+            hash_signature(&self.der, data)
+        }
+
+        fn get_cert_bytes(&self) -> &[u8] {
+            &self.der
+        }
+    }
+
+    impl RsaVerifierImpl for HashRsa {
+        fn verify_signature(&self, cert: &[u8], data: &[u8], signature: &[u8]) -> bool {
+            util::ct_eq(&hash_signature(cert, data), signature)
+        }
+
+        fn get_key_hash(&self, cert: &[u8]) -> [u8; 32] {
+            util::slice_to_32_byte_array(&Sha256::digest(cert))
+        }
+    }
+
+    #[test]
+    fn test_ed25519_identity_round_trip() {
+        let rsa = HashRsa {
+            der: b"identity cert".to_vec(),
+        };
+        let identity_cert = X509Cert::new(rsa.get_cert_bytes());
+        let identity_key = keys::Ed25519Key::new();
+        let identity = identity_cert.sign_ed25519_key(&identity_key, &rsa, DEFAULT_EXPIRATION_DATE);
+        let mut bytes = Vec::new();
+        identity.write_to(&mut bytes);
+        let read = Ed25519Identity::read_new(&mut &bytes[..]).unwrap();
+        assert_eq!(read.get_key_bytes(), &identity_key.get_public_key_bytes()[..]);
+        assert_eq!(read.get_expiration_date(), DEFAULT_EXPIRATION_DATE);
+        assert_eq!(read.get_signature(), identity.get_signature());
+        assert!(identity_cert.check_ed25519_identity_signature(&read, &rsa));
+
+        // Signed by some other cert, or for some other key or expiration date, it doesn't verify.
+        let other_cert = X509Cert::new(b"other identity cert");
+        assert!(!other_cert.check_ed25519_identity_signature(&read, &rsa));
+        let other_key = Ed25519Identity::new(
+            keys::Ed25519Key::new().get_public_key_bytes(),
+            DEFAULT_EXPIRATION_DATE,
+            read.get_signature().to_vec(),
+        );
+        assert!(!identity_cert.check_ed25519_identity_signature(&other_key, &rsa));
+        let other_date = Ed25519Identity::new(
+            identity_key.get_public_key_bytes(),
+            DEFAULT_EXPIRATION_DATE + 1,
+            read.get_signature().to_vec(),
+        );
+        assert!(!identity_cert.check_ed25519_identity_signature(&other_date, &rsa));
+    }
+}
@@ -1,68 +1,304 @@
 use base64;
+use flate2::read::ZlibDecoder;
 use rand::{thread_rng, Rng};
 use sha2::{Digest, Sha256};
-use std::collections::HashSet;
-use std::net::Ipv4Addr;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::io::{Error, ErrorKind};
+use std::io::prelude::*;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use util;
 
 #[derive(Debug)]
 pub struct TorPeerList {
     peers: HashSet<PreTorPeer>,
+    bandwidth_weights: BandwidthWeights,
+    /// When the consensus stops being valid, in seconds since the epoch (if it said).
+    valid_until: Option<u64>,
+    /// The families relays declare in their microdescriptors, by node id (see `set_family`).
+    families: HashMap<[u8; 20], Vec<[u8; 20]>>,
 }
 
 impl TorPeerList {
     pub fn new(consensus: &str) -> TorPeerList {
         let mut peers = HashSet::new();
-        let mut router_line: Option<&str> = None;
-        let mut mdesc_line: Option<&str> = None;
-        let mut flags_line: Option<&str> = None;
-        // TODO: so this doesn't protect against misordered lines... (maybe verify signature first?)
-        // (probably still want to validate the structure of the data too...)
+        let mut bandwidth_weights = BandwidthWeights::default();
+        let mut valid_until = None;
+        // Each router status entry starts with an "r" line and runs until the next "r" line (or
+        // the footer), so gather up each entry's lines and parse them together.
+        // TODO: still need to verify the signature and validate the rest of the structure...
+        let mut entry: Vec<&str> = Vec::new();
         for line in consensus.lines() {
-            if line.starts_with("r ") && router_line.is_none() {
-                router_line = Some(line);
+            if line.starts_with("r ") || line.starts_with("directory-footer") {
+                if let Some(peer) = PreTorPeer::from_entry(&entry) {
+                    peers.insert(peer);
+                }
+                entry.clear();
             }
-            if line.starts_with("m ") && mdesc_line.is_none() {
-                mdesc_line = Some(line);
+            if line.starts_with("r ") || entry.len() > 0 {
+                entry.push(line);
             }
-            if line.starts_with("s ") && flags_line.is_none() {
-                flags_line = Some(line);
+            if line.starts_with("bandwidth-weights ") {
+                bandwidth_weights = BandwidthWeights::new(line);
             }
-            if router_line.is_some() && mdesc_line.is_some() && flags_line.is_some() {
-                peers.insert(PreTorPeer::new(
-                    router_line.take().unwrap(),
-                    mdesc_line.take().unwrap(),
-                    flags_line.take().unwrap(),
-                ));
+            if line.starts_with("valid-until ") {
+                valid_until = util::parse_utc_timestamp(&line["valid-until ".len()..]);
             }
         }
+        if let Some(peer) = PreTorPeer::from_entry(&entry) {
+            peers.insert(peer);
+        }
         TorPeerList {
             peers,
+            bandwidth_weights,
+            valid_until,
+            families: HashMap::new(),
         }
     }
 
-    pub fn get_guard_node(&self) -> Option<&PreTorPeer> {
-        let candidates = self.peers
-            .iter()
-            .filter(|node| node.is_usable && node.is_guard);
-        let mut collected: Vec<&PreTorPeer> = candidates.collect();
-        thread_rng().shuffle(&mut collected);
-        if collected.len() > 0 {
-            Some(collected[0])
-        } else {
-            None
+    /// Records the family a relay declares in its microdescriptor (see
+    /// `Microdescriptor::get_family`). The consensus doesn't list families, so until this is
+    /// called for them, relays aren't considered to be in any.
+    pub fn set_family(&mut self, node_id: [u8; 20], family: Vec<[u8; 20]>) {
+        self.families.insert(node_id, family);
+    }
+
+    /// Returns true if `a` and `b` are in the same family. As in tor, that takes both relays
+    /// listing the other, since anyone can claim to be in a family with anyone.
+    fn same_family(&self, a: &PreTorPeer, b: &PreTorPeer) -> bool {
+        let lists = |node: &PreTorPeer, other: &PreTorPeer| match self.families.get(&node.node_id) {
+            Some(family) => family.contains(&other.node_id),
+            None => false,
+        };
+        lists(a, b) && lists(b, a)
+    }
+
+    /// Returns when this consensus stops being valid, in seconds since the epoch.
+    pub fn get_valid_until(&self) -> Option<u64> {
+        self.valid_until
+    }
+
+    /// Returns whether this consensus is still valid (i.e. it's before its valid-until time). A
+    /// consensus without a (parseable) valid-until is never considered fresh.
+    pub fn is_fresh(&self) -> bool {
+        let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(now) => now.as_secs(),
+            Err(_) => return false,
+        };
+        match self.valid_until {
+            Some(valid_until) => now < valid_until,
+            None => false,
         }
     }
 
+    /// The bandwidth weights from the consensus footer.
+    pub fn get_bandwidth_weights(&self) -> &BandwidthWeights {
+        &self.bandwidth_weights
+    }
+
+    // Like tor does for general-purpose circuits, only nodes with the Fast flag are selected for
+    // any position.
+    pub fn get_guard_node(&self) -> Option<&PreTorPeer> {
+        self.get_guard_node_excluding(&[])
+    }
+
+    /// Like `get_guard_node`, but won't pick anything in `blacklist` (see `get_interior_node`).
+    pub fn get_guard_node_excluding(&self, blacklist: &[&PreTorPeer]) -> Option<&PreTorPeer> {
+        self.choose_node(Position::Guard, blacklist, &mut thread_rng())
+    }
+
+    /// Picks a middle node, weighted by bandwidth. Nothing in `blacklist`, in the same /16 as
+    /// anything in `blacklist`, or in the same family (see `set_family`) as anything in
+    /// `blacklist`, will be picked.
     pub fn get_interior_node(&self, blacklist: &[&PreTorPeer]) -> Option<&PreTorPeer> {
-        self.peers.iter().find(|node| node.is_usable && node.not_in(blacklist))
+        self.choose_node(Position::Middle, blacklist, &mut thread_rng())
     }
 
     pub fn get_exit_node(&self, blacklist: &[&PreTorPeer]) -> Option<&PreTorPeer> {
-        self.peers.iter().find(|node| node.is_usable && node.is_exit && node.not_in(blacklist))
+        self.choose_node(Position::Exit, blacklist, &mut thread_rng())
+    }
+
+    /// Picks a node for `position` that isn't excluded by `blacklist` (see `get_interior_node`),
+    /// weighted by bandwidth.
+    fn choose_node<R: Rng>(
+        &self,
+        position: Position,
+        blacklist: &[&PreTorPeer],
+        rng: &mut R,
+    ) -> Option<&PreTorPeer> {
+        let mut candidates: Vec<(&PreTorPeer, u64)> = self.peers
+            .iter()
+            .filter(|node| {
+                let suits_position = match position {
+                    Position::Guard => node.is_guard,
+                    Position::Middle => true,
+                    Position::Exit => node.is_exit,
+                };
+                node.is_usable && node.is_fast && suits_position && node.not_in(blacklist)
+                    && !blacklist.iter().any(|peer| self.same_family(node, peer))
+            })
+            .map(|node| (node, self.bandwidth_weights.weigh(node, position)))
+            .collect();
+        // `peers` iterates in a different order every time, so put the candidates in a fixed one
+        // (which makes the choice depend only on `rng`).
+        candidates.sort_by_key(|&(node, _)| node.node_id);
+        weighted_choice(candidates, rng)
+    }
+
+    /// Picks a path of `len` nodes (at least 2): a guard, `len - 2` middle nodes, and an exit, in
+    /// that order, each weighted by bandwidth and the consensus bandwidth weights. No two nodes
+    /// in the path are in the same /16 or in the same family (as far as `set_family` has been
+    /// told). As in tor, the exit is picked first, since it's the most constrained. Returns
+    /// `None` if there aren't enough suitable nodes.
+    pub fn select_path(&self, len: usize) -> Option<Vec<&PreTorPeer>> {
+        self.select_path_with_rng(len, &mut thread_rng())
+    }
+
+    /// Like `select_path`, but with the given source of randomness.
+    pub fn select_path_with_rng<R: Rng>(
+        &self,
+        len: usize,
+        rng: &mut R,
+    ) -> Option<Vec<&PreTorPeer>> {
+        if len < 2 {
+            return None;
+        }
+        let exit = self.choose_node(Position::Exit, &[], rng)?;
+        let guard = self.choose_node(Position::Guard, &[exit], rng)?;
+        let mut chosen = vec![exit, guard];
+        let mut middles = Vec::new();
+        for _ in 0..len - 2 {
+            let middle = self.choose_node(Position::Middle, &chosen, rng)?;
+            chosen.push(middle);
+            middles.push(middle);
+        }
+        let mut path = vec![guard];
+        path.extend(middles);
+        path.push(exit);
+        Some(path)
+    }
+}
+
+/// Picks one of the given candidates at random, with probability proportional to its weight. If
+/// none of the candidates have any weight (e.g. a test network without bandwidth measurements),
+/// picks uniformly instead.
+fn weighted_choice<'a, R: Rng>(
+    candidates: Vec<(&'a PreTorPeer, u64)>,
+    rng: &mut R,
+) -> Option<&'a PreTorPeer> {
+    if candidates.len() == 0 {
+        return None;
+    }
+    // The weights come from the consensus, so don't trust them not to overflow.
+    let total = candidates
+        .iter()
+        .fold(0u64, |total, &(_, weight)| total.saturating_add(weight));
+    if total == 0 {
+        return Some(candidates[rng.gen_range(0, candidates.len())].0);
+    }
+    let mut choice = rng.gen_range(0, total);
+    for (node, weight) in candidates {
+        if choice < weight {
+            return Some(node);
+        }
+        choice -= weight;
+    }
+    None
+}
+
+/// The position in a circuit a node is being selected for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Position {
+    Guard,
+    Middle,
+    Exit,
+}
+
+/// The weight all the bandwidth weights are relative to (dir-spec.txt's "bwweightscale").
+const BANDWIDTH_WEIGHT_SCALE: u64 = 10000;
+
+/// The "bandwidth-weights" line from the consensus footer (dir-spec.txt section 3.4.3). These
+/// balance how often a node is selected for each position based on its flags. The naming is
+/// W<position><flags>, where position is g(uard), m(iddle), or e(xit), and flags is g(uard only),
+/// e(xit only), d(both), or m(neither).
+#[derive(Debug)]
+pub struct BandwidthWeights {
+    wgg: u64,
+    wgd: u64,
+    wmg: u64,
+    wmm: u64,
+    wme: u64,
+    wmd: u64,
+    wee: u64,
+    wed: u64,
+}
+
+impl BandwidthWeights {
+    /// Parses a "bandwidth-weights Wbd=... Wbe=..." line. Any weight that's missing or malformed
+    /// is left at the scale (i.e. 1.0).
+    pub fn new(line: &str) -> BandwidthWeights {
+        let mut weights = BandwidthWeights::default();
+        for item in line.split(" ").skip(1) {
+            let mut parts = item.splitn(2, "=");
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name, value),
+                _ => continue,
+            };
+            let value = match u64::from_str(value) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            match name {
+                "Wgg" => weights.wgg = value,
+                "Wgd" => weights.wgd = value,
+                "Wmg" => weights.wmg = value,
+                "Wmm" => weights.wmm = value,
+                "Wme" => weights.wme = value,
+                "Wmd" => weights.wmd = value,
+                "Wee" => weights.wee = value,
+                "Wed" => weights.wed = value,
+                _ => {}
+            }
+        }
+        weights
+    }
+
+    /// The weight to give `node` when choosing a node for `position` (its bandwidth scaled by the
+    /// weight appropriate for its flags).
+    fn weigh(&self, node: &PreTorPeer, position: Position) -> u64 {
+        let weight = match (position, node.is_guard, node.is_exit) {
+            (Position::Guard, true, true) => self.wgd,
+            (Position::Guard, true, false) => self.wgg,
+            (Position::Guard, false, _) => 0,
+            (Position::Middle, true, true) => self.wmd,
+            (Position::Middle, true, false) => self.wmg,
+            (Position::Middle, false, true) => self.wme,
+            (Position::Middle, false, false) => self.wmm,
+            (Position::Exit, true, true) => self.wed,
+            (Position::Exit, false, true) => self.wee,
+            (Position::Exit, _, false) => 0,
+        };
+        node.bandwidth.saturating_mul(weight)
+    }
+}
+
+/// Every weight at the scale (i.e. 1.0), for a consensus without a "bandwidth-weights" line.
+impl Default for BandwidthWeights {
+    fn default() -> BandwidthWeights {
+        BandwidthWeights {
+            wgg: BANDWIDTH_WEIGHT_SCALE,
+            wgd: BANDWIDTH_WEIGHT_SCALE,
+            wmg: BANDWIDTH_WEIGHT_SCALE,
+            wmm: BANDWIDTH_WEIGHT_SCALE,
+            wme: BANDWIDTH_WEIGHT_SCALE,
+            wmd: BANDWIDTH_WEIGHT_SCALE,
+            wee: BANDWIDTH_WEIGHT_SCALE,
+            wed: BANDWIDTH_WEIGHT_SCALE,
+        }
     }
 }
 
@@ -77,27 +313,133 @@ pub struct PreTorPeer {
     is_exit: bool,
     /// Is this a guard node?
     is_guard: bool,
+    /// Is this node fast enough to be used for general-purpose circuits?
+    is_fast: bool,
+    /// Is this node suitable for long-lived circuits?
+    is_stable: bool,
     /// Is this node running and valid?
     is_usable: bool,
+    /// The bandwidth from the "w Bandwidth=..." line, if any (0 otherwise).
+    bandwidth: u64,
+    /// The protocol versions from the "pr" line.
+    protocols: ProtocolVersions,
 }
 
 impl PreTorPeer {
-    fn new(router_line: &str, m_hash_line: &str, flags_line: &str) -> PreTorPeer {
-        let mut flags = flags_line.split(" ");
+    /// Parses a router status entry (the "r" line and everything up to the next one). Returns
+    /// `None` if the entry is missing its "r", "m", or "s" line, or if its "r" line is malformed.
+    fn from_entry(lines: &[&str]) -> Option<PreTorPeer> {
+        let router_line = lines.iter().find(|line| line.starts_with("r "));
+        let m_hash_line = lines.iter().find(|line| line.starts_with("m "));
+        let flags_line = lines.iter().find(|line| line.starts_with("s "));
+        let bandwidth_line = lines.iter().find(|line| line.starts_with("w "));
+        let protocols_line = lines.iter().find(|line| line.starts_with("pr "));
+        match (router_line, m_hash_line, flags_line) {
+            (Some(router_line), Some(m_hash_line), Some(flags_line)) => PreTorPeer::new(
+                router_line,
+                m_hash_line,
+                flags_line,
+                bandwidth_line.map(|line| *line),
+                protocols_line.map(|line| *line),
+            ),
+            _ => None,
+        }
+    }
+
+    fn new(
+        router_line: &str,
+        m_hash_line: &str,
+        flags_line: &str,
+        bandwidth_line: Option<&str>,
+        protocols_line: Option<&str>,
+    ) -> Option<PreTorPeer> {
+        let flags: Vec<&str> = flags_line.split(" ").collect();
+        // "r" SP nickname SP identity SP publication SP IP SP ORPort SP DirPort
+        // (the publication time is itself "YYYY-MM-DD HH:MM:SS", hence 8 parts)
         let router_parts: Vec<&str> = router_line.split(" ").collect();
-        let node_id: [u8; 20] =
-            util::slice_to_20_byte_array(&base64::decode(router_parts[2]).unwrap());
-        PreTorPeer {
-            mdesc_hash: m_hash_line.split(" ").nth(1).unwrap().to_owned(),
-            ip_address: router_parts[5].parse().unwrap(),
-            port: u16::from_str(router_parts[6]).unwrap(),
-            node_id: node_id,
-            is_exit: flags.find(|s| s == &"Exit").is_some(),
-            is_guard: flags.find(|s| s == &"Guard").is_some(),
-            is_usable: flags.find(|s| s == &"Running").is_some()
-                && flags.find(|s| s == &"Valid").is_some()
-                && flags.find(|s| s== &"Authority").is_none(),
+        if router_parts.len() < 8 {
+            return None;
         }
+        let node_id = match decode_unpadded_base64(router_parts[2]) {
+            Some(ref node_id) if node_id.len() == 20 => util::slice_to_20_byte_array(node_id),
+            _ => return None,
+        };
+        let ip_address = match router_parts[5].parse() {
+            Ok(ip_address) => ip_address,
+            Err(_) => return None,
+        };
+        let port = match u16::from_str(router_parts[6]) {
+            Ok(port) => port,
+            Err(_) => return None,
+        };
+        let mdesc_hash = match m_hash_line.split(" ").nth(1) {
+            Some(mdesc_hash) => mdesc_hash.to_owned(),
+            None => return None,
+        };
+        let mut bandwidth = 0;
+        if let Some(bandwidth_line) = bandwidth_line {
+            for item in bandwidth_line.split(" ") {
+                if item.starts_with("Bandwidth=") {
+                    bandwidth = u64::from_str(&item["Bandwidth=".len()..]).unwrap_or(0);
+                }
+            }
+        }
+        Some(PreTorPeer {
+            mdesc_hash: mdesc_hash,
+            ip_address: ip_address,
+            port: port,
+            node_id: node_id,
+            is_exit: flags.contains(&"Exit"),
+            is_guard: flags.contains(&"Guard"),
+            is_fast: flags.contains(&"Fast"),
+            is_stable: flags.contains(&"Stable"),
+            is_usable: flags.contains(&"Running")
+                && flags.contains(&"Valid")
+                && !flags.contains(&"Authority"),
+            bandwidth: bandwidth,
+            protocols: match protocols_line {
+                Some(protocols_line) => ProtocolVersions::new(protocols_line),
+                None => ProtocolVersions::default(),
+            },
+        })
+    }
+
+    /// The relay's RSA identity fingerprint (the SHA-1 hash of its identity key), as decoded
+    /// from the "r" line.
+    pub fn rsa_fingerprint(&self) -> [u8; 20] {
+        self.node_id
+    }
+
+    /// The relay's RSA identity fingerprint as uppercase hex, the way tor displays it.
+    pub fn fingerprint_hex(&self) -> String {
+        util::to_hex(&self.node_id).to_uppercase()
+    }
+
+    /// Returns whether the relay advertises support for the given version of the given
+    /// subprotocol (e.g. `supports("Relay", 4)`).
+    pub fn supports(&self, protocol: &str, version: u32) -> bool {
+        self.protocols.supports(protocol, version)
+    }
+
+    pub fn is_exit(&self) -> bool {
+        self.is_exit
+    }
+
+    pub fn is_guard(&self) -> bool {
+        self.is_guard
+    }
+
+    pub fn is_fast(&self) -> bool {
+        self.is_fast
+    }
+
+    pub fn is_stable(&self) -> bool {
+        self.is_stable
+    }
+
+    /// The bandwidth from the "w" line (0 if there wasn't one).
+    pub fn get_bandwidth(&self) -> u64 {
+        self.bandwidth
     }
 
     pub fn get_microdescriptor_uri(&self, hostport: &str) -> String {
@@ -118,48 +460,31 @@ impl PreTorPeer {
         if hashed_encoded != self.mdesc_hash {
             return Err(Error::new(ErrorKind::Other, "microdescriptor hash mismatch"));
         }
-
-        let mut ntor_onion_key: [u8; 32] = [0; 32];
-        let mut ed25519_id_key: [u8; 32] = [0; 32];
-        let mut in_rsa_key = false;
-        let mut rsa_public_key_base64 = String::new();
-        for line in microdescriptor.lines() {
-            if line == "-----END RSA PUBLIC KEY-----" {
-                in_rsa_key = false;
-            }
-            if in_rsa_key {
-                rsa_public_key_base64.push_str(line);
-            }
-            if line == "-----BEGIN RSA PUBLIC KEY-----" {
-                in_rsa_key = true;
-            }
-            if line.starts_with("ntor-onion-key") {
-                ntor_onion_key = util::slice_to_32_byte_array(&base64::decode(
-                    line.split(" ").nth(1).unwrap(),
-                ).unwrap());
-            }
-            if line.starts_with("id ed25519") {
-                ed25519_id_key = util::slice_to_32_byte_array(&base64::decode(
-                    line.split(" ").nth(2).unwrap(),
-                ).unwrap());
-            }
-        }
+        let microdescriptor = parse_microdescriptor(microdescriptor)?;
         Ok(TorPeer {
             ip_address: self.ip_address,
             port: self.port,
-            rsa_public_key: base64::decode(&rsa_public_key_base64).unwrap(),
-            ntor_onion_key: ntor_onion_key,
-            ed25519_id_key: ed25519_id_key,
+            additional_or_addresses: microdescriptor.or_addresses,
+            rsa_public_key: microdescriptor.onion_key,
+            ntor_onion_key: microdescriptor.ntor_onion_key,
+            ed25519_id_key: microdescriptor.ed25519_id_key,
             node_id: self.node_id,
+            protocols: self.protocols.clone(),
         })
     }
 
+    /// Returns true if this node isn't in `blacklist` and doesn't share a /16 with anything in it
+    /// (relays in the same /16 are likely run by the same operator or watched by the same
+    /// network).
     fn not_in(&self, blacklist: &[&PreTorPeer]) -> bool {
         for peer in blacklist {
             // TODO: something stronger than node_id?
             if self.node_id == peer.node_id {
                 return false;
             }
+            if self.ip_address.octets()[..2] == peer.ip_address.octets()[..2] {
+                return false;
+            }
         }
         true
     }
@@ -169,27 +494,64 @@ impl PreTorPeer {
 pub struct TorPeer {
     ip_address: Ipv4Addr,
     port: u16,
-    /// I don't know what this is for.
-    rsa_public_key: Vec<u8>,
+    /// Any other addresses the relay listens on (e.g. IPv6), from the microdescriptor.
+    additional_or_addresses: Vec<SocketAddr>,
+    /// The relay's RSA onion key (DER-encoded), used by the TAP handshake. Newer relays don't
+    /// have one.
+    rsa_public_key: Option<Vec<u8>>,
     /// Ntor handshake key, right?
     ntor_onion_key: [u8; 32],
     /// sha-1 hash of the peer's RSA ID key (not the above rsa_public_key)
     node_id: [u8; 20],
     /// Ed25519 identity public key
     ed25519_id_key: [u8; 32],
+    /// The protocol versions the relay advertised in the consensus.
+    protocols: ProtocolVersions,
 }
 
 impl TorPeer {
+    /// Returns whether the relay advertises support for the given version of the given
+    /// subprotocol (e.g. `supports("Relay", 4)`).
+    pub fn supports(&self, protocol: &str, version: u32) -> bool {
+        self.protocols.supports(protocol, version)
+    }
+
+    /// The parts of this peer needed to extend a circuit to it.
+    pub fn to_extend_target(&self) -> ExtendTarget {
+        ExtendTarget::new(
+            self.ip_address,
+            self.port,
+            self.node_id,
+            self.ed25519_id_key,
+            self.ntor_onion_key,
+        )
+    }
+
     /// Get the sha-1 hash of the node's RSA identity key. For use in the Ntor handshake.
     pub fn get_node_id(&self) -> [u8; 20] {
         self.node_id
     }
 
+    /// The relay's RSA identity fingerprint (the same as `get_node_id`).
+    pub fn rsa_fingerprint(&self) -> [u8; 20] {
+        self.node_id
+    }
+
+    /// The relay's RSA identity fingerprint as uppercase hex, the way tor displays it.
+    pub fn fingerprint_hex(&self) -> String {
+        util::to_hex(&self.node_id).to_uppercase()
+    }
+
     /// Get the node's public Ntor key. For use in the Ntor handshake.
     pub fn get_ntor_key(&self) -> [u8; 32] {
         self.ntor_onion_key
     }
 
+    /// Get the node's RSA onion key (DER-encoded), if it has one. For use in the TAP handshake.
+    pub fn get_tap_onion_key(&self) -> Option<&[u8]> {
+        self.rsa_public_key.as_ref().map(|key| &key[..])
+    }
+
     /// Get the node's public Ed25519 identity key. For use in the link handshake.
     pub fn get_ed25519_id_key(&self) -> [u8; 32] {
         self.ed25519_id_key
@@ -206,4 +568,734 @@ impl TorPeer {
     pub fn get_port(&self) -> u16 {
         self.port
     }
+
+    /// Addresses the relay listens on besides the one in the consensus (e.g. IPv6 ones).
+    pub fn get_additional_or_addresses(&self) -> &[SocketAddr] {
+        &self.additional_or_addresses
+    }
+}
+
+/// The parts of a relay's microdescriptor (dir-spec.txt section 3.3) needed to build circuits
+/// through it.
+#[derive(Debug)]
+pub struct Microdescriptor {
+    /// The RSA onion key (DER-encoded), from the "onion-key" PEM block. This is only needed for
+    /// TAP, so newer relays leave it out.
+    onion_key: Option<Vec<u8>>,
+    /// The curve25519 key from the "ntor-onion-key" line.
+    ntor_onion_key: [u8; 32],
+    /// The Ed25519 identity key from the "id ed25519" line.
+    ed25519_id_key: [u8; 32],
+    /// The addresses from any "a" lines.
+    or_addresses: Vec<SocketAddr>,
+    /// The node ids of the relays the "family" line lists.
+    family: Vec<[u8; 20]>,
+}
+
+impl Microdescriptor {
+    pub fn get_onion_key(&self) -> Option<&[u8]> {
+        self.onion_key.as_ref().map(|key| &key[..])
+    }
+
+    pub fn get_ntor_onion_key(&self) -> [u8; 32] {
+        self.ntor_onion_key
+    }
+
+    pub fn get_ed25519_id_key(&self) -> [u8; 32] {
+        self.ed25519_id_key
+    }
+
+    pub fn get_or_addresses(&self) -> &[SocketAddr] {
+        &self.or_addresses
+    }
+
+    /// The node ids of the relays this relay says are in its family (see
+    /// `TorPeerList::set_family`). Family members listed by nickname rather than by fingerprint
+    /// are left out, since nicknames aren't unique.
+    pub fn get_family(&self) -> &[[u8; 20]] {
+        &self.family
+    }
+}
+
+/// Parses a single microdescriptor. The "onion-key", "ntor-onion-key", and "id ed25519" items
+/// are required. This doesn't check the microdescriptor against its hash in the consensus (see
+/// `PreTorPeer::to_tor_peer`).
+pub fn parse_microdescriptor(text: &str) -> Result<Microdescriptor, Error> {
+    let mut onion_key_base64: Option<String> = None;
+    let mut ntor_onion_key: Option<[u8; 32]> = None;
+    let mut ed25519_id_key: Option<[u8; 32]> = None;
+    let mut or_addresses = Vec::new();
+    let mut family = Vec::new();
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        let mut parts = line.split(" ");
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("onion-key"), _, _) => {
+                if lines.next() != Some("-----BEGIN RSA PUBLIC KEY-----") {
+                    return Err(Error::new(ErrorKind::InvalidData, "malformed onion-key"));
+                }
+                let mut key_base64 = String::new();
+                loop {
+                    match lines.next() {
+                        Some("-----END RSA PUBLIC KEY-----") => break,
+                        Some(key_line) => key_base64.push_str(key_line),
+                        None => {
+                            return Err(Error::new(ErrorKind::InvalidData, "malformed onion-key"))
+                        }
+                    }
+                }
+                onion_key_base64 = Some(key_base64);
+            }
+            (Some("ntor-onion-key"), Some(key), _) => {
+                ntor_onion_key = match decode_unpadded_base64(key) {
+                    Some(ref key) if key.len() == 32 => Some(util::slice_to_32_byte_array(key)),
+                    _ => return Err(Error::new(ErrorKind::InvalidData, "bad ntor-onion-key")),
+                };
+            }
+            (Some("id"), Some("ed25519"), Some(key)) => {
+                ed25519_id_key = match decode_unpadded_base64(key) {
+                    Some(ref key) if key.len() == 32 => Some(util::slice_to_32_byte_array(key)),
+                    _ => return Err(Error::new(ErrorKind::InvalidData, "bad ed25519 id")),
+                };
+            }
+            (Some("a"), Some(address), _) => match SocketAddr::from_str(address) {
+                Ok(address) => or_addresses.push(address),
+                Err(_) => return Err(Error::new(ErrorKind::InvalidData, "bad \"a\" line")),
+            },
+            (Some("family"), _, _) => {
+                family = line.split(" ").skip(1).filter_map(parse_family_member).collect();
+            }
+            _ => {}
+        }
+    }
+    let onion_key = match onion_key_base64.map(|key| base64::decode(&key)) {
+        Some(Ok(onion_key)) => Some(onion_key),
+        Some(Err(_)) => return Err(Error::new(ErrorKind::InvalidData, "bad onion-key")),
+        None => None,
+    };
+    match (ntor_onion_key, ed25519_id_key) {
+        (Some(ntor_onion_key), Some(ed25519_id_key)) => Ok(Microdescriptor {
+            onion_key: onion_key,
+            ntor_onion_key: ntor_onion_key,
+            ed25519_id_key: ed25519_id_key,
+            or_addresses: or_addresses,
+            family: family,
+        }),
+        (None, _) => Err(Error::new(ErrorKind::InvalidData, "missing ntor-onion-key")),
+        (_, None) => Err(Error::new(ErrorKind::InvalidData, "missing id ed25519")),
+    }
+}
+
+/// Parses a "$" followed by a hex fingerprint (optionally followed by "~" or "=" and a nickname)
+/// from a "family" line into a node id. Returns `None` for anything else.
+fn parse_family_member(member: &str) -> Option<[u8; 20]> {
+    if !member.starts_with('$') || member.len() < 41 || !member.is_char_boundary(41) {
+        return None;
+    }
+    let hex = &member[1..41];
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    match member[41..].chars().next() {
+        None | Some('~') | Some('=') => {}
+        _ => return None,
+    }
+    let mut node_id = [0; 20];
+    for (i, byte) in node_id.iter_mut().enumerate() {
+        *byte = match u8::from_str_radix(&hex[2 * i..2 * i + 2], 16) {
+            Ok(byte) => byte,
+            Err(_) => return None,
+        };
+    }
+    Some(node_id)
+}
+
+/// Decodes base64 that may or may not have its trailing "=" padding (directory documents omit
+/// it for identities and digests).
+fn decode_unpadded_base64(encoded: &str) -> Option<Vec<u8>> {
+    base64::decode_config(encoded.trim_end_matches('='), base64::STANDARD_NO_PAD).ok()
+}
+
+/// The subprotocol versions a relay supports, from a "pr" line in the consensus (e.g.
+/// "pr Cons=1-2 Desc=1-2 Link=1-5 Relay=1-2"). See dir-spec.txt section 3.4.1.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ProtocolVersions {
+    /// (protocol name, lowest version, highest version) for each range listed.
+    ranges: Vec<(String, u32, u32)>,
+}
+
+impl ProtocolVersions {
+    /// Parses a "pr" line. Malformed entries are skipped.
+    pub fn new(line: &str) -> ProtocolVersions {
+        let mut ranges = Vec::new();
+        for entry in line.split(" ").skip(1) {
+            let mut parts = entry.splitn(2, "=");
+            let (name, versions) = match (parts.next(), parts.next()) {
+                (Some(name), Some(versions)) => (name, versions),
+                _ => continue,
+            };
+            for range in versions.split(",") {
+                let mut bounds = range.splitn(2, "-");
+                let low = match bounds.next().map(u32::from_str) {
+                    Some(Ok(low)) => low,
+                    _ => continue,
+                };
+                let high = match bounds.next().map(u32::from_str) {
+                    Some(Ok(high)) => high,
+                    Some(Err(_)) => continue,
+                    None => low,
+                };
+                ranges.push((name.to_owned(), low, high));
+            }
+        }
+        ProtocolVersions { ranges: ranges }
+    }
+
+    pub fn supports(&self, protocol: &str, version: u32) -> bool {
+        self.ranges
+            .iter()
+            .any(|&(ref name, low, high)| name == protocol && low <= version && version <= high)
+    }
+}
+
+/// Everything needed to extend a circuit to a relay, which doesn't have to come from the directory
+/// (e.g. for testing against a private relay that isn't in any consensus).
+#[derive(Clone, Debug)]
+pub struct ExtendTarget {
+    ip_address: Ipv4Addr,
+    port: u16,
+    /// sha-1 hash of the relay's RSA identity key
+    node_id: [u8; 20],
+    /// Ed25519 identity public key
+    ed25519_id_key: [u8; 32],
+    /// Ntor handshake key
+    ntor_onion_key: [u8; 32],
+}
+
+impl ExtendTarget {
+    pub fn new(
+        ip_address: Ipv4Addr,
+        port: u16,
+        node_id: [u8; 20],
+        ed25519_id_key: [u8; 32],
+        ntor_onion_key: [u8; 32],
+    ) -> ExtendTarget {
+        ExtendTarget {
+            ip_address: ip_address,
+            port: port,
+            node_id: node_id,
+            ed25519_id_key: ed25519_id_key,
+            ntor_onion_key: ntor_onion_key,
+        }
+    }
+
+    pub fn get_node_id(&self) -> [u8; 20] {
+        self.node_id
+    }
+
+    pub fn get_ntor_key(&self) -> [u8; 32] {
+        self.ntor_onion_key
+    }
+
+    pub fn get_ed25519_id_key(&self) -> [u8; 32] {
+        self.ed25519_id_key
+    }
+
+    pub fn get_ipv4_as_bytes(&self) -> [u8; 4] {
+        self.ip_address.octets()
+    }
+
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+}
+
+/// Directory ports of the directory authorities, used to bootstrap when we don't have a consensus
+/// yet. (A real client would prefer the much longer list of fallback directory mirrors, so as not
+/// to load the authorities.)
+const DIRECTORY_AUTHORITIES: &'static [&'static str] = &[
+    "128.31.0.39:9231",    // moria1
+    "217.196.147.77:80",   // tor26
+    "45.66.35.11:80",      // dizum
+    "131.188.40.189:80",   // gabelmoo
+    "193.23.244.244:80",   // dannenberg
+    "171.25.193.9:443",    // maatuska
+    "216.218.219.41:80",   // Faravahar
+    "199.58.81.140:80",    // longclaw
+    "204.13.164.118:80",   // bastet
+];
+
+fn directory_authorities() -> Vec<SocketAddr> {
+    DIRECTORY_AUTHORITIES
+        .iter()
+        .map(|addr| addr.parse().expect("DIRECTORY_AUTHORITIES should all be valid"))
+        .collect()
+}
+
+/// Picks one of the directory authorities at random.
+pub fn pick_directory_authority<R: Rng>(rng: &mut R) -> SocketAddr {
+    let dirs = directory_authorities();
+    dirs[rng.gen_range(0, dirs.len())]
+}
+
+/// Fetches `request` from the directory authorities, trying them in a random order so that no one
+/// server is a single point of failure (see `fetch`).
+pub fn fetch_from_directory_authorities(
+    request: &DirRequest,
+    timeout: Duration,
+) -> Result<String, Error> {
+    let mut dirs = directory_authorities();
+    thread_rng().shuffle(&mut dirs);
+    fetch(&dirs, request, timeout)
+}
+
+/// An HTTP request for a directory document.
+#[derive(Clone, Debug)]
+pub struct DirRequest {
+    /// The resource to fetch, e.g. "/tor/status-vote/current/consensus-microdesc" or
+    /// "/tor/micro/d/<digest>".
+    pub path: String,
+    /// If set, the value of the Accept-Encoding header (e.g. "identity" to ask for the document
+    /// uncompressed, or "deflate, identity" to let the server compress it). If not set, the header
+    /// is omitted. `parse_http_response` can only undo "deflate".
+    pub accept_encoding: Option<String>,
+}
+
+impl DirRequest {
+    pub fn new(path: &str) -> DirRequest {
+        DirRequest {
+            path: path.to_owned(),
+            accept_encoding: None,
+        }
+    }
+
+    /// A request for the current microdescriptor consensus, compressed with deflate if the server
+    /// is willing (it's several megabytes otherwise).
+    pub fn consensus() -> DirRequest {
+        DirRequest::new("/tor/status-vote/current/consensus-microdesc")
+            .with_accept_encoding("deflate, identity")
+    }
+
+    pub fn with_accept_encoding(mut self, accept_encoding: &str) -> DirRequest {
+        self.accept_encoding = Some(accept_encoding.to_owned());
+        self
+    }
+
+    /// Serializes this request as HTTP/1.0 with the given Host header.
+    pub fn to_http(&self, host: &str) -> String {
+        let mut request = format!("GET {} HTTP/1.0\r\nHost: {}\r\n", self.path, host);
+        if let Some(ref accept_encoding) = self.accept_encoding {
+            request.push_str(&format!("Accept-Encoding: {}\r\n", accept_encoding));
+        }
+        request.push_str("\r\n");
+        request
+    }
+}
+
+/// Fetches `request` over plain HTTP from the given directory servers, trying each in turn until
+/// one succeeds. Each attempt (connecting, writing the request, and reading the response) is
+/// bounded by `timeout`, so a hung server only delays bootstrapping rather than blocking it. If
+/// every server fails, the returned error lists what went wrong with each of them.
+pub fn fetch(
+    addrs: &[SocketAddr],
+    request: &DirRequest,
+    timeout: Duration,
+) -> Result<String, Error> {
+    let mut failures: Vec<String> = Vec::new();
+    for addr in addrs {
+        match fetch_from(addr, request, timeout) {
+            Ok(body) => return Ok(body),
+            Err(e) => {
+                println!("fetching {} from {} failed: {}", request.path, addr, e);
+                failures.push(format!("{}: {}", addr, e));
+            }
+        }
+    }
+    if failures.len() == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "no directory servers given"));
+    }
+    Err(Error::new(
+        ErrorKind::Other,
+        format!("all directory servers failed ({})", failures.join("; ")),
+    ))
+}
+
+fn fetch_from(
+    addr: &SocketAddr,
+    request: &DirRequest,
+    timeout: Duration,
+) -> Result<String, Error> {
+    let mut stream = TcpStream::connect_timeout(addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.write_all(request.to_http(&addr.to_string()).as_bytes())?;
+    let mut response: Vec<u8> = Vec::new();
+    stream.read_to_end(&mut response)?;
+    parse_http_response(response)
+}
+
+/// Checks that `response` is a successful HTTP response and returns its body, decompressing it if
+/// the server sent it with "Content-Encoding: deflate" (which in tor's case means zlib). This is
+/// shared between fetching over plain HTTP and fetching over a BEGIN_DIR stream.
+pub fn parse_http_response(response: Vec<u8>) -> Result<String, Error> {
+    let index = match response.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(index) => index,
+        None => return Err(Error::new(ErrorKind::InvalidData, "malformed HTTP response")),
+    };
+    let head = match String::from_utf8(response[..index].to_vec()) {
+        Ok(head) => head,
+        Err(_) => return Err(Error::new(ErrorKind::InvalidData, "HTTP headers not valid UTF-8")),
+    };
+    let body = &response[index + 4..];
+    let mut lines = head.lines();
+    let status_line = lines.next().unwrap_or("");
+    if status_line.split(" ").nth(1) != Some("200") {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("unexpected HTTP status: {}", status_line),
+        ));
+    }
+    let mut content_encoding = "identity".to_owned();
+    for line in lines {
+        let mut parts = line.splitn(2, ":");
+        let name = parts.next().unwrap_or("");
+        if name.eq_ignore_ascii_case("Content-Encoding") {
+            content_encoding = parts.next().unwrap_or("").trim().to_lowercase();
+        }
+    }
+    let body = match content_encoding.as_str() {
+        "identity" => body.to_vec(),
+        "deflate" => {
+            let mut decompressed = Vec::new();
+            ZlibDecoder::new(body).read_to_end(&mut decompressed)?;
+            decompressed
+        }
+        _ => {
+            let msg = format!("unsupported Content-Encoding: {}", content_encoding);
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+    };
+    match String::from_utf8(body) {
+        Ok(body) => Ok(body),
+        Err(_) => Err(Error::new(ErrorKind::InvalidData, "response not valid UTF-8")),
+    }
+}
+
+/// Fetches the current microdescriptor consensus from the given directory servers (see `fetch`)
+/// and parses it. Over a circuit, use `Circuit::poll_dir_fetch` with `DirRequest::consensus()`
+/// instead.
+pub fn fetch_consensus(addrs: &[SocketAddr], timeout: Duration) -> Result<TorPeerList, Error> {
+    let consensus = fetch(addrs, &DirRequest::consensus(), timeout)?;
+    Ok(TorPeerList::new(&consensus))
+}
+
+/// A copy of the most recently fetched consensus, kept in a file.
+pub struct ConsensusCache {
+    path: PathBuf,
+}
+
+impl ConsensusCache {
+    pub fn new<P: Into<PathBuf>>(path: P) -> ConsensusCache {
+        ConsensusCache { path: path.into() }
+    }
+
+    /// Returns the cached consensus, if there is one.
+    pub fn load(&self) -> Option<String> {
+        let mut file = File::open(&self.path).ok()?;
+        let mut consensus = String::new();
+        file.read_to_string(&mut consensus).ok()?;
+        Some(consensus)
+    }
+
+    pub fn store(&self, consensus: &str) -> Result<(), Error> {
+        let mut file = File::create(&self.path)?;
+        file.write_all(consensus.as_bytes())
+    }
+}
+
+/// Returns the cached consensus if it's still valid, and otherwise fetches a new one from the
+/// directory authorities and caches it. Using a stale consensus risks picking relays that have
+/// since rotated their keys (so handshakes with them would fail).
+pub fn ensure_fresh_consensus(
+    cache: &ConsensusCache,
+    timeout: Duration,
+) -> Result<TorPeerList, Error> {
+    match cache.load() {
+        Some(consensus) => {
+            let peers = TorPeerList::new(&consensus);
+            if peers.is_fresh() {
+                return Ok(peers);
+            }
+            println!("cached consensus has expired - fetching a new one");
+        }
+        None => println!("no cached consensus - fetching one"),
+    }
+    let consensus = fetch_from_directory_authorities(&DirRequest::consensus(), timeout)?;
+    let peers = TorPeerList::new(&consensus);
+    if !peers.is_fresh() {
+        return Err(Error::new(ErrorKind::InvalidData, "fetched consensus isn't valid"));
+    }
+    cache.store(&consensus)?;
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+    use rand::{SeedableRng, XorShiftRng};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_directory_authorities() {
+        let dirs = directory_authorities();
+        assert_eq!(dirs.len(), DIRECTORY_AUTHORITIES.len());
+        assert!(dirs.contains(&"128.31.0.39:9231".parse().unwrap()));
+        assert!(dirs.contains(&"217.196.147.77:80".parse().unwrap()));
+    }
+
+    const TEST_CONSENSUS: &'static str = "network-status-version 3 microdesc
+valid-until 2049-01-01 00:00:00
+r relay1 AAAAAAAAAAAAAAAAAAAAAAAAAAA 2018-01-01 00:00:00 10.0.0.1 9001 0
+m 1111111111111111111111111111111111111111111
+s Fast Guard Running Stable Valid
+w Bandwidth=100
+r relay2 AQEBAQEBAQEBAQEBAQEBAQEBAQE 2018-01-01 00:00:00 10.1.0.1 9001 0
+m 2222222222222222222222222222222222222222222
+s Exit Fast Running Valid
+w Bandwidth=200
+directory-footer
+bandwidth-weights Wgg=10000 Wee=10000
+";
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn http_response(headers: &str, body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.0 200 OK\r\n{}\r\n", headers).into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    #[test]
+    fn test_parse_http_response() {
+        let response = http_response("Content-Type: text/plain\r\n", b"hello");
+        assert_eq!(parse_http_response(response).unwrap(), "hello");
+        let response = http_response("Content-Encoding: deflate\r\n", &deflate(b"hello"));
+        assert_eq!(parse_http_response(response).unwrap(), "hello");
+        let response = http_response("Content-Encoding: x-zstd\r\n", b"hello");
+        assert!(parse_http_response(response).is_err());
+        let response = b"HTTP/1.0 404 Not found\r\n\r\n".to_vec();
+        assert!(parse_http_response(response).is_err());
+        assert!(parse_http_response(b"HTTP/1.0 200 OK\r\n".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_consensus_fixture() {
+        let peers = TorPeerList::new(TEST_CONSENSUS);
+        assert_eq!(peers.peers.len(), 2);
+        // valid-until 2049-01-01 00:00:00
+        assert_eq!(peers.get_valid_until(), Some(2493072000));
+        assert!(peers.is_fresh());
+    }
+
+    #[test]
+    fn test_consensus_bad_valid_until() {
+        for valid_until in &["2049-02-30 00:00:00", "2049-01-01", "soon"] {
+            let consensus = TEST_CONSENSUS.replace(
+                "valid-until 2049-01-01 00:00:00",
+                &format!("valid-until {}", valid_until),
+            );
+            let peers = TorPeerList::new(&consensus);
+            assert_eq!(peers.get_valid_until(), None);
+            assert!(!peers.is_fresh());
+        }
+    }
+
+    /// Serves one HTTP request with `response`, returning the request that was sent.
+    fn serve_once(listener: TcpListener, response: Vec<u8>) -> thread::JoinHandle<String> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let len = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            stream.write_all(&response).unwrap();
+            String::from_utf8(request).unwrap()
+        })
+    }
+
+    #[test]
+    fn test_fetch_consensus() {
+        // Nothing is listening on the first address, so `fetch_consensus` has to move on to the
+        // second.
+        let closed_addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let response = http_response(
+            "Content-Encoding: deflate\r\n",
+            &deflate(TEST_CONSENSUS.as_bytes()),
+        );
+        let server = serve_once(listener, response);
+        let peers = fetch_consensus(&[closed_addr, addr], Duration::from_secs(10)).unwrap();
+        assert_eq!(peers.peers.len(), 2);
+        let request = server.join().unwrap();
+        assert!(request.starts_with("GET /tor/status-vote/current/consensus-microdesc HTTP/1.0"));
+        assert!(request.contains("Accept-Encoding: deflate, identity\r\n"));
+    }
+
+    /// Guards 1 and 2, middles 3 and 4 (4 shares a /16 with 1), and exits 5 and 6.
+    const PATH_CONSENSUS: &'static str = "network-status-version 3 microdesc
+valid-until 2049-01-01 00:00:00
+r relay1 AQEBAQEBAQEBAQEBAQEBAQEBAQE 2018-01-01 00:00:00 10.1.0.1 9001 0
+m 1111111111111111111111111111111111111111111
+s Fast Guard Running Stable Valid
+w Bandwidth=100
+r relay2 AgICAgICAgICAgICAgICAgICAgI 2018-01-01 00:00:00 10.2.0.1 9001 0
+m 2222222222222222222222222222222222222222222
+s Fast Guard Running Stable Valid
+w Bandwidth=100
+r relay3 AwMDAwMDAwMDAwMDAwMDAwMDAwM 2018-01-01 00:00:00 10.3.0.1 9001 0
+m 3333333333333333333333333333333333333333333
+s Fast Running Valid
+w Bandwidth=100
+r relay4 BAQEBAQEBAQEBAQEBAQEBAQEBAQ 2018-01-01 00:00:00 10.1.0.2 9001 0
+m 4444444444444444444444444444444444444444444
+s Fast Running Valid
+w Bandwidth=100
+r relay5 BQUFBQUFBQUFBQUFBQUFBQUFBQU 2018-01-01 00:00:00 10.5.0.1 9001 0
+m 5555555555555555555555555555555555555555555
+s Exit Fast Running Valid
+w Bandwidth=300
+r relay6 BgYGBgYGBgYGBgYGBgYGBgYGBgY 2018-01-01 00:00:00 10.6.0.1 9001 0
+m 6666666666666666666666666666666666666666666
+s Exit Fast Running Valid
+w Bandwidth=100
+directory-footer
+bandwidth-weights Wgg=10000 Wee=10000 Wmg=10000 Wmm=10000 Wme=10000
+";
+
+    fn path_ids(path: &[&PreTorPeer]) -> Vec<u8> {
+        path.iter().map(|node| node.node_id[0]).collect()
+    }
+
+    #[test]
+    fn test_select_path_with_rng() {
+        let mut peers = TorPeerList::new(PATH_CONSENSUS);
+        // 1 and 5 are a family. 2 claims 6, but 6 doesn't claim 2 back, so they aren't.
+        peers.set_family([1; 20], vec![[5; 20]]);
+        peers.set_family([5; 20], vec![[1; 20]]);
+        peers.set_family([2; 20], vec![[6; 20]]);
+
+        let first = peers.select_path_with_rng(3, &mut XorShiftRng::from_seed([1, 2, 3, 4]));
+        let second = peers.select_path_with_rng(3, &mut XorShiftRng::from_seed([1, 2, 3, 4]));
+        assert_eq!(path_ids(&first.unwrap()), path_ids(&second.unwrap()));
+
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let mut exit5_count = 0;
+        let mut saw_2_and_6 = false;
+        for _ in 0..1000 {
+            let path = path_ids(&peers.select_path_with_rng(3, &mut rng).unwrap());
+            assert_eq!(path.len(), 3);
+            assert!(path[0] == 1 || path[0] == 2);
+            assert!(path[2] == 5 || path[2] == 6);
+            // Same /16.
+            assert!(!(path.contains(&1) && path.contains(&4)));
+            // Same family.
+            assert!(!(path.contains(&1) && path.contains(&5)));
+            if path[2] == 5 {
+                exit5_count += 1;
+            }
+            if path.contains(&2) && path.contains(&6) {
+                saw_2_and_6 = true;
+            }
+        }
+        // Exit 5 has three times the bandwidth of exit 6.
+        assert!(exit5_count > 650 && exit5_count < 850);
+        assert!(saw_2_and_6);
+    }
+
+    #[test]
+    fn test_select_path_huge_bandwidths() {
+        // Bandwidths and weights this big overflow when multiplied and summed.
+        let consensus = PATH_CONSENSUS
+            .replace("Bandwidth=100", "Bandwidth=18446744073709551615")
+            .replace("=10000", "=18446744073709551615");
+        let peers = TorPeerList::new(&consensus);
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        for _ in 0..100 {
+            assert_eq!(peers.select_path_with_rng(3, &mut rng).unwrap().len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_select_path_runs_out_of_nodes() {
+        let peers = TorPeerList::new(PATH_CONSENSUS);
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        assert!(peers.select_path_with_rng(1, &mut rng).is_none());
+        // There are only five distinct /16s to go around.
+        assert!(peers.select_path_with_rng(6, &mut rng).is_none());
+    }
+
+    #[test]
+    fn test_parse_microdescriptor_family() {
+        let text = "onion-key
+-----BEGIN RSA PUBLIC KEY-----
+AAAA
+-----END RSA PUBLIC KEY-----
+ntor-onion-key AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA
+family $0101010101010101010101010101010101010101 nickname \
+$0202020202020202020202020202020202020202~relay2 $03030303030303030303030303030303030303=relay3 \
+$zz04040404040404040404040404040404040404
+id ed25519 AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA
+";
+        let microdescriptor = parse_microdescriptor(text).unwrap();
+        assert_eq!(microdescriptor.get_family(), &[[1; 20], [2; 20]]);
+    }
+
+    /// A microdescriptor with no "onion-key", as newer relays publish.
+    const NTOR_ONLY_MICRODESCRIPTOR: &'static str = "ntor-onion-key \
+AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE
+a [2001:db8::1]:9001
+id ed25519 AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI
+";
+
+    #[test]
+    fn test_parse_microdescriptor_onion_key_optional() {
+        let microdescriptor = parse_microdescriptor(NTOR_ONLY_MICRODESCRIPTOR).unwrap();
+        assert_eq!(microdescriptor.get_onion_key(), None);
+        assert_eq!(microdescriptor.get_ntor_onion_key(), [1; 32]);
+        assert_eq!(microdescriptor.get_ed25519_id_key(), [2; 32]);
+        let with_onion_key = format!(
+            "onion-key\n-----BEGIN RSA PUBLIC KEY-----\nAQID\n-----END RSA PUBLIC KEY-----\n{}",
+            NTOR_ONLY_MICRODESCRIPTOR
+        );
+        let microdescriptor = parse_microdescriptor(&with_onion_key).unwrap();
+        assert_eq!(microdescriptor.get_onion_key(), Some(&[1, 2, 3][..]));
+        // An "onion-key" that's there but isn't base64 is still an error.
+        let bad_onion_key = with_onion_key.replace("AQID", "!!!!");
+        assert!(parse_microdescriptor(&bad_onion_key).is_err());
+    }
+
+    #[test]
+    fn test_to_tor_peer_without_onion_key() {
+        let hash = Sha256::digest(NTOR_ONLY_MICRODESCRIPTOR.as_bytes());
+        let consensus = TEST_CONSENSUS.replace(
+            "1111111111111111111111111111111111111111111",
+            &base64::encode_config(&hash, base64::STANDARD_NO_PAD),
+        );
+        let peers = TorPeerList::new(&consensus);
+        let pre_peer = peers.peers.iter().find(|peer| peer.node_id == [0; 20]).unwrap();
+        let peer = pre_peer.to_tor_peer(NTOR_ONLY_MICRODESCRIPTOR).unwrap();
+        assert_eq!(peer.get_tap_onion_key(), None);
+        assert_eq!(peer.get_ntor_key(), [1; 32]);
+        assert_eq!(peer.get_additional_or_addresses().len(), 1);
+    }
 }
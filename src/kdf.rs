@@ -0,0 +1,136 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// The length of the output of HMAC-SHA256.
+const HASH_LEN: usize = 32;
+
+/// HMAC-SHA256 of the concatenation of `data` under `key`.
+pub fn hmac_sha256(key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new(key).unwrap();
+    for part in data {
+        mac.input(part);
+    }
+    mac.result().code().as_slice().to_vec()
+}
+
+/// HKDF-Extract from RFC 5869, using HMAC-SHA256. Note that the salt is used as the HMAC key.
+pub fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+    hmac_sha256(salt, &[ikm])
+}
+
+/// HKDF-Expand from RFC 5869, using HMAC-SHA256:
+/// T(1) = HMAC(prk, info | 0x01)
+/// T(n) = HMAC(prk, T(n-1) | info | n)
+/// The output is the first `out_len` bytes of T(1) | T(2) | ... `out_len` can be at most
+/// 255 * 32 bytes.
+pub fn hkdf_expand(prk: &[u8], info: &[u8], out_len: usize) -> Vec<u8> {
+    assert!(out_len <= 255 * HASH_LEN);
+    let mut output: Vec<u8> = Vec::with_capacity(out_len);
+    let mut previous: Vec<u8> = Vec::new();
+    let mut counter: u8 = 1;
+    while output.len() < out_len {
+        previous = hmac_sha256(prk, &[&previous[..], info, &[counter][..]]);
+        let needed = out_len - output.len();
+        output.extend(previous.iter().take(needed));
+        counter = counter.wrapping_add(1);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util;
+
+    /// Runs HKDF-Extract and HKDF-Expand, returning the hex of PRK and OKM.
+    fn hkdf(ikm: &[u8], salt: &[u8], info: &[u8], out_len: usize) -> (String, String) {
+        let prk = hkdf_extract(salt, ikm);
+        let okm = hkdf_expand(&prk, info, out_len);
+        (util::to_hex(&prk), util::to_hex(&okm))
+    }
+
+    // The test vectors are from RFC 5869 appendix A.
+
+    #[test]
+    fn test_hkdf_rfc5869_a1() {
+        let ikm = [0x0b; 22];
+        let salt: Vec<u8> = (0x00..0x0d).collect();
+        let info: Vec<u8> = (0xf0..0xfa).collect();
+        let (prk, okm) = hkdf(&ikm, &salt, &info, 42);
+        assert_eq!(prk, "077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5");
+        assert_eq!(
+            okm,
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865"
+        );
+    }
+
+    #[test]
+    fn test_hkdf_rfc5869_a2() {
+        let ikm: Vec<u8> = (0x00..0x50).collect();
+        let salt: Vec<u8> = (0x60..0xb0).collect();
+        let info: Vec<u8> = (0xb0..0x100).map(|b: u16| b as u8).collect();
+        let (prk, okm) = hkdf(&ikm, &salt, &info, 82);
+        assert_eq!(prk, "06a6b88c5853361a06104c9ceb35b45cef760014904671014a193f40c15fc244");
+        assert_eq!(
+            okm,
+            concat!(
+                "b11e398dc80327a1c8e7f78c596a49344f012eda2d4efad8a050cc4c19afa97c",
+                "59045a99cac7827271cb41c65e590e09da3275600c2f09b8367793a9aca3db71",
+                "cc30c58179ec3e87c14c01d5c1f3434f1d87"
+            )
+        );
+    }
+
+    #[test]
+    fn test_hkdf_rfc5869_a3() {
+        // An empty salt is the same as HASH_LEN zero bytes, since HMAC pads its key with zeroes.
+        let ikm = [0x0b; 22];
+        let (prk, okm) = hkdf(&ikm, &[], &[], 42);
+        assert_eq!(prk, "19ef24a32c717b167f33a91d6f648bdf96596776afdb6377ac434c1c293ccb04");
+        assert_eq!(
+            okm,
+            "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d9d201395faa4b61a96c8"
+        );
+    }
+
+    #[test]
+    fn test_hmac_sha256_parts() {
+        // RFC 4231 test case 2, with the data split into parts (including an empty one).
+        let expected = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+        let data = b"what do ya want for nothing?";
+        assert_eq!(util::to_hex(&hmac_sha256(b"Jefe", &[data])), expected);
+        let parts: [&[u8]; 4] = [&data[..4], &[], &data[4..20], &data[20..]];
+        assert_eq!(util::to_hex(&hmac_sha256(b"Jefe", &parts)), expected);
+    }
+
+    #[test]
+    fn test_hkdf_expand_blocks() {
+        let prk = [0x42; HASH_LEN];
+        let info = b"info";
+        let longest = hkdf_expand(&prk, info, 255 * HASH_LEN);
+        assert_eq!(longest.len(), 255 * HASH_LEN);
+        // Asking for less just truncates, however the length falls relative to the blocks.
+        for &out_len in &[0, 1, 31, 32, 33, 64, 65, 100, 254 * HASH_LEN + 1] {
+            assert_eq!(hkdf_expand(&prk, info, out_len), &longest[..out_len]);
+        }
+        // Each block is chained from the one before it.
+        let t1 = hmac_sha256(&prk, &[info, &[1]]);
+        let t2 = hmac_sha256(&prk, &[&t1, info, &[2]]);
+        let t3 = hmac_sha256(&prk, &[&t2, info, &[3]]);
+        assert_eq!(&longest[..HASH_LEN], &t1[..]);
+        assert_eq!(&longest[HASH_LEN..2 * HASH_LEN], &t2[..]);
+        assert_eq!(&longest[2 * HASH_LEN..3 * HASH_LEN], &t3[..]);
+        // The counter goes up to 255 without wrapping around.
+        let mut t = Vec::new();
+        for counter in 1..256 {
+            t = hmac_sha256(&prk, &[&t, info, &[counter as u8]]);
+        }
+        assert_eq!(&longest[254 * HASH_LEN..], &t[..]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_hkdf_expand_too_long() {
+        hkdf_expand(&[0x42; HASH_LEN], b"info", 255 * HASH_LEN + 1);
+    }
+}
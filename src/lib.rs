@@ -4,37 +4,54 @@ extern crate constant_time_eq;
 extern crate crypto;
 extern crate curve25519_dalek;
 extern crate ed25519_dalek;
+extern crate flate2;
+#[cfg(feature = "async")]
+extern crate futures;
 extern crate hmac;
 extern crate num;
 extern crate rand;
 extern crate sha1;
 extern crate sha2;
+#[cfg(feature = "async")]
+extern crate tokio;
 
-mod certs;
+pub mod certs;
 pub mod dir;
-mod keys;
+mod kdf;
+pub mod keys;
+mod ntor3;
+pub mod onion;
+mod tap;
+#[cfg(feature = "async")]
+pub mod tokio_handshake;
 pub mod types;
 mod util;
 
-use constant_time_eq::constant_time_eq;
 use crypto::{aessafe, blockmodes};
 use crypto::symmetriccipher::SynchronousStreamCipher;
-use curve25519_dalek::montgomery;
-use curve25519_dalek::scalar;
-use hmac::{Hmac, Mac};
 use num::PrimInt;
 use rand::{OsRng, Rand, Rng};
 use sha1::Sha1;
 use sha2::{Digest, Sha256};
-use std::collections::{HashMap, HashSet};
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error;
+use std::fmt;
 use std::hash::Hash;
 use std::io::{Cursor, Error, ErrorKind, Seek, SeekFrom};
 use std::io::prelude::*;
-use std::ops::Mul;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub trait TlsImpl {
+    /// The sha-256 hash of the peer's TLS certificate (`get_peer_cert_der`).
     fn get_peer_cert_hash(&self) -> Result<[u8; 32], Error>;
+    /// The DER encoding of the peer's (leaf) TLS certificate. Implementations that can't get at
+    /// the certificate itself can leave this out.
+    fn get_peer_cert_der(&self) -> Result<Vec<u8>, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "getting the peer certificate is unsupported"))
+    }
     fn get_tls_secrets(&self, label: &str, context: &[u8]) -> Result<Vec<u8>, Error>;
 }
 
@@ -48,6 +65,12 @@ pub trait RsaSignerImpl {
     fn get_cert_bytes(&self) -> &[u8];
 }
 
+pub trait RsaEncryptorImpl {
+    /// Encrypts `data` with RSA-OAEP (SHA-1, MGF1) to the given public key (the DER encoding of a
+    /// PKCS #1 RSAPublicKey).
+    fn encrypt_oaep(&self, key: &[u8], data: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
 /// Generates unique IDs.
 pub struct IdTracker<T>
 where
@@ -84,11 +107,23 @@ where
             }
             retries -= 1;
         }
-        panic!("couldn't generate new circuit id. (maybe implement gc?)");
+        panic!("couldn't generate new id (too many in use - are they being released?)");
+    }
+
+    /// Makes an id available to be handed out again (e.g. when the circuit or stream it
+    /// identified is gone). Releasing an id that isn't in use does nothing.
+    pub fn release(&mut self, id: T) {
+        self.used_ids.remove(&id);
+    }
+
+    /// Records that an id that came from somewhere else is in use. Returns false if it already
+    /// was.
+    pub fn mark_used(&mut self, id: T) -> bool {
+        self.used_ids.insert(id)
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 enum CircuitState {
     NegotiateWriting,
     NegotiateReading,
@@ -103,6 +138,13 @@ enum CircuitState {
     Ready,
     Extend2Writing,
     Extended2Reading,
+    ExtendTruncatedReading,
+    ExtendBackoff,
+    CreateBackoff,
+    EstablishRendezvousWriting,
+    RendezvousEstablishedReading,
+    TruncateWriting,
+    TruncatedReading,
     Error,
 }
 
@@ -112,6 +154,106 @@ pub enum Async<T> {
     NotReady,
 }
 
+/// Errors that can happen while setting up a circuit.
+#[derive(Debug)]
+pub enum TorError {
+    /// Reading from or writing to the connection failed.
+    Io(Error),
+    /// The peer sent something we couldn't decode.
+    Parse(String),
+    /// The peer sent something that decoded fine but that we didn't expect or can't work with
+    /// (or, for messages starting with "library error", we got ourselves into a bad state).
+    Protocol(String),
+    /// A certificate, signature, or key exchange didn't check out.
+    Crypto(String),
+    /// The peer doesn't support any of the link protocol versions we do. These are the versions
+    /// it offered.
+    NoCommonVersion(Vec<u16>),
+    /// The peer closed the connection instead of sending NETINFO, which is how a responder
+    /// rejects our AUTHENTICATE cell.
+    AuthRejected,
+}
+
+impl fmt::Display for TorError {
+    fn fmt(&self, dest: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &TorError::Io(ref e) => write!(dest, "I/O error: {}", e),
+            &TorError::Parse(ref msg) => write!(dest, "parse error: {}", msg),
+            &TorError::Protocol(ref msg) => write!(dest, "protocol error: {}", msg),
+            &TorError::Crypto(ref msg) => write!(dest, "crypto error: {}", msg),
+            &TorError::NoCommonVersion(ref versions) => {
+                write!(dest, "no link protocol version in common (peer offered {:?})", versions)
+            }
+            &TorError::AuthRejected => {
+                write!(dest, "peer closed connection instead of sending NETINFO")
+            }
+        }
+    }
+}
+
+// The message comes from `Display`; only I/O errors have an underlying cause.
+impl error::Error for TorError {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match self {
+            &TorError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for TorError {
+    fn from(e: Error) -> TorError {
+        TorError::Io(e)
+    }
+}
+
+/// Why a circuit handshake (CREATE_FAST, TAP, or ntor) failed.
+#[derive(Debug, PartialEq)]
+pub enum HandshakeError {
+    /// The relay's half of the handshake couldn't be decoded.
+    Malformed,
+    /// The relay's KH (CREATE_FAST and TAP) didn't match ours, so it doesn't know the shared
+    /// secret.
+    KhMismatch,
+    /// The relay's AUTH (ntor) didn't match ours, so it isn't who we think it is.
+    AuthMismatch,
+    /// A relay's public key was zero or of small order (ntor), which would make the shared
+    /// secret predictable.
+    DegenerateKey,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, dest: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &HandshakeError::Malformed => write!(dest, "malformed server handshake"),
+            &HandshakeError::KhMismatch => write!(dest, "server's KH doesn't match"),
+            &HandshakeError::AuthMismatch => write!(dest, "server's AUTH doesn't match"),
+            &HandshakeError::DegenerateKey => write!(dest, "server's public key is degenerate"),
+        }
+    }
+}
+
+impl From<HandshakeError> for TorError {
+    fn from(e: HandshakeError) -> TorError {
+        TorError::Crypto(e.to_string())
+    }
+}
+
+/// So that callers (and the parts of this crate) still working in terms of `std::io::Error` can
+/// use `?` on a `TorError`.
+impl From<TorError> for Error {
+    fn from(e: TorError) -> Error {
+        match e {
+            TorError::Io(e) => e,
+            TorError::Parse(msg) => Error::new(ErrorKind::InvalidData, msg),
+            TorError::Protocol(msg) => Error::new(ErrorKind::Other, msg),
+            TorError::Crypto(msg) => Error::new(ErrorKind::InvalidData, msg),
+            e @ TorError::NoCommonVersion(_) => Error::new(ErrorKind::InvalidData, e.to_string()),
+            e @ TorError::AuthRejected => Error::new(ErrorKind::PermissionDenied, e.to_string()),
+        }
+    }
+}
+
 pub struct Circuit<T, V>
 where
     T: TlsImpl + Read + Write,
@@ -129,16 +271,45 @@ where
     circ_id: u32,
     /// The expected Ed25519 identity key from the peer.
     expected_ed25519_id_key: [u8; 32],
+    /// If set, the sha-256 hash the peer's TLS certificate must have.
+    pinned_peer_cert_hash: Option<[u8; 32]>,
+    /// If set, the certs to send in our CERTS cell instead of the ones from `initiator_certs`.
+    initiator_raw_certs: Option<Vec<types::RawCert>>,
     /// Maybe the certs parsed and validated from a peer's CERTS cell
     responder_certs: Option<ResponderCerts>,
+    /// Snapshot of the write digest taken right after sending our CERTS cell, for the CLOG field
+    /// of the AUTHENTICATE cell.
+    clog: Option<Vec<u8>>,
     /// Maybe the peer's OR address
     other_or_address: Option<types::OrAddress>,
+    /// The address (if any) to report as our own in our NETINFO cell.
+    reported_address: Option<types::OrAddress>,
+    /// The negotiated link protocol version (4 until negotiated - only VERSIONS cells, which have
+    /// their own framing, are exchanged before then).
+    link_version: u16,
+    /// Whether the link handshake completed (the responder accepted our AUTHENTICATE cell and
+    /// sent NETINFO, and we sent ours).
+    authenticated: bool,
     /// 20 byte random value for Tor KDF
     x: [u8; 20],
+    /// If set, the first hop is created with a TAP handshake (CREATE) instead of CREATE_FAST.
+    tap_handshake: Option<tap::TapClientHandshake>,
     /// Maybe Ntor client keypair for an in-progress extend.
     ntor_keypair: Option<keys::Curve25519Keypair>,
+    /// The handshake to use when extending the circuit.
+    extend_handshake_type: types::ClientHandshakeType,
+    /// Maybe the client side of an in-progress ntor v3 extend.
+    ntor3_handshake: Option<ntor3::Ntor3ClientHandshake>,
     /// Sequence of CircuitKeys for each hop in this circuit.
     circuit_keys: Vec<CircuitKeys>,
+    /// Whether to ask for congestion control in ntor v3 handshakes.
+    request_congestion_control: bool,
+    /// The SENDME increment each hop agreed to in its ntor v3 congestion control response (`None`
+    /// for hops that didn't negotiate congestion control).
+    congestion_control_sendme_incs: Vec<Option<u8>>,
+    /// Ed25519 identities of the hops we've extended to (the first hop's is
+    /// `expected_ed25519_id_key`).
+    extended_ed25519_ids: Vec<[u8; 32]>,
     /// Stream IDs that have been used
     used_stream_ids: IdTracker<u16>,
     /// How many times we've used RELAY_EARLY.
@@ -146,18 +317,97 @@ where
     /// Internal read buffer for when some data is available from the peer but not enough to
     /// complete the operation we're doing.
     buffer: Cursor<Vec<u8>>,
+    /// A relay cell (already encrypted) waiting to be queued by `poll_send_write_buffer`.
     write_buffer: Vec<u8>,
+    /// Encoded cells that have been queued to be sent (so the crypto, window, and SENDME state
+    /// already accounts for them) but that the connection hasn't taken yet. They must go out
+    /// before anything else, so everything we write goes through here (see `send_buffer`).
+    pending_output: Vec<u8>,
     /// Map of ids to currently-open `Stream`s.
     streams: HashMap<u16, Stream>,
-    /// After reading a RELAY_DATA cell, check this value. If it is 0, send a RELAY_SENDME cell and
-    /// reset this to 100. Decrement it for each RELAY_DATA cell read.
-    sendme_indicator: u8,
+    /// How many RELAY_DATA cells we've received on this circuit. We send a circuit-level SENDME
+    /// after every 100.
+    data_cells_received: u64,
     /// If this goes to 0, we can't send more RELAY_DATA cells until we receive a RELAY_SENDME.
     send_window: u16,
+    /// How many RELAY_DATA cells we've sent on this circuit.
+    data_cells_sent: u64,
+    /// The running forward digest after each RELAY_DATA cell that a circuit-level SENDME will
+    /// acknowledge (every 100th), oldest first. An authenticated SENDME has to echo the first.
+    expected_sendme_digests: VecDeque<[u8; 20]>,
     /// Buffered relay cells for streams that have yet to poll.
     buffered_relay_cells: Vec<types::RelayCell>,
+    /// If set, `tick` tears the circuit down after this long without any cells sent or received.
+    idle_timeout: Option<Duration>,
+    /// When we last sent or received a cell.
+    last_activity: Instant,
+    /// The most cells we'll process in one go in a loop that reads until some condition is met
+    /// (and the most DROP cells we'll skip in a row), so a hostile relay can't keep us spinning.
+    /// Reading loops that hit this after making progress return `Async::NotReady` and pick up where
+    /// they left off on the next poll.
+    max_cells_per_poll: usize,
+    /// How many DROP cells we've discarded since the last cell that wasn't one.
+    consecutive_drop_cells: usize,
+    /// How many times to retry a CREATE_FAST or an extend that fails in a way that may well be
+    /// transient (see `set_extend_retries`) before giving up.
+    max_extend_retries: u32,
+    /// How long to wait before the first retry. Each subsequent retry waits twice as long as the
+    /// previous one, up to `MAX_RETRY_DELAY_SECS`.
+    extend_retry_delay: Duration,
+    /// How many times the current CREATE_FAST or extend has been retried.
+    extend_retries: u32,
+    /// When the current CREATE_FAST or extend should next be retried.
+    extend_retry_at: Option<Instant>,
+    /// If set, how long to wait for EXTENDED2 before giving up on an extend.
+    extend_timeout: Option<Duration>,
+    /// Set when a hop truncates the circuit without our asking (see
+    /// `take_unexpected_truncation`).
+    unexpected_truncation: Option<types::DestroyReason>,
+    /// If set, how long `poll` may take to get the circuit to `Ready` before giving up.
+    handshake_timeout: Option<Duration>,
+    /// When the circuit was created (i.e. when the handshake started).
+    handshake_started: Instant,
+    /// How long each stage of building the circuit took.
+    build_timings: BuildTimings,
+    /// When the current stage of building the circuit (or the current extend) started.
+    stage_started: Instant,
+}
+
+/// How long each stage of building a circuit took (`None` for stages that haven't completed).
+#[derive(Clone, Debug, Default)]
+pub struct BuildTimings {
+    /// Sending and receiving VERSIONS.
+    pub versions: Option<Duration>,
+    /// Receiving and validating the responder's CERTS.
+    pub certs: Option<Duration>,
+    /// Receiving AUTH_CHALLENGE and sending our CERTS and AUTHENTICATE.
+    pub authenticate: Option<Duration>,
+    /// Receiving and sending NETINFO.
+    pub netinfo: Option<Duration>,
+    /// CREATE_FAST/CREATED_FAST.
+    pub create: Option<Duration>,
+    /// Each completed extend, in order.
+    pub extends: Vec<Duration>,
 }
 
+/// The most data `Circuit::poll_stream_buffer` will buffer for a stream.
+const MAX_STREAM_BUFFER: usize = 64 * 1024;
+
+/// The most RELAY_EARLY cells we may send on a circuit (tor-spec.txt section 5.6).
+const MAX_RELAY_EARLY: usize = 8;
+
+/// The link protocol versions we offer in our VERSIONS cell. Both have 4-byte circuit ids.
+/// (Version 5 adds link padding negotiation, which we don't use but which doesn't change anything
+/// else.) We don't offer version 3, which has 2-byte circuit ids, since ours are 4 bytes.
+const LINK_PROTOCOL_VERSIONS: [u16; 2] = [4, 5];
+
+/// The longest we'll wait before retrying a CREATE_FAST or an extend, however many times it's
+/// been retried already.
+const MAX_RETRY_DELAY_SECS: u64 = 60;
+
+/// The default for `Circuit::set_max_cells_per_poll`.
+const DEFAULT_MAX_CELLS_PER_POLL: usize = 1000;
+
 impl<T, V> Circuit<T, V>
 where
     T: TlsImpl + Read + Write,
@@ -177,24 +427,54 @@ where
             initiator_certs: InitiatorCerts::new(rsa_signer),
             circ_id,
             expected_ed25519_id_key,
+            pinned_peer_cert_hash: None,
+            initiator_raw_certs: None,
             responder_certs: None,
+            clog: None,
             other_or_address: None,
+            reported_address: None,
+            link_version: 4,
+            authenticated: false,
             // This gets filled in in `do_create_fast_write`.
             x: [0; 20],
+            tap_handshake: None,
             ntor_keypair: None,
+            extend_handshake_type: types::ClientHandshakeType::Ntor,
+            ntor3_handshake: None,
             circuit_keys: Vec::new(),
+            request_congestion_control: false,
+            congestion_control_sendme_incs: Vec::new(),
+            extended_ed25519_ids: Vec::new(),
             used_stream_ids: IdTracker::new(),
             relay_early_count: 0,
             buffer: Cursor::new(Vec::new()),
             write_buffer: Vec::new(),
+            pending_output: Vec::new(),
             streams: HashMap::new(),
-            sendme_indicator: 100,
+            data_cells_received: 0,
             send_window: 1000,
             buffered_relay_cells: Vec::new(),
+            max_cells_per_poll: DEFAULT_MAX_CELLS_PER_POLL,
+            consecutive_drop_cells: 0,
+            data_cells_sent: 0,
+            expected_sendme_digests: VecDeque::new(),
+            idle_timeout: None,
+            last_activity: Instant::now(),
+            max_extend_retries: 0,
+            extend_retry_delay: Duration::from_millis(500),
+            extend_retries: 0,
+            extend_retry_at: None,
+            extend_timeout: None,
+            unexpected_truncation: None,
+            handshake_timeout: None,
+            handshake_started: Instant::now(),
+            build_timings: BuildTimings::default(),
+            stage_started: Instant::now(),
         }
     }
 
-    pub fn poll(&mut self) -> Result<Async<()>, Error> {
+    pub fn poll(&mut self) -> Result<Async<()>, TorError> {
+        let old_state = self.state.clone();
         let result = match self.state {
             CircuitState::NegotiateWriting => self.do_negotiate_write(),
             CircuitState::NegotiateReading => self.do_negotiate_read(),
@@ -206,16 +486,58 @@ where
             CircuitState::NetinfoWriting => self.do_netinfo_write(),
             CircuitState::CreateFastWriting => self.do_create_fast_write(),
             CircuitState::CreateFastReading => self.do_create_fast_read(),
+            CircuitState::CreateBackoff => self.do_create_backoff(),
             CircuitState::Ready => return Ok(Async::Ready(())),
-            _ => Err(Error::new(ErrorKind::Other, "library error: invalid state")),
+            _ => Err(TorError::Protocol("library error: invalid state".to_owned())),
+        };
+        let result = match result {
+            Ok(Async::NotReady) => self.check_handshake_timeout().map(|()| Async::NotReady),
+            result => result,
         };
         if result.is_err() {
             self.state = CircuitState::Error;
             return result;
         }
+        if self.state != old_state {
+            self.record_stage_timing();
+        }
         Ok(Async::NotReady)
     }
 
+    /// Fails with `ErrorKind::TimedOut` if a handshake timeout is set and has passed.
+    fn check_handshake_timeout(&self) -> Result<(), TorError> {
+        match self.handshake_timeout {
+            Some(timeout) if self.handshake_started.elapsed() >= timeout => Err(TorError::Io(
+                Error::new(ErrorKind::TimedOut, "timed out building circuit"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Called when the handshake moves to a new state. If that state starts a new stage, records
+    /// how long the stage that just finished took.
+    fn record_stage_timing(&mut self) {
+        let elapsed = self.stage_started.elapsed();
+        let timing = match self.state {
+            CircuitState::CertsReading => &mut self.build_timings.versions,
+            CircuitState::AuthChallengeReading => &mut self.build_timings.certs,
+            CircuitState::NetinfoReading => &mut self.build_timings.authenticate,
+            // (We also get back here when retrying CREATE_FAST, which is still the create stage.)
+            CircuitState::CreateFastWriting if self.build_timings.netinfo.is_none() => {
+                &mut self.build_timings.netinfo
+            }
+            CircuitState::Ready => &mut self.build_timings.create,
+            _ => return,
+        };
+        *timing = Some(elapsed);
+        self.stage_started = Instant::now();
+    }
+
+    /// Returns how long each stage of building this circuit has taken so far.
+    pub fn get_build_timings(&self) -> &BuildTimings {
+        &self.build_timings
+    }
+
     /// Attempt to read as much as possible from `self.tls_connection`, appending to the local
     /// buffer. After doing so, if there is no data available in the read buffer, returns
     /// `Ok(Async::NotReady)`.
@@ -247,7 +569,10 @@ where
             self.buffer.write_all(&tmp[..bytes_read])?;
             self.buffer.set_position(read_position);
         }
-        // Find out if there's any data past the read position.
+        // Find out if there's any data past the read position. Note that this may well be more
+        // than one cell (relays often send VERSIONS, CERTS, AUTH_CHALLENGE, and NETINFO in one TLS
+        // record). Each `do_*_read` only consumes the cell it's after, so whatever is left stays
+        // in the buffer and is handed out, in order, on the next poll.
         let read_position = self.buffer.position();
         self.buffer.seek(SeekFrom::End(0))?;
         let has_data = read_position != self.buffer.position();
@@ -259,23 +584,21 @@ where
         }
     }
 
-    fn do_negotiate_write(&mut self) -> Result<Async<()>, Error> {
-        let versions = types::VersionsCell::new(vec![4]);
+    fn do_negotiate_write(&mut self) -> Result<Async<()>, TorError> {
+        let versions = types::VersionsCell::new(LINK_PROTOCOL_VERSIONS.to_vec());
         let mut buf: Vec<u8> = Vec::new();
-        if let Err(e) = versions.write_to(&mut buf) {
-            return Err(e);
-        }
+        versions.write_to(&mut buf)?;
         match self.tls_connection.write_all(&buf) {
             Ok(_) => {
                 self.state = CircuitState::NegotiateReading;
                 Ok(Async::Ready(()))
             }
             Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(Async::NotReady),
-            Err(e) => Err(e),
+            Err(e) => Err(TorError::Io(e)),
         }
     }
 
-    fn do_negotiate_read(&mut self) -> Result<Async<()>, Error> {
+    fn do_negotiate_read(&mut self) -> Result<Async<()>, TorError> {
         match self.read_to_buffer()? {
             Async::Ready(()) => {}
             Async::NotReady => return Ok(Async::NotReady),
@@ -286,107 +609,87 @@ where
         let saved_position = self.buffer.position();
         let peer_versions = match types::VersionsCell::read_new(&mut self.buffer) {
             Ok(peer_versions) => peer_versions,
-            // TODO: differentiate between EOF (i.e. need to read more) and error decoding due to
-            // bad data.
-            Err(_) => {
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
                 self.buffer.set_position(saved_position);
                 return Ok(Async::NotReady);
             }
+            Err(e) => return Err(TorError::Parse(e.to_string())),
         };
         // TODO: a not-great thing is we have to re-create the `versions` we created in
         // `do_negotiate_write` - maybe make it essentially a constant?
-        let versions = types::VersionsCell::new(vec![4]);
+        let versions = types::VersionsCell::new(LINK_PROTOCOL_VERSIONS.to_vec());
         let version = match versions.negotiate(&peer_versions) {
             Ok(version) => version,
-            Err(_) => {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    "couldn't negotiate version",
-                ))
-            }
+            Err(_) => return Err(TorError::NoCommonVersion(peer_versions.get_versions().to_vec())),
         };
+        self.link_version = version;
         self.state = CircuitState::CertsReading;
         Ok(Async::Ready(()))
     }
 
-    fn do_certs_read(&mut self) -> Result<Async<()>, Error> {
+    fn do_certs_read(&mut self) -> Result<Async<()>, TorError> {
         match self.read_to_buffer()? {
             Async::Ready(()) => {}
             Async::NotReady => return Ok(Async::NotReady),
         }
-        let saved_position = self.buffer.position();
-        let cell = match types::Cell::read_new(&mut self.buffer) {
-            Ok(cell) => cell,
-            Err(_) => {
-                self.buffer.set_position(saved_position);
-                return Ok(Async::NotReady);
-            }
+        let cell = match self.read_buffered_cell() {
+            Some(cell) => cell,
+            None => return Ok(Async::NotReady),
         };
         if cell.command != types::Command::Certs {
-            return Err(Error::new(ErrorKind::InvalidInput, "unexpected cell type"));
+            return Err(TorError::Protocol("unexpected cell type".to_owned()));
         }
         let certs_cell = match types::CertsCell::read_new(&mut &cell.payload[..]) {
             Ok(certs_cell) => certs_cell,
-            Err(_) => {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    "error decoding CERTS cell",
-                ))
-            }
+            Err(_) => return Err(TorError::Parse("error decoding CERTS cell".to_owned())),
         };
         let responder_certs = match ResponderCerts::new(certs_cell.decode_certs()) {
             Ok(responder_certs) => responder_certs,
-            Err(_) => {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    "error decoding certs in CERTS cell",
-                ))
-            }
+            Err(_) => return Err(TorError::Parse("error decoding certs in CERTS cell".to_owned())),
         };
-        let peer_cert_hash = match self.tls_connection.get_peer_cert_hash() {
-            Ok(peer_cert_hash) => peer_cert_hash,
-            Err(e) => return Err(e),
-        };
-        // TODO map_err I think
+        let peer_cert_hash = self.tls_connection.get_peer_cert_hash()?;
+        if let Some(pinned_peer_cert_hash) = self.pinned_peer_cert_hash {
+            if !util::ct_eq(&pinned_peer_cert_hash, &peer_cert_hash) {
+                return Err(TorError::Crypto(
+                    "peer TLS certificate doesn't match pinned hash".to_owned(),
+                ));
+            }
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
         if let Err(e) = responder_certs.validate(
             &self.expected_ed25519_id_key,
             &peer_cert_hash,
             &self.rsa_verifier,
+            now,
         ) {
-            return Err(Error::new(ErrorKind::Other, e));
+            return Err(TorError::Crypto(e.to_string()));
         }
         self.responder_certs = Some(responder_certs);
         self.state = CircuitState::AuthChallengeReading;
         Ok(Async::Ready(()))
     }
 
-    fn do_auth_challenge_read(&mut self) -> Result<Async<()>, Error> {
+    fn do_auth_challenge_read(&mut self) -> Result<Async<()>, TorError> {
         match self.read_to_buffer()? {
             Async::Ready(()) => {}
             Async::NotReady => return Ok(Async::NotReady),
         }
-        let saved_position = self.buffer.position();
-        let cell = match types::Cell::read_new(&mut self.buffer) {
-            Ok(cell) => cell,
-            Err(_) => {
-                self.buffer.set_position(saved_position);
-                return Ok(Async::NotReady);
-            }
+        let cell = match self.read_buffered_cell() {
+            Some(cell) => cell,
+            None => return Ok(Async::NotReady),
         };
         if cell.command != types::Command::AuthChallenge {
-            return Err(Error::new(ErrorKind::InvalidInput, "unexpected cell type"));
+            return Err(TorError::Protocol("unexpected cell type".to_owned()));
         }
         let auth_challenge = match types::AuthChallengeCell::read_new(&mut &cell.payload[..]) {
             Ok(auth_challenge_cell) => auth_challenge_cell,
-            Err(_) => {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    "error decoding AUTH CHALLENGE cell",
-                ))
-            }
+            Err(_) => return Err(TorError::Parse("error decoding AUTH CHALLENGE cell".to_owned())),
         };
         if !auth_challenge.has_auth_type(types::AuthType::Ed25519Sha256Rfc5705) {
-            return Err(Error::new(ErrorKind::InvalidInput, "unsupported auth type"));
+            return Err(TorError::Protocol("unsupported auth type".to_owned()));
         }
         // It seems we don't actually have to do anything else here, since the only thing we would
         // need is actually in our connection's read digest.
@@ -394,34 +697,125 @@ where
         Ok(Async::Ready(()))
     }
 
-    fn do_certs_write(&mut self) -> Result<Async<()>, Error> {
-        let certs_cell = self.initiator_certs.to_certs_cell();
-        let mut buf: Vec<u8> = Vec::new();
-        if certs_cell.write_to(&mut buf).is_err() {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                "couldn't serialize CERTS cell",
-            ));
+    /// Pins the first hop's TLS certificate: the handshake will fail if the sha-256 hash of the
+    /// peer's certificate isn't `pinned_peer_cert_hash` (e.g. a value previously recorded with
+    /// `get_peer_cert_hash`). Must be called before the handshake gets to reading CERTS.
+    pub fn set_pinned_peer_cert_hash(&mut self, pinned_peer_cert_hash: [u8; 32]) {
+        self.pinned_peer_cert_hash = Some(pinned_peer_cert_hash);
+    }
+
+    /// Sets which handshake to use when extending the circuit. By default this is ntor; ntor v3
+    /// needs the relay to support "Relay=4" (see `dir::TorPeer::supports`).
+    pub fn set_extend_handshake_type(&mut self, extend_handshake_type: types::ClientHandshakeType) {
+        self.extend_handshake_type = extend_handshake_type;
+    }
+
+    /// Sets whether to offer congestion control (the ntor v3 congestion control request
+    /// extension) when creating or extending to a hop with ntor v3. Off by default.
+    pub fn set_request_congestion_control(&mut self, request_congestion_control: bool) {
+        self.request_congestion_control = request_congestion_control;
+    }
+
+    /// Returns the SENDME increment negotiated with the given hop (0 is the first hop), or `None`
+    /// if that hop didn't negotiate congestion control.
+    pub fn get_congestion_control_sendme_inc(&self, hop_index: usize) -> Option<u8> {
+        match self.congestion_control_sendme_incs.get(hop_index) {
+            Some(sendme_inc) => *sendme_inc,
+            None => None,
+        }
+    }
+
+    /// The extensions to send in an ntor v3 client handshake.
+    fn ntor3_client_extensions(&self) -> Vec<types::Ntor3Extension> {
+        let mut extensions = Vec::new();
+        if self.request_congestion_control {
+            extensions.push(types::Ntor3Extension::CongestionControlRequest);
+        }
+        extensions
+    }
+
+    /// Records what the most recently installed hop agreed to in the extensions of its ntor v3
+    /// server handshake.
+    fn record_ntor3_server_extensions(&mut self, extensions: &[types::Ntor3Extension]) {
+        for extension in extensions {
+            if let &types::Ntor3Extension::CongestionControlResponse { sendme_inc } = extension {
+                if let Some(last) = self.congestion_control_sendme_incs.last_mut() {
+                    *last = Some(sendme_inc);
+                }
+            }
         }
+    }
+
+    /// Sets the address to report as our own in our NETINFO cell. By default (`None`) no address
+    /// is reported, which is what clients generally should do. Must be called before the
+    /// handshake gets to sending NETINFO.
+    pub fn set_reported_address(&mut self, reported_address: Option<types::OrAddress>) {
+        self.reported_address = reported_address;
+    }
+
+    /// Uses the given Ed25519 identity key (e.g. one loaded from disk with
+    /// `keys::Ed25519Key::from_secret_key_bytes`) instead of a random one, so that our link
+    /// identity stays the same across runs. Together with a `rsa_signer` holding the same RSA key
+    /// each time, that makes the whole identity persistent. Our certs are made to be valid for
+    /// `cert_lifetime` from now. Must be called before the handshake gets to sending CERTS.
+    pub fn set_initiator_identity(
+        &mut self,
+        rsa_signer: &RsaSignerImpl,
+        ed25519_identity_key: &keys::Ed25519Key,
+        cert_lifetime: Duration,
+    ) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        // Round up to the next hour so the certs last at least `cert_lifetime`.
+        let expiration_date = (now + cert_lifetime.as_secs() + 3599) / 3600;
+        self.initiator_certs = InitiatorCerts::from_keys(
+            rsa_signer,
+            ed25519_identity_key,
+            expiration_date as certs::HoursSinceEpoch,
+        );
+    }
+
+    /// Returns the certs that would normally be sent in our CERTS cell (RSA identity, Ed25519
+    /// identity, Ed25519 signing, and Ed25519 authenticate, as the spec requires).
+    pub fn get_default_initiator_certs(&self) -> Vec<types::RawCert> {
+        self.initiator_certs.to_raw_certs()
+    }
+
+    /// Sends the given certs in our CERTS cell instead of the defaults. This is for experimenting
+    /// with what relays will accept (e.g. omitting a cert from `get_default_initiator_certs`) - a
+    /// CERTS cell that doesn't have all of the default certs will presumably be rejected. Must be
+    /// called before the handshake gets to sending CERTS.
+    pub fn set_initiator_certs(&mut self, certs: Vec<types::RawCert>) {
+        self.initiator_raw_certs = Some(certs);
+    }
+
+    fn do_certs_write(&mut self) -> Result<Async<()>, TorError> {
+        let certs_cell = match self.initiator_raw_certs {
+            Some(ref certs) => types::CertsCell::new_from_raw_certs(certs.clone()),
+            None => self.initiator_certs.to_certs_cell(),
+        };
+        let mut buf: Vec<u8> = Vec::new();
+        certs_cell.write_to(&mut buf)?;
         let cell = types::Cell::new(0, types::Command::Certs, buf);
         let mut buf: Vec<u8> = Vec::new();
-        if cell.write_to(&mut buf).is_err() {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                "couldn't serialize cell",
-            ));
-        }
+        cell.write_to_for_version(&mut buf, self.link_version)?;
         match self.tls_connection.write_all(&buf) {
             Ok(_) => {
+                // CLOG has to cover exactly what we've sent so far (VERSIONS and CERTS), so take
+                // the snapshot now rather than when building the AUTHENTICATE cell (which might
+                // be retried after a partial write of itself).
+                self.clog = Some(self.tls_connection.get_write_digest());
                 self.state = CircuitState::AuthenticateWriting;
                 Ok(Async::Ready(()))
             }
             Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(Async::NotReady),
-            Err(e) => Err(e),
+            Err(e) => Err(TorError::Io(e)),
         }
     }
 
-    fn do_authenticate_write(&mut self) -> Result<Async<()>, Error> {
+    fn do_authenticate_write(&mut self) -> Result<Async<()>, TorError> {
         // tor-spec.txt section 4.4.2: With Ed25519-SHA256-RFC5705 link authentication, the
         // authentication field of the AUTHENTICATE cell is as follows:
         // "AUTH0003" [8 bytes]
@@ -432,8 +826,7 @@ where
         // CID_ED: initiator's Ed25519 identity public key [32 bytes]
         // SID_ED: responder's Ed25519 identity public key [32 bytes]
         // SLOG: sha-256 hash of all bytes received from responder by initiator (should be VERSIONS
-        //       cell, CERTS cell, AUTH_CHALLENGE cell, and any padding cells (currently not
-        //       handled...)) [32 bytes]
+        //       cell, CERTS cell, AUTH_CHALLENGE cell, and any padding cells) [32 bytes]
         // CLOG: sha-256 hash of all bytes sent to responder by initiator (should be VERSIONS cell,
         //       CERTS cell, and any padding cells (currently not sent...)) [32 bytes]
         // SCERT: sha-256 hash of the responder's TLS link certificate [32 bytes]
@@ -446,27 +839,17 @@ where
         // SIG: a signature over this data using the initiator's Ed25519 authenticate key
         //      [variable length? (shouldn't it just be 64 bytes?)]
 
-        // "AUTH0003"
-        let mut buf: Vec<u8> = b"AUTH0003".to_vec();
         // CID
         let cid = self.rsa_verifier
             .get_key_hash(&self.initiator_certs.rsa_identity_cert.get_bytes());
-        buf.extend(&cid);
         // SID
         let responder_certs = match self.responder_certs {
             Some(ref responder_certs) => responder_certs,
-            None => return Err(Error::new(ErrorKind::Other, "`responder_certs` not set?")),
+            None => return Err(TorError::Protocol("`responder_certs` not set?".to_owned())),
         };
         let sid = self.rsa_verifier
             .get_key_hash(&responder_certs.rsa_identity_cert.get_bytes());
-        buf.extend(&sid);
-        // CID_ED
-        let cid_ed = self.initiator_certs.ed25519_identity_cert.get_key_bytes();
-        buf.extend(cid_ed);
-        // SID_ED
-        let sid_ed = responder_certs.ed25519_identity_cert.get_key_bytes();
-        buf.extend(sid_ed);
-        // SLOG (yes, the responder is first this time. don't know why)
+        // SLOG
         // For CLOG, our `TlsHashWrapper` intercepts all written bytes and keeps track of the
         // running hash. This doesn't work with SLOG, because we read as much as we can until the
         // stream blocks, which means the hash covers more data than we've actually processed, and
@@ -478,15 +861,15 @@ where
         self.buffer.seek(SeekFrom::Start(0))?;
         self.buffer.read(&mut hash_buf)?;
         hash.input(&hash_buf);
-        let mut slog = Vec::new();
-        slog.extend(hash.result().into_iter());
-        buf.extend(slog);
+        let mut slog = [0; 32];
+        slog.copy_from_slice(&hash.result());
         // CLOG
-        let clog = self.tls_connection.get_write_digest();
-        buf.extend(clog);
+        let clog = match self.clog {
+            Some(ref clog) => util::slice_to_32_byte_array(clog),
+            None => return Err(TorError::Protocol("`clog` not set?".to_owned())),
+        };
         // SCERT
         let scert = self.tls_connection.get_peer_cert_hash()?;
-        buf.extend(&scert);
         // TLSSECRETS
         // tor-spec.txt section 4.4.1 is wrong here - the context is the sha-256 hash of the
         // initiator's RSA identity cert (in other words, CID)
@@ -494,15 +877,31 @@ where
         // label "EXPORTER FOR TOR TLS CLIENT BINDING AUTH0003", and the given context.
         const TLS_SECRET_LABEL: &'static str = "EXPORTER FOR TOR TLS CLIENT BINDING AUTH0003";
         let tlssecrets = self.tls_connection.get_tls_secrets(TLS_SECRET_LABEL, &cid)?;
-        buf.extend(tlssecrets);
+        if tlssecrets.len() != 32 {
+            return Err(TorError::Crypto("TLSSECRETS must be 32 bytes".to_owned()));
+        }
         // RAND
         let mut rand = [0; 24];
         let mut csprng: OsRng = match OsRng::new() {
             Ok(csprng) => csprng,
-            Err(e) => return Err(Error::new(ErrorKind::Other, e)),
+            Err(e) => return Err(TorError::Crypto(e.to_string())),
         };
         csprng.fill_bytes(&mut rand);
-        buf.extend(rand.iter());
+        let mut buf = build_auth0003_body(&Auth0003Fields {
+            cid: cid,
+            sid: sid,
+            cid_ed: util::slice_to_32_byte_array(
+                self.initiator_certs.ed25519_identity_cert.get_key_bytes(),
+            ),
+            sid_ed: util::slice_to_32_byte_array(
+                responder_certs.ed25519_identity_cert.get_key_bytes(),
+            ),
+            slog: slog,
+            clog: clog,
+            scert: scert,
+            tlssecrets: util::slice_to_32_byte_array(&tlssecrets),
+            rand: rand,
+        });
         // SIG
         let ed25519_authenticate_key = self.initiator_certs.get_ed25519_authenticate_key();
         let signature = ed25519_authenticate_key.sign_data(&buf);
@@ -511,161 +910,276 @@ where
         let authenticate_cell =
             types::AuthenticateCell::new(types::AuthType::Ed25519Sha256Rfc5705, buf);
         let mut buf: Vec<u8> = Vec::new();
-        if authenticate_cell.write_to(&mut buf).is_err() {
-            return Err(Error::new(
-                ErrorKind::Other,
-                "couldn't serialize AUTHENTICATE cell",
-            ));
-        }
+        authenticate_cell.write_to(&mut buf)?;
         let cell = types::Cell::new(0, types::Command::Authenticate, buf);
         let mut buf: Vec<u8> = Vec::new();
-        if cell.write_to(&mut buf).is_err() {
-            return Err(Error::new(ErrorKind::Other, "couldn't serialize cell"));
-        }
+        cell.write_to_for_version(&mut buf, self.link_version)?;
         match self.tls_connection.write_all(&buf) {
             Ok(_) => {
                 self.state = CircuitState::NetinfoReading;
                 Ok(Async::Ready(()))
             }
             Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(Async::NotReady),
-            Err(e) => Err(e),
+            Err(e) => Err(TorError::Io(e)),
         }
     }
 
-    fn do_netinfo_read(&mut self) -> Result<Async<()>, Error> {
-        match self.read_to_buffer()? {
-            Async::Ready(()) => {}
-            Async::NotReady => return Ok(Async::NotReady),
-        }
-        let saved_position = self.buffer.position();
-        let cell = match types::Cell::read_new(&mut self.buffer) {
-            Ok(cell) => cell,
-            Err(_) => {
-                self.buffer.set_position(saved_position);
-                return Ok(Async::NotReady);
+    fn do_netinfo_read(&mut self) -> Result<Async<()>, TorError> {
+        match self.read_to_buffer() {
+            Ok(Async::Ready(())) => {}
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            // The responder doesn't say so when it rejects our AUTHENTICATE cell - it just never
+            // sends NETINFO.
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(TorError::AuthRejected);
             }
+            Err(e) => return Err(TorError::Io(e)),
+        }
+        let cell = match self.read_buffered_cell() {
+            Some(cell) => cell,
+            None => return Ok(Async::NotReady),
         };
         if cell.command != types::Command::Netinfo {
-            return Err(Error::new(ErrorKind::Other, "unexpected cell type"));
+            return Err(TorError::Protocol("unexpected cell type".to_owned()));
         }
         let netinfo = match types::NetinfoCell::read_new(&mut &cell.payload[..]) {
             Ok(netinfo_cell) => netinfo_cell,
-            Err(_) => return Err(Error::new(ErrorKind::Other, "couldn't decode NETINFO cell")),
+            Err(_) => return Err(TorError::Parse("couldn't decode NETINFO cell".to_owned())),
         };
-        self.other_or_address = Some(netinfo.get_other_or_address());
+        match netinfo.get_other_or_address() {
+            Some(other_or_address) => self.other_or_address = Some(other_or_address),
+            None => return Err(TorError::Parse("NETINFO cell has no usable address".to_owned())),
+        }
         self.state = CircuitState::NetinfoWriting;
         Ok(Async::Ready(()))
     }
 
-    fn do_netinfo_write(&mut self) -> Result<Async<()>, Error> {
+    fn do_netinfo_write(&mut self) -> Result<Async<()>, TorError> {
         let timestamp: types::EpochSeconds = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs() as u32;
         let other_or_address = match self.other_or_address {
             Some(ref other_or_address) => other_or_address.clone(),
-            None => return Err(Error::new(ErrorKind::Other, "other_or_address not set?")),
+            None => return Err(TorError::Protocol("other_or_address not set?".to_owned())),
         };
-        let localhost = types::OrAddress::IPv4Address([127, 0, 0, 1]);
-        let netinfo = types::NetinfoCell::new(timestamp, other_or_address, localhost);
+        let this_or_addresses = match self.reported_address {
+            Some(ref reported_address) => vec![reported_address.clone()],
+            None => Vec::new(),
+        };
+        let netinfo = types::NetinfoCell::new(timestamp, other_or_address, this_or_addresses);
         let mut buf: Vec<u8> = Vec::new();
-        if netinfo.write_to(&mut buf).is_err() {
-            return Err(Error::new(
-                ErrorKind::Other,
-                "error serializing NETINFO cell",
-            ));
-        }
+        netinfo.write_to(&mut buf)?;
         let cell = types::Cell::new(0, types::Command::Netinfo, buf);
         let mut buf: Vec<u8> = Vec::new();
-        if cell.write_to(&mut buf).is_err() {
-            return Err(Error::new(ErrorKind::Other, "error serializing cell"));
-        }
+        cell.write_to_for_version(&mut buf, self.link_version)?;
         match self.tls_connection.write_all(&buf) {
             Ok(_) => {
+                self.authenticated = true;
                 self.state = CircuitState::CreateFastWriting;
                 Ok(Async::Ready(()))
             }
             Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(Async::NotReady),
-            Err(e) => Err(e),
+            Err(e) => Err(TorError::Io(e)),
         }
     }
 
-    fn do_create_fast_write(&mut self) -> Result<Async<()>, Error> {
-        let mut csprng: OsRng = match OsRng::new() {
-            Ok(csprng) => csprng,
-            Err(e) => return Err(Error::new(ErrorKind::Other, e)),
+    /// Uses a TAP handshake (CREATE/CREATED) with the first hop instead of CREATE_FAST. This is
+    /// the old, RSA and 1024-bit Diffie-Hellman based handshake, for relays that don't do anything
+    /// newer - it's slower than CREATE_FAST and ntor, and unlike CREATE_FAST it authenticates the
+    /// relay. `node` must be the relay we're connected to. Must be called before the handshake
+    /// gets to sending CREATE_FAST. Fails if the relay's microdescriptor had no TAP onion key.
+    pub fn set_tap_handshake(
+        &mut self,
+        node: &dir::TorPeer,
+        rsa_encryptor: &RsaEncryptorImpl,
+    ) -> Result<(), Error> {
+        let onion_key = match node.get_tap_onion_key() {
+            Some(onion_key) => onion_key,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "relay has no TAP onion key")),
         };
-        csprng.fill_bytes(&mut self.x);
-        let create_fast_cell = types::CreateFastCell::new(self.x);
+        let handshake = tap::TapClientHandshake::new(onion_key, rsa_encryptor)?;
+        self.tap_handshake = Some(handshake);
+        Ok(())
+    }
+
+    fn do_create_fast_write(&mut self) -> Result<Async<()>, TorError> {
         let mut buf: Vec<u8> = Vec::new();
-        if create_fast_cell.write_to(&mut buf).is_err() {
-            return Err(Error::new(
-                ErrorKind::Other,
-                "couldn't serialize CREATE FAST cell",
-            ));
-        }
-        let cell = types::Cell::new(self.circ_id, types::Command::CreateFast, buf);
+        let command = match self.tap_handshake {
+            Some(ref tap_handshake) => {
+                let create_cell = types::CreateCell::new(tap_handshake.get_onion_skin().to_vec());
+                create_cell.write_to(&mut buf)?;
+                types::Command::Create
+            }
+            None => {
+                let mut csprng: OsRng = match OsRng::new() {
+                    Ok(csprng) => csprng,
+                    Err(e) => return Err(TorError::Crypto(e.to_string())),
+                };
+                csprng.fill_bytes(&mut self.x);
+                let create_fast_cell = types::CreateFastCell::new(self.x);
+                create_fast_cell.write_to(&mut buf)?;
+                types::Command::CreateFast
+            }
+        };
+        let cell = types::Cell::new(self.circ_id, command, buf);
         let mut buf: Vec<u8> = Vec::new();
-        if cell.write_to(&mut buf).is_err() {
-            return Err(Error::new(ErrorKind::Other, "couldn't serialize cell"));
-        }
+        cell.write_to_for_version(&mut buf, self.link_version)?;
         match self.tls_connection.write_all(&buf) {
             Ok(_) => {
                 self.state = CircuitState::CreateFastReading;
                 Ok(Async::Ready(()))
             }
             Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(Async::NotReady),
-            Err(e) => Err(e),
+            Err(e) => Err(TorError::Io(e)),
         }
     }
 
-    fn do_create_fast_read(&mut self) -> Result<Async<()>, Error> {
+    fn do_create_fast_read(&mut self) -> Result<Async<()>, TorError> {
         match self.read_to_buffer()? {
             Async::Ready(()) => {}
             Async::NotReady => return Ok(Async::NotReady),
         }
-        let saved_position = self.buffer.position();
-        let cell = match types::Cell::read_new(&mut self.buffer) {
-            Ok(cell) => cell,
-            Err(_) => {
-                self.buffer.set_position(saved_position);
+        let cell = match self.read_buffered_cell() {
+            Some(cell) => cell,
+            None => return Ok(Async::NotReady),
+        };
+        // The relay may turn down the circuit for reasons that have nothing to do with us (it's
+        // overloaded, or it's just being told to shut down), in which case it's worth trying
+        // again.
+        if cell.command == types::Command::Destroy {
+            let reason = match cell.payload.first() {
+                Some(reason) => types::DestroyReason::from_u8(*reason),
+                None => types::DestroyReason::None,
+            };
+            let transient = match reason {
+                types::DestroyReason::Requested
+                | types::DestroyReason::ResourceLimit
+                | types::DestroyReason::Hibernating => true,
+                _ => false,
+            };
+            let msg = format!("circuit destroyed ({:?})", reason);
+            if transient && self.schedule_retry(CircuitState::CreateBackoff) {
                 return Ok(Async::NotReady);
             }
-        };
-        // TODO: handle DESTROY differently here?
+            return Err(TorError::Protocol(msg));
+        }
+        if let Some(tap_handshake) = self.tap_handshake.take() {
+            if cell.command != types::Command::Created {
+                return Err(TorError::Protocol("unexpected cell type".to_owned()));
+            }
+            let created = match types::CreatedCell::read_new(&mut &cell.payload[..]) {
+                Ok(created) => created,
+                Err(_) => return Err(TorError::Parse("error decoding CREATED cell".to_owned())),
+            };
+            let (k0, kh) = match tap_handshake.complete(created.get_handshake_data()) {
+                Ok(result) => result,
+                Err(e) => return Err(TorError::Crypto(e.to_string())),
+            };
+            let circuit_keys = kdf_tor(&k0, &kh)?;
+            self.install_circuit_keys(circuit_keys);
+            self.extend_retries = 0;
+            self.state = CircuitState::Ready;
+            return Ok(Async::Ready(()));
+        }
         if cell.command != types::Command::CreatedFast {
-            return Err(Error::new(ErrorKind::InvalidInput, "unexpected cell type"));
+            return Err(TorError::Protocol("unexpected cell type".to_owned()));
         }
         let created_fast = match types::CreatedFastCell::read_new(&mut &cell.payload[..]) {
             Ok(created_fast) => created_fast,
-            Err(_) => {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    "error decoding CREATED FAST cell",
-                ))
-            }
+            Err(_) => return Err(TorError::Parse("error decoding CREATED FAST cell".to_owned())),
         };
-        let circuit_keys = match tor_kdf(&self.x, created_fast.get_y(), created_fast.get_kh()) {
-            Ok(circuit_keys) => circuit_keys,
-            Err(_) => return Err(Error::new(ErrorKind::Other, "Tor KDF failed")),
-        };
-        self.circuit_keys.push(circuit_keys);
+        let circuit_keys = tor_kdf(&self.x, created_fast.get_y(), created_fast.get_kh())?;
+        self.install_circuit_keys(circuit_keys);
+        self.extend_retries = 0;
         self.state = CircuitState::Ready;
         Ok(Async::Ready(()))
     }
 
+    fn do_create_backoff(&mut self) -> Result<Async<()>, TorError> {
+        match self.extend_retry_at {
+            Some(retry_at) if Instant::now() < retry_at => return Ok(Async::NotReady),
+            _ => {}
+        }
+        self.extend_retry_at = None;
+        self.state = CircuitState::CreateFastWriting;
+        self.do_create_fast_write()
+    }
+
+    /// How long to wait before the next retry of a CREATE_FAST or an extend, or None if it's
+    /// been retried as many times as it may be.
+    fn next_retry_delay(&self) -> Option<Duration> {
+        if self.extend_retries >= self.max_extend_retries {
+            return None;
+        }
+        let max_delay = Duration::from_secs(MAX_RETRY_DELAY_SECS);
+        // Past a point doubling the delay overflows, but by then it's long since hit the cap.
+        let delay = match 2u32.checked_pow(self.extend_retries) {
+            Some(factor) => self.extend_retry_delay.checked_mul(factor),
+            None => None,
+        };
+        Some(cmp::min(delay.unwrap_or(max_delay), max_delay))
+    }
+
+    /// Arranges for the failed CREATE_FAST or extend to be retried after a delay, by going to
+    /// `backoff_state`. Returns false (and leaves the state alone) if it's already been retried as
+    /// many times as it may be.
+    fn schedule_retry(&mut self, backoff_state: CircuitState) -> bool {
+        let delay = match self.next_retry_delay() {
+            Some(delay) => delay,
+            None => {
+                self.extend_retries = 0;
+                return false;
+            }
+        };
+        self.extend_retries += 1;
+        self.extend_retry_at = Some(Instant::now() + delay);
+        self.state = backoff_state;
+        true
+    }
+
+    fn install_circuit_keys(&mut self, circuit_keys: CircuitKeys) {
+        // So a cell dump can be matched up with the keys at the other end.
+        #[cfg(feature = "dump-cells")]
+        println!(
+            "keys:{}:{}",
+            self.circuit_keys.len() + 1,
+            util::to_hex(&circuit_keys.fingerprint())
+        );
+        self.circuit_keys.push(circuit_keys);
+        self.congestion_control_sendme_incs.push(None);
+        self.reset_circuit_flow_control();
+    }
+
     // TODO: stream_id == 0 for control commands - how do we make this easy/automatic?
     // (maybe tie it into the "get me a new stream id" function?)
+    /// Builds a relay cell for the last hop and onion-encrypts it for sending. Outbound cells use
+    /// the *forward* digest and key of each hop, applied from the last hop back to the first (so
+    /// the first hop's layer is outermost).
     fn encrypt_cell_bytes(
         &mut self,
         relay_command: types::RelayCommand,
         in_bytes: &[u8],
         stream_id: u16,
+    ) -> Vec<u8> {
+        let last_hop = self.circuit_keys.len().saturating_sub(1);
+        self.encrypt_cell_bytes_for_hop(relay_command, in_bytes, stream_id, last_hop)
+    }
+
+    /// Like `encrypt_cell_bytes`, but builds the relay cell for the hop at `hop_index` (0 being
+    /// the first hop) rather than the last hop.
+    fn encrypt_cell_bytes_for_hop(
+        &mut self,
+        relay_command: types::RelayCommand,
+        in_bytes: &[u8],
+        stream_id: u16,
+        hop_index: usize,
     ) -> Vec<u8> {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(in_bytes);
         let mut first = true;
-        for circuit_keys in self.circuit_keys.iter_mut().rev() {
+        let hop_count = cmp::min(hop_index + 1, self.circuit_keys.len());
+        for circuit_keys in self.circuit_keys[..hop_count].iter_mut().rev() {
             // TODO: this 0 may need to be something else in the future?
             // (for non-command cells)
             if first {
@@ -686,10 +1200,20 @@ where
         bytes
     }
 
-    fn decrypt_cell_bytes(&mut self, in_bytes: &[u8]) -> Result<types::RelayCell, Error> {
+    /// Removes the onion encryption from an inbound relay cell. Each relay encrypted the cell with
+    /// what it calls its backward key, which is our *backward* key for that hop (the relay's
+    /// forward key is our forward key, and is only ever used for cells we send), so layers are
+    /// removed with the backward keys starting from the first hop. Returns the index of the hop
+    /// the cell came from (0 being the first hop) along with the cell.
+    fn decrypt_cell_bytes(&mut self, in_bytes: &[u8]) -> Result<(usize, types::RelayCell), Error> {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(in_bytes);
-        for circuit_keys in self.circuit_keys.iter_mut() {
+        // Peel off one layer at a time. The cell came from the first hop whose layer leaves the
+        // "recognized" field zeroed and whose running digest matches. A zeroed "recognized" field
+        // alone happens by chance 1 in 65536 times for cells from further along the circuit, so
+        // if the digest doesn't match we keep peeling (unless this is the last hop).
+        let hop_count = self.circuit_keys.len();
+        for (hop_index, circuit_keys) in self.circuit_keys.iter_mut().enumerate() {
             let mut decrypted_cell_bytes: Vec<u8> = Vec::with_capacity(bytes.len());
             decrypted_cell_bytes.resize(bytes.len(), 0);
             // So we have to have some way to roll back things that weren't actually for us (or
@@ -699,13 +1223,93 @@ where
                 .backward_key
                 .aes
                 .process(&bytes, &mut decrypted_cell_bytes);
-            // TODO: handle digest, things not for us, etc.
             bytes = decrypted_cell_bytes;
+            let is_last_hop = hop_index + 1 == hop_count;
+            // The recognized field is the two bytes after the relay command.
+            if bytes.len() < 3 || bytes[1] != 0 || bytes[2] != 0 {
+                continue;
+            }
+            let relay_cell = match types::RelayCell::read_new(&mut &bytes[..]) {
+                Ok(decrypted_cell) => decrypted_cell,
+                Err(_) if !is_last_hop => continue,
+                Err(_) => {
+                    self.state = CircuitState::Error;
+                    return Err(Error::new(ErrorKind::Other, "could not decode RELAY cell"));
+                }
+            };
+            // `check_digest` leaves the running digest alone if it doesn't match.
+            if relay_cell.check_digest(&mut circuit_keys.backward_digest) {
+                return Ok((hop_index, relay_cell));
+            }
+            // If the digest doesn't match at the last hop, the cell was corrupted, replayed, or
+            // otherwise tampered with. Like the canonical implementation, we kill the circuit.
+            if is_last_hop {
+                self.state = CircuitState::Error;
+                return Err(Error::new(ErrorKind::Other, "RELAY cell digest mismatch"));
+            }
         }
-        match types::RelayCell::read_new(&mut &bytes[..]) {
-            Ok(decrypted_cell) => Ok(decrypted_cell),
-            Err(_) => Err(Error::new(ErrorKind::Other, "could not decrypt RELAY cell")),
+        // No hop recognized this cell, so it's garbage as far as we're concerned (most likely our
+        // keys are out of sync with the relays'). There's no recovering from that.
+        self.state = CircuitState::Error;
+        Err(Error::new(
+            ErrorKind::Other,
+            "RELAY cell not recognized at any hop",
+        ))
+    }
+
+    /// Called after sending each RELAY_DATA cell. The exit acknowledges every 100th one with a
+    /// circuit-level SENDME, so remember the digest of those to check against the SENDME.
+    fn record_data_cell_sent(&mut self) {
+        self.data_cells_sent += 1;
+        if self.data_cells_sent % 100 != 0 {
+            return;
+        }
+        if let Some(circuit_keys) = self.circuit_keys.last() {
+            let digest = circuit_keys.forward_digest.digest().bytes();
+            self.expected_sendme_digests.push_back(digest);
+        }
+    }
+
+    /// Called after receiving each RELAY_DATA cell. Every 100th one, sends a circuit-level SENDME
+    /// so the exit can keep sending (its window starts at 1000 cells). These are authenticated
+    /// (version 1) SENDMEs, which echo the last hop's running backward digest as of the cell being
+    /// acknowledged - relays stopped accepting unauthenticated ones.
+    fn record_data_cell_received(&mut self) -> Result<(), Error> {
+        self.data_cells_received += 1;
+        if self.data_cells_received % 100 != 0 {
+            return Ok(());
+        }
+        let digest = match self.circuit_keys.last() {
+            Some(circuit_keys) => circuit_keys.backward_digest.digest().bytes(),
+            None => return Ok(()),
+        };
+        let sendme = types::SendMeCell::V1 { digest: digest };
+        let mut data = Vec::new();
+        sendme.write_to(&mut data)?;
+        let bytes = self.encrypt_cell_bytes(types::RelayCommand::SendMe, &data, 0);
+        // so this isn't that easy probably - we'll need to loop if this write blocks...
+        self.send_cell_bytes(bytes)?;
+        Ok(())
+    }
+
+    /// Checks an inbound circuit-level SENDME. If it's authenticated (version 1), the digest it
+    /// echoes has to be that of the oldest cell we're expecting an acknowledgement for - otherwise
+    /// the exit is trying to open our window without having received our data, so we kill the
+    /// circuit.
+    fn check_circuit_sendme(&mut self, relay_cell: &types::RelayCell) -> Result<(), Error> {
+        let sendme = types::SendMeCell::read_new(relay_cell.get_data())?;
+        let expected_digest = self.expected_sendme_digests.pop_front();
+        if let types::SendMeCell::V1 { digest } = sendme {
+            let matches = match expected_digest {
+                Some(expected_digest) => util::ct_eq(&expected_digest, &digest),
+                None => false,
+            };
+            if !matches {
+                self.state = CircuitState::Error;
+                return Err(Error::new(ErrorKind::Other, "SENDME digest mismatch"));
+            }
         }
+        Ok(())
     }
 
     fn get_buffered_relay_cell(&mut self, stream_id: u16) -> Option<types::RelayCell> {
@@ -726,37 +1330,85 @@ where
     }
 
     fn poll_read_relay_cell(&mut self) -> Result<Async<()>, Error> {
-        let cell = match self.poll_read_cell()? {
-            Async::Ready(cell) => cell,
-            Async::NotReady => {
-                if self.buffered_relay_cells.len() > 0 {
-                    return Ok(Async::Ready(()));
-                }
-                return Ok(Async::NotReady);
+        // Anything we queued earlier goes out as we wait for replies to it.
+        self.poll_flush()?;
+        // Cells that get dropped here don't end the poll, since the next one may already be
+        // buffered. DROP cells are limited by `consecutive_drop_cells`, and the rest by this.
+        let mut cells_dropped = 0;
+        loop {
+            let cell = match self.poll_read_cell()? {
+                Async::Ready(cell) => cell,
+                Async::NotReady => return Ok(self.relay_cells_buffered()),
+            };
+            if cell.command == types::Command::Destroy {
+                let reason = match cell.payload.first() {
+                    Some(reason) => types::DestroyReason::from_u8(*reason),
+                    None => types::DestroyReason::None,
+                };
+                // The circuit is dead, so there's no point keeping its keys around.
+                self.circuit_keys.clear();
+                self.state = CircuitState::Error;
+                let msg = format!("circuit destroyed ({:?})", reason);
+                return Err(Error::new(ErrorKind::Other, msg));
             }
-        };
-        if cell.command == types::Command::Destroy {
-            println!("got circuit destroy cell: {:?}", cell);
-            self.state = CircuitState::Error;
-            return Err(Error::new(ErrorKind::Other, "circuit destroyed"));
-        }
-        if cell.command != types::Command::Relay {
-            let msg = format!("expected Command::Relay, got {:?}", cell.command);
-            return Err(Error::new(ErrorKind::Other, msg));
-        }
-        let relay_cell = self.decrypt_cell_bytes(&cell.payload)?;
-        if relay_cell.relay_command == types::RelayCommand::SendMe {
-            if relay_cell.stream_id == 0 {
-                self.send_window += 100;
-            } else if let Some(stream) = self.streams.get_mut(&relay_cell.stream_id) {
-                stream.send_window += 50;
-            } else {
-                let msg = format!("unexpected stream id {}", relay_cell.stream_id);
+            if cell.command != types::Command::Relay {
+                let msg = format!("expected Command::Relay, got {}", cell);
                 return Err(Error::new(ErrorKind::Other, msg));
             }
+            let (hop_index, relay_cell) = self.decrypt_cell_bytes(&cell.payload)?;
+            if relay_cell.relay_command == types::RelayCommand::Data {
+                self.record_data_cell_received()?;
+            }
+            // DROP cells are long-range padding - they carry nothing and we just discard them.
+            if relay_cell.relay_command == types::RelayCommand::Drop {
+                self.consecutive_drop_cells += 1;
+                if self.consecutive_drop_cells > self.max_cells_per_poll {
+                    return Err(too_many_cells_error());
+                }
+                continue;
+            }
+            self.consecutive_drop_cells = 0;
+            // A hop's connection to the next hop is gone, so every hop after it is too. Only a
+            // truncate or extend we're waiting on wants to see the cell itself.
+            let mut wanted = true;
+            if relay_cell.relay_command == types::RelayCommand::Truncated {
+                self.handle_truncated(hop_index, &relay_cell);
+                wanted = self.state == CircuitState::TruncatedReading
+                    || self.state == CircuitState::Extended2Reading
+                    || self.state == CircuitState::ExtendTruncatedReading;
+            }
+            // Cells for streams we've closed (or never opened) are dropped - we've already sent
+            // our END, and the exit may have sent more before seeing it.
+            if relay_cell.stream_id != 0 && !self.streams.contains_key(&relay_cell.stream_id) {
+                wanted = false;
+            }
+            if !wanted {
+                cells_dropped += 1;
+                if cells_dropped >= self.max_cells_per_poll {
+                    return Ok(self.relay_cells_buffered());
+                }
+                continue;
+            }
+            if relay_cell.relay_command == types::RelayCommand::SendMe {
+                if relay_cell.stream_id == 0 {
+                    self.check_circuit_sendme(&relay_cell)?;
+                    self.send_window += 100;
+                } else if let Some(stream) = self.streams.get_mut(&relay_cell.stream_id) {
+                    stream.send_window += 50;
+                }
+            }
+            self.buffered_relay_cells.push(relay_cell);
+            return Ok(Async::Ready(()));
+        }
+    }
+
+    /// Ready if there are relay cells waiting to be handled (by `get_buffered_relay_cell`).
+    fn relay_cells_buffered(&self) -> Async<()> {
+        if self.buffered_relay_cells.len() > 0 {
+            Async::Ready(())
+        } else {
+            Async::NotReady
         }
-        self.buffered_relay_cells.push(relay_cell);
-        Ok(Async::Ready(()))
     }
 
     // We want to make sure there aren't live streams when we're extending, because we don't
@@ -765,34 +1417,119 @@ where
     // extending, so this doesn't really work... (I guess we need to make sure they're really dead
     // before continuing?)
     pub fn poll_extend(&mut self, node: &dir::TorPeer) -> Result<Async<()>, Error> {
+        self.poll_extend_to(&node.to_extend_target())
+    }
+
+    /// Like `poll_extend`, but the relay to extend to doesn't have to come from the directory.
+    /// `node` must be the same in each call until the extend completes.
+    pub fn poll_extend_to(&mut self, node: &dir::ExtendTarget) -> Result<Async<()>, Error> {
+        let last_hop = self.circuit_keys.len().saturating_sub(1);
+        self.poll_extend_from(last_hop, node)
+    }
+
+    /// Extends the circuit to `node` from the hop at `hop_index` (0 being the first hop) rather
+    /// than from the last hop. Any hops after `hop_index` are dropped from the circuit once the
+    /// extend succeeds - the relay at `hop_index` will only accept the EXTEND2 if it doesn't
+    /// already have a next hop, so they must already have been truncated. `hop_index` and `node`
+    /// must be the same in each call until the extend completes.
+    /// If the extend fails in a way that leaves the circuit unusable (as opposed to the extending
+    /// hop reporting it couldn't reach `node`, or a timeout), the circuit is torn down: a DESTROY
+    /// is sent to the first hop so it can free its side of the circuit, and `get_status` reports
+    /// `Destroyed` (so e.g. `CircuitManager::remove_destroyed` will forget about it).
+    pub fn poll_extend_from(
+        &mut self,
+        hop_index: usize,
+        node: &dir::ExtendTarget,
+    ) -> Result<Async<()>, Error> {
+        let result = self.do_extend_from(hop_index, node);
+        // Failures that leave the circuit usable put it back in the Ready state. If it's been
+        // destroyed by the other side, there are no keys left and nothing to tell the first hop.
+        if result.is_err() && self.state != CircuitState::Ready && !self.circuit_keys.is_empty() {
+            self.state = CircuitState::Error;
+            self.circuit_keys.clear();
+            self.ntor_keypair = None;
+            self.ntor3_handshake = None;
+            if let Err(destroy_error) = self.send_destroy(types::DestroyReason::None) {
+                // The extend's error is the one that matters, but the first hop may now be
+                // holding on to its side of the circuit, so say so.
+                return result.map_err(|e| {
+                    let msg = format!("{} (and sending DESTROY failed: {})", e, destroy_error);
+                    Error::new(e.kind(), msg)
+                });
+            }
+        }
+        result
+    }
+
+    fn do_extend_from(
+        &mut self,
+        hop_index: usize,
+        node: &dir::ExtendTarget,
+    ) -> Result<Async<()>, Error> {
         match self.state {
             CircuitState::Ready => {
-                let client_keypair = keys::Curve25519Keypair::new();
-                let ntor_client_handshake = types::NtorClientHandshake::new(node, &client_keypair);
-                let mut ntor_client_handshake_bytes = Vec::new();
-                if ntor_client_handshake
-                    .write_to(&mut ntor_client_handshake_bytes)
-                    .is_err()
+                if hop_index >= self.circuit_keys.len() {
+                    return Err(Error::new(ErrorKind::InvalidInput, "invalid hop index"));
+                }
+                // Relays only accept EXTEND2 in a RELAY_EARLY cell (and will close the circuit
+                // otherwise).
+                if self.relay_early_remaining() == 0 {
+                    return Err(Error::new(ErrorKind::Other, "RELAY_EARLY budget exhausted"));
+                }
+                // A relay must never appear twice in the same circuit.
+                let ed25519_id_key = node.get_ed25519_id_key();
+                if ed25519_id_key == self.expected_ed25519_id_key
+                    || self.extended_ed25519_ids[..hop_index].contains(&ed25519_id_key)
                 {
-                    return Err(Error::new(ErrorKind::Other,
-                                          "couldn't serialize NtorClientHandshake"));
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "extend target is already in the circuit",
+                    ));
                 }
-                let extend2 = types::Extend2Cell::new(node, ntor_client_handshake_bytes);
+                self.stage_started = Instant::now();
+                let client_keypair = keys::Curve25519Keypair::new();
+                let h_data = match self.extend_handshake_type {
+                    types::ClientHandshakeType::Ntor => {
+                        let ntor_client_handshake =
+                            types::NtorClientHandshake::with_extend_target(node, &client_keypair);
+                        let mut ntor_client_handshake_bytes = Vec::new();
+                        if ntor_client_handshake
+                            .write_to(&mut ntor_client_handshake_bytes)
+                            .is_err()
+                        {
+                            return Err(Error::new(ErrorKind::Other,
+                                                  "couldn't serialize NtorClientHandshake"));
+                        }
+                        self.ntor_keypair = Some(client_keypair);
+                        ntor_client_handshake_bytes
+                    }
+                    types::ClientHandshakeType::Ntor3 => {
+                        let mut message = Vec::new();
+                        types::Ntor3Extension::write_list(&self.ntor3_client_extensions(),
+                                                          &mut message)?;
+                        let ntor3_handshake =
+                            ntor3::Ntor3ClientHandshake::new(node, client_keypair, &message)?;
+                        let h_data = ntor3_handshake.get_h_data();
+                        self.ntor3_handshake = Some(ntor3_handshake);
+                        h_data
+                    }
+                };
+                let extend2 = types::Extend2Cell::with_extend_target(
+                    node,
+                    self.extend_handshake_type,
+                    h_data,
+                );
                 let mut extend2_bytes = Vec::new();
-                if extend2.write_to(&mut extend2_bytes).is_err() {
-                    return Err(Error::new(ErrorKind::Other, "couldn't serialize EXTEND2 cell"));
-                }
-                let bytes = self.encrypt_cell_bytes(types::RelayCommand::Extend2, &extend2_bytes,
-                                                    0);
+                extend2.write_to(&mut extend2_bytes)?;
+                let bytes = self.encrypt_cell_bytes_for_hop(types::RelayCommand::Extend2,
+                                                            &extend2_bytes, 0, hop_index);
                 self.write_buffer.clear();
                 self.write_buffer.extend(bytes);
-                self.ntor_keypair = Some(client_keypair);
                 self.state = CircuitState::Extend2Writing;
                 Ok(Async::NotReady)
             }
             CircuitState::Extend2Writing => {
-                let bytes = self.write_buffer.clone();
-                match self.send_cell_bytes(bytes)? {
+                match self.poll_send_write_buffer(true)? {
                     Async::Ready(()) => {
                         self.state = CircuitState::Extended2Reading;
                         Ok(Async::NotReady)
@@ -803,14 +1540,28 @@ where
                 }
             }
             CircuitState::Extended2Reading => {
-                match self.poll_read_relay_cell()? {
-                    Async::Ready(()) => {},
-                    Async::NotReady => return Ok(Async::NotReady),
-                }
-                let relay_cell = match self.get_buffered_relay_cell(0) {
+                let relay_cell = match self.poll_read_relay_cell()? {
+                    Async::Ready(()) => self.get_buffered_relay_cell(0),
+                    Async::NotReady => None,
+                };
+                let relay_cell = match relay_cell {
                     Some(cell) => cell,
-                    None => return Ok(Async::NotReady),
+                    None => return self.check_extend_timeout(hop_index),
                 };
+                // The extending hop couldn't reach the next one. That may well be transient, so
+                // try again (after waiting a bit) if we haven't run out of retries.
+                if relay_cell.relay_command == types::RelayCommand::Truncated {
+                    let reason = match relay_cell.get_data().first() {
+                        Some(reason) => types::DestroyReason::from_u8(*reason),
+                        None => types::DestroyReason::None,
+                    };
+                    let msg = format!("extend failed ({:?})", reason);
+                    if self.schedule_retry(CircuitState::ExtendBackoff) {
+                        return Ok(Async::NotReady);
+                    }
+                    self.state = CircuitState::Ready;
+                    return Err(Error::new(ErrorKind::Other, msg));
+                }
                 if relay_cell.relay_command != types::RelayCommand::Extended2 {
                     return Err(unexpected_relay_command_error(relay_cell.relay_command,
                                                               types::RelayCommand::Extended2));
@@ -821,25 +1572,86 @@ where
                     Err(_) => return Err(Error::new(ErrorKind::Other,
                                                     "couldn't decode EXTENDED2 cell")),
                 };
-                let client_keypair = match self.ntor_keypair.take() {
-                    Some(client_keypair) => client_keypair,
-                    None => return Err(Error::new(ErrorKind::Other,
-                                                  "library error: ntor_keypair should be Some")),
-                };
-                let circuit_keys = match ntor_handshake(
-                    &extended2,
-                    node.get_node_id(),
-                    node.get_ntor_key(),
-                    client_keypair.get_public_key_bytes(),
-                    client_keypair.get_secret_key_bytes(),
-                ) {
-                    Ok(circuit_keys) => circuit_keys,
-                    Err(_) => return Err(Error::new(ErrorKind::Other, "Ntor handshake failed")),
+                let mut server_extensions = Vec::new();
+                let circuit_keys = if let Some(ntor3_handshake) = self.ntor3_handshake.take() {
+                    let result = ntor3_handshake.complete(&extended2.h_data, 92);
+                    let (key_material, reply) = match result {
+                        Ok(result) => result,
+                        Err(e) => {
+                            let msg = format!("ntor v3 handshake failed: {}", e);
+                            return Err(Error::new(ErrorKind::InvalidData, msg));
+                        }
+                    };
+                    server_extensions = types::Ntor3Extension::read_list(&mut &reply[..])?;
+                    CircuitKeys::new(&key_material)
+                } else {
+                    let client_keypair = match self.ntor_keypair.take() {
+                        Some(client_keypair) => client_keypair,
+                        None => {
+                            let msg = "library error: ntor_keypair should be Some";
+                            return Err(Error::new(ErrorKind::Other, msg));
+                        }
+                    };
+                    match ntor_handshake(
+                        &extended2,
+                        node.get_node_id(),
+                        node.get_ntor_key(),
+                        &client_keypair,
+                    ) {
+                        Ok(circuit_keys) => circuit_keys,
+                        Err(e) => {
+                            let msg = format!("ntor handshake failed: {}", e);
+                            return Err(Error::new(ErrorKind::InvalidData, msg));
+                        }
+                    }
                 };
-                self.circuit_keys.push(circuit_keys);
+                // Forget about any hops after the one we extended from.
+                self.forget_hops_after(hop_index);
+                self.install_circuit_keys(circuit_keys);
+                self.record_ntor3_server_extensions(&server_extensions);
+                self.extended_ed25519_ids.push(node.get_ed25519_id_key());
+                self.build_timings.extends.push(self.stage_started.elapsed());
+                self.extend_retries = 0;
                 self.state = CircuitState::Ready;
                 Ok(Async::Ready(()))
             }
+            CircuitState::ExtendTruncatedReading => loop {
+                let relay_cell = match self.poll_read_relay_cell()? {
+                    Async::Ready(()) => self.get_buffered_relay_cell(0),
+                    Async::NotReady => None,
+                };
+                let relay_cell = match relay_cell {
+                    Some(cell) => cell,
+                    None => return self.check_extend_truncate_timeout(),
+                };
+                match relay_cell.relay_command {
+                    // The EXTENDED2 turned up after all, but the TRUNCATE undoes the extend. Any
+                    // circuit-level SENDMEs have already opened our window.
+                    types::RelayCommand::Extended2 | types::RelayCommand::SendMe => continue,
+                    types::RelayCommand::Truncated => {}
+                    relay_command => {
+                        self.state = CircuitState::Error;
+                        return Err(unexpected_relay_command_error(
+                            relay_command,
+                            types::RelayCommand::Truncated,
+                        ));
+                    }
+                }
+                if self.schedule_retry(CircuitState::ExtendBackoff) {
+                    return Ok(Async::NotReady);
+                }
+                self.state = CircuitState::Ready;
+                return Err(Error::new(ErrorKind::TimedOut, "timed out waiting for EXTENDED2"));
+            },
+            CircuitState::ExtendBackoff => {
+                match self.extend_retry_at {
+                    Some(retry_at) if Instant::now() < retry_at => return Ok(Async::NotReady),
+                    _ => {}
+                }
+                self.extend_retry_at = None;
+                self.state = CircuitState::Ready;
+                self.do_extend_from(hop_index, node)
+            }
             _ => {
                 self.state = CircuitState::Error;
                 Err(Error::new(ErrorKind::Other, "invalid state in poll_extend"))
@@ -847,17 +1659,277 @@ where
         }
     }
 
+    /// Called while waiting for EXTENDED2. If the extend timeout has passed, sends the extending
+    /// hop (at `hop_index`) a TRUNCATE, so it abandons the extend and a late EXTENDED2 can't be
+    /// mistaken for the reply to the next one. Once its TRUNCATED arrives, the extend is retried
+    /// if it may be, and otherwise fails with a `TimedOut` error (the circuit goes back to being
+    /// usable as it was before the extend). Returns NotReady if it hasn't timed out.
+    fn check_extend_timeout(&mut self, hop_index: usize) -> Result<Async<()>, Error> {
+        match self.extend_timeout {
+            Some(timeout) if self.stage_started.elapsed() >= timeout => {
+                self.ntor_keypair = None;
+                self.ntor3_handshake = None;
+                let bytes = self.encrypt_cell_bytes_for_hop(types::RelayCommand::Truncate, &[], 0,
+                                                            hop_index);
+                // If this can't all go out yet, the rest goes with whatever we write next.
+                self.send_cell_bytes(bytes)?;
+                self.stage_started = Instant::now();
+                self.state = CircuitState::ExtendTruncatedReading;
+                Ok(Async::NotReady)
+            }
+            _ => Ok(Async::NotReady),
+        }
+    }
+
+    /// Called while waiting for the TRUNCATED that abandons a timed-out extend. If that times out
+    /// too, the extending hop has stopped responding altogether, so the circuit is given up on.
+    fn check_extend_truncate_timeout(&mut self) -> Result<Async<()>, Error> {
+        match self.extend_timeout {
+            Some(timeout) if self.stage_started.elapsed() >= timeout => {
+                self.state = CircuitState::Error;
+                Err(Error::new(ErrorKind::TimedOut, "timed out waiting for TRUNCATED"))
+            }
+            _ => Ok(Async::NotReady),
+        }
+    }
+
+    /// Drops the last hop from the circuit, by sending RELAY_TRUNCATE to the hop before it and
+    /// waiting for RELAY_TRUNCATED. The circuit can then be extended somewhere else. Streams are
+    /// carried by the last hop, so there mustn't be any open.
+    pub fn poll_truncate(&mut self) -> Result<Async<()>, Error> {
+        if self.circuit_keys.len() < 2 {
+            return Err(Error::new(ErrorKind::InvalidInput, "circuit has only one hop"));
+        }
+        let hop_index = self.circuit_keys.len() - 2;
+        self.poll_truncate_to(hop_index)
+    }
+
+    /// Like `poll_truncate`, but drops every hop after the one at `hop_index` (0 being the first
+    /// hop). `hop_index` must be the same in each call until the truncate completes.
+    pub fn poll_truncate_to(&mut self, hop_index: usize) -> Result<Async<()>, Error> {
+        match self.state {
+            CircuitState::Ready => {
+                if hop_index + 1 >= self.circuit_keys.len() {
+                    return Err(Error::new(ErrorKind::InvalidInput, "invalid hop index"));
+                }
+                if !self.streams.is_empty() {
+                    return Err(Error::new(ErrorKind::InvalidInput,
+                                          "can't truncate a circuit with open streams"));
+                }
+                let bytes = self.encrypt_cell_bytes_for_hop(types::RelayCommand::Truncate, &[], 0,
+                                                            hop_index);
+                self.write_buffer.clear();
+                self.write_buffer.extend(bytes);
+                self.state = CircuitState::TruncateWriting;
+                Ok(Async::NotReady)
+            }
+            CircuitState::TruncateWriting => {
+                match self.poll_send_write_buffer(false)? {
+                    Async::Ready(()) => {
+                        self.state = CircuitState::TruncatedReading;
+                        Ok(Async::NotReady)
+                    }
+                    Async::NotReady => Ok(Async::NotReady),
+                }
+            }
+            CircuitState::TruncatedReading => {
+                let relay_cell = loop {
+                    match self.poll_read_relay_cell()? {
+                        Async::Ready(()) => {},
+                        Async::NotReady => return Ok(Async::NotReady),
+                    }
+                    let relay_cell = match self.get_buffered_relay_cell(0) {
+                        Some(cell) => cell,
+                        None => return Ok(Async::NotReady),
+                    };
+                    // The hops we're dropping may have sent circuit-level SENDMEs before seeing
+                    // the TRUNCATE. `poll_read_relay_cell` has already opened our window for
+                    // them, so there's nothing more to do with them.
+                    if relay_cell.relay_command != types::RelayCommand::SendMe {
+                        break relay_cell;
+                    }
+                };
+                if relay_cell.relay_command != types::RelayCommand::Truncated {
+                    self.state = CircuitState::Error;
+                    return Err(unexpected_relay_command_error(
+                        relay_cell.relay_command,
+                        types::RelayCommand::Truncated,
+                    ));
+                }
+                // `poll_read_relay_cell` has already dropped the hops after whichever hop sent
+                // the TRUNCATED. If that was a hop before `hop_index`, we've lost more than we
+                // asked to, which `get_hop_count` will show.
+                self.state = CircuitState::Ready;
+                Ok(Async::Ready(()))
+            }
+            _ => {
+                self.state = CircuitState::Error;
+                Err(Error::new(ErrorKind::Other, "invalid state in poll_truncate"))
+            }
+        }
+    }
+
+    /// Returns the reason given if a hop has truncated the circuit without our asking (because
+    /// its connection to the next hop went away), and forgets about it. The hops after that one
+    /// will already have been dropped (see `get_hop_count`).
+    pub fn take_unexpected_truncation(&mut self) -> Option<types::DestroyReason> {
+        self.unexpected_truncation.take()
+    }
+
+    /// Called with each RELAY_TRUNCATED cell: `hop_index` has no next hop any more, so neither do
+    /// we. A TRUNCATED from the last hop (e.g. a failed extend) doesn't drop anything.
+    fn handle_truncated(&mut self, hop_index: usize, relay_cell: &types::RelayCell) {
+        if hop_index + 1 >= self.circuit_keys.len() {
+            return;
+        }
+        let reason = match relay_cell.get_data().first() {
+            Some(reason) => types::DestroyReason::from_u8(*reason),
+            None => types::DestroyReason::None,
+        };
+        self.forget_hops_after(hop_index);
+        if self.state != CircuitState::TruncatedReading
+            && self.state != CircuitState::ExtendTruncatedReading
+        {
+            self.unexpected_truncation = Some(reason);
+        }
+    }
+
+    /// Forgets about any hops after the one at `hop_index`. (The first hop doesn't have an entry
+    /// in `extended_ed25519_ids`.)
+    fn forget_hops_after(&mut self, hop_index: usize) {
+        self.circuit_keys.truncate(hop_index + 1);
+        self.congestion_control_sendme_incs.truncate(hop_index + 1);
+        self.extended_ed25519_ids.truncate(hop_index);
+        self.reset_circuit_flow_control();
+    }
+
+    /// Starts circuit-level flow control over with whichever hop is now last. The windows, cell
+    /// counts, and SENDME digests are all between us and the last hop, so none of the old last
+    /// hop's carry over to the new one.
+    fn reset_circuit_flow_control(&mut self) {
+        self.data_cells_received = 0;
+        self.send_window = 1000;
+        self.data_cells_sent = 0;
+        self.expected_sendme_digests.clear();
+    }
+
+    /// Asks the last hop of this circuit to act as a rendezvous point for the given cookie, by
+    /// sending ESTABLISH_RENDEZVOUS and waiting for RENDEZVOUS_ESTABLISHED.
+    pub fn poll_establish_rendezvous(&mut self, cookie: [u8; 20]) -> Result<Async<()>, Error> {
+        match self.state {
+            CircuitState::Ready => {
+                let establish_rendezvous = types::EstablishRendezvousCell::new(cookie);
+                let mut establish_rendezvous_bytes = Vec::new();
+                if establish_rendezvous
+                    .write_to(&mut establish_rendezvous_bytes)
+                    .is_err()
+                {
+                    return Err(Error::new(ErrorKind::Other,
+                                          "couldn't serialize ESTABLISH_RENDEZVOUS cell"));
+                }
+                let bytes = self.encrypt_cell_bytes(types::RelayCommand::EstablishRendezvous,
+                                                    &establish_rendezvous_bytes, 0);
+                self.write_buffer.clear();
+                self.write_buffer.extend(bytes);
+                self.state = CircuitState::EstablishRendezvousWriting;
+                Ok(Async::NotReady)
+            }
+            CircuitState::EstablishRendezvousWriting => {
+                match self.poll_send_write_buffer(false)? {
+                    Async::Ready(()) => {
+                        self.state = CircuitState::RendezvousEstablishedReading;
+                        Ok(Async::NotReady)
+                    }
+                    Async::NotReady => Ok(Async::NotReady),
+                }
+            }
+            CircuitState::RendezvousEstablishedReading => {
+                match self.poll_read_relay_cell()? {
+                    Async::Ready(()) => {},
+                    Async::NotReady => return Ok(Async::NotReady),
+                }
+                let relay_cell = match self.get_buffered_relay_cell(0) {
+                    Some(cell) => cell,
+                    None => return Ok(Async::NotReady),
+                };
+                if relay_cell.relay_command != types::RelayCommand::RendezvousEstablished {
+                    self.state = CircuitState::Error;
+                    return Err(unexpected_relay_command_error(
+                        relay_cell.relay_command,
+                        types::RelayCommand::RendezvousEstablished,
+                    ));
+                }
+                self.state = CircuitState::Ready;
+                Ok(Async::Ready(()))
+            }
+            _ => {
+                self.state = CircuitState::Error;
+                Err(Error::new(ErrorKind::Other, "invalid state in poll_establish_rendezvous"))
+            }
+        }
+    }
+
+    /// Returns the KH value derived when the last hop of this circuit was created or extended, if
+    /// there is a last hop. The hidden service protocol needs this.
+    pub fn get_last_hop_kh(&self) -> Option<[u8; 20]> {
+        self.circuit_keys.last().map(|circuit_keys| circuit_keys.kh)
+    }
+
+    /// Returns the DER encoding of the first hop's TLS certificate (e.g. for pinning, or for
+    /// checking the link authentication offline).
+    pub fn get_peer_cert_der(&self) -> Result<Vec<u8>, Error> {
+        self.tls_connection.get_peer_cert_der()
+    }
+
+    /// Returns the sha-256 hash of the first hop's TLS certificate (see
+    /// `set_pinned_peer_cert_hash`).
+    pub fn get_peer_cert_hash(&self) -> Result<[u8; 32], Error> {
+        self.tls_connection.get_peer_cert_hash()
+    }
+
+    /// Returns whether the link handshake has completed. The responder only sends NETINFO once
+    /// it has accepted our AUTHENTICATE cell, so this is the confirmation that link
+    /// authentication succeeded.
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// How many more RELAY_EARLY cells can be sent on this circuit. Each extend takes one (and
+    /// nothing else does), so once this reaches 0 the circuit can't be extended any further.
+    pub fn relay_early_remaining(&self) -> u8 {
+        MAX_RELAY_EARLY.saturating_sub(self.relay_early_count) as u8
+    }
+
+    /// The link protocol version negotiated with the first hop.
+    pub fn get_link_version(&self) -> u16 {
+        self.link_version
+    }
+
+    pub fn get_circ_id(&self) -> u32 {
+        self.circ_id
+    }
+
+    /// Returns how many hops this circuit currently has.
+    pub fn get_hop_count(&self) -> usize {
+        self.circuit_keys.len()
+    }
+
+    /// Returns a coarse summary of what this circuit is up to.
+    pub fn get_status(&self) -> CircuitStatus {
+        match self.state {
+            CircuitState::Error => CircuitStatus::Destroyed,
+            CircuitState::Ready => if self.streams.len() > 0 {
+                CircuitStatus::Streaming
+            } else {
+                CircuitStatus::Ready
+            },
+            _ => CircuitStatus::Building,
+        }
+    }
+
     pub fn open_dir_stream(&mut self) -> u16 {
         let stream_id = self.used_stream_ids.get_new_id();
-        let stream = Stream {
-            state: StreamState::New,
-            flavor: StreamFlavor::Dir,
-            destination: String::new(),
-            buffer: Vec::new(),
-            sendme_indicator: 50,
-            send_window: 500,
-            last_cell_sent: Instant::now(),
-        };
+        let stream = Stream::new(StreamFlavor::Dir, String::new());
         self.streams.insert(stream_id, stream);
         stream_id
     }
@@ -887,21 +1959,27 @@ where
                         }
                         types::RelayCommand::Begin
                     }
+                    StreamFlavor::Resolve => {
+                        self.streams.insert(stream_id, stream);
+                        return Err(Error::new(ErrorKind::Other,
+                                              "poll_stream_setup: use poll_resolve"));
+                    }
                 };
                 let bytes = self.encrypt_cell_bytes(command, &stream.buffer, stream_id);
-                match self.send_cell_bytes(bytes)? {
-                    Async::Ready(()) => {
-                        stream.state = StreamState::ReadingBegan;
-                        Ok(Async::NotReady)
-                    }
-                    Async::NotReady => {
-                        panic!("we had to buffer sending bytes :(");
-                        Ok(Async::NotReady)
-                    }
-                }
+                // Once it's queued, the cell will go out as we poll for the reply.
+                self.send_cell_bytes(bytes)?;
+                stream.state = StreamState::ReadingBegan;
+                Ok(Async::NotReady)
             }
             StreamState::ReadingBegan => {
                 if let Some(relay_cell) = self.get_buffered_relay_cell(stream_id) {
+                    // The exit refused (or couldn't make) the connection.
+                    if let Some(reason) = relay_cell.get_end_reason() {
+                        stream.state = StreamState::Dead;
+                        self.streams.insert(stream_id, stream);
+                        let msg = format!("stream refused by exit ({:?})", reason);
+                        return Err(Error::new(ErrorKind::ConnectionRefused, msg));
+                    }
                     if relay_cell.relay_command != types::RelayCommand::Connected {
                         println!("{}", relay_cell);
                         return Err(unexpected_relay_command_error(relay_cell.relay_command,
@@ -920,7 +1998,12 @@ where
         result
     }
 
+    /// Sends `data` on the given stream in a single RELAY_DATA cell, so it can be at most
+    /// `types::RELAY_PAYLOAD_LEN` bytes. Use `poll_stream_write_all` to send more than that.
     pub fn poll_stream_write(&mut self, stream_id: u16, data: &[u8]) -> Result<Async<()>, Error> {
+        if data.len() > types::RELAY_PAYLOAD_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, "too much data for one DATA cell"));
+        }
         let _ = self.poll_read_relay_cell()?;
         let mut stream = match self.streams.remove(&stream_id) {
             Some(stream) => stream,
@@ -930,16 +2013,101 @@ where
             let msg = format!("poll_stream_write: invalid stream state: {:?}", stream.state);
             return Err(Error::new(ErrorKind::Other, msg));
         }
+        if stream.pending_write.is_some() {
+            let result = self.poll_pending_write(&mut stream);
+            self.streams.insert(stream_id, stream);
+            return match result? {
+                Async::Ready(_) => Ok(Async::Ready(())),
+                Async::NotReady => Ok(Async::NotReady),
+            };
+        }
         if self.send_window == 0 || stream.send_window == 0 {
+            self.streams.insert(stream_id, stream);
             return Ok(Async::NotReady);
         }
         let bytes = self.encrypt_cell_bytes(types::RelayCommand::Data, data, stream_id);
-        let async = self.send_cell_bytes(bytes)?;
+        let result = self.send_cell_bytes(bytes);
+        if result.is_err() {
+            self.streams.insert(stream_id, stream);
+            return result;
+        }
+        self.record_data_cell_sent();
         self.send_window -= 1;
         stream.send_window -= 1;
         stream.last_cell_sent = Instant::now();
+        if let Ok(Async::NotReady) = result {
+            stream.pending_write = Some(data.len());
+        }
+        self.streams.insert(stream_id, stream);
+        result
+    }
+
+    /// Sends as much of `data` as the circuit and stream windows allow, split into as many
+    /// RELAY_DATA cells as needed. The cells are encrypted in order and written to the connection
+    /// together in one write. Returns the number of bytes of `data` that were sent, or NotReady if
+    /// a window is exhausted and nothing could be sent (poll again once a SENDME arrives) or if
+    /// the connection couldn't take all of the cells yet. In the latter case the cells stay
+    /// queued, and the next call for this stream finishes writing them and returns how much of
+    /// `data` they carried (so pass the same data again).
+    pub fn poll_stream_write_all(
+        &mut self,
+        stream_id: u16,
+        data: &[u8],
+    ) -> Result<Async<usize>, Error> {
+        let _ = self.poll_read_relay_cell()?;
+        let mut stream = match self.streams.remove(&stream_id) {
+            Some(stream) => stream,
+            None => return Err(Error::new(ErrorKind::Other, "invalid stream_id")),
+        };
+        if stream.state != StreamState::Ready {
+            let msg = format!("poll_stream_write_all: invalid stream state: {:?}", stream.state);
+            self.streams.insert(stream_id, stream);
+            return Err(Error::new(ErrorKind::Other, msg));
+        }
+        if stream.pending_write.is_some() {
+            let result = self.poll_pending_write(&mut stream);
+            self.streams.insert(stream_id, stream);
+            return result;
+        }
+        let cell_count = cmp::min(self.send_window, stream.send_window) as usize;
+        let capacity = cmp::min(data.len() / types::RELAY_PAYLOAD_LEN + 1, cell_count);
+        let mut buf: Vec<u8> = Vec::with_capacity(capacity * types::CELL_LEN);
+        let mut sent = 0;
+        for chunk in data.chunks(types::RELAY_PAYLOAD_LEN).take(cell_count) {
+            let bytes = self.encrypt_cell_bytes(types::RelayCommand::Data, chunk, stream_id);
+            if let Err(e) = self.append_relay_cell(bytes, false, &mut buf) {
+                self.streams.insert(stream_id, stream);
+                return Err(e);
+            }
+            self.record_data_cell_sent();
+            self.send_window -= 1;
+            stream.send_window -= 1;
+            sent += chunk.len();
+        }
+        if sent == 0 && !data.is_empty() {
+            self.streams.insert(stream_id, stream);
+            return Ok(Async::NotReady);
+        }
+        stream.last_cell_sent = Instant::now();
+        let result = match self.send_buffer(&buf) {
+            Ok(Async::Ready(())) => Ok(Async::Ready(sent)),
+            Ok(Async::NotReady) => {
+                stream.pending_write = Some(sent);
+                Ok(Async::NotReady)
+            }
+            Err(e) => Err(e),
+        };
         self.streams.insert(stream_id, stream);
-        Ok(async)
+        result
+    }
+
+    /// Finishes a write on `stream` that was queued but that the connection couldn't take all of
+    /// at the time. Returns how much data the write carried once it's all been written.
+    fn poll_pending_write(&mut self, stream: &mut Stream) -> Result<Async<usize>, Error> {
+        match self.poll_flush()? {
+            Async::Ready(()) => Ok(Async::Ready(stream.pending_write.take().unwrap_or(0))),
+            Async::NotReady => Ok(Async::NotReady),
+        }
     }
 
     pub fn poll_stream_read(&mut self, stream_id: u16) -> Result<Async<Vec<u8>>, Error> {
@@ -959,17 +2127,8 @@ where
         let result = if let Some(relay_cell) = self.get_buffered_relay_cell(stream_id) {
             match relay_cell.relay_command {
                 types::RelayCommand::Data => {
-                    // We have to send a SENDME on the *stream* every 50 RELAY_DATA cells and a
-                    // SENDME on the *circuit* every 100.
-                    if self.sendme_indicator == 0 {
-                        let data = Vec::new();
-                        let bytes = self.encrypt_cell_bytes(types::RelayCommand::SendMe, &data, 0);
-                        // so this isn't that easy probably - we'll need to loop if this write
-                        // blocks...
-                        let async = self.send_cell_bytes(bytes)?;
-                        self.sendme_indicator = 100;
-                    }
-                    self.sendme_indicator -= 1;
+                    // We have to send a SENDME on the *stream* every 50 RELAY_DATA cells (the
+                    // circuit-level ones are handled by `record_data_cell_received`).
                     if stream.sendme_indicator == 0 {
                         let data = Vec::new();
                         let bytes = self.encrypt_cell_bytes(types::RelayCommand::SendMe, &data,
@@ -984,6 +2143,7 @@ where
                 }
                 types::RelayCommand::End => {
                     stream.state = StreamState::Dead;
+                    stream.end_reason = relay_cell.get_end_reason();
                     Ok(Async::Ready(Vec::new()))
                 }
                 types::RelayCommand::SendMe => {
@@ -1009,74 +2169,773 @@ where
         result
     }
 
-    pub fn open_stream(&mut self, destination: &str) -> u16 {
-        let stream_id = self.used_stream_ids.get_new_id();
-        let stream = Stream {
-            state: StreamState::New,
-            flavor: StreamFlavor::Data,
-            destination: destination.to_owned(),
-            buffer: Vec::new(),
-            sendme_indicator: 50,
-            send_window: 500,
-            last_cell_sent: Instant::now(),
-        };
-        self.streams.insert(stream_id, stream);
-        stream_id
+    /// Sends a circuit-level RELAY_DROP cell to the last hop. These are ignored by the recipient,
+    /// so this is only useful as cover traffic.
+    pub fn poll_send_drop(&mut self) -> Result<Async<()>, Error> {
+        if self.state != CircuitState::Ready {
+            return Err(Error::new(ErrorKind::Other, "poll_send_drop: circuit not ready"));
+        }
+        let bytes = self.encrypt_cell_bytes(types::RelayCommand::Drop, &[], 0);
+        self.send_cell_bytes(bytes)
     }
 
-    fn send_cell_bytes(
+    /// Escape hatch for protocol testing: writes a cell on this circuit with the given raw command
+    /// byte and payload, bypassing the typed cell constructors entirely. The cell is still framed
+    /// according to the command and goes through the write digest like everything else, but
+    /// nothing about its contents is validated.
+    #[cfg(feature = "testing")]
+    pub fn poll_send_raw_cell(
         &mut self,
-        bytes: Vec<u8>,
+        command_byte: u8,
+        payload: Vec<u8>,
     ) -> Result<Async<()>, Error> {
-        let command = if self.relay_early_count < 8 {
-            self.relay_early_count += 1;
-            types::Command::RelayEarly
-        } else {
-            types::Command::Relay
-        };
-        let cell = types::Cell::new(self.circ_id, command, bytes);
+        let cell = types::Cell::new(self.circ_id, types::Command::from_u8(command_byte), payload);
         let mut buf: Vec<u8> = Vec::new();
-        if let Err(e) = cell.write_to(&mut buf) {
-            return Err(e);
-        }
-        match self.tls_connection.write_all(&buf) {
-            Ok(_) => {
-                Ok(Async::Ready(()))
+        cell.write_to_for_version(&mut buf, self.link_version)?;
+        self.send_buffer(&buf)
+    }
+
+    /// Reads everything from the given stream until the exit sends RELAY_END or until `timeout`
+    /// has elapsed since the first call for this read. SENDMEs are handled along the way by
+    /// `poll_stream_read`. Once the stream ends or the timeout hits, returns all of the data read
+    /// along with how the read finished, so callers can tell a clean end from a timeout. Each call
+    /// reads at most `max_cells_per_poll` cells (see `set_max_cells_per_poll`), so large documents
+    /// take several polls.
+    pub fn poll_stream_read_until_end(
+        &mut self,
+        stream_id: u16,
+        timeout: Duration,
+    ) -> Result<Async<(Vec<u8>, StreamEnd)>, Error> {
+        let started = match self.streams.get_mut(&stream_id) {
+            Some(stream) => *stream.read_started.get_or_insert_with(Instant::now),
+            None => return Err(Error::new(ErrorKind::Other, "invalid stream_id")),
+        };
+        let mut cells_read = 0;
+        let mut made_progress = false;
+        loop {
+            match self.streams.get(&stream_id) {
+                Some(stream) => if stream.state == StreamState::Dead {
+                    break;
+                },
+                None => return Err(Error::new(ErrorKind::Other, "invalid stream_id")),
             }
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                panic!("this is probably a bug - we need to buffer a write");
-                Ok(Async::NotReady)
+            cells_read += 1;
+            if cells_read > self.max_cells_per_poll {
+                if made_progress {
+                    // There's probably more waiting - leave it for the next poll.
+                    return Ok(Async::NotReady);
+                }
+                return Err(too_many_cells_error());
+            }
+            match self.poll_stream_read(stream_id)? {
+                Async::Ready(data) => {
+                    made_progress |= data.len() > 0;
+                    if let Some(stream) = self.streams.get_mut(&stream_id) {
+                        stream.accumulated.extend(data);
+                    }
+                }
+                Async::NotReady => break,
             }
-            Err(e) => Err(e),
         }
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return Err(Error::new(ErrorKind::Other, "invalid stream_id")),
+        };
+        let end = if stream.state == StreamState::Dead {
+            StreamEnd::End(stream.end_reason.take().unwrap_or(types::EndReason::Misc))
+        } else if started.elapsed() > timeout {
+            StreamEnd::TimedOut
+        } else {
+            return Ok(Async::NotReady);
+        };
+        stream.read_started = None;
+        let data = mem::replace(&mut stream.accumulated, Vec::new());
+        Ok(Async::Ready((data, end)))
     }
 
-    // TODO: check circ_id on received cells...?
-    fn poll_read_cell(&mut self) -> Result<Async<types::Cell>, Error> {
-        match self.read_to_buffer()? {
-            Async::Ready(()) => {}
-            Async::NotReady => return Ok(Async::NotReady),
-        }
-        let saved_position = self.buffer.position();
-        match types::Cell::read_new(&mut self.buffer) {
-            Ok(cell) => Ok(Async::Ready(cell)),
-            Err(_) => {
-                self.buffer.set_position(saved_position);
-                Ok(Async::NotReady)
+    /// Like `poll_stream_read_until_end`, but hands each RELAY_DATA payload to `on_chunk` as it
+    /// arrives rather than accumulating the whole response (e.g. to stream a large download to
+    /// disk). SENDMEs are handled as usual. Returns `Async::Ready` once the stream has ended.
+    pub fn poll_stream_read_chunks<F: FnMut(&[u8])>(
+        &mut self,
+        stream_id: u16,
+        mut on_chunk: F,
+    ) -> Result<Async<()>, Error> {
+        let mut cells_read = 0;
+        let mut made_progress = false;
+        loop {
+            match self.streams.get(&stream_id) {
+                Some(stream) => if stream.state == StreamState::Dead {
+                    return Ok(Async::Ready(()));
+                },
+                None => return Err(Error::new(ErrorKind::Other, "invalid stream_id")),
+            }
+            cells_read += 1;
+            if cells_read > self.max_cells_per_poll {
+                if made_progress {
+                    return Ok(Async::NotReady);
+                }
+                return Err(too_many_cells_error());
+            }
+            match self.poll_stream_read(stream_id)? {
+                Async::Ready(data) => if data.len() > 0 {
+                    made_progress = true;
+                    on_chunk(&data);
+                },
+                Async::NotReady => return Ok(Async::NotReady),
             }
         }
     }
-}
-
-fn unexpected_relay_command_error(
-    actual: types::RelayCommand,
-    expected: types::RelayCommand
-) -> Error {
-    Error::new(ErrorKind::Other, format!("expected {:?}, got {:?}", expected, actual))
-}
 
-#[derive(Debug, PartialEq)]
-enum StreamState {
+    /// Fetches a directory document over the directory stream `stream_id` (which must have been
+    /// opened with `open_dir_stream` and set up with `poll_stream_setup`). Sends `request`, reads
+    /// until the relay closes the stream (or `timeout` elapses), and returns the response body.
+    pub fn poll_dir_fetch(
+        &mut self,
+        stream_id: u16,
+        request: &dir::DirRequest,
+        timeout: Duration,
+    ) -> Result<Async<String>, Error> {
+        let request_sent = match self.streams.get(&stream_id) {
+            Some(stream) => stream.dir_request_sent,
+            None => return Err(Error::new(ErrorKind::Other, "invalid stream_id")),
+        };
+        if !request_sent {
+            // Relays don't look at the Host header on BEGIN_DIR streams.
+            let http = request.to_http("127.0.0.1");
+            if http.len() > types::RELAY_PAYLOAD_LEN {
+                return Err(Error::new(ErrorKind::InvalidInput, "directory request too long"));
+            }
+            match self.poll_stream_write(stream_id, http.as_bytes())? {
+                Async::Ready(()) => {}
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+            if let Some(stream) = self.streams.get_mut(&stream_id) {
+                stream.dir_request_sent = true;
+            }
+        }
+        let response = match self.poll_stream_read_until_end(stream_id, timeout)? {
+            Async::Ready((response, StreamEnd::End(_))) => response,
+            Async::Ready((_, StreamEnd::TimedOut)) => {
+                return Err(Error::new(ErrorKind::TimedOut, "directory fetch timed out"))
+            }
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+        dir::parse_http_response(response).map(Async::Ready)
+    }
+
+    /// Reads any available RELAY_DATA for the stream into its inbound buffer (see
+    /// `stream_buffered_len`, `peek_stream_buffer`, and `take_stream_buffer`), so the application
+    /// can read it whenever it's ready. Once the buffer holds `MAX_STREAM_BUFFER` bytes, this stops
+    /// reading the stream's cells, which also stops us sending SENDMEs for them - so the exit
+    /// stops sending once its window runs out, until the application catches up. Returns
+    /// `Async::Ready` if data was added to the buffer or the stream has ended.
+    pub fn poll_stream_buffer(&mut self, stream_id: u16) -> Result<Async<()>, Error> {
+        let mut added = false;
+        loop {
+            match self.streams.get(&stream_id) {
+                Some(stream) => {
+                    if stream.state == StreamState::Dead {
+                        return Ok(Async::Ready(()));
+                    }
+                    if stream.inbound.len() + types::RELAY_PAYLOAD_LEN > MAX_STREAM_BUFFER {
+                        break;
+                    }
+                }
+                None => return Err(Error::new(ErrorKind::Other, "invalid stream_id")),
+            }
+            match self.poll_stream_read(stream_id)? {
+                Async::Ready(data) => {
+                    if let Some(stream) = self.streams.get_mut(&stream_id) {
+                        added = added || data.len() > 0;
+                        stream.inbound.extend(data);
+                    }
+                }
+                Async::NotReady => break,
+            }
+        }
+        if added {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Buffers the stream's RELAY_DATA (as `poll_stream_buffer` does) until `len` bytes are
+    /// available, then removes and returns exactly that many, however many cells they arrived in.
+    /// If the stream ends first, this fails with `ErrorKind::UnexpectedEof` and whatever did arrive
+    /// is left in the buffer for `take_stream_buffer`. `len` can be at most `MAX_STREAM_BUFFER`
+    /// less one cell's worth of data, since the buffer stops filling once another cell might not
+    /// fit (so more than that might never arrive).
+    pub fn poll_stream_read_exact(
+        &mut self,
+        stream_id: u16,
+        len: usize,
+    ) -> Result<Async<Vec<u8>>, Error> {
+        if len > MAX_STREAM_BUFFER - types::RELAY_PAYLOAD_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, "read too large to buffer"));
+        }
+        let _ = self.poll_stream_buffer(stream_id)?;
+        let (buffered, ended) = match self.streams.get(&stream_id) {
+            Some(stream) => (stream.inbound.len(), stream.state == StreamState::Dead),
+            None => return Err(Error::new(ErrorKind::Other, "invalid stream_id")),
+        };
+        if buffered >= len {
+            Ok(Async::Ready(self.take_stream_buffer(stream_id, len)))
+        } else if ended {
+            let msg = format!("stream ended after {} of {} bytes", buffered, len);
+            Err(Error::new(ErrorKind::UnexpectedEof, msg))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Returns how many bytes are waiting in the stream's inbound buffer.
+    pub fn stream_buffered_len(&self, stream_id: u16) -> usize {
+        match self.streams.get(&stream_id) {
+            Some(stream) => stream.inbound.len(),
+            None => 0,
+        }
+    }
+
+    /// Returns the data waiting in the stream's inbound buffer without consuming it.
+    pub fn peek_stream_buffer(&self, stream_id: u16) -> Option<&[u8]> {
+        self.streams.get(&stream_id).map(|stream| &stream.inbound[..])
+    }
+
+    /// Removes and returns up to `max_len` bytes from the front of the stream's inbound buffer.
+    pub fn take_stream_buffer(&mut self, stream_id: u16, max_len: usize) -> Vec<u8> {
+        match self.streams.get_mut(&stream_id) {
+            Some(stream) => {
+                let len = cmp::min(max_len, stream.inbound.len());
+                stream.inbound.drain(..len).collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Closes the stream. If the exit hasn't already ended it, sends a RELAY_END with the given
+    /// reason (usually `EndReason::Done`). Either way, the stream is forgotten immediately - any
+    /// further cells the exit sends for it are ignored.
+    pub fn poll_stream_end(
+        &mut self,
+        stream_id: u16,
+        reason: types::EndReason,
+    ) -> Result<Async<()>, Error> {
+        let already_ended = match self.streams.get(&stream_id) {
+            Some(stream) => stream.state == StreamState::Dead,
+            None => return Err(Error::new(ErrorKind::Other, "invalid stream_id")),
+        };
+        if !already_ended {
+            let bytes =
+                self.encrypt_cell_bytes(types::RelayCommand::End, &[reason.as_u8()], stream_id);
+            match self.send_cell_bytes(bytes)? {
+                Async::Ready(()) => {}
+                // The END is queued, so it mustn't be sent again - it'll go out with whatever the
+                // circuit writes next (or on `poll_flush`).
+                Async::NotReady => {}
+            }
+        }
+        self.streams.remove(&stream_id);
+        self.buffered_relay_cells
+            .retain(|relay_cell| relay_cell.stream_id != stream_id);
+        self.used_stream_ids.release(stream_id);
+        Ok(Async::Ready(()))
+    }
+
+    /// Sets the most cells that will be processed in one call to a reading loop like
+    /// `poll_stream_read_until_end` (and the most DROP cells that will be skipped in a row). A loop
+    /// that reaches this having read some data returns `Async::NotReady` (more cells may already
+    /// be waiting, so poll it again right away); one that read no data at all gives up with an
+    /// error.
+    pub fn set_max_cells_per_poll(&mut self, max_cells_per_poll: usize) {
+        self.max_cells_per_poll = max_cells_per_poll;
+    }
+
+    /// Returns the key material for each hop of the circuit (first hop first), e.g. to decrypt a
+    /// packet capture of this circuit.
+    /// WARNING: this exposes secrets that completely break the security of the circuit. It's only
+    /// available with the "debug-keys" feature, which should never be enabled in real use.
+    #[cfg(feature = "debug-keys")]
+    pub fn export_keys(&self) -> Vec<HopKeys> {
+        println!("WARNING: exporting circuit keys");
+        self.circuit_keys
+            .iter()
+            .map(|circuit_keys| {
+                let k = &circuit_keys.key_material;
+                let mut hop_keys = HopKeys {
+                    forward_digest_seed: [0; 20],
+                    backward_digest_seed: [0; 20],
+                    forward_key: [0; 16],
+                    backward_key: [0; 16],
+                };
+                hop_keys.forward_digest_seed.copy_from_slice(&k[0..20]);
+                hop_keys.backward_digest_seed.copy_from_slice(&k[20..40]);
+                hop_keys.forward_key.copy_from_slice(&k[40..56]);
+                hop_keys.backward_key.copy_from_slice(&k[56..72]);
+                hop_keys
+            })
+            .collect()
+    }
+
+    /// Sets how long to wait for the reply to an EXTEND2 before the extend is retried (see
+    /// `set_extend_retries`) or, once it's out of retries, `poll_extend` fails with
+    /// `ErrorKind::TimedOut`, so the caller can try a different relay. By default there is no
+    /// timeout. Either way, the extending hop is first sent a TRUNCATE so that it abandons the
+    /// extend, and `poll_extend` waits (for as long again) for its TRUNCATED. If that doesn't
+    /// come either, the circuit is torn down.
+    pub fn set_extend_timeout(&mut self, extend_timeout: Duration) {
+        self.extend_timeout = Some(extend_timeout);
+    }
+
+    /// Sets how long (from when the circuit was created) `poll` may keep returning `NotReady`
+    /// before it fails with `ErrorKind::TimedOut`, so a first hop that stops responding can be
+    /// given up on and another tried. The connection itself should be non-blocking, so reads
+    /// that would block come back as `NotReady` rather than hanging. By default there is no
+    /// timeout.
+    pub fn set_handshake_timeout(&mut self, handshake_timeout: Duration) {
+        self.handshake_timeout = Some(handshake_timeout);
+    }
+
+    /// Sets how many times CREATE_FAST and extends are retried when they fail in a way that may
+    /// well be transient, and how long to wait before the first retry (each retry waits twice as
+    /// long as the last, up to a minute). CREATE_FAST is retried if the first hop turns it down
+    /// with a DESTROY because it was asked to, is overloaded, or is hibernating; an extend is
+    /// retried if the extending hop can't reach the next one or the extend times out. By default
+    /// nothing is retried. Failures that indicate something wrong with the circuit itself (e.g.
+    /// a bad handshake or certificate, or a DESTROY once the circuit is built) are never retried.
+    pub fn set_extend_retries(&mut self, max_extend_retries: u32, extend_retry_delay: Duration) {
+        self.max_extend_retries = max_extend_retries;
+        self.extend_retry_delay = extend_retry_delay;
+    }
+
+    /// Sets how long the circuit may go without sending or receiving any cells before `tick`
+    /// tears it down.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = Some(idle_timeout);
+    }
+
+    /// Checks whether the circuit has been idle for longer than the idle timeout (if one was set
+    /// with `set_idle_timeout`) and if so, sends a DESTROY cell and marks the circuit as
+    /// destroyed. Callers should call this periodically. Returns whether the circuit was torn down.
+    pub fn tick(&mut self) -> Result<bool, Error> {
+        let idle_timeout = match self.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return Ok(false),
+        };
+        if self.state == CircuitState::Error || self.last_activity.elapsed() < idle_timeout {
+            return Ok(false);
+        }
+        println!("circuit {} idle for too long - destroying", self.circ_id);
+        self.state = CircuitState::Error;
+        self.send_destroy(types::DestroyReason::None)?;
+        Ok(true)
+    }
+
+    /// Sends a DESTROY cell for this circuit. This doesn't go through `send_cell_bytes` because
+    /// it's a link-level cell rather than a relay cell.
+    fn send_destroy(&mut self, reason: types::DestroyReason) -> Result<(), Error> {
+        let cell = types::Cell::new(self.circ_id, types::Command::Destroy, vec![reason.as_u8()]);
+        let mut buf: Vec<u8> = Vec::new();
+        cell.write_to_for_version(&mut buf, self.link_version)?;
+        self.send_buffer(&buf).map(|_| ())
+    }
+
+    /// Like `open_stream`, but takes the host (a hostname or a literal IPv4 or IPv6 address) and
+    /// port separately and makes sure they'll be encoded correctly in the BEGIN cell.
+    pub fn open_stream_to_host(&mut self, host: &str, port: u16) -> Result<u16, Error> {
+        let begin = types::BeginCell::from_host_port(host, port)?;
+        Ok(self.open_stream(begin.get_addrport()))
+    }
+
+    pub fn open_stream(&mut self, destination: &str) -> u16 {
+        let stream_id = self.used_stream_ids.get_new_id();
+        let stream = Stream::new(StreamFlavor::Data, destination.to_owned());
+        self.streams.insert(stream_id, stream);
+        stream_id
+    }
+
+    /// Starts a DNS lookup of `hostname` by the exit. Call `poll_resolve` with the returned id to
+    /// send the request and get the answer.
+    pub fn open_resolve(&mut self, hostname: &str) -> u16 {
+        let stream_id = self.used_stream_ids.get_new_id();
+        let stream = Stream::new(StreamFlavor::Resolve, hostname.to_owned());
+        self.streams.insert(stream_id, stream);
+        stream_id
+    }
+
+    /// Sends the RELAY_RESOLVE for a lookup started with `open_resolve` and waits for the exit's
+    /// RELAY_RESOLVED. Returns the IPv4 and IPv6 addresses in the answer (hostname answers, from
+    /// reverse lookups, aren't returned). If the exit only sent errors, this fails with
+    /// `ErrorKind::NotFound` (permanent failures) or `ErrorKind::Other` (transient ones, which may
+    /// be worth retrying). Either way, the stream id is released once the answer arrives.
+    pub fn poll_resolve(&mut self, stream_id: u16) -> Result<Async<Vec<IpAddr>>, Error> {
+        let _ = self.poll_read_relay_cell()?;
+        let mut stream = match self.streams.remove(&stream_id) {
+            Some(stream) => stream,
+            None => return Err(Error::new(ErrorKind::Other, "invalid stream_id")),
+        };
+        if stream.flavor != StreamFlavor::Resolve {
+            self.streams.insert(stream_id, stream);
+            return Err(Error::new(ErrorKind::Other, "poll_resolve: not a resolve stream"));
+        }
+        match stream.state {
+            StreamState::New => {
+                let resolve = match types::ResolveCell::new(&stream.destination) {
+                    Ok(resolve) => resolve,
+                    Err(e) => {
+                        self.used_stream_ids.release(stream_id);
+                        return Err(e);
+                    }
+                };
+                resolve.write_to(&mut stream.buffer)?;
+                let bytes = self.encrypt_cell_bytes(types::RelayCommand::Resolve, &stream.buffer,
+                                                    stream_id);
+                stream.buffer.clear();
+                let async = self.send_cell_bytes(bytes);
+                stream.state = StreamState::ReadingBegan;
+                self.streams.insert(stream_id, stream);
+                async?;
+                Ok(Async::NotReady)
+            }
+            StreamState::ReadingBegan => {
+                let relay_cell = match self.get_buffered_relay_cell(stream_id) {
+                    Some(relay_cell) => relay_cell,
+                    None => {
+                        self.streams.insert(stream_id, stream);
+                        return Ok(Async::NotReady);
+                    }
+                };
+                // A RESOLVE doesn't open anything at the exit, so there's nothing to END - the id
+                // is free again as soon as the answer is in.
+                self.used_stream_ids.release(stream_id);
+                if let Some(reason) = relay_cell.get_end_reason() {
+                    let msg = format!("resolve refused by exit ({:?})", reason);
+                    return Err(Error::new(ErrorKind::ConnectionRefused, msg));
+                }
+                if relay_cell.relay_command != types::RelayCommand::Resolved {
+                    return Err(unexpected_relay_command_error(relay_cell.relay_command,
+                                                              types::RelayCommand::Resolved));
+                }
+                let resolved = types::ResolvedCell::read_new(relay_cell.get_data())?;
+                let mut addresses = Vec::new();
+                let mut permanent_failure = false;
+                for answer in resolved.get_answers() {
+                    match answer {
+                        &types::ResolvedAnswer::IPv4 { address, .. } => {
+                            addresses.push(IpAddr::V4(Ipv4Addr::from(address)));
+                        }
+                        &types::ResolvedAnswer::IPv6 { address, .. } => {
+                            addresses.push(IpAddr::V6(Ipv6Addr::from(address)));
+                        }
+                        &types::ResolvedAnswer::Error { ref error, .. } => {
+                            permanent_failure = *error == types::ResolveError::Permanent;
+                        }
+                        _ => {}
+                    }
+                }
+                if addresses.is_empty() && permanent_failure {
+                    return Err(Error::new(ErrorKind::NotFound, "exit couldn't resolve hostname"));
+                } else if addresses.is_empty() {
+                    return Err(Error::new(ErrorKind::Other, "exit failed to resolve hostname"));
+                }
+                Ok(Async::Ready(addresses))
+            }
+            _ => {
+                self.streams.insert(stream_id, stream);
+                Err(Error::new(ErrorKind::Other, "poll_resolve: invalid state"))
+            }
+        }
+    }
+
+    fn send_cell_bytes(
+        &mut self,
+        bytes: Vec<u8>,
+    ) -> Result<Async<()>, Error> {
+        let mut buf: Vec<u8> = Vec::new();
+        self.append_relay_cell(bytes, false, &mut buf)?;
+        self.send_buffer(&buf)
+    }
+
+    /// Like `send_cell_bytes`, but sends a RELAY_EARLY cell, as EXTEND2 has to be. Fails if the
+    /// circuit's RELAY_EARLY budget has been used up.
+    fn send_relay_early_cell_bytes(&mut self, bytes: Vec<u8>) -> Result<Async<()>, Error> {
+        let mut buf: Vec<u8> = Vec::new();
+        self.append_relay_cell(bytes, true, &mut buf)?;
+        self.send_buffer(&buf)
+    }
+
+    /// Wraps an already-encrypted relay payload in a RELAY (or, if `early` is set, RELAY_EARLY)
+    /// cell and appends its wire encoding to `buf`. Only EXTEND2 needs RELAY_EARLY, and relays kill
+    /// circuits that send more than `MAX_RELAY_EARLY` of them, so everything else goes as RELAY.
+    fn append_relay_cell(
+        &mut self,
+        bytes: Vec<u8>,
+        early: bool,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let command = if early {
+            if self.relay_early_count >= MAX_RELAY_EARLY {
+                return Err(Error::new(ErrorKind::Other, "RELAY_EARLY budget exhausted"));
+            }
+            self.relay_early_count += 1;
+            types::Command::RelayEarly
+        } else {
+            types::Command::Relay
+        };
+        let cell = types::Cell::new(self.circ_id, command, bytes);
+        cell.write_to_for_version(buf, self.link_version)
+    }
+
+    /// Queues one or more encoded cells and writes as much of what's queued as the connection
+    /// will take, in as few writes as it can. Returns NotReady if some of it is still queued -
+    /// it'll be written by later calls (or `poll_flush`), so the caller mustn't send it again.
+    fn send_buffer(&mut self, buf: &[u8]) -> Result<Async<()>, Error> {
+        self.pending_output.extend_from_slice(buf);
+        self.poll_flush()
+    }
+
+    /// Queues the relay cell in `write_buffer` (the first time it's called for it) and writes
+    /// what's queued, as `send_buffer` does.
+    fn poll_send_write_buffer(&mut self, relay_early: bool) -> Result<Async<()>, Error> {
+        if !self.write_buffer.is_empty() {
+            let bytes = mem::replace(&mut self.write_buffer, Vec::new());
+            let mut buf: Vec<u8> = Vec::new();
+            self.append_relay_cell(bytes, relay_early, &mut buf)?;
+            self.pending_output.extend(buf);
+        }
+        self.poll_flush()
+    }
+
+    /// Writes cells that were queued when the connection couldn't take them. Returns Ready once
+    /// there's nothing left to write. Reading from the circuit does this too, so it's only needed
+    /// when there's nothing to read.
+    pub fn poll_flush(&mut self) -> Result<Async<()>, Error> {
+        while !self.pending_output.is_empty() {
+            match self.tls_connection.write(&self.pending_output) {
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "connection closed")),
+                Ok(written) => {
+                    self.pending_output.drain(..written);
+                    self.last_activity = Instant::now();
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+
+    // TODO: check circ_id on received cells...?
+    fn poll_read_cell(&mut self) -> Result<Async<types::Cell>, Error> {
+        match self.read_to_buffer()? {
+            Async::Ready(()) => {}
+            Async::NotReady => return Ok(Async::NotReady),
+        }
+        match self.read_buffered_cell() {
+            Some(cell) => {
+                self.last_activity = Instant::now();
+                Ok(Async::Ready(cell))
+            }
+            None => Ok(Async::NotReady),
+        }
+    }
+
+    /// Decodes the next complete cell in the read buffer, skipping PADDING and VPADDING cells
+    /// (which relays may send at any time and which carry nothing). Returns `None`, leaving any
+    /// partial cell in the buffer, if there isn't a complete non-padding cell yet. Skipped cells
+    /// were still hashed as they were read, so they're counted in SLOG as they should be.
+    fn read_buffered_cell(&mut self) -> Option<types::Cell> {
+        loop {
+            let saved_position = self.buffer.position();
+            match types::Cell::read_new_for_version(&mut self.buffer, self.link_version) {
+                Ok(cell) => match cell.command {
+                    types::Command::Padding | types::Command::VPadding => continue,
+                    _ => return Some(cell),
+                },
+                Err(_) => {
+                    self.buffer.set_position(saved_position);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// The key material for one hop of a circuit, as exported by `Circuit::export_keys`.
+#[cfg(feature = "debug-keys")]
+#[derive(Clone, Debug)]
+pub struct HopKeys {
+    /// The seed of the running digest of cells sent to the hop (Df).
+    pub forward_digest_seed: [u8; 20],
+    /// The seed of the running digest of cells sent by the hop (Db).
+    pub backward_digest_seed: [u8; 20],
+    /// The AES-128 key for cells sent to the hop (Kf).
+    pub forward_key: [u8; 16],
+    /// The AES-128 key for cells sent by the hop (Kb).
+    pub backward_key: [u8; 16],
+}
+
+/// The fields of an AUTH0003 (Ed25519-SHA256-RFC5705) AUTHENTICATE body that go before the
+/// signature. See tor-spec.txt section 4.4.2.
+pub struct Auth0003Fields {
+    /// sha-256 hash of the initiator's RSA identity key.
+    pub cid: [u8; 32],
+    /// sha-256 hash of the responder's RSA identity key.
+    pub sid: [u8; 32],
+    /// The initiator's Ed25519 identity public key.
+    pub cid_ed: [u8; 32],
+    /// The responder's Ed25519 identity public key.
+    pub sid_ed: [u8; 32],
+    /// sha-256 hash of all bytes received from the responder by the initiator.
+    pub slog: [u8; 32],
+    /// sha-256 hash of all bytes sent to the responder by the initiator.
+    pub clog: [u8; 32],
+    /// sha-256 hash of the responder's TLS link certificate.
+    pub scert: [u8; 32],
+    /// Output from the RFC5705 exporter on the TLS session.
+    pub tlssecrets: [u8; 32],
+    /// Random bytes chosen by the initiator.
+    pub rand: [u8; 24],
+}
+
+/// Assembles the part of an AUTH0003 AUTHENTICATE body that gets signed: "AUTH0003", CID, SID,
+/// CID_ED, SID_ED, SLOG, CLOG, SCERT, TLSSECRETS, and RAND, in that order. (The initiator then
+/// appends SIG, a signature over all of this with its Ed25519 authenticate key.)
+pub fn build_auth0003_body(fields: &Auth0003Fields) -> Vec<u8> {
+    let mut body: Vec<u8> = b"AUTH0003".to_vec();
+    body.extend(fields.cid.iter());
+    body.extend(fields.sid.iter());
+    body.extend(fields.cid_ed.iter());
+    body.extend(fields.sid_ed.iter());
+    body.extend(fields.slog.iter());
+    body.extend(fields.clog.iter());
+    body.extend(fields.scert.iter());
+    body.extend(fields.tlssecrets.iter());
+    body.extend(fields.rand.iter());
+    body
+}
+
+/// A coarse summary of a `Circuit`'s state.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CircuitStatus {
+    /// The link handshake, CREATE_FAST, or an extend is in progress.
+    Building,
+    /// The circuit can be extended or have streams opened on it.
+    Ready,
+    /// The circuit is ready and has at least one stream open on it.
+    Streaming,
+    /// The circuit has failed or been destroyed and can't be used any more.
+    Destroyed,
+}
+
+/// A snapshot of a circuit, as listed by `CircuitManager::list`.
+#[derive(Clone, Debug)]
+pub struct CircuitDescription {
+    pub circ_id: u32,
+    pub status: CircuitStatus,
+    pub hop_count: usize,
+}
+
+/// Keeps track of a set of circuits by circuit ID. This is the top-level object a client would
+/// hold. Note that each `Circuit` currently has its own TLS connection (circuits aren't
+/// multiplexed over a shared connection yet), so there's no shared read loop to coordinate - each
+/// circuit is still polled individually.
+pub struct CircuitManager<T, V>
+where
+    T: TlsImpl + Read + Write,
+    V: RsaVerifierImpl,
+{
+    circuits: HashMap<u32, Circuit<T, V>>,
+    /// The ids of the circuits in `circuits`. They're released when the circuits are removed.
+    circ_ids: IdTracker<u32>,
+}
+
+impl<T, V> CircuitManager<T, V>
+where
+    T: TlsImpl + Read + Write,
+    V: RsaVerifierImpl,
+{
+    pub fn new() -> CircuitManager<T, V> {
+        CircuitManager {
+            circuits: HashMap::new(),
+            circ_ids: IdTracker::new(),
+        }
+    }
+
+    /// Picks a circuit id that no circuit this manager has (or will have, until it's removed) is
+    /// using, for making a new `Circuit` to `add`.
+    pub fn new_circ_id(&mut self) -> u32 {
+        self.circ_ids.get_new_id()
+    }
+
+    /// Takes ownership of `circuit`, returning its circuit ID.
+    pub fn add(&mut self, circuit: Circuit<T, V>) -> u32 {
+        let circ_id = circuit.get_circ_id();
+        self.circ_ids.mark_used(circ_id);
+        self.circuits.insert(circ_id, circuit);
+        circ_id
+    }
+
+    pub fn get_mut(&mut self, circ_id: u32) -> Option<&mut Circuit<T, V>> {
+        self.circuits.get_mut(&circ_id)
+    }
+
+    pub fn remove(&mut self, circ_id: u32) -> Option<Circuit<T, V>> {
+        self.circ_ids.release(circ_id);
+        self.circuits.remove(&circ_id)
+    }
+
+    /// Calls `tick` on each circuit (tearing down any that have been idle for too long) and then
+    /// forgets about any circuits that have been destroyed.
+    pub fn tick(&mut self) {
+        for (circ_id, circuit) in self.circuits.iter_mut() {
+            if let Err(e) = circuit.tick() {
+                println!("error tearing down circuit {}: {}", circ_id, e);
+            }
+        }
+        self.remove_destroyed();
+    }
+
+    /// Forgets about any circuits that have been destroyed (by us, or by a DESTROY from the
+    /// relay), making their ids available again.
+    pub fn remove_destroyed(&mut self) {
+        let circ_ids = &mut self.circ_ids;
+        self.circuits.retain(|circ_id, circuit| {
+            let destroyed = circuit.get_status() == CircuitStatus::Destroyed;
+            if destroyed {
+                circ_ids.release(*circ_id);
+            }
+            !destroyed
+        });
+    }
+
+    /// Lists all circuits and what they're up to.
+    pub fn list(&self) -> Vec<CircuitDescription> {
+        self.circuits
+            .values()
+            .map(|circuit| CircuitDescription {
+                circ_id: circuit.get_circ_id(),
+                status: circuit.get_status(),
+                hop_count: circuit.get_hop_count(),
+            })
+            .collect()
+    }
+}
+
+fn too_many_cells_error() -> Error {
+    Error::new(ErrorKind::Other, "too many cells read without making progress")
+}
+
+fn unexpected_relay_command_error(
+    actual: types::RelayCommand,
+    expected: types::RelayCommand
+) -> Error {
+    Error::new(ErrorKind::Other, format!("expected {:?}, got {:?}", expected, actual))
+}
+
+#[derive(Debug, PartialEq)]
+enum StreamState {
     New,
     //WritingBegin,
     ReadingBegan,
@@ -1088,6 +2947,8 @@ enum StreamState {
 enum StreamFlavor {
     Dir,
     Data,
+    /// Not a real stream - just the stream id a RELAY_RESOLVE and its answer are sent on.
+    Resolve,
 }
 
 struct Stream {
@@ -1098,6 +2959,48 @@ struct Stream {
     sendme_indicator: u8,
     send_window: u16,
     last_cell_sent: Instant,
+    /// Data accumulated so far by `poll_stream_read_until_end`.
+    accumulated: Vec<u8>,
+    /// When `poll_stream_read_until_end` was first called for the current read.
+    read_started: Option<Instant>,
+    /// The reason given in the RELAY_END cell that ended this stream, if any.
+    end_reason: Option<types::EndReason>,
+    /// Whether `poll_dir_fetch` has sent its request on this stream yet.
+    dir_request_sent: bool,
+    /// Data read by `poll_stream_buffer` that the application hasn't taken yet.
+    inbound: Vec<u8>,
+    /// Set when a write on this stream was queued but couldn't all be written yet, to how much
+    /// data it carried (see `Circuit::poll_stream_write_all`).
+    pending_write: Option<usize>,
+}
+
+impl Stream {
+    fn new(flavor: StreamFlavor, destination: String) -> Stream {
+        Stream {
+            state: StreamState::New,
+            flavor: flavor,
+            destination: destination,
+            buffer: Vec::new(),
+            sendme_indicator: 50,
+            send_window: 500,
+            last_cell_sent: Instant::now(),
+            accumulated: Vec::new(),
+            read_started: None,
+            end_reason: None,
+            dir_request_sent: false,
+            inbound: Vec::new(),
+            pending_write: None,
+        }
+    }
+}
+
+/// How a `poll_stream_read_until_end` finished.
+#[derive(Debug, PartialEq)]
+pub enum StreamEnd {
+    /// The exit closed the stream with a RELAY_END carrying the given reason.
+    End(types::EndReason),
+    /// The timeout elapsed before the stream was closed.
+    TimedOut,
 }
 
 struct TlsHashWrapper<T: TlsImpl + Read + Write> {
@@ -1132,6 +3035,10 @@ impl<T: TlsImpl + Read + Write> TlsImpl for TlsHashWrapper<T> {
         self.tls_impl.get_peer_cert_hash()
     }
 
+    fn get_peer_cert_der(&self) -> Result<Vec<u8>, Error> {
+        self.tls_impl.get_peer_cert_der()
+    }
+
     fn get_tls_secrets(&self, label: &str, context_key: &[u8]) -> Result<Vec<u8>, Error> {
         self.tls_impl.get_tls_secrets(label, context_key)
     }
@@ -1139,7 +3046,16 @@ impl<T: TlsImpl + Read + Write> TlsImpl for TlsHashWrapper<T> {
 
 impl<T: TlsImpl + Read + Write> Read for TlsHashWrapper<T> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
-        self.tls_impl.read(buf)
+        let result = self.tls_impl.read(buf);
+        #[cfg(feature = "dump-cells")]
+        {
+            if let &Ok(len) = &result {
+                if len > 0 {
+                    println!("read:{}", util::to_hex(&buf[..len]));
+                }
+            }
+        }
+        result
     }
 }
 
@@ -1148,6 +3064,8 @@ impl<T: TlsImpl + Read + Write> Write for TlsHashWrapper<T> {
         let result = self.tls_impl.write(data);
         if let &Ok(len) = &result {
             self.write_log.input(&data[..len]);
+            #[cfg(feature = "dump-cells")]
+            println!("write:{}", util::to_hex(&data[..len]));
         }
         result
     }
@@ -1157,37 +3075,82 @@ impl<T: TlsImpl + Read + Write> Write for TlsHashWrapper<T> {
     }
 }
 
+/// AES in counter mode with an all-zero IV, as used for relay cell encryption.
 struct AesContext {
-    aes: blockmodes::CtrModeX8<aessafe::AesSafe128EncryptorX8>,
+    aes: Box<SynchronousStreamCipher>,
 }
 
 impl AesContext {
+    /// The length of `key` selects the key size: 16 bytes for AES-128 (what CREATE_FAST, TAP and
+    /// ntor derive) or 32 bytes for AES-256. Panics on any other length.
     fn new(key: &[u8]) -> AesContext {
         let iv: [u8; 16] = [0; 16];
-        let key: [u8; 16] = slice_to_16_byte_array(key);
-        let aes_dec = aessafe::AesSafe128EncryptorX8::new(&key);
-        AesContext {
-            aes: blockmodes::CtrModeX8::new(aes_dec, &iv),
-        }
+        let aes: Box<SynchronousStreamCipher> = match key.len() {
+            16 => {
+                let key: [u8; 16] = slice_to_16_byte_array(key);
+                let aes_enc = aessafe::AesSafe128EncryptorX8::new(&key);
+                Box::new(blockmodes::CtrModeX8::new(aes_enc, &iv))
+            }
+            32 => {
+                let key: [u8; 32] = util::slice_to_32_byte_array(key);
+                let aes_enc = aessafe::AesSafe256EncryptorX8::new(&key);
+                Box::new(blockmodes::CtrModeX8::new(aes_enc, &iv))
+            }
+            len => panic!("unsupported AES key length {}", len),
+        };
+        AesContext { aes: aes }
     }
 }
 
+/// The keys shared with one hop of a circuit. "Forward" is the direction away from us (cells we
+/// send) and "backward" is towards us (cells we receive), for both us and the relay.
 struct CircuitKeys {
+    /// Running digest of cells we send to this hop (Df).
     forward_digest: Sha1,
-    // backward_digest: Sha1, TODO: use this
+    /// Running digest of cells this hop sends to us (Db).
+    backward_digest: Sha1,
+    /// Encrypts cells we send (Kf).
     forward_key: AesContext,
+    /// Decrypts cells we receive (Kb).
     backward_key: AesContext,
+    /// The derived KH value, which the hidden service protocol uses to prove knowledge of the
+    /// shared secret.
+    kh: [u8; 20],
+    /// A non-secret identifier for these keys (see `fingerprint`).
+    fingerprint: [u8; 8],
+    /// The raw key material these keys were made from (Df | Db | Kf | Kb), for `export_keys`.
+    #[cfg(feature = "debug-keys")]
+    key_material: Vec<u8>,
 }
 
 impl CircuitKeys {
+    /// Expects at least 92 bytes of key material laid out as
+    /// Df (20 bytes) | Db (20 bytes) | Kf (16 bytes) | Kb (16 bytes) | KH (20 bytes).
     fn new(k: &[u8]) -> CircuitKeys {
+        let mut hash = Sha256::new();
+        hash.input(b"toroxide circuit keys fingerprint");
+        hash.input(&k[0..72]);
+        let mut fingerprint = [0; 8];
+        fingerprint.copy_from_slice(&hash.result()[0..8]);
         CircuitKeys {
             forward_digest: Sha1::from(&k[0..20]),
-            // backward_digest: Sha1::from(&k[20..40]),
+            backward_digest: Sha1::from(&k[20..40]),
             forward_key: AesContext::new(&k[40..56]),
             backward_key: AesContext::new(&k[56..72]),
+            kh: util::slice_to_20_byte_array(&k[72..92]),
+            fingerprint: fingerprint,
+            #[cfg(feature = "debug-keys")]
+            key_material: k[0..72].to_owned(),
         }
     }
+
+    /// A short identifier for this hop's keys, for correlating logs between the two ends of a
+    /// circuit while debugging. It's 64 bits of a labelled sha-256 hash of the key material, so
+    /// it reveals nothing useful about the keys themselves. Only printed with `dump-cells`.
+    #[cfg_attr(not(feature = "dump-cells"), allow(dead_code))]
+    fn fingerprint(&self) -> [u8; 8] {
+        self.fingerprint
+    }
 }
 
 /// Represents the certs that are supposed to be present in a responder's CERTS cell.
@@ -1207,8 +3170,7 @@ impl ResponderCerts {
         let mut ed25519_link_cert: Option<certs::Ed25519Cert> = None;
         let mut ed25519_identity_cert: Option<certs::Ed25519Identity> = None;
 
-        // Technically we're supposed to ensure all X509 certificates have valid dates and that all
-        // certificate are correctly signed, but...
+        // Dates and signatures are checked in `validate`.
         for cert in certs {
             match cert {
                 certs::Cert::RsaIdentity(cert) => {
@@ -1263,7 +3225,25 @@ impl ResponderCerts {
         expected_ed25519_id_key: &[u8; 32],
         peer_cert_hash: &[u8; 32],
         rsa_verifier: &RsaVerifierImpl,
+        now: u64,
     ) -> Result<(), &'static str> {
+        // Every cert has to be valid at `now` (seconds since the epoch).
+        let (not_before, not_after) = self.rsa_identity_cert.get_validity()?;
+        if now < not_before {
+            return Err("RSA identity cert is not yet valid");
+        }
+        if now > not_after {
+            return Err("RSA identity cert has expired");
+        }
+        if now >= self.ed25519_identity_cert.get_expiration_date() as u64 * 3600 {
+            return Err("Ed25519 identity cert has expired");
+        }
+        if self.ed25519_signing_cert.is_expired(now) {
+            return Err("Ed25519 signing cert has expired");
+        }
+        if self.ed25519_link_cert.is_expired(now) {
+            return Err("Ed25519 link cert has expired");
+        }
         // Need to check:
         // rsa_identity_cert is self-signed
         /* honestly, not sure what this protects against
@@ -1282,11 +3262,26 @@ impl ResponderCerts {
             return Err("RSA identity key wrong size");
         }
         */
+        // Each Ed25519 cert has to actually claim the role it's being used for (otherwise e.g. a
+        // link cert could be passed off as a signing cert).
+        if *self.ed25519_signing_cert.get_cert_type() != certs::Ed25519CertType::SigningKey {
+            return Err("Ed25519 signing cert has the wrong cert type");
+        }
+        if *self.ed25519_link_cert.get_cert_type() != certs::Ed25519CertType::TlsLinkCertificate {
+            return Err("Ed25519 link cert has the wrong cert type");
+        }
         // ed25519 identity key (in ed25519_identity_cert) signed ed25519_signing_cert
         let ed25519_identity_key = self.ed25519_identity_cert.get_key();
         if !ed25519_identity_key.matches_expected_key(expected_ed25519_id_key) {
             return Err("Ed25519 identity key does not match the expected key");
         }
+        // The signing cert has to name the identity key as its signer (cert-spec.txt section
+        // A.1), not just happen to verify with it.
+        match self.ed25519_signing_cert.get_signed_with_key() {
+            Some(key) if key == self.ed25519_identity_cert.get_key_bytes() => {}
+            Some(_) => return Err("Ed25519 signing cert names a different signing key"),
+            None => return Err("Ed25519 signing cert has no signed-with-ed25519-key extension"),
+        }
         if !ed25519_identity_key.check_ed25519_signature(&self.ed25519_signing_cert) {
             return Err("Ed25519 identity key did not sign Ed25519 signing cert");
         }
@@ -1316,21 +3311,43 @@ struct InitiatorCerts {
 }
 
 impl InitiatorCerts {
+    /// Makes certs for a fresh, random Ed25519 identity.
     fn new(rsa_signer: &RsaSignerImpl) -> InitiatorCerts {
-        // Apparently we don't need to keep this around for now.
-        let rsa_identity_cert = certs::X509Cert::new(rsa_signer.get_cert_bytes());
         // Apparently we don't need to keep this around for now.
         let ed25519_identity_key = keys::Ed25519Key::new();
-        let ed25519_identity_cert =
-            rsa_identity_cert.sign_ed25519_key(&ed25519_identity_key, rsa_signer);
+        InitiatorCerts::from_keys(
+            rsa_signer,
+            &ed25519_identity_key,
+            certs::DEFAULT_EXPIRATION_DATE,
+        )
+    }
+
+    /// Makes certs for the given RSA (via `rsa_signer`) and Ed25519 identities, expiring at
+    /// `expiration_date`. The signing and authenticate keys are always fresh.
+    fn from_keys(
+        rsa_signer: &RsaSignerImpl,
+        ed25519_identity_key: &keys::Ed25519Key,
+        expiration_date: certs::HoursSinceEpoch,
+    ) -> InitiatorCerts {
+        // Apparently we don't need to keep this around for now.
+        let rsa_identity_cert = certs::X509Cert::new(rsa_signer.get_cert_bytes());
+        let ed25519_identity_cert = rsa_identity_cert.sign_ed25519_key(
+            ed25519_identity_key,
+            rsa_signer,
+            expiration_date,
+        );
         // Apparently we don't need to keep this around for now.
         let ed25519_signing_key = keys::Ed25519Key::new();
-        let ed25519_signing_cert = ed25519_identity_key
-            .sign_ed25519_key(&ed25519_signing_key, certs::Ed25519CertType::SigningKey);
+        let ed25519_signing_cert = ed25519_identity_key.sign_ed25519_key(
+            &ed25519_signing_key,
+            certs::Ed25519CertType::SigningKey,
+            expiration_date,
+        );
         let ed25519_authenticate_key = keys::Ed25519Key::new();
         let ed25519_authenticate_cert = ed25519_signing_key.sign_ed25519_key(
             &ed25519_authenticate_key,
             certs::Ed25519CertType::AuthenticationKey,
+            expiration_date,
         );
         InitiatorCerts {
             rsa_identity_cert: rsa_identity_cert,
@@ -1342,6 +3359,10 @@ impl InitiatorCerts {
     }
 
     fn to_certs_cell(&self) -> types::CertsCell {
+        types::CertsCell::new_from_raw_certs(self.to_raw_certs())
+    }
+
+    fn to_raw_certs(&self) -> Vec<types::RawCert> {
         let mut certs: Vec<types::RawCert> = Vec::new();
         let mut bytes: Vec<u8> = Vec::new();
         self.rsa_identity_cert.write_to(&mut bytes);
@@ -1362,7 +3383,7 @@ impl InitiatorCerts {
             bytes,
         ));
 
-        types::CertsCell::new_from_raw_certs(certs)
+        certs
     }
 
     fn get_ed25519_authenticate_key(&self) -> &keys::Ed25519Key {
@@ -1371,26 +3392,30 @@ impl InitiatorCerts {
 }
 
 /// Implements KDF-TOR as specified by tor-spec.txt section 5.2.1 in the context of a CREATE FAST
-/// handshake. The TAP handshake is not implemented.
-/// Given K0 as `x` and `y` concatenated together, computes
+/// handshake, where K0 is `x` and `y` concatenated together. See `kdf_tor`.
+fn tor_kdf(x: &[u8; 20], y: &[u8; 20], kh: &[u8; 20]) -> Result<CircuitKeys, HandshakeError> {
+    let mut k0: Vec<u8> = Vec::with_capacity(40);
+    k0.extend(x.iter());
+    k0.extend(y.iter());
+    kdf_tor(&k0, kh)
+}
+
+/// Implements KDF-TOR as specified by tor-spec.txt section 5.2.1 (for CREATE FAST, K0 is X | Y; for
+/// TAP, it's g^xy). Computes
 /// K = H(K0 | [00]) | H(K0 | [01]) | H(K0 | [02]) | ...
 /// where H is SHA-1 (?), '|' indicated concatenation, and [XX] is a byte of the indicated value.
 /// The first 20 bytes should equal the given `kh` (this demonstrates that the server knows `x`).
 /// The next 20 bytes are the forward digest. The next 20 bytes are the backward digest. The next
 /// 16 bytes are the forward encryption key. The next 16 bytes are the backward encryption key.
 /// In total, 92 bytes of K need to be generated, which means 5 blocks in total (the last 8 bytes
-/// are discarded).
-fn tor_kdf(x: &[u8; 20], y: &[u8; 20], kh: &[u8; 20]) -> Result<CircuitKeys, ()> {
-    let mut k0: Vec<u8> = Vec::with_capacity(40);
-    k0.extend(x.iter());
-    k0.extend(y.iter());
-
+/// are discarded). KH is kept around in the resulting `CircuitKeys`.
+fn kdf_tor(k0: &[u8], kh: &[u8; 20]) -> Result<CircuitKeys, HandshakeError> {
     let mut hash = Sha1::new();
     hash.update(&k0);
     hash.update(&[0]);
     let kh_calculated = hash.digest().bytes();
-    if !constant_time_eq(&kh_calculated, kh) {
-        return Err(());
+    if !util::ct_eq(&kh_calculated, kh) {
+        return Err(HandshakeError::KhMismatch);
     }
 
     let mut buffer: Vec<u8> = Vec::new();
@@ -1400,6 +3425,9 @@ fn tor_kdf(x: &[u8; 20], y: &[u8; 20], kh: &[u8; 20]) -> Result<CircuitKeys, ()>
         hash.update(&[i]);
         buffer.extend(hash.digest().bytes().iter());
     }
+    // `CircuitKeys` expects KH to come after the digests and keys.
+    buffer.truncate(72);
+    buffer.extend(kh_calculated.iter());
     Ok(CircuitKeys::new(&buffer))
 }
 
@@ -1408,20 +3436,22 @@ fn ntor_handshake(
     created2_cell: &types::Created2Cell,
     router_id: [u8; 20],
     server_B: [u8; 32],
-    client_X: [u8; 32],
-    mut client_x: [u8; 32],
-) -> Result<CircuitKeys, ()> {
+    client_keypair: &keys::Curve25519Keypair,
+) -> Result<CircuitKeys, HandshakeError> {
     // technically we should check the corresponding create2_cell type here
-    let server_handshake =
-        types::NtorServerHandshake::read_new(&mut &created2_cell.h_data[..]).unwrap();
-    client_x[0] &= 248;
-    client_x[31] &= 127;
-    client_x[31] |= 64;
-    let Y = montgomery::CompressedMontgomeryU(server_handshake.server_pk);
-    let x = scalar::Scalar::from_bits(client_x);
-    let exp_Y_x = curve25519_multiply(&Y, &x);
-    let B = montgomery::CompressedMontgomeryU(server_B);
-    let exp_B_x = curve25519_multiply(&B, &x);
+    let server_handshake = match types::NtorServerHandshake::from_h_data(&created2_cell.h_data) {
+        Ok(server_handshake) => server_handshake,
+        Err(_) => return Err(HandshakeError::Malformed),
+    };
+    let client_X = client_keypair.get_public_key_bytes();
+    let exp_Y_x = match client_keypair.diffie_hellman(&server_handshake.server_pk) {
+        Some(exp_Y_x) => exp_Y_x,
+        None => return Err(HandshakeError::DegenerateKey),
+    };
+    let exp_B_x = match client_keypair.diffie_hellman(&server_B) {
+        Some(exp_B_x) => exp_B_x,
+        None => return Err(HandshakeError::DegenerateKey),
+    };
     let mut secret_input: Vec<u8> = Vec::new();
     secret_input.extend(exp_Y_x.iter());
     secret_input.extend(exp_B_x.iter());
@@ -1430,7 +3460,8 @@ fn ntor_handshake(
     secret_input.extend(client_X.iter());
     secret_input.extend(server_handshake.server_pk.iter());
     secret_input.extend("ntor-curve25519-sha256-1".as_bytes());
-    let verify = ntor_hmac(&secret_input, b"ntor-curve25519-sha256-1:verify");
+    // ntor's H(x, t) is HMAC-SHA256 keyed with the (public) tweak t (tor-spec.txt section 5.1.4).
+    let verify = kdf::hmac_sha256(b"ntor-curve25519-sha256-1:verify", &[&secret_input[..]]);
     let mut auth_input: Vec<u8> = Vec::new();
     auth_input.extend(verify.iter());
     auth_input.extend(router_id.iter());
@@ -1439,13 +3470,13 @@ fn ntor_handshake(
     auth_input.extend(client_X.iter());
     auth_input.extend("ntor-curve25519-sha256-1".as_bytes());
     auth_input.extend("Server".as_bytes());
-    let calculated_auth = ntor_hmac(&auth_input, b"ntor-curve25519-sha256-1:mac");
-    if constant_time_eq(&calculated_auth, &server_handshake.auth) {
+    let calculated_auth = kdf::hmac_sha256(b"ntor-curve25519-sha256-1:mac", &[&auth_input[..]]);
+    if util::ct_eq(&calculated_auth, &server_handshake.auth) {
         // so this is actually the prk in the kdf... (confusing documentation)
-        let key_seed = ntor_hmac(&secret_input, b"ntor-curve25519-sha256-1:key_extract");
+        let key_seed = kdf::hkdf_extract(b"ntor-curve25519-sha256-1:key_extract", &secret_input);
         Ok(compute_ntor_keys(&key_seed))
     } else {
-        Err(())
+        Err(HandshakeError::AuthMismatch)
     }
 }
 
@@ -1455,20 +3486,7 @@ fn slice_to_16_byte_array(bytes: &[u8]) -> [u8; 16] {
     fixed_size
 }
 
-fn curve25519_multiply(x: &montgomery::CompressedMontgomeryU, s: &scalar::Scalar) -> [u8; 32] {
-    x.decompress().mul(s).compress().to_bytes()
-}
-
-fn ntor_hmac(input: &[u8], context: &[u8]) -> Vec<u8> {
-    // We seem to be using a public value for a private key here - am I misunderstanding?
-    let mut mac = Hmac::<Sha256>::new(context).unwrap();
-    mac.input(input);
-    let mut bytes: Vec<u8> = Vec::new();
-    bytes.extend(mac.result().code().as_slice().iter());
-    bytes
-}
-
-// TODO: maybe rename this function (tor-spec.txt section 5.2.2. KDF-RFC5869)
+// tor-spec.txt section 5.2.2. KDF-RFC5869
 fn compute_ntor_keys(key_seed: &[u8]) -> CircuitKeys {
     // We need to generate:
     // HASH_LEN bytes (forward digest)
@@ -1477,36 +3495,2167 @@ fn compute_ntor_keys(key_seed: &[u8]) -> CircuitKeys {
     // KEY_LEN bytes (backward key)
     // HASH_LEN bytes (KH in hidden service protocol (?))
     // where HASH_LEN is 20 bytes and KEY_LEN is 16 bytes.
-    // We're using HMAC-SHA256, so each out block is 32 bytes.
-    // We'll need 3 total blocks.
-    // m_expand = b"ntor-curve25519-sha256-1:key_expand"
-    // HMAC-SHA256(x, t): input is x, key is t
-    // K(1) = HMAC-SHA256(m_expand | 0x01 as u8, key_seed)
-    // K(2) = HMAC-SHA256(K(1) | m_expand | 0x02 as u8, key_seed)
-    // K(2) = HMAC-SHA256(K(2) | m_expand | 0x03 as u8, key_seed)
-    let mut m_expand_1: Vec<u8> = Vec::new();
-    m_expand_1
-        .write_all(b"ntor-curve25519-sha256-1:key_expand")
-        .unwrap();
-    m_expand_1.push(1);
-    let k_1 = ntor_hmac(&m_expand_1, key_seed);
-    let mut m_expand_2: Vec<u8> = Vec::new();
-    m_expand_2.write_all(&k_1).unwrap();
-    m_expand_2
-        .write_all(b"ntor-curve25519-sha256-1:key_expand")
-        .unwrap();
-    m_expand_2.push(2);
-    let k_2 = ntor_hmac(&m_expand_2, key_seed);
-    let mut m_expand_3: Vec<u8> = Vec::new();
-    m_expand_3.write_all(&k_2).unwrap();
-    m_expand_3
-        .write_all(b"ntor-curve25519-sha256-1:key_expand")
-        .unwrap();
-    m_expand_3.push(3);
-    let k_3 = ntor_hmac(&m_expand_3, key_seed);
-    let mut k: Vec<u8> = Vec::new();
-    k.write_all(&k_1).unwrap();
-    k.write_all(&k_2).unwrap();
-    k.write_all(&k_3).unwrap();
+    let k = kdf::hkdf_expand(key_seed, b"ntor-curve25519-sha256-1:key_expand", 92);
     CircuitKeys::new(&k)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// An in-memory connection. Reads come from `inbound` (failing with `WouldBlock` when it's
+    /// empty, like a non-blocking socket, or returning 0 once `closed` is set) and writes go to
+    /// `outbound`. Clones share the buffers, so a test can keep one to play the relay's side.
+    #[derive(Clone, Default)]
+    struct MockTls {
+        inbound: Rc<RefCell<Vec<u8>>>,
+        outbound: Rc<RefCell<Vec<u8>>>,
+        /// How many times `write` has been called.
+        write_count: Rc<RefCell<usize>>,
+        /// If set, how many more bytes writes will take before they'd block.
+        write_capacity: Rc<RefCell<Option<usize>>>,
+        /// Whether the peer has closed the connection.
+        closed: Rc<RefCell<bool>>,
+    }
+
+    /// What `MockTls` claims is the peer's TLS certificate.
+    const MOCK_PEER_CERT: &'static [u8] = b"mock TLS certificate";
+
+    impl TlsImpl for MockTls {
+        fn get_peer_cert_hash(&self) -> Result<[u8; 32], Error> {
+            Ok(util::slice_to_32_byte_array(&Sha256::digest(MOCK_PEER_CERT)))
+        }
+
+        fn get_peer_cert_der(&self) -> Result<Vec<u8>, Error> {
+            Ok(MOCK_PEER_CERT.to_vec())
+        }
+
+        fn get_tls_secrets(&self, _label: &str, _context: &[u8]) -> Result<Vec<u8>, Error> {
+            Ok(vec![0; 32])
+        }
+    }
+
+    impl Read for MockTls {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            let mut inbound = self.inbound.borrow_mut();
+            if inbound.is_empty() {
+                if *self.closed.borrow() {
+                    return Ok(0);
+                }
+                return Err(Error::new(ErrorKind::WouldBlock, "no data"));
+            }
+            let len = cmp::min(buf.len(), inbound.len());
+            buf[..len].copy_from_slice(&inbound[..len]);
+            inbound.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl Write for MockTls {
+        fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+            *self.write_count.borrow_mut() += 1;
+            let mut write_capacity = self.write_capacity.borrow_mut();
+            let len = match *write_capacity {
+                Some(0) => return Err(Error::new(ErrorKind::WouldBlock, "buffer full")),
+                Some(ref mut capacity) => {
+                    let len = cmp::min(*capacity, data.len());
+                    *capacity -= len;
+                    len
+                }
+                None => data.len(),
+            };
+            self.outbound.borrow_mut().extend_from_slice(&data[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// Accepts every signature and signs with garbage.
+    struct MockRsa;
+
+    impl RsaVerifierImpl for MockRsa {
+        fn verify_signature(&self, _cert: &[u8], _data: &[u8], _signature: &[u8]) -> bool {
+            true
+        }
+
+        fn get_key_hash(&self, cert: &[u8]) -> [u8; 32] {
+            util::slice_to_32_byte_array(&Sha256::digest(cert))
+        }
+    }
+
+    impl RsaSignerImpl for MockRsa {
+        fn sign_data(&self, _data: &[u8]) -> Vec<u8> {
+            vec![0; 128]
+        }
+
+        fn get_cert_bytes(&self) -> &[u8] {
+            &[]
+        }
+    }
+
+    const TEST_CIRC_ID: u32 = 0x80000001;
+
+    /// Key material for the hop at `hop_index` of a test circuit.
+    fn hop_key_material(hop_index: usize) -> Vec<u8> {
+        (0..92).map(|i| (i as u8) ^ (0x11 * (hop_index as u8 + 1))).collect()
+    }
+
+    /// Plays the relays' side of a circuit made by `ready_circuit`.
+    struct MockRelays {
+        tls: MockTls,
+        /// Each hop's keys. The relay's forward and backward keys are the same as ours.
+        circuit_keys: Vec<CircuitKeys>,
+    }
+
+    impl MockRelays {
+        /// Sends a relay cell from the hop at `hop_index`.
+        fn send(
+            &mut self,
+            hop_index: usize,
+            relay_command: types::RelayCommand,
+            stream_id: u16,
+            data: &[u8],
+        ) {
+            let mut relay_cell = types::RelayCell::new(relay_command, stream_id, data.to_owned());
+            relay_cell.set_digest(&mut self.circuit_keys[hop_index].backward_digest);
+            let mut bytes = Vec::new();
+            relay_cell.write_to(&mut bytes).unwrap();
+            for circuit_keys in self.circuit_keys[..hop_index + 1].iter_mut().rev() {
+                let mut encrypted_bytes = vec![0; bytes.len()];
+                circuit_keys.backward_key.aes.process(&bytes, &mut encrypted_bytes);
+                bytes = encrypted_bytes;
+            }
+            self.send_cell(types::Cell::new(TEST_CIRC_ID, types::Command::Relay, bytes));
+        }
+
+        fn send_cell(&mut self, cell: types::Cell) {
+            let mut inbound = self.tls.inbound.borrow_mut();
+            cell.write_to_for_version(&mut *inbound, 4).unwrap();
+        }
+
+        /// Decodes everything the circuit has written so far.
+        fn receive_cells(&mut self) -> Vec<types::Cell> {
+            let outbound = mem::replace(&mut *self.tls.outbound.borrow_mut(), Vec::new());
+            let mut reader = &outbound[..];
+            let mut cells = Vec::new();
+            while !reader.is_empty() {
+                cells.push(types::Cell::read_new_for_version(&mut reader, 4).unwrap());
+            }
+            cells
+        }
+
+        /// Decodes and decrypts the relay cells the circuit has written so far, along with the
+        /// index of the hop each was for.
+        fn receive(&mut self) -> Vec<(usize, types::RelayCell)> {
+            let mut relay_cells = Vec::new();
+            for cell in self.receive_cells() {
+                assert!(
+                    cell.command == types::Command::Relay
+                        || cell.command == types::Command::RelayEarly
+                );
+                relay_cells.push(self.decrypt(&cell.payload));
+            }
+            relay_cells
+        }
+
+        fn decrypt(&mut self, payload: &[u8]) -> (usize, types::RelayCell) {
+            let mut bytes = payload.to_owned();
+            for (hop_index, circuit_keys) in self.circuit_keys.iter_mut().enumerate() {
+                let mut decrypted_bytes = vec![0; bytes.len()];
+                circuit_keys.forward_key.aes.process(&bytes, &mut decrypted_bytes);
+                bytes = decrypted_bytes;
+                if bytes[1] != 0 || bytes[2] != 0 {
+                    continue;
+                }
+                let relay_cell = types::RelayCell::read_new(&mut &bytes[..]).unwrap();
+                if relay_cell.check_digest(&mut circuit_keys.forward_digest) {
+                    return (hop_index, relay_cell);
+                }
+            }
+            panic!("relay cell not recognized at any hop");
+        }
+    }
+
+    /// Makes a circuit that's already been built through `hop_count` hops, along with the relays
+    /// at the other end of it.
+    fn ready_circuit(hop_count: usize) -> (Circuit<MockTls, MockRsa>, MockRelays) {
+        let tls = MockTls::default();
+        let mut circuit = Circuit::new(tls.clone(), MockRsa, &MockRsa, TEST_CIRC_ID, [0; 32]);
+        let mut relays = MockRelays {
+            tls: tls,
+            circuit_keys: Vec::new(),
+        };
+        for hop_index in 0..hop_count {
+            circuit.install_circuit_keys(CircuitKeys::new(&hop_key_material(hop_index)));
+            relays
+                .circuit_keys
+                .push(CircuitKeys::new(&hop_key_material(hop_index)));
+        }
+        circuit.state = CircuitState::Ready;
+        (circuit, relays)
+    }
+
+    /// Opens a stream on `circuit` that the exit has already accepted.
+    fn ready_stream(circuit: &mut Circuit<MockTls, MockRsa>) -> u16 {
+        let stream_id = circuit.open_dir_stream();
+        circuit.streams.get_mut(&stream_id).unwrap().state = StreamState::Ready;
+        stream_id
+    }
+
+    /// Encodes a DER TLV.
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![tag];
+        if contents.len() < 0x80 {
+            bytes.push(contents.len() as u8);
+        } else {
+            bytes.push(0x82);
+            bytes.push((contents.len() >> 8) as u8);
+            bytes.push(contents.len() as u8);
+        }
+        bytes.extend_from_slice(contents);
+        bytes
+    }
+
+    /// A bare-bones X509 certificate with the given validity (as UTCTimes, e.g. "200101000000Z").
+    /// It has just enough structure for `X509Cert::get_validity` - `MockRsa` doesn't look at keys
+    /// or signatures.
+    fn x509_cert_der(not_before: &str, not_after: &str) -> Vec<u8> {
+        x509_cert_der_with_names(Some("mock relay"), Some("mock relay"), not_before, not_after)
+    }
+
+    /// Like `x509_cert_der`, but with the given issuer and subject commonNames (a name without one
+    /// has just an organizationName).
+    fn x509_cert_der_with_names(
+        issuer_cn: Option<&str>,
+        subject_cn: Option<&str>,
+        not_before: &str,
+        not_after: &str,
+    ) -> Vec<u8> {
+        let der_name = |cn: Option<&str>| {
+            let attribute = match cn {
+                Some(cn) => [der(0x06, &[0x55, 0x04, 0x03]), der(0x13, cn.as_bytes())].concat(),
+                None => [der(0x06, &[0x55, 0x04, 0x0a]), der(0x13, b"mock org")].concat(),
+            };
+            der(0x30, &der(0x31, &der(0x30, &attribute)))
+        };
+        let validity = der(0x30, &[
+            der(0x17, not_before.as_bytes()),
+            der(0x17, not_after.as_bytes()),
+        ].concat());
+        let tbs_certificate = der(0x30, &[
+            der(0xa0, &der(0x02, &[2])),
+            der(0x02, &[0x00, 0x9c, 0x41]),
+            der(0x30, &der(0x06, &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b])),
+            der_name(issuer_cn),
+            validity,
+            der_name(subject_cn),
+        ].concat());
+        der(0x30, &tbs_certificate)
+    }
+
+    /// The identity and CERTS cell of the first hop of a test circuit.
+    struct MockResponder {
+        ed25519_identity_key: keys::Ed25519Key,
+        certs: Vec<types::RawCert>,
+    }
+
+    impl MockResponder {
+        /// A responder whose RSA identity cert is valid from 2020 to 2049 and whose Ed25519 certs
+        /// are valid until the default expiration date.
+        fn new() -> MockResponder {
+            MockResponder::with_validity(
+                "200101000000Z",
+                "491231000000Z",
+                certs::DEFAULT_EXPIRATION_DATE,
+            )
+        }
+
+        fn with_validity(
+            not_before: &str,
+            not_after: &str,
+            expiration_date: certs::HoursSinceEpoch,
+        ) -> MockResponder {
+            let rsa_identity_cert = certs::X509Cert::new(&x509_cert_der(not_before, not_after));
+            let ed25519_identity_key = keys::Ed25519Key::new();
+            let ed25519_identity_cert = rsa_identity_cert.sign_ed25519_key(
+                &ed25519_identity_key,
+                &MockRsa,
+                expiration_date,
+            );
+            let ed25519_signing_key = keys::Ed25519Key::new();
+            let ed25519_signing_cert = ed25519_identity_key.sign_ed25519_key(
+                &ed25519_signing_key,
+                certs::Ed25519CertType::SigningKey,
+                expiration_date,
+            );
+            let mut ed25519_link_cert = certs::Ed25519Cert::new_unsigned(
+                certs::Ed25519CertType::TlsLinkCertificate,
+                util::slice_to_32_byte_array(&Sha256::digest(MOCK_PEER_CERT)),
+                &ed25519_signing_key.get_public_key_bytes(),
+                expiration_date,
+            );
+            let signature = ed25519_signing_key.sign_data(&ed25519_link_cert.get_tbs_bytes());
+            ed25519_link_cert.set_signature(signature);
+
+            let mut certs = Vec::new();
+            let mut bytes = Vec::new();
+            rsa_identity_cert.write_to(&mut bytes);
+            certs.push(types::RawCert::new(types::CertType::RsaIdentity, bytes));
+            let mut bytes = Vec::new();
+            ed25519_identity_cert.write_to(&mut bytes);
+            certs.push(types::RawCert::new(types::CertType::Ed25519Identity, bytes));
+            let mut bytes = Vec::new();
+            ed25519_signing_cert.write_to(&mut bytes);
+            certs.push(types::RawCert::new(types::CertType::Ed25519Signing, bytes));
+            let mut bytes = Vec::new();
+            ed25519_link_cert.write_to(&mut bytes);
+            certs.push(types::RawCert::new(types::CertType::Ed25519Link, bytes));
+            MockResponder {
+                ed25519_identity_key: ed25519_identity_key,
+                certs: certs,
+            }
+        }
+
+        fn get_ed25519_id_key(&self) -> [u8; 32] {
+            self.ed25519_identity_key.get_public_key_bytes()
+        }
+
+        fn certs_cell(&self) -> types::Cell {
+            let mut payload = Vec::new();
+            types::CertsCell::new_from_raw_certs(self.certs.clone())
+                .write_to(&mut payload)
+                .unwrap();
+            types::Cell::new(0, types::Command::Certs, payload)
+        }
+
+        fn responder_certs(&self) -> ResponderCerts {
+            let certs_cell = types::CertsCell::new_from_raw_certs(self.certs.clone());
+            ResponderCerts::new(certs_cell.decode_certs()).unwrap()
+        }
+    }
+
+    /// The cells a responder sends after VERSIONS: CERTS, AUTH_CHALLENGE (offering
+    /// Ed25519-SHA256-RFC5705 authentication), and NETINFO.
+    fn link_handshake_cells(responder: &MockResponder) -> Vec<types::Cell> {
+        let mut auth_challenge = vec![0x33; 32];
+        auth_challenge.extend_from_slice(&[0, 1, 0, 3]);
+        let netinfo = types::NetinfoCell::new(
+            0,
+            types::OrAddress::IPv4Address([127, 0, 0, 1]),
+            vec![types::OrAddress::IPv4Address([10, 0, 0, 1])],
+        );
+        let mut netinfo_payload = Vec::new();
+        netinfo.write_to(&mut netinfo_payload).unwrap();
+        vec![
+            responder.certs_cell(),
+            types::Cell::new(0, types::Command::AuthChallenge, auth_challenge),
+            types::Cell::new(0, types::Command::Netinfo, netinfo_payload),
+        ]
+    }
+
+    /// A relay a test circuit can be extended to, with what it needs for its side of the ntor
+    /// handshake.
+    struct MockExtendTarget {
+        node_id: [u8; 20],
+        ed25519_id_key: [u8; 32],
+        onion_keypair: keys::Curve25519Keypair,
+    }
+
+    impl MockExtendTarget {
+        fn new(index: u8) -> MockExtendTarget {
+            MockExtendTarget {
+                node_id: [index; 20],
+                ed25519_id_key: [index; 32],
+                onion_keypair: keys::Curve25519Keypair::new(),
+            }
+        }
+
+        fn to_extend_target(&self) -> dir::ExtendTarget {
+            dir::ExtendTarget::new(
+                Ipv4Addr::new(10, self.node_id[0], 0, 1),
+                9001,
+                self.node_id,
+                self.ed25519_id_key,
+                self.onion_keypair.get_public_key_bytes(),
+            )
+        }
+
+        /// The relay's side of the ntor handshake (tor-spec.txt section 5.1.4), given the HDATA of
+        /// the client's EXTEND2. Returns the body of the EXTENDED2 to reply with and the keys.
+        #[allow(non_snake_case)]
+        fn ntor_server_handshake(&self, h_data: &[u8]) -> (Vec<u8>, CircuitKeys) {
+            let client_handshake = types::NtorClientHandshake::from_h_data(h_data).unwrap();
+            assert_eq!(client_handshake.get_node_id(), self.node_id);
+            let B = self.onion_keypair.get_public_key_bytes();
+            assert_eq!(client_handshake.get_key_id(), B);
+            let X = client_handshake.get_client_pk();
+            let ephemeral_keypair = keys::Curve25519Keypair::new();
+            let Y = ephemeral_keypair.get_public_key_bytes();
+            let mut secret_input = Vec::new();
+            secret_input.extend_from_slice(&ephemeral_keypair.diffie_hellman(&X).unwrap());
+            secret_input.extend_from_slice(&self.onion_keypair.diffie_hellman(&X).unwrap());
+            secret_input.extend_from_slice(&self.node_id);
+            secret_input.extend_from_slice(&B);
+            secret_input.extend_from_slice(&X);
+            secret_input.extend_from_slice(&Y);
+            secret_input.extend_from_slice(b"ntor-curve25519-sha256-1");
+            let verify = kdf::hmac_sha256(b"ntor-curve25519-sha256-1:verify", &[&secret_input]);
+            let mut auth_input = Vec::new();
+            auth_input.extend_from_slice(&verify);
+            auth_input.extend_from_slice(&self.node_id);
+            auth_input.extend_from_slice(&B);
+            auth_input.extend_from_slice(&Y);
+            auth_input.extend_from_slice(&X);
+            auth_input.extend_from_slice(b"ntor-curve25519-sha256-1Server");
+            let auth = kdf::hmac_sha256(b"ntor-curve25519-sha256-1:mac", &[&auth_input]);
+            let key_seed =
+                kdf::hkdf_extract(b"ntor-curve25519-sha256-1:key_extract", &secret_input);
+            let mut extended2 = vec![0, 64];
+            extended2.extend_from_slice(&Y);
+            extended2.extend_from_slice(&auth);
+            (extended2, compute_ntor_keys(&key_seed))
+        }
+    }
+
+    /// Splits the body of an EXTEND2 cell into its link specifiers, HTYPE, and HDATA.
+    fn parse_extend2(data: &[u8]) -> (Vec<types::LinkSpecifier>, u16, Vec<u8>) {
+        let mut reader = data;
+        let link_specifiers = types::LinkSpecifier::read_list(&mut reader).unwrap();
+        let h_type = ((reader[0] as u16) << 8) | reader[1] as u16;
+        let h_len = ((reader[2] as usize) << 8) | reader[3] as usize;
+        (link_specifiers, h_type, reader[4..4 + h_len].to_vec())
+    }
+
+    /// Runs the link handshake on a fresh circuit with `responder` as the first hop, up to the
+    /// circuit sending CREATE_FAST. Returns the CREATE_FAST cell.
+    fn link_handshake(
+        circuit: &mut Circuit<MockTls, MockRsa>,
+        relays: &mut MockRelays,
+        responder: &MockResponder,
+    ) -> types::Cell {
+        let mut cells = link_handshake_with_cells(circuit, relays, link_handshake_cells(responder));
+        cells.pop().unwrap()
+    }
+
+    /// Like `link_handshake`, but the responder sends `cells` after VERSIONS. Returns the cells
+    /// the circuit sent (CERTS, AUTHENTICATE, NETINFO, and CREATE_FAST).
+    fn link_handshake_with_cells(
+        circuit: &mut Circuit<MockTls, MockRsa>,
+        relays: &mut MockRelays,
+        cells: Vec<types::Cell>,
+    ) -> Vec<types::Cell> {
+        circuit.poll().unwrap();
+        let outbound = mem::replace(&mut *relays.tls.outbound.borrow_mut(), Vec::new());
+        let versions = types::VersionsCell::read_new(&mut &outbound[..]).unwrap();
+        // Like real relays, the responder sends everything up to NETINFO in one go.
+        let mut inbound = Vec::new();
+        types::VersionsCell::new(vec![3, 4, 5])
+            .write_to(&mut inbound)
+            .unwrap();
+        relays.tls.inbound.borrow_mut().extend(inbound);
+        let link_version = versions
+            .negotiate(&types::VersionsCell::new(vec![3, 4, 5]))
+            .unwrap();
+        for cell in cells {
+            relays.send_cell(cell);
+        }
+        for _ in 0..20 {
+            if circuit.state == CircuitState::CreateFastReading {
+                break;
+            }
+            circuit.poll().unwrap();
+        }
+        assert_eq!(circuit.state, CircuitState::CreateFastReading);
+        assert!(circuit.is_authenticated());
+        assert_eq!(circuit.get_link_version(), link_version);
+
+        let cells = relays.receive_cells();
+        let commands: Vec<&types::Command> = cells.iter().map(|cell| &cell.command).collect();
+        assert_eq!(
+            commands,
+            vec![
+                &types::Command::Certs,
+                &types::Command::Authenticate,
+                &types::Command::Netinfo,
+                &types::Command::CreateFast,
+            ]
+        );
+        cells
+    }
+
+    /// Plays the first hop's side of CREATE_FAST, sending CREATED_FAST and recording the keys.
+    fn answer_create_fast(relays: &mut MockRelays, create_fast: &types::Cell) {
+        assert_eq!(create_fast.circ_id, TEST_CIRC_ID);
+        let (created_fast, circuit_keys) = created_fast_reply(create_fast);
+        relays.send_cell(created_fast);
+        relays.circuit_keys.push(circuit_keys);
+    }
+
+    /// The relay's CREATED_FAST answer to `create_fast`, and the circuit keys they now share.
+    fn created_fast_reply(create_fast: &types::Cell) -> (types::Cell, CircuitKeys) {
+        let mut k0 = create_fast.payload[..20].to_vec();
+        let y = [0x44; 20];
+        k0.extend_from_slice(&y);
+        let kh = Sha1::from(&[&k0[..], &[0]].concat()).digest().bytes();
+        let mut created_fast = y.to_vec();
+        created_fast.extend_from_slice(&kh);
+        let cell = types::Cell::new(create_fast.circ_id, types::Command::CreatedFast, created_fast);
+        (cell, kdf_tor(&k0, &kh).unwrap())
+    }
+
+    /// Runs the link handshake and CREATE_FAST on a fresh circuit with `responder` as the first
+    /// hop, leaving the circuit ready and `relays` holding the first hop's keys.
+    fn build_first_hop(
+        circuit: &mut Circuit<MockTls, MockRsa>,
+        relays: &mut MockRelays,
+        responder: &MockResponder,
+    ) {
+        let create_fast = link_handshake(circuit, relays, responder);
+        answer_create_fast(relays, &create_fast);
+        circuit.poll().unwrap();
+        assert_eq!(circuit.state, CircuitState::Ready);
+    }
+
+    /// A fresh circuit to `responder`, along with the relays at the other end of it.
+    fn new_circuit(responder: &MockResponder) -> (Circuit<MockTls, MockRsa>, MockRelays) {
+        let tls = MockTls::default();
+        let circuit = Circuit::new(
+            tls.clone(),
+            MockRsa,
+            &MockRsa,
+            TEST_CIRC_ID,
+            responder.get_ed25519_id_key(),
+        );
+        let relays = MockRelays {
+            tls: tls,
+            circuit_keys: Vec::new(),
+        };
+        (circuit, relays)
+    }
+
+    /// Extends `circuit` from the hop at `hop_index` to `target`, playing the relays' side.
+    fn extend_from(
+        circuit: &mut Circuit<MockTls, MockRsa>,
+        relays: &mut MockRelays,
+        hop_index: usize,
+        target: &MockExtendTarget,
+    ) {
+        let extend_target = target.to_extend_target();
+        for _ in 0..2 {
+            match circuit.poll_extend_from(hop_index, &extend_target).unwrap() {
+                Async::NotReady => {}
+                Async::Ready(()) => panic!("extend completed too early"),
+            }
+        }
+        let cells = relays.receive_cells();
+        assert_eq!(cells.len(), 1);
+        assert_eq!(cells[0].command, types::Command::RelayEarly);
+        let (extending_hop, relay_cell) = relays.decrypt(&cells[0].payload);
+        assert_eq!(extending_hop, hop_index);
+        assert_eq!(relay_cell.relay_command, types::RelayCommand::Extend2);
+        let (link_specifiers, h_type, h_data) = parse_extend2(relay_cell.get_data());
+        assert!(link_specifiers.contains(&types::LinkSpecifier::LegacyId(target.node_id)));
+        assert_eq!(h_type, 2);
+        let (extended2, circuit_keys) = target.ntor_server_handshake(&h_data);
+        relays.send(hop_index, types::RelayCommand::Extended2, 0, &extended2);
+        match circuit.poll_extend_from(hop_index, &extend_target).unwrap() {
+            Async::Ready(()) => {}
+            Async::NotReady => panic!("extend didn't complete"),
+        }
+        relays.circuit_keys.truncate(hop_index + 1);
+        relays.circuit_keys.push(circuit_keys);
+    }
+
+    /// Builds a circuit through three mock relays from scratch.
+    fn three_hop_circuit() -> (Circuit<MockTls, MockRsa>, MockRelays) {
+        let responder = MockResponder::new();
+        let (mut circuit, mut relays) = new_circuit(&responder);
+        build_first_hop(&mut circuit, &mut relays, &responder);
+        extend_from(&mut circuit, &mut relays, 0, &MockExtendTarget::new(1));
+        extend_from(&mut circuit, &mut relays, 1, &MockExtendTarget::new(2));
+        (circuit, relays)
+    }
+
+    #[test]
+    fn test_read_until_end_spans_polls() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        let stream_id = ready_stream(&mut circuit);
+        circuit.set_max_cells_per_poll(5);
+        for i in 0..12 {
+            relays.send(0, types::RelayCommand::Data, stream_id, &[i; 10]);
+        }
+        relays.send(0, types::RelayCommand::End, stream_id, &[6]);
+        let timeout = Duration::from_secs(60);
+        let mut polls = 0;
+        let (data, end) = loop {
+            polls += 1;
+            match circuit.poll_stream_read_until_end(stream_id, timeout).unwrap() {
+                Async::Ready(result) => break result,
+                Async::NotReady => assert!(polls < 10),
+            }
+        };
+        assert_eq!(polls, 3);
+        assert_eq!(data.len(), 120);
+        assert_eq!(&data[110..], &[11; 10]);
+        assert_eq!(end, StreamEnd::End(types::EndReason::Done));
+    }
+
+    #[test]
+    fn test_drop_cells_skipped() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        let stream_id = ready_stream(&mut circuit);
+        relays.send(0, types::RelayCommand::Drop, 0, &[]);
+        relays.send(0, types::RelayCommand::Drop, stream_id, &[]);
+        relays.send(0, types::RelayCommand::Data, stream_id, b"after padding");
+        // The DATA cell is read in the same poll as the DROP cells before it.
+        match circuit.poll_stream_read(stream_id).unwrap() {
+            Async::Ready(data) => assert_eq!(data, b"after padding"),
+            Async::NotReady => panic!("DATA cell after DROP cells not read"),
+        }
+        relays.send(0, types::RelayCommand::Drop, 0, &[]);
+        match circuit.poll_stream_read(stream_id).unwrap() {
+            Async::Ready(_) => panic!("read from a DROP cell"),
+            Async::NotReady => {}
+        }
+    }
+
+    #[test]
+    fn test_too_many_drop_cells() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        let stream_id = ready_stream(&mut circuit);
+        circuit.set_max_cells_per_poll(5);
+        for _ in 0..6 {
+            relays.send(0, types::RelayCommand::Drop, 0, &[]);
+        }
+        assert!(circuit.poll_stream_read(stream_id).is_err());
+    }
+
+    #[test]
+    fn test_read_until_end_without_progress() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        let stream_id = ready_stream(&mut circuit);
+        circuit.set_max_cells_per_poll(5);
+        for _ in 0..6 {
+            relays.send(0, types::RelayCommand::Data, stream_id, &[]);
+        }
+        assert!(
+            circuit
+                .poll_stream_read_until_end(stream_id, Duration::from_secs(60))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_read_chunks_spans_polls() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        let stream_id = ready_stream(&mut circuit);
+        circuit.set_max_cells_per_poll(5);
+        for i in 0..8 {
+            relays.send(0, types::RelayCommand::Data, stream_id, &[i; 10]);
+        }
+        relays.send(0, types::RelayCommand::End, stream_id, &[6]);
+        let mut data = Vec::new();
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            let result = circuit.poll_stream_read_chunks(stream_id, |chunk| {
+                data.extend_from_slice(chunk)
+            });
+            match result.unwrap() {
+                Async::Ready(()) => break,
+                Async::NotReady => assert!(polls < 10),
+            }
+        }
+        assert_eq!(polls, 2);
+        assert_eq!(data.len(), 80);
+    }
+
+    #[test]
+    fn test_clog_excludes_authenticate_cell() {
+        let tls = MockTls::default();
+        let responder = MockResponder::new();
+        let mut circuit = Circuit::new(
+            tls.clone(),
+            MockRsa,
+            &MockRsa,
+            TEST_CIRC_ID,
+            responder.get_ed25519_id_key(),
+        );
+        circuit.responder_certs = Some(responder.responder_certs());
+        circuit.poll().unwrap(); // VERSIONS
+        circuit.state = CircuitState::CertsWriting;
+        circuit.poll().unwrap(); // CERTS
+        let sent_before_authenticate = tls.outbound.borrow().clone();
+        circuit.poll().unwrap(); // AUTHENTICATE
+        assert_eq!(circuit.state, CircuitState::NetinfoReading);
+
+        let outbound = tls.outbound.borrow().clone();
+        let mut reader = &outbound[sent_before_authenticate.len()..];
+        let cell = types::Cell::read_new_for_version(&mut reader, 4).unwrap();
+        assert_eq!(cell.command, types::Command::Authenticate);
+        let authenticate = types::AuthenticateCell::read_new(&mut &cell.payload[..]).unwrap();
+        // "AUTH0003" | CID | SID | CID_ED | SID_ED | SLOG | CLOG | ...
+        let clog = &authenticate.get_authentication()[168..200];
+        assert_eq!(clog, &Sha256::digest(&sent_before_authenticate)[..]);
+        assert!(clog != &Sha256::digest(&outbound)[..]);
+    }
+
+    #[test]
+    fn test_authenticate_rejected() {
+        let tls = MockTls::default();
+        let responder = MockResponder::new();
+        let mut circuit = Circuit::new(
+            tls.clone(),
+            MockRsa,
+            &MockRsa,
+            TEST_CIRC_ID,
+            responder.get_ed25519_id_key(),
+        );
+        circuit.responder_certs = Some(responder.responder_certs());
+        circuit.poll().unwrap(); // VERSIONS
+        circuit.state = CircuitState::CertsWriting;
+        circuit.poll().unwrap(); // CERTS
+        circuit.poll().unwrap(); // AUTHENTICATE
+        assert_eq!(circuit.state, CircuitState::NetinfoReading);
+        *tls.closed.borrow_mut() = true;
+        match circuit.poll() {
+            Err(TorError::AuthRejected) => {}
+            result => panic!("expected AuthRejected, got {:?}", result),
+        }
+    }
+
+    /// A `TlsImpl` that only provides what it has to.
+    struct MinimalTls;
+
+    impl TlsImpl for MinimalTls {
+        fn get_peer_cert_hash(&self) -> Result<[u8; 32], Error> {
+            Ok([0; 32])
+        }
+
+        fn get_tls_secrets(&self, _label: &str, _context: &[u8]) -> Result<Vec<u8>, Error> {
+            Ok(vec![0; 32])
+        }
+    }
+
+    impl Read for MinimalTls {
+        fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Error> {
+            Err(Error::new(ErrorKind::WouldBlock, "no data"))
+        }
+    }
+
+    impl Write for MinimalTls {
+        fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_get_peer_cert_der() {
+        let (circuit, _) = ready_circuit(1);
+        assert_eq!(circuit.get_peer_cert_der().unwrap(), MOCK_PEER_CERT);
+        let circuit = Circuit::new(MinimalTls, MockRsa, &MockRsa, TEST_CIRC_ID, [0; 32]);
+        let error = circuit.get_peer_cert_der().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_three_hop_circuit() {
+        let (mut circuit, mut relays) = three_hop_circuit();
+        assert_eq!(circuit.get_hop_count(), 3);
+        assert_eq!(circuit.relay_early_remaining(), 6);
+
+        let stream_id = circuit.open_dir_stream();
+        match circuit.poll_stream_setup(stream_id).unwrap() {
+            Async::NotReady => {}
+            Async::Ready(()) => panic!("stream set up without CONNECTED"),
+        }
+        let received = relays.receive();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, 2);
+        assert_eq!(received[0].1.relay_command, types::RelayCommand::BeginDir);
+        assert_eq!(received[0].1.stream_id, stream_id);
+        relays.send(2, types::RelayCommand::Connected, stream_id, &[]);
+        match circuit.poll_stream_setup(stream_id).unwrap() {
+            Async::Ready(()) => {}
+            Async::NotReady => panic!("stream not set up"),
+        }
+
+        match circuit.poll_stream_write(stream_id, b"ping").unwrap() {
+            Async::Ready(()) => {}
+            Async::NotReady => panic!("couldn't write"),
+        }
+        let received = relays.receive();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, 2);
+        assert_eq!(received[0].1.relay_command, types::RelayCommand::Data);
+        assert_eq!(received[0].1.get_data(), b"ping");
+
+        relays.send(2, types::RelayCommand::Data, stream_id, b"pong");
+        match circuit.poll_stream_read(stream_id).unwrap() {
+            Async::Ready(data) => assert_eq!(data, b"pong"),
+            Async::NotReady => panic!("nothing read"),
+        }
+    }
+
+    #[test]
+    fn test_ntor_handshake_short_h_data() {
+        let mut created2_payload = vec![0, 10];
+        created2_payload.extend_from_slice(&[7; 10]);
+        let created2 = types::Created2Cell::read_new(&mut &created2_payload[..]).unwrap();
+        let result = ntor_handshake(&created2, [0; 20], [9; 32], &keys::Curve25519Keypair::new());
+        assert_eq!(result.err(), Some(HandshakeError::Malformed));
+    }
+
+    #[test]
+    fn test_outbound_cells_use_forward_keys() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        let bytes = circuit.encrypt_cell_bytes(types::RelayCommand::Data, b"forward", 1);
+        // The relay's backward key (which only we encrypt with) doesn't recover the cell...
+        let mut hop_keys = CircuitKeys::new(&hop_key_material(0));
+        let mut decrypted_bytes = vec![0; bytes.len()];
+        hop_keys.backward_key.aes.process(&bytes, &mut decrypted_bytes);
+        assert!(decrypted_bytes[1] != 0 || decrypted_bytes[2] != 0);
+        // ...but its forward key does.
+        let (hop_index, relay_cell) = relays.decrypt(&bytes);
+        assert_eq!(hop_index, 0);
+        assert_eq!(relay_cell.relay_command, types::RelayCommand::Data);
+        assert_eq!(relay_cell.get_data(), b"forward");
+    }
+
+    #[test]
+    fn test_inbound_cells_use_backward_keys() {
+        let (mut circuit, _) = ready_circuit(1);
+        // Cells the relay encrypts with its backward key decrypt with ours...
+        let mut hop_keys = CircuitKeys::new(&hop_key_material(0));
+        let mut relay_cell =
+            types::RelayCell::new(types::RelayCommand::Data, 1, b"backward".to_vec());
+        relay_cell.set_digest(&mut hop_keys.backward_digest);
+        let mut bytes = Vec::new();
+        relay_cell.write_to(&mut bytes).unwrap();
+        let mut encrypted_bytes = vec![0; bytes.len()];
+        hop_keys.backward_key.aes.process(&bytes, &mut encrypted_bytes);
+        let (hop_index, decrypted_cell) = circuit.decrypt_cell_bytes(&encrypted_bytes).unwrap();
+        assert_eq!(hop_index, 0);
+        assert_eq!(decrypted_cell.get_data(), b"backward");
+
+        // ...but a cell encrypted with the forward key (as if the directions were swapped) is
+        // garbage.
+        let mut relay_cell =
+            types::RelayCell::new(types::RelayCommand::Data, 1, b"forward".to_vec());
+        relay_cell.set_digest(&mut hop_keys.forward_digest);
+        let mut bytes = Vec::new();
+        relay_cell.write_to(&mut bytes).unwrap();
+        let mut encrypted_bytes = vec![0; bytes.len()];
+        hop_keys.forward_key.aes.process(&bytes, &mut encrypted_bytes);
+        assert!(circuit.decrypt_cell_bytes(&encrypted_bytes).is_err());
+    }
+
+    #[test]
+    fn test_build_auth0003_body() {
+        let fields = Auth0003Fields {
+            cid: [1; 32],
+            sid: [2; 32],
+            cid_ed: [3; 32],
+            sid_ed: [4; 32],
+            slog: [5; 32],
+            clog: [6; 32],
+            scert: [7; 32],
+            tlssecrets: [8; 32],
+            rand: [9; 24],
+        };
+        let body = build_auth0003_body(&fields);
+        assert_eq!(body.len(), 288);
+        assert_eq!(&body[..8], b"AUTH0003");
+        for (i, field) in body[8..264].chunks(32).enumerate() {
+            assert_eq!(field, &[i as u8 + 1; 32]);
+        }
+        assert_eq!(&body[264..], &[9; 24]);
+        let mut hash = Sha256::new();
+        hash.input(&body);
+        assert_eq!(
+            util::to_hex(&hash.result()),
+            "def8f5ef93c2f1864739e4dc972b64576eab2a0b0aea83818d1b2869d126bc41"
+        );
+    }
+
+    #[test]
+    fn test_retry_delay_doubles_up_to_cap() {
+        let (mut circuit, _) = ready_circuit(1);
+        circuit.set_extend_retries(u32::max_value(), Duration::from_secs(1));
+        assert_eq!(circuit.next_retry_delay(), Some(Duration::from_secs(1)));
+        circuit.extend_retries = 3;
+        assert_eq!(circuit.next_retry_delay(), Some(Duration::from_secs(8)));
+        circuit.extend_retries = 6;
+        assert_eq!(circuit.next_retry_delay(), Some(Duration::from_secs(MAX_RETRY_DELAY_SECS)));
+        // 2^40 doesn't fit in a u32, and 2^31 seconds doesn't fit in a Duration multiplication
+        // without hitting the cap.
+        circuit.extend_retries = 40;
+        assert_eq!(circuit.next_retry_delay(), Some(Duration::from_secs(MAX_RETRY_DELAY_SECS)));
+        circuit.extend_retries = 31;
+        assert_eq!(circuit.next_retry_delay(), Some(Duration::from_secs(MAX_RETRY_DELAY_SECS)));
+        circuit.set_extend_retries(2, Duration::from_secs(1));
+        circuit.extend_retries = 2;
+        assert_eq!(circuit.next_retry_delay(), None);
+    }
+
+    #[test]
+    fn test_create_fast_retried_after_destroy_requested() {
+        let responder = MockResponder::new();
+        let (mut circuit, mut relays) = new_circuit(&responder);
+        circuit.set_extend_retries(1, Duration::from_millis(0));
+        link_handshake(&mut circuit, &mut relays, &responder);
+        relays.send_cell(types::Cell::new(
+            TEST_CIRC_ID,
+            types::Command::Destroy,
+            vec![types::DestroyReason::Requested.as_u8()],
+        ));
+        circuit.poll().unwrap();
+        assert_eq!(circuit.state, CircuitState::CreateBackoff);
+        circuit.poll().unwrap();
+        assert_eq!(circuit.state, CircuitState::CreateFastReading);
+        let cells = relays.receive_cells();
+        assert_eq!(cells.len(), 1);
+        assert_eq!(cells[0].command, types::Command::CreateFast);
+        answer_create_fast(&mut relays, &cells[0]);
+        circuit.poll().unwrap();
+        assert_eq!(circuit.state, CircuitState::Ready);
+        assert_eq!(circuit.extend_retries, 0);
+    }
+
+    #[test]
+    fn test_create_fast_retries_run_out() {
+        let responder = MockResponder::new();
+        let (mut circuit, mut relays) = new_circuit(&responder);
+        circuit.set_extend_retries(1, Duration::from_millis(0));
+        link_handshake(&mut circuit, &mut relays, &responder);
+        let destroy = vec![types::DestroyReason::ResourceLimit.as_u8()];
+        relays.send_cell(types::Cell::new(TEST_CIRC_ID, types::Command::Destroy, destroy.clone()));
+        circuit.poll().unwrap();
+        assert_eq!(circuit.state, CircuitState::CreateBackoff);
+        circuit.poll().unwrap();
+        assert_eq!(circuit.state, CircuitState::CreateFastReading);
+        relays.send_cell(types::Cell::new(TEST_CIRC_ID, types::Command::Destroy, destroy));
+        assert!(circuit.poll().is_err());
+        assert_eq!(circuit.state, CircuitState::Error);
+    }
+
+    #[test]
+    fn test_create_fast_not_retried_after_protocol_destroy() {
+        let responder = MockResponder::new();
+        let (mut circuit, mut relays) = new_circuit(&responder);
+        circuit.set_extend_retries(3, Duration::from_millis(0));
+        link_handshake(&mut circuit, &mut relays, &responder);
+        relays.send_cell(types::Cell::new(
+            TEST_CIRC_ID,
+            types::Command::Destroy,
+            vec![types::DestroyReason::Protocol.as_u8()],
+        ));
+        assert!(circuit.poll().is_err());
+        assert_eq!(circuit.state, CircuitState::Error);
+    }
+
+    /// Starts extending `circuit` from its last hop, returning the EXTEND2 relay cell.
+    fn start_extend(
+        circuit: &mut Circuit<MockTls, MockRsa>,
+        relays: &mut MockRelays,
+        target: &dir::ExtendTarget,
+    ) -> types::RelayCell {
+        for _ in 0..2 {
+            match circuit.poll_extend_to(target).unwrap() {
+                Async::NotReady => {}
+                Async::Ready(()) => panic!("extend completed too early"),
+            }
+        }
+        let mut received = relays.receive();
+        assert_eq!(received.len(), 1);
+        let (_, relay_cell) = received.pop().unwrap();
+        assert_eq!(relay_cell.relay_command, types::RelayCommand::Extend2);
+        relay_cell
+    }
+
+    #[test]
+    fn test_extend_retried_after_truncated() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        circuit.set_extend_retries(1, Duration::from_millis(0));
+        let target = MockExtendTarget::new(1);
+        let extend_target = target.to_extend_target();
+        start_extend(&mut circuit, &mut relays, &extend_target);
+        let reason = types::DestroyReason::ConnectFailed.as_u8();
+        relays.send(0, types::RelayCommand::Truncated, 0, &[reason]);
+        match circuit.poll_extend_to(&extend_target).unwrap() {
+            Async::NotReady => {}
+            Async::Ready(()) => panic!("extend completed"),
+        }
+        assert_eq!(circuit.state, CircuitState::ExtendBackoff);
+        // The retry sends a fresh EXTEND2, which succeeds this time.
+        let extend2 = start_extend(&mut circuit, &mut relays, &extend_target);
+        let (_, _, h_data) = parse_extend2(extend2.get_data());
+        let (extended2, _) = target.ntor_server_handshake(&h_data);
+        relays.send(0, types::RelayCommand::Extended2, 0, &extended2);
+        match circuit.poll_extend_to(&extend_target).unwrap() {
+            Async::Ready(()) => {}
+            Async::NotReady => panic!("extend didn't complete"),
+        }
+        assert_eq!(circuit.get_hop_count(), 2);
+    }
+
+    /// Starts extending `circuit` from its last hop and lets the extend time out, checking that
+    /// the circuit then sends that hop a TRUNCATE.
+    fn time_out_extend(
+        circuit: &mut Circuit<MockTls, MockRsa>,
+        relays: &mut MockRelays,
+        target: &dir::ExtendTarget,
+    ) {
+        start_extend(circuit, relays, target);
+        match circuit.poll_extend_to(target).unwrap() {
+            Async::NotReady => {}
+            Async::Ready(()) => panic!("extend completed"),
+        }
+        assert_eq!(circuit.state, CircuitState::ExtendTruncatedReading);
+        let received = relays.receive();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, relays.circuit_keys.len() - 1);
+        assert_eq!(received[0].1.relay_command, types::RelayCommand::Truncate);
+    }
+
+    #[test]
+    fn test_extend_retried_after_timeout() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        circuit.set_extend_retries(1, Duration::from_millis(0));
+        circuit.set_extend_timeout(Duration::from_millis(0));
+        let extend_target = MockExtendTarget::new(1).to_extend_target();
+        time_out_extend(&mut circuit, &mut relays, &extend_target);
+        // The EXTENDED2 turns up after all, but after the TRUNCATE it doesn't count.
+        relays.send(0, types::RelayCommand::Extended2, 0, &[0; 66]);
+        let reason = types::DestroyReason::Requested.as_u8();
+        relays.send(0, types::RelayCommand::Truncated, 0, &[reason]);
+        match circuit.poll_extend_to(&extend_target).unwrap() {
+            Async::NotReady => {}
+            Async::Ready(()) => panic!("extend completed"),
+        }
+        assert_eq!(circuit.state, CircuitState::ExtendBackoff);
+        time_out_extend(&mut circuit, &mut relays, &extend_target);
+        relays.send(0, types::RelayCommand::Truncated, 0, &[reason]);
+        // That was the only retry, so this timeout is the end of it.
+        let error = circuit.poll_extend_to(&extend_target).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert_eq!(circuit.state, CircuitState::Ready);
+        assert_eq!(circuit.get_hop_count(), 1);
+        assert!(circuit.take_unexpected_truncation().is_none());
+    }
+
+    #[test]
+    fn test_extend_timeout_without_truncated() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        circuit.set_extend_timeout(Duration::from_millis(0));
+        let extend_target = MockExtendTarget::new(1).to_extend_target();
+        time_out_extend(&mut circuit, &mut relays, &extend_target);
+        // The extending hop doesn't answer the TRUNCATE either, so the circuit is torn down.
+        let error = circuit.poll_extend_to(&extend_target).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert_eq!(circuit.get_status(), CircuitStatus::Destroyed);
+        let cells = relays.receive_cells();
+        assert_eq!(cells.len(), 1);
+        assert_eq!(cells[0].command, types::Command::Destroy);
+    }
+
+    #[test]
+    fn test_write_all_uses_one_write() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        let stream_id = ready_stream(&mut circuit);
+        // A stream's window is 500 cells, which is as much as one call can send.
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+        let sent = match circuit.poll_stream_write_all(stream_id, &data).unwrap() {
+            Async::Ready(sent) => sent,
+            Async::NotReady => panic!("nothing sent"),
+        };
+        assert_eq!(sent, 500 * types::RELAY_PAYLOAD_LEN);
+        assert_eq!(*relays.tls.write_count.borrow(), 1);
+        assert_eq!(relays.tls.outbound.borrow().len(), 500 * types::CELL_LEN);
+        let received: Vec<u8> = relays
+            .receive()
+            .into_iter()
+            .flat_map(|(_, relay_cell)| relay_cell.get_data().to_vec())
+            .collect();
+        assert_eq!(&received[..], &data[..sent]);
+        // The window's used up now.
+        match circuit.poll_stream_write_all(stream_id, &data[sent..]).unwrap() {
+            Async::NotReady => {}
+            Async::Ready(sent) => panic!("sent {} bytes with no window", sent),
+        }
+    }
+
+    #[test]
+    fn test_write_all_when_connection_blocks() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        let stream_id = ready_stream(&mut circuit);
+        let data = vec![0x55; 3 * types::RELAY_PAYLOAD_LEN];
+        *relays.tls.write_capacity.borrow_mut() = Some(1000);
+        match circuit.poll_stream_write_all(stream_id, &data).unwrap() {
+            Async::NotReady => {}
+            Async::Ready(sent) => panic!("sent {} bytes into a full connection", sent),
+        }
+        assert_eq!(relays.tls.outbound.borrow().len(), 1000);
+        // Still blocked: nothing more gets written, and nothing's encrypted twice.
+        match circuit.poll_stream_write_all(stream_id, &data).unwrap() {
+            Async::NotReady => {}
+            Async::Ready(sent) => panic!("sent {} bytes into a full connection", sent),
+        }
+        assert_eq!(relays.tls.outbound.borrow().len(), 1000);
+        *relays.tls.write_capacity.borrow_mut() = None;
+        match circuit.poll_stream_write_all(stream_id, &data).unwrap() {
+            Async::Ready(sent) => assert_eq!(sent, data.len()),
+            Async::NotReady => panic!("write didn't finish"),
+        }
+        let received = relays.receive();
+        assert_eq!(received.len(), 3);
+        for (_, relay_cell) in received {
+            assert_eq!(relay_cell.get_data(), &data[..types::RELAY_PAYLOAD_LEN]);
+        }
+        // Later writes carry on from there.
+        match circuit.poll_stream_write(stream_id, b"more").unwrap() {
+            Async::Ready(()) => {}
+            Async::NotReady => panic!("couldn't write"),
+        }
+        assert_eq!(relays.receive()[0].1.get_data(), b"more");
+    }
+
+    #[test]
+    fn test_versions_certs_auth_challenge_in_one_read() {
+        let responder = MockResponder::new();
+        let (mut circuit, mut relays) = new_circuit(&responder);
+        circuit.poll().unwrap();
+        relays.tls.outbound.borrow_mut().clear();
+        let mut record = Vec::new();
+        types::VersionsCell::new(vec![4, 5])
+            .write_to(&mut record)
+            .unwrap();
+        for cell in link_handshake_cells(&responder).into_iter().take(2) {
+            cell.write_to(&mut record).unwrap();
+        }
+        *relays.tls.inbound.borrow_mut() = record;
+        circuit.poll().unwrap();
+        assert!(relays.tls.inbound.borrow().is_empty());
+        // Everything up to NETINFO is handled without any more data arriving.
+        for _ in 0..10 {
+            if circuit.state == CircuitState::NetinfoReading {
+                break;
+            }
+            circuit.poll().unwrap();
+        }
+        assert_eq!(circuit.state, CircuitState::NetinfoReading);
+        assert_eq!(circuit.get_link_version(), 5);
+        let commands: Vec<types::Command> = relays
+            .receive_cells()
+            .into_iter()
+            .map(|cell| cell.command)
+            .collect();
+        assert_eq!(commands, vec![types::Command::Certs, types::Command::Authenticate]);
+    }
+
+    #[test]
+    fn test_ninth_extend_refused() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        for hop_index in 0..8 {
+            let target = MockExtendTarget::new(hop_index as u8 + 1);
+            extend_from(&mut circuit, &mut relays, hop_index, &target);
+        }
+        assert_eq!(circuit.get_hop_count(), 9);
+        assert_eq!(circuit.relay_early_remaining(), 0);
+        let target = MockExtendTarget::new(9).to_extend_target();
+        let error = circuit.poll_extend_from(8, &target).unwrap_err();
+        assert_eq!(error.to_string(), "RELAY_EARLY budget exhausted");
+        assert!(relays.tls.outbound.borrow().is_empty());
+        assert_eq!(circuit.state, CircuitState::Ready);
+    }
+
+    #[test]
+    fn test_begin_ipv4_exit_target() {
+        let (mut circuit, mut relays) = ready_circuit(3);
+        let stream_id = circuit.open_stream_to_host("192.0.2.7", 443).unwrap();
+        assert!(stream_id != 0);
+        match circuit.poll_stream_setup(stream_id).unwrap() {
+            Async::NotReady => {}
+            Async::Ready(()) => panic!("stream set up without CONNECTED"),
+        }
+        let cells = relays.receive_cells();
+        assert_eq!(cells.len(), 1);
+        assert_eq!(cells[0].command, types::Command::Relay);
+        let (hop_index, begin) = relays.decrypt(&cells[0].payload);
+        assert_eq!(hop_index, 2);
+        assert_eq!(begin.relay_command, types::RelayCommand::Begin);
+        assert_eq!(begin.stream_id, stream_id);
+        assert_eq!(begin.get_data(), b"192.0.2.7:443\0\0\0\0\x01");
+        relays.send(2, types::RelayCommand::Connected, stream_id, &[192, 0, 2, 7, 0, 0, 1, 0]);
+        match circuit.poll_stream_setup(stream_id).unwrap() {
+            Async::Ready(()) => {}
+            Async::NotReady => panic!("stream not set up"),
+        }
+    }
+
+    #[test]
+    fn test_begin_refused() {
+        let (mut circuit, mut relays) = ready_circuit(3);
+        let stream_id = circuit.open_stream_to_host("192.0.2.7", 443).unwrap();
+        circuit.poll_stream_setup(stream_id).unwrap();
+        relays.receive();
+        let reason = types::EndReason::ExitPolicy.as_u8();
+        relays.send(2, types::RelayCommand::End, stream_id, &[reason]);
+        let error = circuit.poll_stream_setup(stream_id).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn test_data_round_trip_through_chunking() {
+        let (mut circuit, mut relays) = ready_circuit(3);
+        let stream_id = ready_stream(&mut circuit);
+        let other_stream_id = ready_stream(&mut circuit);
+        let data: Vec<u8> = (0..2000).map(|i| (i % 251) as u8).collect();
+        match circuit.poll_stream_write_all(stream_id, &data).unwrap() {
+            Async::Ready(sent) => assert_eq!(sent, 2000),
+            Async::NotReady => panic!("nothing sent"),
+        }
+        let received = relays.receive();
+        let lengths: Vec<usize> = received
+            .iter()
+            .map(|&(_, ref relay_cell)| relay_cell.get_data().len())
+            .collect();
+        assert_eq!(lengths, vec![498, 498, 498, 498, 8]);
+        let mut echoed = Vec::new();
+        for (hop_index, relay_cell) in received {
+            assert_eq!(hop_index, 2);
+            assert_eq!(relay_cell.relay_command, types::RelayCommand::Data);
+            assert_eq!(relay_cell.stream_id, stream_id);
+            echoed.extend_from_slice(relay_cell.get_data());
+        }
+        assert_eq!(echoed, data);
+
+        // Echo it back, interleaved with data for another stream, which gets set aside for that
+        // stream.
+        for chunk in echoed.chunks(types::RELAY_PAYLOAD_LEN) {
+            relays.send(2, types::RelayCommand::Data, other_stream_id, b"other");
+            relays.send(2, types::RelayCommand::Data, stream_id, chunk);
+        }
+        let mut read = Vec::new();
+        for _ in 0..20 {
+            if read.len() >= data.len() {
+                break;
+            }
+            match circuit.poll_stream_read(stream_id).unwrap() {
+                Async::Ready(chunk) => read.extend(chunk),
+                Async::NotReady => {}
+            }
+        }
+        assert_eq!(read, data);
+        match circuit.poll_stream_read(other_stream_id).unwrap() {
+            Async::Ready(chunk) => assert_eq!(chunk, b"other"),
+            Async::NotReady => panic!("other stream's data went missing"),
+        }
+    }
+
+    #[test]
+    fn test_tor_error_display_and_source() {
+        use std::error::Error as StdError;
+
+        let error = TorError::from(Error::new(ErrorKind::TimedOut, "timed out building circuit"));
+        assert_eq!(error.to_string(), "I/O error: timed out building circuit");
+        assert_eq!(error.source().unwrap().to_string(), "timed out building circuit");
+        let error = TorError::Protocol("unexpected cell type".to_owned());
+        assert_eq!(error.to_string(), "protocol error: unexpected cell type");
+        assert!(error.source().is_none());
+        let error = TorError::NoCommonVersion(vec![1, 2]);
+        assert_eq!(error.to_string(), "no link protocol version in common (peer offered [1, 2])");
+        assert_eq!(Error::from(error).kind(), ErrorKind::InvalidData);
+        let error = TorError::AuthRejected;
+        assert_eq!(error.to_string(), "peer closed connection instead of sending NETINFO");
+        assert_eq!(Error::from(error).kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_link_version_3_not_offered() {
+        let responder = MockResponder::new();
+        let (mut circuit, mut relays) = new_circuit(&responder);
+        circuit.poll().unwrap();
+        let outbound = mem::replace(&mut *relays.tls.outbound.borrow_mut(), Vec::new());
+        let versions = types::VersionsCell::read_new(&mut &outbound[..]).unwrap();
+        assert!(versions.negotiate(&types::VersionsCell::new(vec![3])).is_err());
+        let mut inbound = Vec::new();
+        types::VersionsCell::new(vec![3])
+            .write_to(&mut inbound)
+            .unwrap();
+        *relays.tls.inbound.borrow_mut() = inbound;
+        match circuit.poll() {
+            Err(TorError::NoCommonVersion(versions)) => assert_eq!(versions, vec![3]),
+            result => panic!("expected NoCommonVersion, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_id_tracker_release_and_reuse() {
+        let mut ids: IdTracker<u8> = IdTracker::new();
+        // Every id with the high bit set is taken...
+        for id in 0x80..0x100 {
+            assert!(ids.mark_used(id as u8));
+        }
+        // ...except for these, which are the only ones that can be handed out.
+        let released: Vec<u8> = (0x80..0x90).collect();
+        for id in released.iter() {
+            ids.release(*id);
+        }
+        let mut allocated = HashSet::new();
+        for _ in 0..8 {
+            let id = ids.get_new_id();
+            assert!(released.contains(&id));
+            assert!(allocated.insert(id));
+        }
+        // Releasing one of those makes it available once more.
+        let id = *allocated.iter().next().unwrap();
+        ids.release(id);
+        assert!(ids.mark_used(id));
+        assert!(!ids.mark_used(id));
+    }
+
+    #[test]
+    fn test_circuit_manager_releases_destroyed_circuit_ids() {
+        let mut manager: CircuitManager<MockTls, MockRsa> = CircuitManager::new();
+        let circ_id = manager.new_circ_id();
+        assert!(circ_id & 0x80000000 != 0);
+        let tls = MockTls::default();
+        let mut circuit = Circuit::new(tls.clone(), MockRsa, &MockRsa, circ_id, [0; 32]);
+        circuit.state = CircuitState::Ready;
+        assert_eq!(manager.add(circuit), circ_id);
+        assert!(!manager.circ_ids.mark_used(circ_id));
+
+        // The relay tears the circuit down.
+        let destroy = vec![types::DestroyReason::Requested.as_u8()];
+        types::Cell::new(circ_id, types::Command::Destroy, destroy)
+            .write_to(&mut *tls.inbound.borrow_mut())
+            .unwrap();
+        let stream_id = manager.get_mut(circ_id).unwrap().open_dir_stream();
+        assert!(manager.get_mut(circ_id).unwrap().poll_stream_setup(stream_id).is_err());
+        manager.remove_destroyed();
+        assert!(manager.get_mut(circ_id).is_none());
+        assert!(manager.circ_ids.mark_used(circ_id));
+    }
+
+    #[test]
+    fn test_circuit_sendme_every_100_data_cells() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        let stream_id = ready_stream(&mut circuit);
+        for i in 0..100 {
+            relays.send(0, types::RelayCommand::Data, stream_id, &[i as u8]);
+            match circuit.poll_stream_read(stream_id).unwrap() {
+                Async::Ready(data) => assert_eq!(data, vec![i as u8]),
+                Async::NotReady => panic!("DATA cell {} not read", i),
+            }
+            let circuit_sendmes: Vec<types::RelayCell> = relays
+                .receive()
+                .into_iter()
+                .map(|(_, relay_cell)| relay_cell)
+                .filter(|relay_cell| relay_cell.stream_id == 0)
+                .collect();
+            if i < 99 {
+                assert_eq!(circuit_sendmes.len(), 0);
+                continue;
+            }
+            // The 100th cell is acknowledged with the digest of everything up to and including it.
+            assert_eq!(circuit_sendmes.len(), 1);
+            assert_eq!(circuit_sendmes[0].relay_command, types::RelayCommand::SendMe);
+            let expected_digest = relays.circuit_keys[0].backward_digest.digest().bytes();
+            assert_eq!(
+                types::SendMeCell::read_new(circuit_sendmes[0].get_data()).unwrap(),
+                types::SendMeCell::V1 { digest: expected_digest }
+            );
+        }
+    }
+
+    /// Decrypts everything the circuit has sent, returning the exit's (the last hop's) forward
+    /// digest as of every 100th cell (what its circuit-level SENDMEs have to echo).
+    fn receive_sendme_digests(relays: &mut MockRelays) -> Vec<[u8; 20]> {
+        let mut digests = Vec::new();
+        for (index, cell) in relays.receive_cells().into_iter().enumerate() {
+            relays.decrypt(&cell.payload);
+            if (index + 1) % 100 == 0 {
+                let exit_keys = relays.circuit_keys.last().unwrap();
+                digests.push(exit_keys.forward_digest.digest().bytes());
+            }
+        }
+        digests
+    }
+
+    /// Sends an authenticated circuit-level SENDME from the exit.
+    fn send_circuit_sendme(relays: &mut MockRelays, digest: [u8; 20]) {
+        let mut data = Vec::new();
+        types::SendMeCell::V1 { digest: digest }.write_to(&mut data).unwrap();
+        let exit_index = relays.circuit_keys.len() - 1;
+        relays.send(exit_index, types::RelayCommand::SendMe, 0, &data);
+    }
+
+    /// Sends 100 DATA cells on a new stream and checks that the exit's circuit-level SENDME for
+    /// them is accepted.
+    fn exchange_circuit_sendme(circuit: &mut Circuit<MockTls, MockRsa>, relays: &mut MockRelays) {
+        let stream_id = ready_stream(circuit);
+        let data = vec![0; types::RELAY_PAYLOAD_LEN * 100];
+        match circuit.poll_stream_write_all(stream_id, &data).unwrap() {
+            Async::Ready(sent) => assert_eq!(sent, data.len()),
+            Async::NotReady => panic!("nothing sent"),
+        }
+        assert_eq!(circuit.send_window, 900);
+        let digests = receive_sendme_digests(relays);
+        assert_eq!(digests.len(), 1);
+        send_circuit_sendme(relays, digests[0]);
+        match circuit.poll_stream_read(stream_id).unwrap() {
+            Async::Ready(data) => panic!("read {:?} from a SENDME", data),
+            Async::NotReady => {}
+        }
+        assert_eq!(circuit.send_window, 1000);
+        assert_eq!(circuit.state, CircuitState::Ready);
+        circuit.poll_stream_end(stream_id, types::EndReason::Done).unwrap();
+        relays.receive();
+    }
+
+    #[test]
+    fn test_send_windows() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        let stream_id = ready_stream(&mut circuit);
+        let data = vec![0; types::RELAY_PAYLOAD_LEN * 1200];
+        // The stream window (500 cells) runs out first.
+        let sent = match circuit.poll_stream_write_all(stream_id, &data).unwrap() {
+            Async::Ready(sent) => sent,
+            Async::NotReady => panic!("nothing sent"),
+        };
+        assert_eq!(sent, types::RELAY_PAYLOAD_LEN * 500);
+        assert_eq!(circuit.send_window, 500);
+        assert_eq!(circuit.streams[&stream_id].send_window, 0);
+        match circuit.poll_stream_write_all(stream_id, &data).unwrap() {
+            Async::Ready(sent) => panic!("sent {} bytes with no window", sent),
+            Async::NotReady => {}
+        }
+        // A stream-level SENDME opens the stream window by 50 cells.
+        relays.send(0, types::RelayCommand::SendMe, stream_id, &[]);
+        match circuit.poll_stream_write_all(stream_id, &data).unwrap() {
+            Async::Ready(sent) => assert_eq!(sent, types::RELAY_PAYLOAD_LEN * 50),
+            Async::NotReady => panic!("nothing sent after stream SENDME"),
+        }
+        assert_eq!(circuit.send_window, 450);
+        // With the stream window out of the way, the circuit window (1000 cells) runs out.
+        circuit.streams.get_mut(&stream_id).unwrap().send_window = 1000;
+        match circuit.poll_stream_write_all(stream_id, &data).unwrap() {
+            Async::Ready(sent) => assert_eq!(sent, types::RELAY_PAYLOAD_LEN * 450),
+            Async::NotReady => panic!("nothing sent before the circuit window ran out"),
+        }
+        assert_eq!(circuit.send_window, 0);
+        match circuit.poll_stream_write_all(stream_id, &data).unwrap() {
+            Async::Ready(sent) => panic!("sent {} bytes with no window", sent),
+            Async::NotReady => {}
+        }
+        // A circuit-level SENDME for the 100th cell opens the circuit window by 100 cells.
+        let digests = receive_sendme_digests(&mut relays);
+        assert_eq!(digests.len(), 10);
+        send_circuit_sendme(&mut relays, digests[0]);
+        match circuit.poll_stream_write_all(stream_id, &data).unwrap() {
+            Async::Ready(sent) => assert_eq!(sent, types::RELAY_PAYLOAD_LEN * 100),
+            Async::NotReady => panic!("nothing sent after circuit SENDME"),
+        }
+        assert_eq!(circuit.send_window, 0);
+        // The next SENDME has to acknowledge the 200th cell, not the 100th again.
+        send_circuit_sendme(&mut relays, digests[0]);
+        assert!(circuit.poll_stream_write_all(stream_id, &data).is_err());
+        assert_eq!(circuit.state, CircuitState::Error);
+    }
+
+    #[test]
+    fn test_kdf_tor_known_answer() {
+        // K0 is the TAP shared secret g^6 (64, as a 128-byte big-endian number). The expected
+        // values are SHA-1(K0 | [00]) and, from K = SHA-1(K0 | [01]) | ... | SHA-1(K0 | [04]),
+        // SHA-1 of Df and Db and the fingerprint of Df | Db | Kf | Kb.
+        let mut k0 = vec![0; 128];
+        k0[127] = 64;
+        let kh = util::slice_to_20_byte_array(&[
+            0x1a, 0xf5, 0x40, 0x95, 0x1a, 0x45, 0x3f, 0x0e, 0x8d, 0xe8, 0x3b, 0x53, 0x3c, 0x1a,
+            0x4c, 0xa5, 0xa6, 0xb4, 0x56, 0x4a,
+        ]);
+        let circuit_keys = kdf_tor(&k0, &kh).unwrap();
+        assert_eq!(circuit_keys.kh, kh);
+        assert_eq!(
+            util::to_hex(&circuit_keys.forward_digest.digest().bytes()),
+            "ae689cb783302a859190cd02c4f78f8741b510d9"
+        );
+        assert_eq!(
+            util::to_hex(&circuit_keys.backward_digest.digest().bytes()),
+            "719aef199b8ca8832da7860ffa63a1aee09a31f3"
+        );
+        assert_eq!(util::to_hex(&circuit_keys.fingerprint()), "a355cd9b5616a1ca");
+    }
+
+    /// Encrypts `plaintext` with one context and decrypts it with another, in uneven pieces (so the
+    /// counter has to carry over between calls).
+    fn aes_round_trip(key: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut encryptor = AesContext::new(key);
+        let mut decryptor = AesContext::new(key);
+        let mut ciphertext = vec![0; plaintext.len()];
+        encryptor.aes.process(plaintext, &mut ciphertext);
+        assert!(ciphertext != plaintext);
+        let mut decrypted = Vec::new();
+        for chunk in ciphertext.chunks(7) {
+            let mut decrypted_chunk = vec![0; chunk.len()];
+            decryptor.aes.process(chunk, &mut decrypted_chunk);
+            decrypted.extend(decrypted_chunk);
+        }
+        assert_eq!(decrypted, plaintext);
+        ciphertext
+    }
+
+    #[test]
+    fn test_aes_128_round_trip() {
+        let plaintext: Vec<u8> = (0..100).collect();
+        aes_round_trip(&[0x2b; 16], &plaintext);
+        // With a zero IV, the first block of keystream is just the zero block encrypted (here
+        // under the all-zero key, which has a well-known answer).
+        let keystream = aes_round_trip(&[0; 16], &[0; 16]);
+        assert_eq!(util::to_hex(&keystream), "66e94bd4ef8a2c3b884cfa59ca342b2e");
+    }
+
+    #[test]
+    fn test_aes_256_round_trip() {
+        let plaintext: Vec<u8> = (0..100).collect();
+        let ciphertext = aes_round_trip(&[0x2b; 32], &plaintext);
+        // A 32-byte key really is used as AES-256, not truncated to AES-128.
+        assert!(ciphertext != aes_round_trip(&[0x2b; 16], &plaintext));
+        let keystream = aes_round_trip(&[0; 32], &[0; 16]);
+        assert_eq!(util::to_hex(&keystream), "dc95c078a2408989ad48a21492842087");
+    }
+
+    #[test]
+    fn test_resolve() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        let stream_id = circuit.open_resolve("example.com");
+        match circuit.poll_resolve(stream_id).unwrap() {
+            Async::Ready(addresses) => panic!("resolved to {:?} before asking", addresses),
+            Async::NotReady => {}
+        }
+        let relay_cells = relays.receive();
+        assert_eq!(relay_cells.len(), 1);
+        assert_eq!(relay_cells[0].1.relay_command, types::RelayCommand::Resolve);
+        assert_eq!(relay_cells[0].1.stream_id, stream_id);
+        assert_eq!(relay_cells[0].1.get_data(), b"example.com\0");
+        let mut answers = vec![4, 4, 93, 184, 216, 34, 0, 0, 0, 60, 6, 16];
+        answers.extend(&[0x26, 0x06, 0x28, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 60]);
+        relays.send(0, types::RelayCommand::Resolved, stream_id, &answers);
+        match circuit.poll_resolve(stream_id).unwrap() {
+            Async::Ready(addresses) => assert_eq!(
+                addresses,
+                vec![
+                    "93.184.216.34".parse::<IpAddr>().unwrap(),
+                    "2606:2800::1".parse::<IpAddr>().unwrap(),
+                ]
+            ),
+            Async::NotReady => panic!("RESOLVED not read"),
+        }
+        assert!(!circuit.streams.contains_key(&stream_id));
+    }
+
+    #[test]
+    fn test_resolve_permanent_failure() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        let stream_id = circuit.open_resolve("nonexistent.invalid");
+        match circuit.poll_resolve(stream_id).unwrap() {
+            Async::Ready(addresses) => panic!("resolved to {:?} before asking", addresses),
+            Async::NotReady => {}
+        }
+        relays.receive();
+        relays.send(0, types::RelayCommand::Resolved, stream_id, &[0xf1, 0, 0, 0, 0, 60]);
+        match circuit.poll_resolve(stream_id) {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::NotFound),
+            Ok(_) => panic!("permanent failure not reported"),
+        }
+    }
+
+    /// 2025-01-01 00:00:00 UTC.
+    const VALIDATION_TIME: u64 = 1735689600;
+
+    fn validate_responder(responder: &MockResponder) -> Result<(), &'static str> {
+        let peer_cert_hash = util::slice_to_32_byte_array(&Sha256::digest(MOCK_PEER_CERT));
+        responder.responder_certs().validate(
+            &responder.get_ed25519_id_key(),
+            &peer_cert_hash,
+            &MockRsa,
+            VALIDATION_TIME,
+        )
+    }
+
+    #[test]
+    fn test_validate_responder_certs() {
+        assert_eq!(validate_responder(&MockResponder::new()), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_expired_rsa_identity_cert() {
+        let responder = MockResponder::with_validity(
+            "200101000000Z",
+            "241231235959Z",
+            certs::DEFAULT_EXPIRATION_DATE,
+        );
+        assert_eq!(validate_responder(&responder), Err("RSA identity cert has expired"));
+    }
+
+    #[test]
+    fn test_validate_not_yet_valid_rsa_identity_cert() {
+        let responder = MockResponder::with_validity(
+            "250101000001Z",
+            "491231000000Z",
+            certs::DEFAULT_EXPIRATION_DATE,
+        );
+        assert_eq!(validate_responder(&responder), Err("RSA identity cert is not yet valid"));
+    }
+
+    #[test]
+    fn test_validate_expired_ed25519_certs() {
+        // The Ed25519 certs expire exactly at the validation time.
+        let expiration_date = (VALIDATION_TIME / 3600) as certs::HoursSinceEpoch;
+        let responder =
+            MockResponder::with_validity("200101000000Z", "491231000000Z", expiration_date);
+        assert_eq!(validate_responder(&responder), Err("Ed25519 identity cert has expired"));
+        let responder =
+            MockResponder::with_validity("200101000000Z", "491231000000Z", expiration_date + 1);
+        assert_eq!(validate_responder(&responder), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_signing_cert_names_other_signer() {
+        let responder = MockResponder::new();
+        let mut responder_certs = responder.responder_certs();
+        // Signed by the identity key, but claiming to be signed by some other key.
+        let other_key = keys::Ed25519Key::new();
+        let mut signing_cert = certs::Ed25519Cert::new_unsigned(
+            certs::Ed25519CertType::SigningKey,
+            keys::Ed25519Key::new().get_public_key_bytes(),
+            &other_key.get_public_key_bytes(),
+            certs::DEFAULT_EXPIRATION_DATE,
+        );
+        let signature = responder
+            .ed25519_identity_key
+            .sign_data(&signing_cert.get_tbs_bytes());
+        signing_cert.set_signature(signature);
+        responder_certs.ed25519_signing_cert = signing_cert;
+        let peer_cert_hash = util::slice_to_32_byte_array(&Sha256::digest(MOCK_PEER_CERT));
+        assert_eq!(
+            responder_certs.validate(
+                &responder.get_ed25519_id_key(),
+                &peer_cert_hash,
+                &MockRsa,
+                VALIDATION_TIME,
+            ),
+            Err("Ed25519 signing cert names a different signing key")
+        );
+    }
+
+    #[test]
+    fn test_read_exact_largest_read() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        let stream_id = ready_stream(&mut circuit);
+        let largest = MAX_STREAM_BUFFER - types::RELAY_PAYLOAD_LEN;
+        match circuit.poll_stream_read_exact(stream_id, largest + 1) {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidInput),
+            Ok(_) => panic!("read that might never fit in the buffer allowed"),
+        }
+        let data: Vec<u8> = (0..MAX_STREAM_BUFFER).map(|i| i as u8).collect();
+        for chunk in data.chunks(types::RELAY_PAYLOAD_LEN) {
+            relays.send(0, types::RelayCommand::Data, stream_id, chunk);
+        }
+        let mut polls = 0;
+        let read = loop {
+            polls += 1;
+            assert!(polls < 1000);
+            match circuit.poll_stream_read_exact(stream_id, largest).unwrap() {
+                Async::Ready(read) => break read,
+                Async::NotReady => {}
+            }
+        };
+        assert_eq!(read, &data[..largest]);
+    }
+
+    /// Polls `poll_stream_read_exact` until it's done one way or the other.
+    fn read_exact(
+        circuit: &mut Circuit<MockTls, MockRsa>,
+        stream_id: u16,
+        len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        for _ in 0..100 {
+            if let Async::Ready(read) = circuit.poll_stream_read_exact(stream_id, len)? {
+                return Ok(read);
+            }
+        }
+        panic!("poll_stream_read_exact never finished");
+    }
+
+    #[test]
+    fn test_read_exact_end_mid_read() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        let stream_id = ready_stream(&mut circuit);
+        relays.send(0, types::RelayCommand::Data, stream_id, &[7; 100]);
+        relays.send(0, types::RelayCommand::End, stream_id, &[6]);
+        let error = read_exact(&mut circuit, stream_id, 200).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(error.to_string(), "stream ended after 100 of 200 bytes");
+        // What did arrive is still there.
+        assert_eq!(circuit.take_stream_buffer(stream_id, 200), vec![7; 100]);
+    }
+
+    #[test]
+    fn test_read_exact_empty_data_cell() {
+        let (mut circuit, mut relays) = ready_circuit(1);
+        let stream_id = ready_stream(&mut circuit);
+        relays.send(0, types::RelayCommand::Data, stream_id, &[1; 50]);
+        relays.send(0, types::RelayCommand::Data, stream_id, &[]);
+        for _ in 0..10 {
+            match circuit.poll_stream_read_exact(stream_id, 100).unwrap() {
+                Async::NotReady => {}
+                Async::Ready(_) => panic!("read finished early"),
+            }
+        }
+        relays.send(0, types::RelayCommand::Data, stream_id, &[2; 50]);
+        let read = read_exact(&mut circuit, stream_id, 100).unwrap();
+        assert_eq!(&read[..50], &[1; 50][..]);
+        assert_eq!(&read[50..], &[2; 50][..]);
+    }
+
+    #[test]
+    fn test_ninth_relay_early_cell_refused() {
+        let (mut circuit, _) = ready_circuit(1);
+        let mut buf = Vec::new();
+        for _ in 0..MAX_RELAY_EARLY {
+            let bytes = circuit.encrypt_cell_bytes(types::RelayCommand::Drop, &[], 0);
+            circuit.append_relay_cell(bytes, true, &mut buf).unwrap();
+        }
+        let cell_count = buf.len() / types::CELL_LEN;
+        assert_eq!(cell_count, MAX_RELAY_EARLY);
+        let bytes = circuit.encrypt_cell_bytes(types::RelayCommand::Drop, &[], 0);
+        let error = circuit.append_relay_cell(bytes, true, &mut buf).unwrap_err();
+        assert_eq!(error.to_string(), "RELAY_EARLY budget exhausted");
+        assert_eq!(buf.len() / types::CELL_LEN, cell_count);
+        // Plain RELAY cells still go out.
+        let bytes = circuit.encrypt_cell_bytes(types::RelayCommand::Drop, &[], 0);
+        circuit.append_relay_cell(bytes, false, &mut buf).unwrap();
+        let mut reader = &buf[..];
+        let mut commands = Vec::new();
+        while !reader.is_empty() {
+            commands.push(types::Cell::read_new_for_version(&mut reader, 4).unwrap().command);
+        }
+        assert_eq!(commands.len(), MAX_RELAY_EARLY + 1);
+        assert!(commands[..MAX_RELAY_EARLY]
+            .iter()
+            .all(|command| *command == types::Command::RelayEarly));
+        assert_eq!(commands[MAX_RELAY_EARLY], types::Command::Relay);
+    }
+
+    #[test]
+    fn test_begin_after_extends_is_relay() {
+        let (mut circuit, mut relays) = three_hop_circuit();
+        assert_eq!(circuit.relay_early_remaining(), MAX_RELAY_EARLY as u8 - 2);
+        let stream_id = circuit.open_stream_to_host("192.0.2.7", 443).unwrap();
+        match circuit.poll_stream_setup(stream_id).unwrap() {
+            Async::NotReady => {}
+            Async::Ready(()) => panic!("stream set up without CONNECTED"),
+        }
+        let cells = relays.receive_cells();
+        assert_eq!(cells.len(), 1);
+        assert_eq!(cells[0].command, types::Command::Relay);
+        assert_eq!(relays.decrypt(&cells[0].payload).1.relay_command, types::RelayCommand::Begin);
+        assert_eq!(circuit.relay_early_remaining(), MAX_RELAY_EARLY as u8 - 2);
+    }
+
+    #[test]
+    fn test_kdf_tor_rejects_corrupted_kh() {
+        // The K0 and KH from `test_kdf_tor_known_answer`, with one bit of KH flipped.
+        let mut k0 = vec![0; 128];
+        k0[127] = 64;
+        let mut kh = [
+            0x1a, 0xf5, 0x40, 0x95, 0x1a, 0x45, 0x3f, 0x0e, 0x8d, 0xe8, 0x3b, 0x53, 0x3c, 0x1a,
+            0x4c, 0xa5, 0xa6, 0xb4, 0x56, 0x4a,
+        ];
+        assert!(kdf_tor(&k0, &kh).is_ok());
+        kh[19] ^= 1;
+        assert_eq!(kdf_tor(&k0, &kh).err(), Some(HandshakeError::KhMismatch));
+    }
+
+    #[test]
+    fn test_ntor_handshake_rejects_corrupted_auth() {
+        let target = MockExtendTarget::new(1);
+        let client_keypair = keys::Curve25519Keypair::new();
+        let mut h_data = Vec::new();
+        types::NtorClientHandshake::with_extend_target(&target.to_extend_target(), &client_keypair)
+            .write_to(&mut h_data)
+            .unwrap();
+        let (mut extended2, _) = target.ntor_server_handshake(&h_data);
+        let server_b = target.onion_keypair.get_public_key_bytes();
+        let created2 = types::Created2Cell::read_new(&mut &extended2[..]).unwrap();
+        assert!(ntor_handshake(&created2, target.node_id, server_b, &client_keypair).is_ok());
+        // The last byte of AUTH.
+        let last = extended2.len() - 1;
+        extended2[last] ^= 1;
+        let created2 = types::Created2Cell::read_new(&mut &extended2[..]).unwrap();
+        let result = ntor_handshake(&created2, target.node_id, server_b, &client_keypair);
+        assert_eq!(result.err(), Some(HandshakeError::AuthMismatch));
+    }
+
+    #[test]
+    fn test_ntor_handshake_degenerate_keys() {
+        let client_keypair = keys::Curve25519Keypair::new();
+        let server_b = keys::Curve25519Keypair::new().get_public_key_bytes();
+        // Y (the relay's ephemeral key) is zero.
+        let mut created2_payload = vec![0, 64];
+        created2_payload.extend_from_slice(&[0; 64]);
+        let created2 = types::Created2Cell::read_new(&mut &created2_payload[..]).unwrap();
+        let result = ntor_handshake(&created2, [0; 20], server_b, &client_keypair);
+        assert_eq!(result.err(), Some(HandshakeError::DegenerateKey));
+        // Y is fine, but B (the relay's onion key) is of small order.
+        let mut created2_payload = vec![0, 64];
+        created2_payload.extend_from_slice(&server_b);
+        created2_payload.extend_from_slice(&[0; 32]);
+        let created2 = types::Created2Cell::read_new(&mut &created2_payload[..]).unwrap();
+        let mut low_order_b = [0; 32];
+        low_order_b[0] = 1;
+        let result = ntor_handshake(&created2, [0; 20], low_order_b, &client_keypair);
+        assert_eq!(result.err(), Some(HandshakeError::DegenerateKey));
+    }
+
+    #[test]
+    fn test_truncate_skips_circuit_sendmes() {
+        let (mut circuit, mut relays) = three_hop_circuit();
+        // Leave the exit 150 cells into the circuit window, with a SENDME still to come.
+        let stream_id = ready_stream(&mut circuit);
+        let data = vec![0; types::RELAY_PAYLOAD_LEN * 150];
+        match circuit.poll_stream_write_all(stream_id, &data).unwrap() {
+            Async::Ready(sent) => assert_eq!(sent, data.len()),
+            Async::NotReady => panic!("nothing sent"),
+        }
+        circuit.poll_stream_end(stream_id, types::EndReason::Done).unwrap();
+        let digests = receive_sendme_digests(&mut relays);
+        assert_eq!(digests.len(), 1);
+        match circuit.poll_truncate().unwrap() {
+            Async::NotReady => {}
+            Async::Ready(()) => panic!("truncate completed before sending TRUNCATE"),
+        }
+        match circuit.poll_truncate().unwrap() {
+            Async::NotReady => {}
+            Async::Ready(()) => panic!("truncate completed before TRUNCATED"),
+        }
+        let received = relays.receive();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, 1);
+        assert_eq!(received[0].1.relay_command, types::RelayCommand::Truncate);
+        // The exit acknowledges the earlier data before the middle hop gets to the TRUNCATE.
+        send_circuit_sendme(&mut relays, digests[0]);
+        let reason = types::DestroyReason::Requested.as_u8();
+        relays.send(1, types::RelayCommand::Truncated, 0, &[reason]);
+        match circuit.poll_truncate().unwrap() {
+            Async::Ready(()) => {}
+            Async::NotReady => panic!("truncate didn't complete"),
+        }
+        assert_eq!(circuit.state, CircuitState::Ready);
+        assert_eq!(circuit.get_hop_count(), 2);
+        assert!(circuit.take_unexpected_truncation().is_none());
+        assert!(circuit.buffered_relay_cells.is_empty());
+        // The new exit's window and SENDMEs start from scratch, not where the old exit's left off.
+        relays.circuit_keys.truncate(2);
+        extend_from(&mut circuit, &mut relays, 1, &MockExtendTarget::new(3));
+        assert_eq!(circuit.get_hop_count(), 3);
+        exchange_circuit_sendme(&mut circuit, &mut relays);
+    }
+
+    #[test]
+    fn test_extend_from_middle_hop() {
+        let (mut circuit, mut relays) = three_hop_circuit();
+        // The old exit is 150 cells into the circuit window when it's replaced.
+        let stream_id = ready_stream(&mut circuit);
+        let data = vec![0; types::RELAY_PAYLOAD_LEN * 150];
+        match circuit.poll_stream_write_all(stream_id, &data).unwrap() {
+            Async::Ready(sent) => assert_eq!(sent, data.len()),
+            Async::NotReady => panic!("nothing sent"),
+        }
+        circuit.poll_stream_end(stream_id, types::EndReason::Done).unwrap();
+        assert_eq!(receive_sendme_digests(&mut relays).len(), 1);
+        let target = MockExtendTarget::new(3);
+        extend_from(&mut circuit, &mut relays, 1, &target);
+        assert_eq!(circuit.get_hop_count(), 3);
+        assert_eq!(circuit.extended_ed25519_ids, vec![[1; 32], target.ed25519_id_key]);
+        exchange_circuit_sendme(&mut circuit, &mut relays);
+    }
+
+    #[test]
+    fn test_initiator_identity_reloaded() {
+        let identity_key = keys::Ed25519Key::new();
+        let reloaded_key = keys::Ed25519Key::from_pkcs8_pem(&identity_key.to_pkcs8_pem()).unwrap();
+        let expiration_date = 500_000;
+        let initiator_certs = InitiatorCerts::from_keys(&MockRsa, &identity_key, expiration_date);
+        let reloaded_certs = InitiatorCerts::from_keys(&MockRsa, &reloaded_key, expiration_date);
+        // The RSA identity's cross-cert for the Ed25519 identity only depends on the two
+        // identities, so it comes out the same.
+        let mut identity_cert_bytes = Vec::new();
+        initiator_certs.ed25519_identity_cert.write_to(&mut identity_cert_bytes);
+        let mut reloaded_identity_cert_bytes = Vec::new();
+        reloaded_certs.ed25519_identity_cert.write_to(&mut reloaded_identity_cert_bytes);
+        assert_eq!(identity_cert_bytes, reloaded_identity_cert_bytes);
+        assert_eq!(
+            reloaded_certs.ed25519_identity_cert.get_key_bytes(),
+            &identity_key.get_public_key_bytes()[..]
+        );
+        // The signing key is fresh each time, but both signing certs are the identity's, and
+        // each verifies against the other's identity key.
+        let signing_certs = [
+            &initiator_certs.ed25519_signing_cert,
+            &reloaded_certs.ed25519_signing_cert,
+        ];
+        for signing_cert in signing_certs.iter() {
+            assert_eq!(signing_cert.get_cert_type(), &certs::Ed25519CertType::SigningKey);
+            assert_eq!(signing_cert.get_expiration_date(), expiration_date);
+            assert_eq!(
+                signing_cert.get_signed_with_key(),
+                Some(&identity_key.get_public_key_bytes()[..])
+            );
+            for initiator in &[&initiator_certs, &reloaded_certs] {
+                let identity = initiator.ed25519_identity_cert.get_key();
+                assert!(identity.check_ed25519_signature(signing_cert));
+            }
+        }
+        // Circuits given the original and the reloaded identity send the same identity cert.
+        let lifetime = Duration::from_secs(86400);
+        let mut circuit =
+            Circuit::new(MockTls::default(), MockRsa, &MockRsa, TEST_CIRC_ID, [0; 32]);
+        circuit.set_initiator_identity(&MockRsa, &identity_key, lifetime);
+        let mut reloaded_circuit =
+            Circuit::new(MockTls::default(), MockRsa, &MockRsa, TEST_CIRC_ID, [0; 32]);
+        reloaded_circuit.set_initiator_identity(&MockRsa, &reloaded_key, lifetime);
+        let raw_certs = circuit.get_default_initiator_certs();
+        assert_eq!(raw_certs[1].get_cert_type(), &types::CertType::Ed25519Identity);
+        let mut raw_cert_bytes = Vec::new();
+        raw_certs[1].write_to(&mut raw_cert_bytes).unwrap();
+        let mut reloaded_raw_cert_bytes = Vec::new();
+        reloaded_circuit.get_default_initiator_certs()[1]
+            .write_to(&mut reloaded_raw_cert_bytes)
+            .unwrap();
+        assert_eq!(raw_cert_bytes, reloaded_raw_cert_bytes);
+    }
+
+    #[test]
+    fn test_x509_cert_fields() {
+        let der = x509_cert_der_with_names(
+            Some("www.issuer.net"),
+            Some("www.subject.com"),
+            "200101000000Z",
+            "491231000000Z",
+        );
+        let cert = certs::X509Cert::new(&der);
+        assert_eq!(cert.get_subject_cn(), Ok("www.subject.com".to_owned()));
+        assert_eq!(cert.get_issuer_cn(), Ok("www.issuer.net".to_owned()));
+        assert_eq!(cert.get_serial_number(), Ok(&[0x00, 0x9c, 0x41][..]));
+        assert_eq!(cert.get_not_before(), Ok(1577836800));
+        assert_eq!(cert.get_not_after(), Ok(2524521600));
+        assert_eq!(cert.get_validity(), Ok((1577836800, 2524521600)));
+    }
+
+    #[test]
+    fn test_x509_cert_without_cn() {
+        let der = x509_cert_der_with_names(None, Some("www.subject.com"), "200101000000Z",
+                                           "491231000000Z");
+        let cert = certs::X509Cert::new(&der);
+        assert_eq!(cert.get_issuer_cn(), Err("x509 name has no commonName"));
+        assert_eq!(cert.get_subject_cn(), Ok("www.subject.com".to_owned()));
+        let der = x509_cert_der_with_names(Some("www.issuer.net"), None, "200101000000Z",
+                                           "491231000000Z");
+        let cert = certs::X509Cert::new(&der);
+        assert_eq!(cert.get_subject_cn(), Err("x509 name has no commonName"));
+        // The other fields don't depend on the names.
+        assert_eq!(cert.get_issuer_cn(), Ok("www.issuer.net".to_owned()));
+        assert_eq!(cert.get_not_after(), Ok(2524521600));
+    }
+
+    #[test]
+    fn test_x509_cert_fields_malformed() {
+        let der = x509_cert_der("200101000000Z", "491231000000Z");
+        let cert = certs::X509Cert::new(&der[..der.len() - 1]);
+        assert!(cert.get_subject_cn().is_err());
+        assert!(cert.get_issuer_cn().is_err());
+        assert!(cert.get_serial_number().is_err());
+        assert!(cert.get_not_before().is_err());
+        assert!(cert.get_not_after().is_err());
+        let cert = certs::X509Cert::new(&x509_cert_der("200101000000Z", "not a time"));
+        assert!(cert.get_not_after().is_err());
+        assert_eq!(cert.get_subject_cn(), Ok("mock relay".to_owned()));
+    }
+
+    #[test]
+    fn test_link_handshake_skips_padding() {
+        let responder = MockResponder::new();
+        let (mut circuit, mut relays) = new_circuit(&responder);
+        let mut cells = link_handshake_cells(&responder);
+        cells.insert(1, types::Cell::new(0, types::Command::VPadding, vec![0; 7]));
+        cells.insert(3, types::Cell::new(0, types::Command::Padding, vec![0; 10]));
+        cells.insert(0, types::Cell::new(0, types::Command::Padding, vec![]));
+        // PADDING, CERTS, VPADDING, AUTH_CHALLENGE, PADDING, NETINFO
+        assert_eq!(cells[3].command, types::Command::AuthChallenge);
+        // SLOG covers everything received up to and including AUTH_CHALLENGE, padding and all.
+        let mut received = Vec::new();
+        types::VersionsCell::new(vec![3, 4, 5]).write_to(&mut received).unwrap();
+        for cell in &cells[..4] {
+            cell.write_to_for_version(&mut received, 4).unwrap();
+        }
+        let sent = link_handshake_with_cells(&mut circuit, &mut relays, cells);
+        let authenticate = types::AuthenticateCell::read_new(&mut &sent[1].payload[..]).unwrap();
+        // "AUTH0003" | CID | SID | CID_ED | SID_ED | SLOG | ...
+        let slog = &authenticate.get_authentication()[136..168];
+        assert_eq!(slog, &Sha256::digest(&received)[..]);
+        answer_create_fast(&mut relays, &sent[3]);
+        circuit.poll().unwrap();
+        assert_eq!(circuit.state, CircuitState::Ready);
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        let responder = MockResponder::new();
+        let (mut circuit, relays) = new_circuit(&responder);
+        circuit.set_handshake_timeout(Duration::from_secs(60));
+        // The relay takes our VERSIONS but never answers.
+        for _ in 0..2 {
+            match circuit.poll().unwrap() {
+                Async::NotReady => {}
+                Async::Ready(()) => panic!("handshake completed without the relay"),
+            }
+        }
+        let outbound = mem::replace(&mut *relays.tls.outbound.borrow_mut(), Vec::new());
+        types::VersionsCell::read_new(&mut &outbound[..]).unwrap();
+        // Once the deadline has passed, the next poll gives up.
+        circuit.set_handshake_timeout(Duration::from_millis(0));
+        match circuit.poll() {
+            Err(TorError::Io(e)) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("handshake didn't time out"),
+        }
+        assert_eq!(circuit.state, CircuitState::Error);
+    }
+
+    /// A plain TCP connection on a tokio event loop, standing in for TLS.
+    #[cfg(feature = "async")]
+    struct TokioTcpTls(tokio::net::TcpStream);
+
+    #[cfg(feature = "async")]
+    impl TlsImpl for TokioTcpTls {
+        fn get_peer_cert_hash(&self) -> Result<[u8; 32], Error> {
+            Ok(util::slice_to_32_byte_array(&Sha256::digest(MOCK_PEER_CERT)))
+        }
+
+        fn get_tls_secrets(&self, _label: &str, _context: &[u8]) -> Result<Vec<u8>, Error> {
+            Ok(vec![0; 32])
+        }
+    }
+
+    #[cfg(feature = "async")]
+    impl Read for TokioTcpTls {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            self.0.read(buf)
+        }
+    }
+
+    #[cfg(feature = "async")]
+    impl Write for TokioTcpTls {
+        fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+            self.0.write(data)
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            self.0.flush()
+        }
+    }
+
+    #[cfg(feature = "async")]
+    impl tokio::io::AsyncRead for TokioTcpTls {}
+
+    #[cfg(feature = "async")]
+    impl tokio::io::AsyncWrite for TokioTcpTls {
+        fn shutdown(&mut self) -> futures::Poll<(), Error> {
+            tokio::io::AsyncWrite::shutdown(&mut self.0)
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_tokio_handshake() {
+        use futures::Future;
+        use std::net::TcpListener;
+        use std::sync::mpsc;
+        use std::thread;
+
+        let responder = MockResponder::new();
+        let ed25519_id_key = responder.get_ed25519_id_key();
+        let cells = link_handshake_cells(&responder);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (done_sender, done) = mpsc::channel();
+        let relay = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let ours = types::VersionsCell::new(vec![4, 5]);
+            let link_version = ours
+                .negotiate(&types::VersionsCell::read_new(&mut stream).unwrap())
+                .unwrap();
+            // Like real relays, send everything up to NETINFO in one go.
+            let mut response = Vec::new();
+            ours.write_to(&mut response).unwrap();
+            for cell in cells {
+                cell.write_to_for_version(&mut response, link_version).unwrap();
+            }
+            stream.write_all(&response).unwrap();
+            let mut commands = Vec::new();
+            loop {
+                let cell = types::Cell::read_new_for_version(&mut stream, link_version).unwrap();
+                if cell.command == types::Command::CreateFast {
+                    let (created_fast, _) = created_fast_reply(&cell);
+                    created_fast
+                        .write_to_for_version(&mut stream, link_version)
+                        .unwrap();
+                    // Keep the connection open until the client has read that.
+                    done.recv().unwrap();
+                    return commands;
+                }
+                commands.push(cell.command);
+            }
+        });
+
+        let handshake = tokio::net::TcpStream::connect(&address)
+            .map_err(TorError::from)
+            .and_then(move |stream| {
+                let circuit = Circuit::new(
+                    TokioTcpTls(stream),
+                    MockRsa,
+                    &MockRsa,
+                    TEST_CIRC_ID,
+                    ed25519_id_key,
+                );
+                tokio_handshake::Handshake::new(circuit)
+            });
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let circuit = runtime.block_on(handshake).unwrap();
+        done_sender.send(()).unwrap();
+        assert_eq!(circuit.state, CircuitState::Ready);
+        assert!(circuit.is_authenticated());
+        assert_eq!(circuit.get_link_version(), 5);
+        assert_eq!(circuit.circuit_keys.len(), 1);
+        assert_eq!(
+            relay.join().unwrap(),
+            vec![
+                types::Command::Certs,
+                types::Command::Authenticate,
+                types::Command::Netinfo,
+            ]
+        );
+    }
+}
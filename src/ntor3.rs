@@ -0,0 +1,317 @@
+use byteorder::{NetworkEndian, WriteBytesExt};
+use crypto::digest::Digest;
+use crypto::sha3::Sha3;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use std::io::{Error, ErrorKind};
+
+use dir;
+use keys;
+use util;
+use AesContext;
+use HandshakeError;
+
+const PROTOID: &'static [u8] = b"ntor3-curve25519-sha3_256-1";
+const T_MSGKDF: &'static [u8] = b"ntor3-curve25519-sha3_256-1:kdf_phase1";
+const T_MSGMAC: &'static [u8] = b"ntor3-curve25519-sha3_256-1:msg_mac";
+const T_KEY_SEED: &'static [u8] = b"ntor3-curve25519-sha3_256-1:key_seed";
+const T_VERIFY: &'static [u8] = b"ntor3-curve25519-sha3_256-1:verify";
+const T_FINAL: &'static [u8] = b"ntor3-curve25519-sha3_256-1:kdf_final";
+const T_AUTH: &'static [u8] = b"ntor3-curve25519-sha3_256-1:auth_final";
+/// The verification string ("VER") for handshakes that create or extend circuits.
+const CIRCUIT_VERIFICATION: &'static [u8] = b"circuit extend";
+/// Length of the AES-256 keys used to encrypt the handshake messages.
+const ENC_KEY_LEN: usize = 32;
+/// Length of the key used to MAC the client's message.
+const MAC_KEY_LEN: usize = 32;
+/// Length of SHA3-256 output (so of MACs, AUTH, and the key seed).
+const DIGEST_LEN: usize = 32;
+
+/// ENCAP(s): s prefixed with its length as an 8-byte big-endian number.
+fn encap(data: &[u8]) -> Vec<u8> {
+    let mut encapsulated = Vec::with_capacity(8 + data.len());
+    encapsulated.write_u64::<NetworkEndian>(data.len() as u64).unwrap();
+    encapsulated.extend_from_slice(data);
+    encapsulated
+}
+
+/// H(s, t) = SHA3_256(ENCAP(t) | s)
+fn h(s: &[u8], t: &[u8]) -> [u8; DIGEST_LEN] {
+    let mut hasher = Sha3::sha3_256();
+    hasher.input(&encap(t));
+    hasher.input(s);
+    let mut digest = [0; DIGEST_LEN];
+    hasher.result(&mut digest);
+    digest
+}
+
+/// MAC(k, msg, t) = SHA3_256(ENCAP(t) | ENCAP(k) | msg)
+fn mac(k: &[u8], msg: &[u8], t: &[u8]) -> [u8; DIGEST_LEN] {
+    let mut hasher = Sha3::sha3_256();
+    hasher.input(&encap(t));
+    hasher.input(&encap(k));
+    hasher.input(msg);
+    let mut digest = [0; DIGEST_LEN];
+    hasher.result(&mut digest);
+    digest
+}
+
+/// KDF(s, t) = SHAKE_256(ENCAP(t) | s), truncated to `out_len` bytes.
+fn kdf(s: &[u8], t: &[u8], out_len: usize) -> Vec<u8> {
+    let mut hasher = Sha3::shake_256();
+    hasher.input(&encap(t));
+    hasher.input(s);
+    let mut output = vec![0; out_len];
+    hasher.result(&mut output);
+    output
+}
+
+/// ENC(k, m) = AES_256_CTR(k, m), with an all-zero IV.
+fn encrypt(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut encrypted = vec![0; message.len()];
+    AesContext::new(key).aes.process(message, &mut encrypted);
+    encrypted
+}
+
+/// The client side of an ntor v3 handshake (tor-spec.txt section 5.1.4, handshake type 3). Like
+/// ntor, it authenticates the relay by its ntor onion key, but it also carries an encrypted (and,
+/// for the relay's reply, authenticated) message each way - a list of `types::Ntor3Extension`s
+/// for circuit handshakes.
+pub struct Ntor3ClientHandshake {
+    client_keypair: keys::Curve25519Keypair,
+    /// The relay's Ed25519 identity (ID).
+    node_id: [u8; 32],
+    /// The relay's ntor onion key (B).
+    server_b: [u8; 32],
+    /// Our message, encrypted.
+    encrypted_msg: Vec<u8>,
+    /// The MAC over the handshake so far, which also goes into the relay's AUTH.
+    msg_mac: [u8; DIGEST_LEN],
+    /// The verification string (VER) both sides mix in.
+    verification: Vec<u8>,
+}
+
+impl Ntor3ClientHandshake {
+    /// Starts a handshake with the given relay, sending it `message`. Fails if the relay's ntor
+    /// onion key is degenerate.
+    pub fn new(
+        node: &dir::ExtendTarget,
+        client_keypair: keys::Curve25519Keypair,
+        message: &[u8],
+    ) -> Result<Ntor3ClientHandshake, Error> {
+        Ntor3ClientHandshake::with_verification(node, client_keypair, CIRCUIT_VERIFICATION, message)
+    }
+
+    /// Like `new`, but with the given verification string rather than the one for circuits.
+    fn with_verification(
+        node: &dir::ExtendTarget,
+        client_keypair: keys::Curve25519Keypair,
+        verification: &[u8],
+        message: &[u8],
+    ) -> Result<Ntor3ClientHandshake, Error> {
+        let node_id = node.get_ed25519_id_key();
+        let server_b = node.get_ntor_key();
+        let client_x = client_keypair.get_public_key_bytes();
+        let bx = match client_keypair.diffie_hellman(&server_b) {
+            Some(bx) => bx,
+            None => return Err(Error::new(ErrorKind::InvalidData, "degenerate ntor onion key")),
+        };
+        let mut secret_input_phase1: Vec<u8> = Vec::new();
+        secret_input_phase1.extend(bx.iter());
+        secret_input_phase1.extend(node_id.iter());
+        secret_input_phase1.extend(client_x.iter());
+        secret_input_phase1.extend(server_b.iter());
+        secret_input_phase1.extend(PROTOID);
+        secret_input_phase1.extend(encap(verification));
+        let phase1_keys = kdf(&secret_input_phase1, T_MSGKDF, ENC_KEY_LEN + MAC_KEY_LEN);
+        let (enc_k1, mac_k1) = phase1_keys.split_at(ENC_KEY_LEN);
+        let encrypted_msg = encrypt(enc_k1, message);
+        let mut mac_input: Vec<u8> = Vec::new();
+        mac_input.extend(node_id.iter());
+        mac_input.extend(server_b.iter());
+        mac_input.extend(client_x.iter());
+        mac_input.extend(encrypted_msg.iter());
+        let msg_mac = mac(mac_k1, &mac_input, T_MSGMAC);
+        Ok(Ntor3ClientHandshake {
+            client_keypair: client_keypair,
+            node_id: node_id,
+            server_b: server_b,
+            encrypted_msg: encrypted_msg,
+            msg_mac: msg_mac,
+            verification: verification.to_vec(),
+        })
+    }
+
+    /// The HDATA to send in the CREATE2/EXTEND2 cell: NODEID [32 bytes], KEYID [32 bytes],
+    /// CLIENT_PK [32 bytes], MSG [the rest, less the MAC], and MAC [32 bytes].
+    pub fn get_h_data(&self) -> Vec<u8> {
+        let mut h_data: Vec<u8> = Vec::new();
+        h_data.extend(self.node_id.iter());
+        h_data.extend(self.server_b.iter());
+        h_data.extend(self.client_keypair.get_public_key_bytes().iter());
+        h_data.extend(self.encrypted_msg.iter());
+        h_data.extend(self.msg_mac.iter());
+        h_data
+    }
+
+    /// Given the HDATA of the CREATED2/EXTENDED2 cell (Y [32 bytes], AUTH [32 bytes], and the
+    /// relay's encrypted message), checks AUTH and returns `key_material_len` bytes of key
+    /// material for the circuit along with the relay's decrypted message.
+    pub fn complete(
+        &self,
+        h_data: &[u8],
+        key_material_len: usize,
+    ) -> Result<(Vec<u8>, Vec<u8>), HandshakeError> {
+        if h_data.len() < 2 * DIGEST_LEN {
+            return Err(HandshakeError::Malformed);
+        }
+        let (server_y, rest) = h_data.split_at(32);
+        let (auth, encrypted_reply) = rest.split_at(DIGEST_LEN);
+        let mut server_y_bytes = [0; 32];
+        server_y_bytes.copy_from_slice(server_y);
+        let xy = match self.client_keypair.diffie_hellman(&server_y_bytes) {
+            Some(xy) => xy,
+            None => return Err(HandshakeError::DegenerateKey),
+        };
+        let xb = match self.client_keypair.diffie_hellman(&self.server_b) {
+            Some(xb) => xb,
+            None => return Err(HandshakeError::DegenerateKey),
+        };
+        let client_x = self.client_keypair.get_public_key_bytes();
+        let mut secret_input: Vec<u8> = Vec::new();
+        secret_input.extend(xy.iter());
+        secret_input.extend(xb.iter());
+        secret_input.extend(self.node_id.iter());
+        secret_input.extend(self.server_b.iter());
+        secret_input.extend(client_x.iter());
+        secret_input.extend(server_y.iter());
+        secret_input.extend(PROTOID);
+        secret_input.extend(encap(&self.verification));
+        let key_seed = h(&secret_input, T_KEY_SEED);
+        let verify = h(&secret_input, T_VERIFY);
+        let mut auth_input: Vec<u8> = Vec::new();
+        auth_input.extend(verify.iter());
+        auth_input.extend(self.node_id.iter());
+        auth_input.extend(self.server_b.iter());
+        auth_input.extend(server_y.iter());
+        auth_input.extend(client_x.iter());
+        auth_input.extend(self.msg_mac.iter());
+        auth_input.extend(encap(encrypted_reply));
+        auth_input.extend(PROTOID);
+        auth_input.extend(b"Server");
+        if !util::ct_eq(&h(&auth_input, T_AUTH), auth) {
+            return Err(HandshakeError::AuthMismatch);
+        }
+        let keystream = kdf(&key_seed, T_FINAL, ENC_KEY_LEN + key_material_len);
+        let (enc_key, key_material) = keystream.split_at(ENC_KEY_LEN);
+        Ok((key_material.to_vec(), encrypt(enc_key, encrypted_reply)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len() / 2)
+            .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn from_hex_32(hex: &str) -> [u8; 32] {
+        util::slice_to_32_byte_array(&from_hex(hex))
+    }
+
+    // The test vector is the one in tor's src/test/test_ntor_v3.c.
+
+    const SERVER_H_DATA: &'static str = concat!(
+        "4bf4814326fdab45ad5184f5518bd7fae25dc59374062698201a50a22954246d",
+        "2fc5f8773ca824542bc6cf6f57c7c29bbf4e5476461ab130c5b18ab0a9127665",
+        "1202c3e1e87c0d32054c"
+    );
+
+    fn test_vector_handshake() -> Ntor3ClientHandshake {
+        let relay_b = keys::Curve25519Keypair::from_secret_key_bytes(&from_hex_32(
+            "4051daa5921cfa2a1c27b08451324919538e79e788a81b38cbed097a5dff454a",
+        ));
+        let relay_id = from_hex_32(
+            "9fad2af287ef942632833d21f946c6260c33fae6172b60006e86e4a6911753a2",
+        );
+        let client_x = keys::Curve25519Keypair::from_secret_key_bytes(&from_hex_32(
+            "b825a3719147bcbe5fb1d0b0fcb9c09e51948048e2e3283d2ab7b45b5ef38b49",
+        ));
+        let node = dir::ExtendTarget::new(
+            Ipv4Addr::new(127, 0, 0, 1),
+            9001,
+            [0; 20],
+            relay_id,
+            relay_b.get_public_key_bytes(),
+        );
+        Ntor3ClientHandshake::with_verification(&node, client_x, b"xyzzy", b"hello world").unwrap()
+    }
+
+    #[test]
+    fn test_ntor3_known_answer() {
+        // The relay's B and the client's X, derived from their secret keys, are in the HDATA.
+        let handshake = test_vector_handshake();
+        assert_eq!(
+            util::to_hex(&handshake.get_h_data()),
+            concat!(
+                "9fad2af287ef942632833d21f946c6260c33fae6172b60006e86e4a6911753a2",
+                "f8307a2bc1870b00b828bb74dbb8fd88e632a6375ab3bcd1ae706aaa8b6cdd1d",
+                "252fe9ae91264c91d4ecb8501f79d0387e34ad8ca0f7c995184f7d11d5da4f46",
+                "3bebd9151fd3b47c180abc9e044d53565f04d82bbb3bebed3d06cea65db8be9c",
+                "72b68cd461942088502f67"
+            )
+        );
+        let server_h_data = from_hex(SERVER_H_DATA);
+        let (key_material, message) = handshake.complete(&server_h_data, 256).unwrap();
+        assert_eq!(message, b"Hola Mundo");
+        assert_eq!(
+            util::to_hex(&key_material),
+            concat!(
+                "9c19b631fd94ed86a817e01f6c80b0743a43f5faebd39cfaa8b00fa8bcc65c3b",
+                "feaa403d91acbd68a821bf6ee8504602b094a254392a07737d5662768c7a9fb1",
+                "b2814bb34780eaee6e867c773e28c212ead563e98a1cd5d5b4576f5ee61c59bd",
+                "e025ff2851bb19b721421694f263818e3531e43a9e4e3e2c661e2ad547d8984c",
+                "aa28ebecd3e4525452299be26b9185a20a90ce1eac20a91f2832d731b54502b0",
+                "9749b5a2a2949292f8cfcbeffb790c7790ed935a9d251e7e336148ea83b063a5",
+                "618fcff674a44581585fd22077ca0e52c59a24347a38d1a1ceebddbf238541f2",
+                "26b8f88d0fb9c07a1bcd2ea764bbbb5dacdaf5312a14c0b9e4f06309b0333b4a"
+            )
+        );
+    }
+
+    #[test]
+    fn test_ntor3_rejects_corrupted_auth() {
+        let handshake = test_vector_handshake();
+        let mut server_h_data = from_hex(SERVER_H_DATA);
+        assert!(handshake.complete(&server_h_data, 256).is_ok());
+        // The first byte of AUTH.
+        server_h_data[32] ^= 1;
+        let result = handshake.complete(&server_h_data, 256);
+        assert_eq!(result.err(), Some(HandshakeError::AuthMismatch));
+        // The relay's message is covered by AUTH too.
+        let mut server_h_data = from_hex(SERVER_H_DATA);
+        server_h_data[64] ^= 1;
+        let result = handshake.complete(&server_h_data, 256);
+        assert_eq!(result.err(), Some(HandshakeError::AuthMismatch));
+        assert_eq!(handshake.complete(&[0; 63], 256).err(), Some(HandshakeError::Malformed));
+    }
+
+    #[test]
+    fn test_ntor3_degenerate_server_key() {
+        let handshake = test_vector_handshake();
+        let mut server_h_data = from_hex(SERVER_H_DATA);
+        for byte in server_h_data[..32].iter_mut() {
+            *byte = 0;
+        }
+        let result = handshake.complete(&server_h_data, 256);
+        assert_eq!(result.err(), Some(HandshakeError::DegenerateKey));
+        // A small-order onion key is refused before anything is sent.
+        let localhost = Ipv4Addr::new(127, 0, 0, 1);
+        let node = dir::ExtendTarget::new(localhost, 9001, [0; 20], [0; 32], [0; 32]);
+        let client_keypair = keys::Curve25519Keypair::new();
+        assert!(Ntor3ClientHandshake::new(&node, client_keypair, b"").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use crypto::digest::Digest;
+use crypto::sha3::Sha3;
+use std::io::{Error, ErrorKind};
+
+use util;
+
+/// The length of a v3 onion address, not counting ".onion".
+const V3_ADDRESS_LEN: usize = 56;
+/// The length of a (deprecated) v2 onion address, not counting ".onion".
+const V2_ADDRESS_LEN: usize = 16;
+
+/// Decodes a v3 onion service address (56 base32 characters, optionally followed by ".onion")
+/// into the service's Ed25519 public key and the version byte, validating the checksum along the
+/// way (rend-spec-v3.txt section 6).
+pub fn parse_v3_address(addr: &str) -> Result<([u8; 32], u8), Error> {
+    let addr = if addr.ends_with(".onion") {
+        &addr[..addr.len() - ".onion".len()]
+    } else {
+        addr
+    };
+    if addr.len() == V2_ADDRESS_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "v2 onion addresses are not supported",
+        ));
+    }
+    if addr.len() != V3_ADDRESS_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "onion address has the wrong length",
+        ));
+    }
+    let decoded = match base32_decode(addr) {
+        Some(decoded) => decoded,
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "onion address is not valid base32",
+            ))
+        }
+    };
+    // 56 base32 characters decode to exactly 35 bytes:
+    // PUBKEY (32 bytes) | CHECKSUM (2 bytes) | VERSION (1 byte)
+    let public_key = util::slice_to_32_byte_array(&decoded[0..32]);
+    let checksum = &decoded[32..34];
+    let version = decoded[34];
+    if version != 3 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "unsupported onion address version",
+        ));
+    }
+    if checksum != &compute_checksum(&public_key, version)[..] {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "onion address checksum mismatch",
+        ));
+    }
+    Ok((public_key, version))
+}
+
+/// CHECKSUM = H(".onion checksum" | PUBKEY | VERSION)[:2], where H is SHA3-256.
+fn compute_checksum(public_key: &[u8; 32], version: u8) -> [u8; 2] {
+    let mut hasher = Sha3::sha3_256();
+    hasher.input(b".onion checksum");
+    hasher.input(public_key);
+    hasher.input(&[version]);
+    let mut hash = [0; 32];
+    hasher.result(&mut hash);
+    [hash[0], hash[1]]
+}
+
+/// Decodes unpadded RFC 4648 base32 (case-insensitively). Returns `None` if there's anything
+/// outside the base32 alphabet.
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in encoded.bytes() {
+        let value = match c {
+            b'a'..=b'z' => c - b'a',
+            b'A'..=b'Z' => c - b'A',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example address from rend-spec-v3.txt section 6.
+    const SPEC_ADDRESS: &'static str = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd";
+
+    fn assert_rejected(addr: &str, message: &str) {
+        let error = parse_v3_address(addr).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), message);
+    }
+
+    #[test]
+    fn test_parse_v3_address() {
+        let expected_key = [
+            0x79, 0xbc, 0xc6, 0x25, 0x18, 0x4b, 0x05, 0x19, 0x49, 0x75, 0xc2, 0x8b, 0x66, 0xb6,
+            0x6b, 0x04, 0x69, 0xf7, 0xf6, 0x55, 0x6f, 0xb1, 0xac, 0x31, 0x89, 0xa7, 0x9b, 0x40,
+            0xdd, 0xa3, 0x2f, 0x1f,
+        ];
+        assert_eq!(parse_v3_address(SPEC_ADDRESS).unwrap(), (expected_key, 3));
+        let with_suffix = format!("{}.onion", SPEC_ADDRESS.to_uppercase());
+        assert_eq!(parse_v3_address(&with_suffix).unwrap(), (expected_key, 3));
+    }
+
+    #[test]
+    fn test_parse_v3_address_bad_checksum() {
+        // SPEC_ADDRESS with the low bit of the first checksum byte flipped.
+        assert_rejected(
+            "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4psaryd",
+            "onion address checksum mismatch",
+        );
+    }
+
+    #[test]
+    fn test_parse_v3_address_wrong_version() {
+        // SPEC_ADDRESS's key with version 4 (and a checksum to match).
+        assert_rejected(
+            "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pwaqae",
+            "unsupported onion address version",
+        );
+    }
+
+    #[test]
+    fn test_parse_v3_address_v2() {
+        assert_rejected("expyuzz4wqqyqhjn.onion", "v2 onion addresses are not supported");
+    }
+
+    #[test]
+    fn test_parse_v3_address_invalid_base32() {
+        // '1' and '8' aren't in the base32 alphabet.
+        let addr = SPEC_ADDRESS.replace("p", "1").replace("c", "8");
+        assert_rejected(&addr, "onion address is not valid base32");
+        assert_rejected("pg6mmjiyjmcrsslvyk", "onion address has the wrong length");
+    }
+}
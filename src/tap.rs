@@ -0,0 +1,155 @@
+use crypto::{aessafe, blockmodes};
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use num::bigint::BigUint;
+use num::One;
+use rand::{OsRng, Rng};
+use std::io::{Error, ErrorKind};
+
+use RsaEncryptorImpl;
+
+/// The 1024-bit safe prime from RFC 2409 section 6.2 (tor-spec.txt section 0.3).
+const DH_P: &'static [u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+                              020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+                              4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED\
+                              EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE65381FFFFFFFFFFFFFFFF";
+const DH_G: u8 = 2;
+/// Length of a DH public value (and of the shared secret).
+const DH_LEN: usize = 128;
+/// Length of our private exponent (tor uses 320 bits).
+const DH_SEC_LEN: usize = 40;
+/// Length of the RSA onion key's modulus.
+const PK_ENC_LEN: usize = 128;
+/// Bytes of overhead from OAEP padding.
+const PK_PAD_LEN: usize = 42;
+/// Length of the symmetric key used for hybrid encryption.
+const KEY_LEN: usize = 16;
+/// Length of the KH value at the end of a CREATED cell.
+const HASH_LEN: usize = 20;
+
+fn dh_p() -> BigUint {
+    BigUint::parse_bytes(DH_P, 16).unwrap()
+}
+
+/// Encodes `value` as a big-endian number exactly DH_LEN bytes long.
+fn to_dh_bytes(value: &BigUint) -> Vec<u8> {
+    let bytes = value.to_bytes_be();
+    let mut padded = Vec::with_capacity(DH_LEN);
+    padded.resize(DH_LEN - bytes.len(), 0);
+    padded.extend(bytes);
+    padded
+}
+
+/// The client side of a TAP handshake (tor-spec.txt section 5.1.3), as sent in a CREATE cell and
+/// answered with a CREATED cell.
+pub struct TapClientHandshake {
+    /// Our DH private exponent.
+    x: BigUint,
+    /// The onion skin: g^x, hybrid-encrypted to the relay's onion key.
+    onion_skin: Vec<u8>,
+}
+
+impl TapClientHandshake {
+    /// Generates a DH keypair and encrypts the public part to the relay's RSA onion key (the DER
+    /// encoding of a PKCS #1 RSAPublicKey, as found in its microdescriptor).
+    pub fn new(
+        onion_key: &[u8],
+        rsa_encryptor: &RsaEncryptorImpl,
+    ) -> Result<TapClientHandshake, Error> {
+        let mut csprng: OsRng = match OsRng::new() {
+            Ok(csprng) => csprng,
+            Err(e) => return Err(Error::new(ErrorKind::Other, e)),
+        };
+        let mut x_bytes = [0; DH_SEC_LEN];
+        csprng.fill_bytes(&mut x_bytes);
+        let x = BigUint::from_bytes_be(&x_bytes);
+        let gx = to_dh_bytes(&BigUint::from_bytes_be(&[DH_G]).modpow(&x, &dh_p()));
+        // tor-spec.txt section 0.4: g^x is too long to encrypt with RSA directly, so a random key
+        // and the first part of g^x are RSA-encrypted and the rest is encrypted with AES-CTR
+        // under that key.
+        let mut key = [0; KEY_LEN];
+        csprng.fill_bytes(&mut key);
+        let split = PK_ENC_LEN - PK_PAD_LEN - KEY_LEN;
+        let mut rsa_plaintext = Vec::with_capacity(KEY_LEN + split);
+        rsa_plaintext.extend(key.iter());
+        rsa_plaintext.extend(gx[..split].iter());
+        let mut onion_skin = rsa_encryptor.encrypt_oaep(onion_key, &rsa_plaintext)?;
+        if onion_skin.len() != PK_ENC_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, "TAP onion key must be 1024 bits"));
+        }
+        let iv = [0; 16];
+        let mut aes = blockmodes::CtrModeX8::new(aessafe::AesSafe128EncryptorX8::new(&key), &iv);
+        let mut encrypted_rest = Vec::with_capacity(DH_LEN - split);
+        encrypted_rest.resize(DH_LEN - split, 0);
+        aes.process(&gx[split..], &mut encrypted_rest);
+        onion_skin.extend(encrypted_rest);
+        Ok(TapClientHandshake {
+            x: x,
+            onion_skin: onion_skin,
+        })
+    }
+
+    /// The onion skin to send in the CREATE cell.
+    pub fn get_onion_skin(&self) -> &[u8] {
+        &self.onion_skin
+    }
+
+    /// Given the body of the CREATED cell (g^y followed by KH), returns the shared secret g^xy
+    /// (to be run through KDF-TOR, which also checks KH) and KH.
+    pub fn complete(&self, created: &[u8]) -> Result<(Vec<u8>, [u8; HASH_LEN]), Error> {
+        if created.len() < DH_LEN + HASH_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "CREATED cell too short"));
+        }
+        let p = dh_p();
+        let gy = BigUint::from_bytes_be(&created[..DH_LEN]);
+        // tor-spec.txt section 5.2: reject g^y values that would make the shared secret
+        // degenerate.
+        if gy <= BigUint::one() || gy >= &p - BigUint::one() {
+            return Err(Error::new(ErrorKind::InvalidData, "bad DH public value in CREATED cell"));
+        }
+        let mut kh = [0; HASH_LEN];
+        kh.copy_from_slice(&created[DH_LEN..DH_LEN + HASH_LEN]);
+        Ok((to_dh_bytes(&gy.modpow(&self.x, &p)), kh))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake_with_x(x: u32) -> TapClientHandshake {
+        TapClientHandshake {
+            x: BigUint::from(x),
+            onion_skin: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_complete() {
+        // With x = 2 and g^y = g^3 = 8, the shared secret is g^6 = 64.
+        let mut created = vec![0; DH_LEN + HASH_LEN];
+        created[DH_LEN - 1] = 8;
+        for byte in created[DH_LEN..].iter_mut() {
+            *byte = 0xaa;
+        }
+        let (shared_secret, kh) = handshake_with_x(2).complete(&created).unwrap();
+        let mut expected = vec![0; DH_LEN];
+        expected[DH_LEN - 1] = 64;
+        assert_eq!(shared_secret, expected);
+        assert_eq!(kh, [0xaa; HASH_LEN]);
+        assert!(handshake_with_x(2).complete(&created[..DH_LEN + HASH_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn test_complete_degenerate() {
+        let handshake = handshake_with_x(2);
+        let mut created = vec![0; DH_LEN + HASH_LEN];
+        // 0 and 1.
+        assert!(handshake.complete(&created).is_err());
+        created[DH_LEN - 1] = 1;
+        assert!(handshake.complete(&created).is_err());
+        // p - 1.
+        let p_minus_one = to_dh_bytes(&(dh_p() - BigUint::one()));
+        created[..DH_LEN].copy_from_slice(&p_minus_one);
+        assert!(handshake.complete(&created).is_err());
+    }
+}
@@ -0,0 +1,115 @@
+//! Runs a `Circuit`'s guard handshake (link handshake and CREATE_FAST) as a tokio future, so one
+//! event loop can build many circuits at once. `Circuit` already does non-blocking I/O - this just
+//! keeps polling it while it's making progress and makes sure the task gets woken up again when
+//! it can't.
+
+use futures::{self, Future, Poll};
+use std::cmp;
+use std::io::{Error, ErrorKind};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::Delay;
+
+use {Async, Circuit, CircuitState, RsaVerifierImpl, TlsImpl, TorError};
+
+/// A future that drives a new circuit through its guard handshake and resolves to the circuit,
+/// ready to extend or open streams on. The connection has to be a tokio one (so that `WouldBlock`
+/// from it means the task will be woken when it's ready again).
+pub struct Handshake<T, V>
+where
+    T: TlsImpl + AsyncRead + AsyncWrite,
+    V: RsaVerifierImpl,
+{
+    circuit: Option<Circuit<T, V>>,
+    /// Wakes the task at the handshake timeout or when a CREATE_FAST retry is due, since no I/O
+    /// will.
+    delay: Option<Delay>,
+}
+
+impl<T, V> Handshake<T, V>
+where
+    T: TlsImpl + AsyncRead + AsyncWrite,
+    V: RsaVerifierImpl,
+{
+    pub fn new(circuit: Circuit<T, V>) -> Handshake<T, V> {
+        Handshake {
+            circuit: Some(circuit),
+            delay: None,
+        }
+    }
+}
+
+impl<T, V> Future for Handshake<T, V>
+where
+    T: TlsImpl + AsyncRead + AsyncWrite,
+    V: RsaVerifierImpl,
+{
+    type Item = Circuit<T, V>;
+    type Error = TorError;
+
+    fn poll(&mut self) -> Poll<Circuit<T, V>, TorError> {
+        loop {
+            let deadline = {
+                let circuit = match self.circuit.as_mut() {
+                    Some(circuit) => circuit,
+                    None => panic!("Handshake polled after it completed"),
+                };
+                // `Circuit::poll` takes one step at a time, so it returns NotReady after moving to
+                // a new state (or consuming a padding cell) even if it could carry on. Only when
+                // neither changes did it stop because the connection would block.
+                let progress = (circuit.state.clone(), buffered_len(circuit));
+                if let Async::Ready(()) = circuit.poll()? {
+                    break;
+                }
+                if (circuit.state.clone(), buffered_len(circuit)) != progress {
+                    continue;
+                }
+                next_deadline(circuit)
+            };
+            let mut delay = match deadline {
+                Some(deadline) => Delay::new(deadline),
+                None => return Ok(futures::Async::NotReady),
+            };
+            match delay.poll() {
+                // The deadline has already passed, so the circuit has something to do.
+                Ok(futures::Async::Ready(())) => {}
+                Ok(futures::Async::NotReady) => {
+                    self.delay = Some(delay);
+                    return Ok(futures::Async::NotReady);
+                }
+                Err(e) => return Err(TorError::Io(Error::new(ErrorKind::Other, e.to_string()))),
+            }
+        }
+        self.delay = None;
+        Ok(futures::Async::Ready(self.circuit.take().unwrap()))
+    }
+}
+
+/// How much of what's been read from the connection hasn't been consumed yet.
+fn buffered_len<T, V>(circuit: &Circuit<T, V>) -> u64
+where
+    T: TlsImpl + AsyncRead + AsyncWrite,
+    V: RsaVerifierImpl,
+{
+    circuit.buffer.get_ref().len() as u64 - circuit.buffer.position()
+}
+
+/// When the circuit next has something to do that doesn't depend on the connection: giving up at
+/// the handshake timeout, or retrying CREATE_FAST after a backoff.
+fn next_deadline<T, V>(circuit: &Circuit<T, V>) -> Option<Instant>
+where
+    T: TlsImpl + AsyncRead + AsyncWrite,
+    V: RsaVerifierImpl,
+{
+    let timeout = circuit
+        .handshake_timeout
+        .map(|timeout| circuit.handshake_started + timeout);
+    let retry = match circuit.state {
+        CircuitState::CreateBackoff => circuit.extend_retry_at,
+        _ => None,
+    };
+    match (timeout, retry) {
+        (Some(timeout), Some(retry)) => Some(cmp::min(timeout, retry)),
+        (timeout, retry) => timeout.or(retry),
+    }
+}
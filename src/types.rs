@@ -1,14 +1,18 @@
-use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt, WriteBytesExt};
 use sha1::Sha1;
 use std::fmt;
 use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::Ipv6Addr;
 
 use certs;
 use dir;
 use keys;
+use util;
 
 const PAYLOAD_LEN: usize = 509;
 pub const RELAY_PAYLOAD_LEN: usize = PAYLOAD_LEN - 11;
+/// The length of a fixed-length cell on the wire (4-byte circuit id, command, payload).
+pub const CELL_LEN: usize = PAYLOAD_LEN + 5;
 
 #[derive(Debug)]
 pub struct Cell {
@@ -73,6 +77,9 @@ impl Command {
             | Command::AuthChallenge
             | Command::Authenticate
             | Command::Authorize => true,
+            // tor-spec.txt section 3: all commands 128 and up are variable-length, so we can still
+            // frame cells with commands we don't know about.
+            Command::Unknown(command) => command >= 128,
             _ => false,
         }
     }
@@ -101,9 +108,46 @@ impl Command {
     }
 }
 
+impl fmt::Display for Command {
+    fn fmt(&self, dest: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Command::Unknown(command) => write!(dest, "unknown command 0x{:02x}", command),
+            _ => write!(dest, "{:?}", self),
+        }
+    }
+}
+
+impl fmt::Display for Cell {
+    fn fmt(&self, dest: &mut fmt::Formatter) -> fmt::Result {
+        write!(dest, "Cell {{ circ_id: {} {} ", self.circ_id, self.command)?;
+        if let Command::Unknown(_) = self.command {
+            // We don't know how to interpret the payload, so the raw bytes are the best we can do.
+            write!(dest, "payload: ")?;
+            for b in &self.payload {
+                write!(dest, "{:02x}", b)?;
+            }
+            write!(dest, " ")?;
+        } else {
+            write!(dest, "length: {} ", self.payload.len())?;
+        }
+        write!(dest, "}}")
+    }
+}
+
 impl Cell {
+    /// Reads a cell framed for link protocol version 4 or later (i.e. with a 4-byte circuit id).
     pub fn read_new<R: Read>(reader: &mut R) -> Result<Cell> {
-        let circ_id = reader.read_u32::<NetworkEndian>()?;
+        Cell::read_new_for_version(reader, 4)
+    }
+
+    /// Reads a cell framed for the given link protocol version. Versions before 4 use 2-byte
+    /// circuit ids.
+    pub fn read_new_for_version<R: Read>(reader: &mut R, link_version: u16) -> Result<Cell> {
+        let circ_id = if link_version < 4 {
+            reader.read_u16::<NetworkEndian>()? as u32
+        } else {
+            reader.read_u32::<NetworkEndian>()?
+        };
         let mut one_byte_buf = [0; 1];
         reader.read_exact(&mut one_byte_buf)?;
         let command = Command::from_u8(one_byte_buf[0]);
@@ -130,16 +174,39 @@ impl Cell {
         }
     }
 
+    /// Writes the cell: CircID [4 bytes], Command [1 byte], and then, for variable-length commands,
+    /// Length [2 bytes] and Payload [Length bytes], or for fixed-length commands, Payload padded
+    /// with zeroes to PAYLOAD_LEN bytes. (VERSIONS cells have their own framing - see
+    /// `VersionsCell`.)
     pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
-        writer.write_u32::<NetworkEndian>(self.circ_id)?;
+        self.write_to_for_version(writer, 4)
+    }
+
+    /// Like `write_to`, but framed for the given link protocol version (versions before 4 use
+    /// 2-byte circuit ids, so the circuit id has to fit in 2 bytes).
+    pub fn write_to_for_version<W: Write>(&self, writer: &mut W, link_version: u16) -> Result<()> {
+        if link_version < 4 && self.circ_id > u16::max_value() as u32 {
+            return Err(Error::new(ErrorKind::InvalidInput, "circ_id too large for link version"));
+        }
+        let max_length = if self.command.is_variable_length() {
+            u16::max_value() as usize
+        } else {
+            PAYLOAD_LEN
+        };
+        if self.payload.len() > max_length {
+            return Err(Error::new(ErrorKind::InvalidInput, "cell payload too long"));
+        }
+        if link_version < 4 {
+            writer.write_u16::<NetworkEndian>(self.circ_id as u16)?;
+        } else {
+            writer.write_u32::<NetworkEndian>(self.circ_id)?;
+        }
         writer.write_u8(self.command.as_u8())?;
         if self.command.is_variable_length() {
-            assert!(self.payload.len() < 65536);
             writer.write_u16::<NetworkEndian>(self.payload.len() as u16)?;
         }
         writer.write_all(&self.payload)?;
         if !self.command.is_variable_length() {
-            assert!(self.payload.len() <= PAYLOAD_LEN);
             let padding_length = PAYLOAD_LEN - self.payload.len();
             let mut zeroes: Vec<u8> = Vec::with_capacity(padding_length);
             zeroes.resize(padding_length, 0);
@@ -149,7 +216,73 @@ impl Cell {
     }
 }
 
-#[derive(Debug)]
+/// Reads cells one at a time from a byte stream where cell boundaries needn't line up with
+/// reads - a single TLS record often carries several cells (e.g. CERTS, AUTH_CHALLENGE, and
+/// NETINFO back to back), and a cell can just as well be split over several records. Bytes
+/// past the end of the cell being returned are kept for the next call to `next_cell`.
+pub struct CellReader<R: Read> {
+    reader: R,
+    buffer: Vec<u8>,
+    link_version: u16,
+}
+
+impl<R: Read> CellReader<R> {
+    /// Creates a reader for cells framed for link protocol version 4 or later.
+    pub fn new(reader: R) -> CellReader<R> {
+        CellReader {
+            reader: reader,
+            buffer: Vec::new(),
+            link_version: 4,
+        }
+    }
+
+    /// Sets the link protocol version the cells are framed for (see
+    /// `Cell::read_new_for_version`).
+    pub fn set_link_version(&mut self, link_version: u16) {
+        self.link_version = link_version;
+    }
+
+    /// The bytes that have been read from the underlying reader but not yet returned as part of
+    /// a cell.
+    pub fn get_buffered_bytes(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Returns the next cell, reading from the underlying reader only when the bytes buffered so
+    /// far don't make up a complete cell. Returns `None` if the reader is at its end and no bytes
+    /// are left over, and an `UnexpectedEof` error if it ends partway through a cell.
+    pub fn next_cell(&mut self) -> Result<Option<Cell>> {
+        loop {
+            let (cell, consumed) = {
+                let mut remaining = &self.buffer[..];
+                match Cell::read_new_for_version(&mut remaining, self.link_version) {
+                    Ok(cell) => (Some(cell), self.buffer.len() - remaining.len()),
+                    Err(_) => (None, 0),
+                }
+            };
+            if let Some(cell) = cell {
+                self.buffer.drain(..consumed);
+                return Ok(Some(cell));
+            }
+            let mut tmp = [0; CELL_LEN];
+            let bytes_read = match self.reader.read(&mut tmp) {
+                Ok(bytes_read) => bytes_read,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if bytes_read == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                let msg = "stream ended partway through a cell";
+                return Err(Error::new(ErrorKind::UnexpectedEof, msg));
+            }
+            self.buffer.extend_from_slice(&tmp[..bytes_read]);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum CertType {
     RsaLink,
     RsaIdentity,
@@ -189,7 +322,7 @@ impl CertType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct RawCert {
     cert_type: CertType,
     bytes: Vec<u8>,
@@ -199,13 +332,17 @@ impl RawCert {
     pub fn read_new<R: Read>(reader: &mut R) -> Result<RawCert> {
         let cert_type_byte = reader.read_u8()?;
         let length = reader.read_u16::<NetworkEndian>()? as usize;
-        let mut cert = RawCert {
+        // Read through `take` rather than allocating `length` bytes up front, so a bogus length
+        // only costs us as much as the data that's actually there.
+        let mut bytes = Vec::new();
+        reader.take(length as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != length {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "truncated cert"));
+        }
+        Ok(RawCert {
             cert_type: CertType::from_u8(cert_type_byte),
-            bytes: Vec::with_capacity(length),
-        };
-        cert.bytes.resize(length, 0);
-        reader.read_exact(cert.bytes.as_mut_slice())?;
-        Ok(cert)
+            bytes: bytes,
+        })
     }
 
     pub fn new(cert_type: CertType, bytes: Vec<u8>) -> RawCert {
@@ -215,6 +352,10 @@ impl RawCert {
         }
     }
 
+    pub fn get_cert_type(&self) -> &CertType {
+        &self.cert_type
+    }
+
     pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
         writer.write_u8(self.cert_type.as_u8())?;
         assert!(self.bytes.len() < 65536);
@@ -223,6 +364,10 @@ impl RawCert {
     }
 }
 
+/// The most certs we'll accept in a CERTS cell. A relay sends at most 5 (RSA link, RSA identity,
+/// Ed25519 signing, Ed25519 link, and RSA->Ed25519 cross-cert).
+const MAX_CERTS: usize = 16;
+
 #[derive(Debug)]
 pub struct CertsCell {
     certs: Vec<RawCert>,
@@ -231,6 +376,9 @@ pub struct CertsCell {
 impl CertsCell {
     pub fn read_new<R: Read>(reader: &mut R) -> Result<CertsCell> {
         let num_certs = reader.read_u8()?;
+        if num_certs as usize > MAX_CERTS {
+            return Err(Error::new(ErrorKind::InvalidData, "too many certs in CERTS cell"));
+        }
         let mut certs: Vec<RawCert> = Vec::with_capacity(num_certs as usize);
         for _ in 0..num_certs {
             let cert = RawCert::read_new(reader)?;
@@ -317,12 +465,35 @@ impl AuthenticateCell {
         }
     }
 
+    /// Writes the AUTHENTICATE cell's payload: AuthType [2 bytes], AuthLen [2 bytes], and
+    /// Authentication [AuthLen bytes]. (The enclosing `Cell` adds the variable-length framing.)
     pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if self.authentication.len() > u16::max_value() as usize {
+            return Err(Error::new(ErrorKind::InvalidInput, "authentication too long"));
+        }
         writer.write_u16::<NetworkEndian>(self.auth_type.as_u16())?;
-        assert!(self.authentication.len() < 65536);
         writer.write_u16::<NetworkEndian>(self.authentication.len() as u16)?;
         writer.write_all(&self.authentication)
     }
+
+    pub fn read_new<R: Read>(reader: &mut R) -> Result<AuthenticateCell> {
+        let auth_type = AuthType::from_u16(reader.read_u16::<NetworkEndian>()?);
+        let length = reader.read_u16::<NetworkEndian>()? as usize;
+        let mut authentication = vec![0; length];
+        reader.read_exact(&mut authentication)?;
+        Ok(AuthenticateCell {
+            auth_type: auth_type,
+            authentication: authentication,
+        })
+    }
+
+    pub fn get_auth_type(&self) -> &AuthType {
+        &self.auth_type
+    }
+
+    pub fn get_authentication(&self) -> &[u8] {
+        &self.authentication
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -404,11 +575,17 @@ impl OrAddress {
                 OrAddress::Hostname(result.to_owned())
             }
             4 => {
+                if address_length != 4 {
+                    return Err(Error::new(ErrorKind::InvalidData, "bad IPv4 address length"));
+                }
                 let mut dest = [0; 4];
                 reader.read_exact(&mut dest)?;
                 OrAddress::IPv4Address(dest)
             }
             6 => {
+                if address_length != 16 {
+                    return Err(Error::new(ErrorKind::InvalidData, "bad IPv6 address length"));
+                }
                 let mut dest = [0; 16];
                 reader.read_exact(&mut dest)?;
                 OrAddress::IPv6Address(dest)
@@ -427,7 +604,13 @@ impl OrAddress {
                 reader.read_exact(&mut buf)?;
                 OrAddress::NontransientError
             }
-            _ => OrAddress::Unknown(address_type), // TODO: still read the length and value?
+            _ => {
+                // Skip the value so the next address (if any) can still be read.
+                let mut buf: Vec<u8> = Vec::with_capacity(address_length as usize);
+                buf.resize(address_length as usize, 0);
+                reader.read_exact(&mut buf)?;
+                OrAddress::Unknown(address_type)
+            }
         })
     }
 
@@ -436,19 +619,23 @@ impl OrAddress {
             &OrAddress::Hostname(ref string) => {
                 writer.write_u8(0)?;
                 let bytes = string.as_bytes();
-                assert!(bytes.len() < 256);
+                if bytes.len() > 255 {
+                    return Err(Error::new(ErrorKind::InvalidInput, "hostname too long"));
+                }
                 writer.write_u8(bytes.len() as u8)?;
                 writer.write_all(bytes)
             }
             &OrAddress::IPv4Address(bytes) => {
+                writer.write_u8(4)?;
                 writer.write_u8(4)?;
                 writer.write_all(&bytes)
             }
             &OrAddress::IPv6Address(bytes) => {
                 writer.write_u8(6)?;
+                writer.write_u8(16)?;
                 writer.write_all(&bytes)
             }
-            _ => panic!("unimplemented"),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "can't serialize this kind of address")),
         }
     }
 }
@@ -492,19 +679,30 @@ impl NetinfoCell {
     pub fn new(
         timestamp: EpochSeconds,
         other_or_address: OrAddress,
-        this_or_address: OrAddress,
+        this_or_addresses: Vec<OrAddress>,
     ) -> NetinfoCell {
         NetinfoCell {
             timestamp: timestamp,
             other_or_address: other_or_address,
-            this_or_addresses: vec![this_or_address],
+            this_or_addresses: this_or_addresses,
         }
     }
 
-    /// Returns some `OrAddress` in `this_or_addresses` or panics.
-    /// TODO: make this robust against adversarial input.
-    pub fn get_other_or_address(&self) -> OrAddress {
-        self.this_or_addresses[0].clone()
+    /// Returns the first IPv4 or IPv6 address the sender listed as its own (to use as the "other
+    /// address" in our reply), if it listed any.
+    pub fn get_other_or_address(&self) -> Option<OrAddress> {
+        self.this_or_addresses
+            .iter()
+            .find(|address| match address {
+                &&OrAddress::IPv4Address(_) | &&OrAddress::IPv6Address(_) => true,
+                _ => false,
+            })
+            .cloned()
+    }
+
+    /// The address the sender saw us connecting from.
+    pub fn get_our_address(&self) -> &OrAddress {
+        &self.other_or_address
     }
 }
 
@@ -534,10 +732,38 @@ pub enum RelayCommand {
     BeginDir,
     Extend2,
     Extended2,
+    EstablishRendezvous,
+    RendezvousEstablished,
     Unknown(u8),
 }
 
+const ALL_RELAY_COMMANDS: &'static [RelayCommand] = &[
+    RelayCommand::Begin,
+    RelayCommand::Data,
+    RelayCommand::End,
+    RelayCommand::Connected,
+    RelayCommand::SendMe,
+    RelayCommand::Extend,
+    RelayCommand::Extended,
+    RelayCommand::Truncate,
+    RelayCommand::Truncated,
+    RelayCommand::Drop,
+    RelayCommand::Resolve,
+    RelayCommand::Resolved,
+    RelayCommand::BeginDir,
+    RelayCommand::Extend2,
+    RelayCommand::Extended2,
+    RelayCommand::EstablishRendezvous,
+    RelayCommand::RendezvousEstablished,
+];
+
 impl RelayCommand {
+    /// Returns every known relay command (i.e. everything but `Unknown`), in order of their
+    /// command values. Each of these round-trips through `as_u8` and `from_u8`.
+    pub fn all() -> &'static [RelayCommand] {
+        ALL_RELAY_COMMANDS
+    }
+
     pub fn from_u8(relay_command: u8) -> RelayCommand {
         match relay_command {
             1 => RelayCommand::Begin,
@@ -555,6 +781,8 @@ impl RelayCommand {
             13 => RelayCommand::BeginDir,
             14 => RelayCommand::Extend2,
             15 => RelayCommand::Extended2,
+            33 => RelayCommand::EstablishRendezvous,
+            39 => RelayCommand::RendezvousEstablished,
             _ => RelayCommand::Unknown(relay_command),
         }
     }
@@ -576,6 +804,8 @@ impl RelayCommand {
             &RelayCommand::BeginDir => 13,
             &RelayCommand::Extend2 => 14,
             &RelayCommand::Extended2 => 15,
+            &RelayCommand::EstablishRendezvous => 33,
+            &RelayCommand::RendezvousEstablished => 39,
             &RelayCommand::Unknown(value) => value,
         }
     }
@@ -593,6 +823,9 @@ impl RelayCell {
         // This isn't making much sense to me. For DATA cells, the length field doesn't seem to
         // correspond to... anything?
         let length = reader.read_u16::<NetworkEndian>()?;
+        if length as usize > RELAY_PAYLOAD_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "RELAY cell length too large"));
+        }
         // So, we have an indication of the length of the data in the relay cell, but there's
         // actually always supposed to be RELAY_PAYLOAD_LEN bytes (the rest padded 0).
         let mut data: Vec<u8> = Vec::with_capacity(RELAY_PAYLOAD_LEN);
@@ -623,6 +856,26 @@ impl RelayCell {
         }
     }
 
+    /// Like `new`, but returns an error rather than panicking if `data` doesn't fit in a relay
+    /// cell. Use stream id 0 for circuit-level commands (EXTEND2, circuit SENDMEs, etc.) and the
+    /// stream's id for everything else.
+    pub fn new_with_stream(
+        relay_command: RelayCommand,
+        stream_id: u16,
+        data: Vec<u8>,
+    ) -> Result<RelayCell> {
+        if data.len() > RELAY_PAYLOAD_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, "too much data for a RELAY cell"));
+        }
+        Ok(RelayCell::new(relay_command, stream_id, data))
+    }
+
+    /// Sets the digest field (see tor-spec.txt section 6.1). With the digest field zeroed, the
+    /// whole cell (header, data, and padding) is added to the given running digest, and the first
+    /// 4 bytes of the result become the digest field. The running digest must be the *forward*
+    /// digest of the hop the cell is meant for, and cells must be digested in the order they're
+    /// sent, since the running digest covers every cell sent to that hop so far.
+    /// This may only be called once, on a cell created with `new` or `new_with_stream`.
     pub fn set_digest(&mut self, digest: &mut Sha1) {
         // This should only be called if the digest hasn't been set or read from the wire.
         assert!(self.digest == 0);
@@ -635,6 +888,38 @@ impl RelayCell {
         self.digest = (&mut &result[..]).read_u32::<NetworkEndian>().unwrap();
     }
 
+    /// Returns true if the "recognized" field is 0 (i.e. this cell may be meant for us).
+    pub fn is_recognized(&self) -> bool {
+        self.recognized == 0
+    }
+
+    /// Checks the digest of a `RelayCell` read from the wire against the given running digest. The
+    /// digest is calculated the same way as in `set_digest` (over the whole cell with the digest
+    /// field set to 0). If it matches, the running digest is updated to include this cell.
+    /// Otherwise, it is left unchanged.
+    pub fn check_digest(&self, digest: &mut Sha1) -> bool {
+        let zeroed = RelayCell {
+            relay_command: self.relay_command.clone(),
+            recognized: self.recognized,
+            stream_id: self.stream_id,
+            digest: 0,
+            length: self.length,
+            data: self.data.clone(),
+        };
+        let mut buf = Vec::new();
+        zeroed.write_to(&mut buf).unwrap();
+        let mut updated_digest = digest.clone();
+        updated_digest.update(&buf);
+        let result = updated_digest.digest().bytes();
+        let mut digest_bytes = [0; 4];
+        NetworkEndian::write_u32(&mut digest_bytes, self.digest);
+        if !util::ct_eq(&result[..4], &digest_bytes) {
+            return false;
+        }
+        *digest = updated_digest;
+        true
+    }
+
     pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
         writer.write_u8(self.relay_command.as_u8())?;
         writer.write_u16::<NetworkEndian>(self.recognized)?;
@@ -656,6 +941,18 @@ impl RelayCell {
     pub fn get_data(&self) -> &[u8] {
         &self.data[..self.length as usize]
     }
+
+    /// If this is a RELAY_END cell, returns the reason it carries. An END cell without a reason is
+    /// treated as MISC.
+    pub fn get_end_reason(&self) -> Option<EndReason> {
+        if self.relay_command != RelayCommand::End {
+            return None;
+        }
+        Some(match self.get_data().first() {
+            Some(reason) => EndReason::from_u8(*reason),
+            None => EndReason::Misc,
+        })
+    }
 }
 
 impl fmt::Display for RelayCell {
@@ -684,6 +981,14 @@ pub struct NtorClientHandshake {
 
 impl NtorClientHandshake {
     pub fn new(peer: &dir::TorPeer, client_key: &keys::Curve25519Keypair) -> NtorClientHandshake {
+        NtorClientHandshake::with_extend_target(&peer.to_extend_target(), client_key)
+    }
+
+    /// Like `new`, but for a relay that may not be in the consensus.
+    pub fn with_extend_target(
+        peer: &dir::ExtendTarget,
+        client_key: &keys::Curve25519Keypair,
+    ) -> NtorClientHandshake {
         NtorClientHandshake {
             node_id: peer.get_node_id(),
             key_id: peer.get_ntor_key(),
@@ -696,6 +1001,45 @@ impl NtorClientHandshake {
         writer.write_all(&self.key_id)?;
         writer.write_all(&self.client_pk)
     }
+
+    /// Parses the HDATA of a CREATE2/EXTEND2 cell, which must be exactly NODEID [20 bytes],
+    /// KEYID (the server's B) [32 bytes], and CLIENT_PK (the client's X) [32 bytes].
+    pub fn from_h_data(h_data: &[u8]) -> Result<NtorClientHandshake> {
+        if h_data.len() != 84 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "ntor client handshake must be 84 bytes",
+            ));
+        }
+        NtorClientHandshake::read_new(&mut &h_data[..])
+    }
+
+    pub fn read_new<R: Read>(reader: &mut R) -> Result<NtorClientHandshake> {
+        let mut handshake = NtorClientHandshake {
+            node_id: [0; 20],
+            key_id: [0; 32],
+            client_pk: [0; 32],
+        };
+        reader.read_exact(&mut handshake.node_id)?;
+        reader.read_exact(&mut handshake.key_id)?;
+        reader.read_exact(&mut handshake.client_pk)?;
+        Ok(handshake)
+    }
+
+    /// The SHA-1 hash of the server's RSA identity key.
+    pub fn get_node_id(&self) -> [u8; 20] {
+        self.node_id
+    }
+
+    /// The server's ntor onion key (B).
+    pub fn get_key_id(&self) -> [u8; 32] {
+        self.key_id
+    }
+
+    /// The client's ephemeral public key (X).
+    pub fn get_client_pk(&self) -> [u8; 32] {
+        self.client_pk
+    }
 }
 
 #[derive(Debug)]
@@ -707,6 +1051,11 @@ pub struct Created2Cell {
 impl Created2Cell {
     pub fn read_new<R: Read>(reader: &mut R) -> Result<Created2Cell> {
         let h_len = reader.read_u16::<NetworkEndian>()?;
+        // HDATA has to fit in the rest of the cell (read_exact will catch a truncated cell, but
+        // there's no point allocating for a length that can't be right).
+        if h_len as usize > PAYLOAD_LEN - 2 {
+            return Err(Error::new(ErrorKind::InvalidData, "CREATED2 HLEN too large"));
+        }
         let mut h_data: Vec<u8> = Vec::with_capacity(h_len as usize);
         h_data.resize(h_len as usize, 0);
         reader.read_exact(&mut h_data)?;
@@ -724,6 +1073,18 @@ pub struct NtorServerHandshake {
 }
 
 impl NtorServerHandshake {
+    /// Parses the HDATA of a CREATED2/EXTENDED2 cell, which must be exactly SERVER_PK [32 bytes]
+    /// followed by AUTH [32 bytes].
+    pub fn from_h_data(h_data: &[u8]) -> Result<NtorServerHandshake> {
+        if h_data.len() != 64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "ntor server handshake must be 64 bytes",
+            ));
+        }
+        NtorServerHandshake::read_new(&mut &h_data[..])
+    }
+
     pub fn read_new<R: Read>(reader: &mut R) -> Result<NtorServerHandshake> {
         let mut handshake = NtorServerHandshake {
             server_pk: [0; 32],
@@ -735,6 +1096,74 @@ impl NtorServerHandshake {
     }
 }
 
+/// An extension carried (encrypted) in an ntor v3 handshake message. A list of these is encoded
+/// as N_EXTENSIONS [1 byte] followed by, for each extension, EXT_FIELD_TYPE [1 byte],
+/// EXT_FIELD_LEN [1 byte], and EXT_FIELD [EXT_FIELD_LEN bytes].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Ntor3Extension {
+    /// Sent by the client to ask to use congestion control on the circuit (type 1, empty body).
+    CongestionControlRequest,
+    /// Sent by the relay to accept congestion control (type 2), with the number of cells between
+    /// SENDMEs it wants.
+    CongestionControlResponse { sendme_inc: u8 },
+    Unknown { ext_type: u8, body: Vec<u8> },
+}
+
+impl Ntor3Extension {
+    pub fn read_list<R: Read>(reader: &mut R) -> Result<Vec<Ntor3Extension>> {
+        let n_extensions = reader.read_u8()?;
+        let mut extensions = Vec::with_capacity(n_extensions as usize);
+        for _ in 0..n_extensions {
+            let ext_type = reader.read_u8()?;
+            let ext_len = reader.read_u8()?;
+            let mut body = vec![0; ext_len as usize];
+            reader.read_exact(&mut body)?;
+            extensions.push(match (ext_type, body.len()) {
+                (1, 0) => Ntor3Extension::CongestionControlRequest,
+                (2, 1) => Ntor3Extension::CongestionControlResponse {
+                    sendme_inc: body[0],
+                },
+                (1, _) | (2, _) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "malformed congestion control extension",
+                    ))
+                }
+                _ => Ntor3Extension::Unknown {
+                    ext_type: ext_type,
+                    body: body,
+                },
+            });
+        }
+        Ok(extensions)
+    }
+
+    pub fn write_list<W: Write>(extensions: &[Ntor3Extension], writer: &mut W) -> Result<()> {
+        if extensions.len() > 255 {
+            return Err(Error::new(ErrorKind::InvalidInput, "too many extensions"));
+        }
+        writer.write_u8(extensions.len() as u8)?;
+        for extension in extensions {
+            extension.write_to(writer)?;
+        }
+        Ok(())
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let (ext_type, body) = match self {
+            &Ntor3Extension::CongestionControlRequest => (1, Vec::new()),
+            &Ntor3Extension::CongestionControlResponse { sendme_inc } => (2, vec![sendme_inc]),
+            &Ntor3Extension::Unknown { ext_type, ref body } => (ext_type, body.clone()),
+        };
+        if body.len() > 255 {
+            return Err(Error::new(ErrorKind::InvalidInput, "extension too long"));
+        }
+        writer.write_u8(ext_type)?;
+        writer.write_u8(body.len() as u8)?;
+        writer.write_all(&body)
+    }
+}
+
 #[derive(Debug)]
 pub struct VersionsCell {
     versions: Vec<u16>,
@@ -742,11 +1171,13 @@ pub struct VersionsCell {
 
 impl VersionsCell {
     pub fn new(versions: Vec<u16>) -> VersionsCell {
-        // we really only support v3, 4, 5 (and we really don't do much validation...)
-        assert!(versions.len() < 3);
         VersionsCell { versions: versions }
     }
 
+    pub fn get_versions(&self) -> &[u16] {
+        &self.versions
+    }
+
     pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
         // a VERSIONS cell is variable-length and has CIRCID_LEN equal to 2.
         // Thus:
@@ -769,17 +1200,23 @@ impl VersionsCell {
     }
 
     pub fn read_new<R: Read>(reader: &mut R) -> Result<VersionsCell> {
-        // TODO
+        // VERSIONS cells always have a 2-byte circuit id (which should be 0), whatever version ends
+        // up being negotiated.
         let circ_id = reader.read_u16::<NetworkEndian>()?;
-        assert!(circ_id == 0);
+        if circ_id != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "VERSIONS cell with nonzero circ_id"));
+        }
         let command = reader.read_u8()?;
-        // TODO
-        assert!(command == 7);
+        if command != 7 {
+            return Err(Error::new(ErrorKind::InvalidData, "expected VERSIONS cell"));
+        }
         let length = reader.read_u16::<NetworkEndian>()?;
-        // TODO
-        assert!(length <= 6);
-        // TODO
-        assert!(length % 2 == 0);
+        if length % 2 != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "odd-length VERSIONS cell"));
+        }
+        if length == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "empty VERSIONS cell"));
+        }
         let mut versions = Vec::new();
         for _ in 0..length / 2 {
             let version = reader.read_u16::<NetworkEndian>()?;
@@ -800,9 +1237,12 @@ impl VersionsCell {
                 highest = *self_version;
             }
         }
-        // In reality we only support version 4 right now.
-        // TODO
-        assert!(highest == 4);
+        if highest == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "no link protocol version in common",
+            ));
+        }
         Ok(highest)
     }
 }
@@ -847,51 +1287,277 @@ impl CreatedFastCell {
     }
 }
 
+/// A CREATE cell, which carries a TAP onion skin (tor-spec.txt section 5.1.3).
+pub struct CreateCell {
+    onion_skin: Vec<u8>,
+}
+
+impl CreateCell {
+    pub fn new(onion_skin: Vec<u8>) -> CreateCell {
+        CreateCell {
+            onion_skin: onion_skin,
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.onion_skin)
+    }
+}
+
+/// A CREATED cell: g^y [128 bytes] followed by KH [20 bytes].
+#[derive(Debug)]
+pub struct CreatedCell {
+    handshake_data: Vec<u8>,
+}
+
+impl CreatedCell {
+    pub fn read_new<R: Read>(reader: &mut R) -> Result<CreatedCell> {
+        let mut handshake_data = Vec::with_capacity(148);
+        handshake_data.resize(148, 0);
+        reader.read_exact(&mut handshake_data)?;
+        Ok(CreatedCell {
+            handshake_data: handshake_data,
+        })
+    }
+
+    pub fn get_handshake_data(&self) -> &[u8] {
+        &self.handshake_data
+    }
+}
+
+/// The body of a RELAY_SENDME cell (tor-spec.txt section 7.4). Version 0 SENDMEs are empty (or
+/// carry nothing we look at); version 1 ("authenticated") SENDMEs carry the digest of the cell
+/// being acknowledged: VERSION [1 byte], DATA_LEN [2 bytes], DATA [DATA_LEN bytes].
+#[derive(Debug, PartialEq)]
+pub enum SendMeCell {
+    V0,
+    V1 { digest: [u8; 20] },
+}
+
+impl SendMeCell {
+    pub fn read_new(data: &[u8]) -> Result<SendMeCell> {
+        if data.len() == 0 {
+            return Ok(SendMeCell::V0);
+        }
+        let mut reader = data;
+        match reader.read_u8()? {
+            0 => Ok(SendMeCell::V0),
+            1 => {
+                let data_len = reader.read_u16::<NetworkEndian>()?;
+                if data_len != 20 {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "SENDME v1 digest must be 20 bytes",
+                    ));
+                }
+                let mut digest = [0; 20];
+                reader.read_exact(&mut digest)?;
+                Ok(SendMeCell::V1 { digest: digest })
+            }
+            _ => Err(Error::new(ErrorKind::InvalidData, "unknown SENDME version")),
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            &SendMeCell::V0 => Ok(()),
+            &SendMeCell::V1 { ref digest } => {
+                writer.write_u8(1)?;
+                writer.write_u16::<NetworkEndian>(20)?;
+                writer.write_all(digest)
+            }
+        }
+    }
+}
+
+/// A link specifier, as carried in EXTEND2 cells (tor-spec.txt section 5.1.2): LSTYPE [1 byte],
+/// LSLEN [1 byte], LSPEC [LSLEN bytes].
+#[derive(Clone, Debug, PartialEq)]
+pub enum LinkSpecifier {
+    /// TLS-over-TCP, IPv4 address and port (type 0x00).
+    IPv4 { address: [u8; 4], port: u16 },
+    /// TLS-over-TCP, IPv6 address and port (type 0x01).
+    IPv6 { address: [u8; 16], port: u16 },
+    /// SHA-1 hash of the node's RSA identity key (type 0x02).
+    LegacyId([u8; 20]),
+    /// The node's Ed25519 identity public key (type 0x03 - the spec says fingerprint but it's the
+    /// key itself).
+    Ed25519Id([u8; 32]),
+    Unknown { ls_type: u8, body: Vec<u8> },
+}
+
+impl LinkSpecifier {
+    pub fn read_new<R: Read>(reader: &mut R) -> Result<LinkSpecifier> {
+        let ls_type = reader.read_u8()?;
+        let ls_len = reader.read_u8()?;
+        let mut body = vec![0; ls_len as usize];
+        reader.read_exact(&mut body)?;
+        let link_specifier = match (ls_type, ls_len) {
+            (0x00, 6) => {
+                let mut address = [0; 4];
+                address.copy_from_slice(&body[0..4]);
+                LinkSpecifier::IPv4 {
+                    address: address,
+                    port: (&body[4..6]).read_u16::<NetworkEndian>()?,
+                }
+            }
+            (0x01, 18) => {
+                let mut address = [0; 16];
+                address.copy_from_slice(&body[0..16]);
+                LinkSpecifier::IPv6 {
+                    address: address,
+                    port: (&body[16..18]).read_u16::<NetworkEndian>()?,
+                }
+            }
+            (0x02, 20) => {
+                let mut id = [0; 20];
+                id.copy_from_slice(&body);
+                LinkSpecifier::LegacyId(id)
+            }
+            (0x03, 32) => {
+                let mut id = [0; 32];
+                id.copy_from_slice(&body);
+                LinkSpecifier::Ed25519Id(id)
+            }
+            (0x00, _) | (0x01, _) | (0x02, _) | (0x03, _) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "link specifier has the wrong length",
+                ))
+            }
+            _ => LinkSpecifier::Unknown {
+                ls_type: ls_type,
+                body: body,
+            },
+        };
+        Ok(link_specifier)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            &LinkSpecifier::IPv4 { ref address, port } => {
+                writer.write_u8(0x00)?;
+                writer.write_u8(6)?;
+                writer.write_all(address)?;
+                writer.write_u16::<NetworkEndian>(port)
+            }
+            &LinkSpecifier::IPv6 { ref address, port } => {
+                writer.write_u8(0x01)?;
+                writer.write_u8(18)?;
+                writer.write_all(address)?;
+                writer.write_u16::<NetworkEndian>(port)
+            }
+            &LinkSpecifier::LegacyId(ref id) => {
+                writer.write_u8(0x02)?;
+                writer.write_u8(20)?;
+                writer.write_all(id)
+            }
+            &LinkSpecifier::Ed25519Id(ref id) => {
+                writer.write_u8(0x03)?;
+                writer.write_u8(32)?;
+                writer.write_all(id)
+            }
+            &LinkSpecifier::Unknown { ls_type, ref body } => {
+                if body.len() > 255 {
+                    return Err(Error::new(ErrorKind::InvalidInput, "link specifier too long"));
+                }
+                writer.write_u8(ls_type)?;
+                writer.write_u8(body.len() as u8)?;
+                writer.write_all(body)
+            }
+        }
+    }
+
+    /// Reads NSPEC [1 byte] followed by that many link specifiers.
+    pub fn read_list<R: Read>(reader: &mut R) -> Result<Vec<LinkSpecifier>> {
+        let n_spec = reader.read_u8()?;
+        let mut link_specifiers = Vec::with_capacity(n_spec as usize);
+        for _ in 0..n_spec {
+            link_specifiers.push(LinkSpecifier::read_new(reader)?);
+        }
+        Ok(link_specifiers)
+    }
+
+    /// Writes NSPEC [1 byte] followed by the link specifiers.
+    pub fn write_list<W: Write>(link_specifiers: &[LinkSpecifier], writer: &mut W) -> Result<()> {
+        if link_specifiers.len() > 255 {
+            return Err(Error::new(ErrorKind::InvalidInput, "too many link specifiers"));
+        }
+        writer.write_u8(link_specifiers.len() as u8)?;
+        for link_specifier in link_specifiers {
+            link_specifier.write_to(writer)?;
+        }
+        Ok(())
+    }
+}
+
+/// The handshake types a client can use in CREATE2/EXTEND2 cells (tor-spec.txt section 5.1).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientHandshakeType {
+    /// ntor (handshake type 2).
+    Ntor,
+    /// ntor v3 (handshake type 3).
+    Ntor3,
+}
+
+impl ClientHandshakeType {
+    pub fn as_u16(&self) -> u16 {
+        match self {
+            &ClientHandshakeType::Ntor => 2,
+            &ClientHandshakeType::Ntor3 => 3,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Extend2Cell {
-    /// The Ed25519 identity key of the node being extended to.
-    ed25519_identity: [u8; 32],
-    /// SHA-1 hash of RSA identity key. Mandatory for some reason (but not specified?)
-    rsa_id: [u8; 20],
-    /// IPv4 address of node being extended to (again mandatory but not specified?)
-    /// (do we have to worry about endianness here?)
-    ipv4: [u8; 4],
-    /// (port for the above)
-    port: u16,
+    /// How to reach the node being extended to. The IPv4 address and RSA ID are mandatory (the
+    /// Ed25519 identity is optional but we always know it).
+    link_specifiers: Vec<LinkSpecifier>,
+    handshake_type: ClientHandshakeType,
     h_data: Vec<u8>,
 }
-
 impl Extend2Cell {
+    /// An EXTEND2 cell carrying an ntor handshake.
     pub fn new(node: &dir::TorPeer, h_data: Vec<u8>) -> Extend2Cell {
+        Extend2Cell::with_extend_target(&node.to_extend_target(), ClientHandshakeType::Ntor, h_data)
+    }
+
+    /// Like `new`, but for a relay that may not be in the consensus, and with any handshake type.
+    pub fn with_extend_target(
+        node: &dir::ExtendTarget,
+        handshake_type: ClientHandshakeType,
+        h_data: Vec<u8>,
+    ) -> Extend2Cell {
         Extend2Cell {
-            ed25519_identity: node.get_ed25519_id_key(),
-            rsa_id: node.get_node_id(),
-            ipv4: node.get_ipv4_as_bytes(),
-            port: node.get_port(),
+            link_specifiers: vec![
+                LinkSpecifier::IPv4 {
+                    address: node.get_ipv4_as_bytes(),
+                    port: node.get_port(),
+                },
+                LinkSpecifier::LegacyId(node.get_node_id()),
+                LinkSpecifier::Ed25519Id(node.get_ed25519_id_key()),
+            ],
+            handshake_type: handshake_type,
             h_data: h_data,
         }
     }
 
+    /// Writes the EXTEND2 body. EXTEND2 travels in a single relay cell (there's no wide format
+    /// for it, whatever the link protocol version), so this fails with `InvalidInput` if the
+    /// handshake data (e.g. an ntor v3 handshake with large extensions) won't fit.
     pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
-        // 3 link specifiers
-        writer.write_u8(3)?;
-        // IPv4 address
-        writer.write_u8(0)?;
-        writer.write_u8(6)?; // 4 bytes for IPv4 plus 2 bytes for port
-        writer.write_all(&self.ipv4)?;
-        writer.write_u16::<NetworkEndian>(self.port)?;
-        // RSA ID hash
-        writer.write_u8(2)?;
-        writer.write_u8(20)?;
-        writer.write_all(&self.rsa_id)?;
-        // Ed25519 public key (docs say fingerprint but that's wrong)
-        writer.write_u8(3)?;
-        writer.write_u8(32)?; // Ed25519 public key is 32 bytes
-        writer.write_all(&self.ed25519_identity)?;
-
-        // Only Ntor (handshake type 2) is implemented.
-        writer.write_u16::<NetworkEndian>(2 as u16)?;
-        assert!(self.h_data.len() < 65536);
+        let mut link_specifiers = Vec::new();
+        LinkSpecifier::write_list(&self.link_specifiers, &mut link_specifiers)?;
+        // NSPEC and the link specifiers | HTYPE (2 bytes) | HLEN (2 bytes) | HDATA
+        if link_specifiers.len() + 4 + self.h_data.len() > RELAY_PAYLOAD_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "EXTEND2 handshake data too long for a relay cell",
+            ));
+        }
+        writer.write_all(&link_specifiers)?;
+        writer.write_u16::<NetworkEndian>(self.handshake_type.as_u16())?;
         writer.write_u16::<NetworkEndian>(self.h_data.len() as u16)?;
         writer.write_all(&self.h_data)
     }
@@ -927,6 +1593,27 @@ impl BeginCell {
         }
     }
 
+    /// Makes a BEGIN cell for the given host and port. The host can be a hostname (which the exit
+    /// will resolve), an IPv4 address, or an IPv6 address (which gets bracketed, as tor-spec.txt
+    /// section 6.2 requires). The exit connects directly to literal addresses.
+    pub fn from_host_port(host: &str, port: u16) -> Result<BeginCell> {
+        if host.len() == 0 || host.contains('\0') {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid host"));
+        }
+        let addrport = if let Ok(ipv6) = host.parse::<Ipv6Addr>() {
+            format!("[{}]:{}", ipv6, port)
+        } else if host.contains(':') {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid host"));
+        } else {
+            format!("{}:{}", host, port)
+        };
+        Ok(BeginCell::new(&addrport))
+    }
+
+    pub fn get_addrport(&self) -> &str {
+        &self.addrport
+    }
+
     pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
         writer.write_all(self.addrport.as_bytes())?;
         writer.write_u8(0)?; // null-terminate the string
@@ -934,6 +1621,46 @@ impl BeginCell {
     }
 }
 
+/// Asks the exit to resolve a hostname (or, for "in-addr.arpa" names, an address) without opening
+/// a connection (tor-spec.txt section 6.4). The answer comes back in a RELAY_RESOLVED cell on the
+/// same stream id.
+#[derive(Debug)]
+pub struct ResolveCell {
+    hostname: String,
+}
+
+impl ResolveCell {
+    pub fn new(hostname: &str) -> Result<ResolveCell> {
+        if hostname.len() == 0 || hostname.contains('\0') || hostname.len() >= RELAY_PAYLOAD_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid hostname"));
+        }
+        Ok(ResolveCell {
+            hostname: hostname.to_owned(),
+        })
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(self.hostname.as_bytes())?;
+        writer.write_u8(0) // null-terminate the string
+    }
+}
+
+/// Sent by a client to a relay to make it a rendezvous point for the given cookie (rend-spec.txt).
+#[derive(Debug)]
+pub struct EstablishRendezvousCell {
+    cookie: [u8; 20],
+}
+
+impl EstablishRendezvousCell {
+    pub fn new(cookie: [u8; 20]) -> EstablishRendezvousCell {
+        EstablishRendezvousCell { cookie: cookie }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.cookie)
+    }
+}
+
 // Maybe just simplify this? (although maybe with a trait it'll be kinda moot)
 #[derive(Debug)]
 pub struct BeginDirCell {}
@@ -948,12 +1675,658 @@ impl BeginDirCell {
     }
 }
 
+/// Why an exit couldn't resolve a name (RESOLVED answer types 0xF0 and 0xF1).
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResolveError {
+    /// The lookup failed but might succeed if retried (0xF0).
+    Transient,
+    /// The lookup failed and retrying won't help (0xF1).
+    Permanent,
+}
+
+/// One answer in a RELAY_RESOLVED cell. Each has a TTL (in seconds), even the errors.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResolvedAnswer {
+    Hostname { hostname: String, ttl: u32 },
+    IPv4 { address: [u8; 4], ttl: u32 },
+    IPv6 { address: [u8; 16], ttl: u32 },
+    Error { error: ResolveError, ttl: u32 },
+    Unknown { answer_type: u8, value: Vec<u8>, ttl: u32 },
+}
+
+/// The body of a RELAY_RESOLVED cell (tor-spec.txt section 6.4): a sequence of answers, each
+/// Type [1 byte], Length [1 byte], Value [Length bytes], TTL [4 bytes].
+#[derive(Debug)]
+pub struct ResolvedCell {
+    answers: Vec<ResolvedAnswer>,
+}
+
+impl ResolvedCell {
+    /// Reads answers until the data runs out.
+    pub fn read_new(mut data: &[u8]) -> Result<ResolvedCell> {
+        let mut answers = Vec::new();
+        while data.len() > 0 {
+            let answer_type = data.read_u8()?;
+            let length = data.read_u8()? as usize;
+            let mut value = vec![0; length];
+            data.read_exact(&mut value)?;
+            let ttl = data.read_u32::<NetworkEndian>()?;
+            let answer = match (answer_type, length) {
+                (0x00, _) => match String::from_utf8(value) {
+                    Ok(hostname) => ResolvedAnswer::Hostname {
+                        hostname: hostname,
+                        ttl: ttl,
+                    },
+                    Err(_) => {
+                        return Err(Error::new(ErrorKind::InvalidData, "invalid hostname"))
+                    }
+                },
+                (0x04, 4) => {
+                    let mut address = [0; 4];
+                    address.copy_from_slice(&value);
+                    ResolvedAnswer::IPv4 {
+                        address: address,
+                        ttl: ttl,
+                    }
+                }
+                (0x06, 16) => {
+                    let mut address = [0; 16];
+                    address.copy_from_slice(&value);
+                    ResolvedAnswer::IPv6 {
+                        address: address,
+                        ttl: ttl,
+                    }
+                }
+                (0x04, _) | (0x06, _) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "RESOLVED address has the wrong length",
+                    ))
+                }
+                (0xF0, _) => ResolvedAnswer::Error {
+                    error: ResolveError::Transient,
+                    ttl: ttl,
+                },
+                (0xF1, _) => ResolvedAnswer::Error {
+                    error: ResolveError::Permanent,
+                    ttl: ttl,
+                },
+                _ => ResolvedAnswer::Unknown {
+                    answer_type: answer_type,
+                    value: value,
+                    ttl: ttl,
+                },
+            };
+            answers.push(answer);
+        }
+        Ok(ResolvedCell { answers: answers })
+    }
+
+    pub fn get_answers(&self) -> &[ResolvedAnswer] {
+        &self.answers
+    }
+}
+
 // TODO...
 /*
 pub struct ConnectedCell {
 
 }
+*/
 
-pub enum RelayEndReason {
+/// The reason carried in a RELAY_END cell (tor-spec.txt section 6.3).
+#[derive(Clone, Debug, PartialEq)]
+pub enum EndReason {
+    Misc,
+    ResolveFailed,
+    ConnectRefused,
+    ExitPolicy,
+    Destroy,
+    Done,
+    Timeout,
+    NoRoute,
+    Hibernating,
+    Internal,
+    ResourceLimit,
+    ConnReset,
+    TorProtocol,
+    NotDirectory,
+    Unknown(u8),
+}
+
+impl EndReason {
+    pub fn from_u8(reason: u8) -> EndReason {
+        match reason {
+            1 => EndReason::Misc,
+            2 => EndReason::ResolveFailed,
+            3 => EndReason::ConnectRefused,
+            4 => EndReason::ExitPolicy,
+            5 => EndReason::Destroy,
+            6 => EndReason::Done,
+            7 => EndReason::Timeout,
+            8 => EndReason::NoRoute,
+            9 => EndReason::Hibernating,
+            10 => EndReason::Internal,
+            11 => EndReason::ResourceLimit,
+            12 => EndReason::ConnReset,
+            13 => EndReason::TorProtocol,
+            14 => EndReason::NotDirectory,
+            _ => EndReason::Unknown(reason),
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            &EndReason::Misc => 1,
+            &EndReason::ResolveFailed => 2,
+            &EndReason::ConnectRefused => 3,
+            &EndReason::ExitPolicy => 4,
+            &EndReason::Destroy => 5,
+            &EndReason::Done => 6,
+            &EndReason::Timeout => 7,
+            &EndReason::NoRoute => 8,
+            &EndReason::Hibernating => 9,
+            &EndReason::Internal => 10,
+            &EndReason::ResourceLimit => 11,
+            &EndReason::ConnReset => 12,
+            &EndReason::TorProtocol => 13,
+            &EndReason::NotDirectory => 14,
+            &EndReason::Unknown(value) => value,
+        }
+    }
+}
+
+/// The reason given in a DESTROY cell (tor-spec.txt section 5.4).
+#[derive(Clone, Debug, PartialEq)]
+pub enum DestroyReason {
+    None,
+    Protocol,
+    Internal,
+    Requested,
+    Hibernating,
+    ResourceLimit,
+    ConnectFailed,
+    OrIdentity,
+    ChannelClosed,
+    Finished,
+    Timeout,
+    Destroyed,
+    NoSuchService,
+    Unknown(u8),
+}
+
+impl DestroyReason {
+    pub fn from_u8(reason: u8) -> DestroyReason {
+        match reason {
+            0 => DestroyReason::None,
+            1 => DestroyReason::Protocol,
+            2 => DestroyReason::Internal,
+            3 => DestroyReason::Requested,
+            4 => DestroyReason::Hibernating,
+            5 => DestroyReason::ResourceLimit,
+            6 => DestroyReason::ConnectFailed,
+            7 => DestroyReason::OrIdentity,
+            8 => DestroyReason::ChannelClosed,
+            9 => DestroyReason::Finished,
+            10 => DestroyReason::Timeout,
+            11 => DestroyReason::Destroyed,
+            12 => DestroyReason::NoSuchService,
+            _ => DestroyReason::Unknown(reason),
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            &DestroyReason::None => 0,
+            &DestroyReason::Protocol => 1,
+            &DestroyReason::Internal => 2,
+            &DestroyReason::Requested => 3,
+            &DestroyReason::Hibernating => 4,
+            &DestroyReason::ResourceLimit => 5,
+            &DestroyReason::ConnectFailed => 6,
+            &DestroyReason::OrIdentity => 7,
+            &DestroyReason::ChannelClosed => 8,
+            &DestroyReason::Finished => 9,
+            &DestroyReason::Timeout => 10,
+            &DestroyReason::Destroyed => 11,
+            &DestroyReason::NoSuchService => 12,
+            &DestroyReason::Unknown(value) => value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay_command_round_trip() {
+        for command in RelayCommand::all() {
+            assert_eq!(&RelayCommand::from_u8(command.as_u8()), command);
+        }
+        for value in 0..256 {
+            let value = value as u8;
+            assert_eq!(RelayCommand::from_u8(value).as_u8(), value);
+        }
+        assert_eq!(RelayCommand::from_u8(16), RelayCommand::Unknown(16));
+    }
+
+    #[test]
+    fn test_versions_negotiate() {
+        let ours = VersionsCell::new(vec![4, 5]);
+        assert_eq!(ours.negotiate(&VersionsCell::new(vec![3, 4, 5])).unwrap(), 5);
+        assert_eq!(ours.negotiate(&VersionsCell::new(vec![4])).unwrap(), 4);
+        assert!(ours.negotiate(&VersionsCell::new(vec![1, 2, 3])).is_err());
+    }
+
+    #[test]
+    fn test_versions_empty() {
+        let mut bytes = Vec::new();
+        VersionsCell::new(vec![]).write_to(&mut bytes).unwrap();
+        assert!(VersionsCell::read_new(&mut &bytes[..]).is_err());
+        let ours = VersionsCell::new(vec![4, 5]);
+        assert!(ours.negotiate(&VersionsCell::new(vec![])).is_err());
+    }
+
+    #[test]
+    fn test_versions_round_trip() {
+        let mut bytes = Vec::new();
+        VersionsCell::new(vec![3, 4, 5]).write_to(&mut bytes).unwrap();
+        assert_eq!(bytes, [0, 0, 7, 0, 6, 0, 3, 0, 4, 0, 5]);
+        let versions = VersionsCell::read_new(&mut &bytes[..]).unwrap();
+        assert_eq!(versions.versions, vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn test_authenticate_round_trip() {
+        let authentication: Vec<u8> = (0..352).map(|i| i as u8).collect();
+        let authenticate =
+            AuthenticateCell::new(AuthType::Ed25519Sha256Rfc5705, authentication.clone());
+        let mut payload = Vec::new();
+        authenticate.write_to(&mut payload).unwrap();
+        assert_eq!(&payload[..4], &[0, 3, 0x01, 0x60]);
+        assert_eq!(&payload[4..], &authentication[..]);
+
+        // AUTHENTICATE is a variable-length cell, so it's framed with a 2-byte length.
+        let mut bytes = Vec::new();
+        Cell::new(0, Command::Authenticate, payload.clone())
+            .write_to_for_version(&mut bytes, 4)
+            .unwrap();
+        assert_eq!(bytes.len(), 4 + 1 + 2 + payload.len());
+        assert_eq!(&bytes[4..7], &[131, 0x01, 0x64]);
+
+        let cell = Cell::read_new_for_version(&mut &bytes[..], 4).unwrap();
+        assert_eq!(cell.command, Command::Authenticate);
+        let read = AuthenticateCell::read_new(&mut &cell.payload[..]).unwrap();
+        assert_eq!(read.get_auth_type(), &AuthType::Ed25519Sha256Rfc5705);
+        assert_eq!(read.get_authentication(), &authentication[..]);
+    }
+
+    #[test]
+    fn test_ntor_server_handshake_length() {
+        assert!(NtorServerHandshake::from_h_data(&[0; 10]).is_err());
+        assert!(NtorServerHandshake::from_h_data(&[0; 65]).is_err());
+        let handshake = NtorServerHandshake::from_h_data(&[[1; 32], [2; 32]].concat()).unwrap();
+        assert_eq!(handshake.server_pk, [1; 32]);
+        assert_eq!(handshake.auth, [2; 32]);
+    }
+
+    #[test]
+    fn test_ntor_client_handshake_round_trip() {
+        let bytes = [[1; 20].to_vec(), [2; 32].to_vec(), [3; 32].to_vec()].concat();
+        let handshake = NtorClientHandshake::read_new(&mut &bytes[..]).unwrap();
+        assert_eq!(handshake.get_node_id(), [1; 20]);
+        assert_eq!(handshake.get_key_id(), [2; 32]);
+        assert_eq!(handshake.get_client_pk(), [3; 32]);
+        let mut encoded = Vec::new();
+        handshake.write_to(&mut encoded).unwrap();
+        assert_eq!(encoded, bytes);
+    }
+
+    #[test]
+    fn test_ntor_client_handshake_short() {
+        let bytes = [0; 83];
+        assert!(NtorClientHandshake::read_new(&mut &bytes[..]).is_err());
+        assert!(NtorClientHandshake::from_h_data(&bytes).is_err());
+        assert!(NtorClientHandshake::from_h_data(&[0; 85]).is_err());
+    }
+
+    #[test]
+    fn test_fixed_length_cell_round_trip() {
+        let cell = Cell::new(0x80000001, Command::CreateFast, vec![7; 20]);
+        let mut bytes = Vec::new();
+        cell.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), CELL_LEN);
+        assert_eq!(&bytes[..5], &[0x80, 0, 0, 1, 5]);
+        assert_eq!(&bytes[5..25], &[7; 20]);
+        assert!(bytes[25..].iter().all(|b| *b == 0));
+        let read = Cell::read_new(&mut &bytes[..]).unwrap();
+        assert_eq!(read.circ_id, 0x80000001);
+        assert_eq!(read.command, Command::CreateFast);
+        // The padding comes back as part of the payload.
+        assert_eq!(read.payload.len(), PAYLOAD_LEN);
+        assert_eq!(&read.payload[..20], &[7; 20]);
+    }
+
+    #[test]
+    fn test_variable_length_cell_round_trip() {
+        let cell = Cell::new(0, Command::Certs, vec![9; 600]);
+        let mut bytes = Vec::new();
+        cell.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 4 + 1 + 2 + 600);
+        assert_eq!(&bytes[..7], &[0, 0, 0, 0, 129, 0x02, 0x58]);
+        let read = Cell::read_new(&mut &bytes[..]).unwrap();
+        assert_eq!(read.circ_id, 0);
+        assert_eq!(read.command, Command::Certs);
+        assert_eq!(read.payload, vec![9; 600]);
+    }
+
+    #[test]
+    fn test_fixed_length_cell_too_long() {
+        let cell = Cell::new(1, Command::Relay, vec![0; PAYLOAD_LEN + 1]);
+        assert!(cell.write_to(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_begin_ipv4_payload() {
+        let begin = BeginCell::from_host_port("192.0.2.7", 443).unwrap();
+        let mut payload = Vec::new();
+        begin.write_to(&mut payload).unwrap();
+        // ADDRPORT, NUL-terminated, then FLAGS with only IPv6-okay set.
+        assert_eq!(payload, b"192.0.2.7:443\0\0\0\0\x01".to_vec());
+    }
+
+    #[test]
+    fn test_begin_hostname_payload() {
+        let begin = BeginCell::from_host_port("example.com", 80).unwrap();
+        let mut payload = Vec::new();
+        begin.write_to(&mut payload).unwrap();
+        assert_eq!(payload, b"example.com:80\0\0\0\0\x01".to_vec());
+        assert!(BeginCell::from_host_port("", 80).is_err());
+        assert!(BeginCell::from_host_port("example.com\0", 80).is_err());
+    }
+
+    #[test]
+    fn test_begin_ipv6_payload() {
+        // The address is bracketed and written in its canonical (compressed) form.
+        let begin = BeginCell::from_host_port("2001:db8:0:0:0:0:0:1", 443).unwrap();
+        let mut payload = Vec::new();
+        begin.write_to(&mut payload).unwrap();
+        assert_eq!(payload, b"[2001:db8::1]:443\0\0\0\0\x01".to_vec());
+        // Something with colons that isn't an IPv6 address would make an ambiguous ADDRPORT.
+        assert!(BeginCell::from_host_port("example.com:80", 443).is_err());
+    }
+
+    #[test]
+    fn test_v3_framing() {
+        let cell = Cell::new(0x1234, Command::CreateFast, vec![7; 20]);
+        let mut bytes = Vec::new();
+        cell.write_to_for_version(&mut bytes, 3).unwrap();
+        assert_eq!(bytes.len(), 2 + 1 + PAYLOAD_LEN);
+        assert_eq!(&bytes[..3], &[0x12, 0x34, 5]);
+        let read = Cell::read_new_for_version(&mut &bytes[..], 3).unwrap();
+        assert_eq!(read.circ_id, 0x1234);
+        assert_eq!(read.command, Command::CreateFast);
+        // Read with 4-byte circuit ids, the command is the first byte of the payload (7, which is
+        // VERSIONS), and the "length" that follows is more than there is.
+        assert!(Cell::read_new_for_version(&mut &bytes[..], 5).is_err());
+        // Version 3 can't carry a 4-byte circuit id.
+        let cell = Cell::new(0x80000001, Command::CreateFast, vec![7; 20]);
+        assert!(cell.write_to_for_version(&mut Vec::new(), 3).is_err());
+    }
+
+    #[test]
+    fn test_v5_framing() {
+        let cell = Cell::new(0x80000001, Command::Certs, vec![1, 2, 3]);
+        let mut bytes = Vec::new();
+        cell.write_to_for_version(&mut bytes, 5).unwrap();
+        assert_eq!(bytes, vec![0x80, 0, 0, 1, 129, 0, 3, 1, 2, 3]);
+        let read = Cell::read_new_for_version(&mut &bytes[..], 5).unwrap();
+        assert_eq!(read.circ_id, 0x80000001);
+        assert_eq!(read.command, Command::Certs);
+        assert_eq!(read.payload, vec![1, 2, 3]);
+        // With 2-byte circuit ids, the command is read from the middle of the circuit id (0, which
+        // is PADDING), and a fixed-length cell is more than there is.
+        assert!(Cell::read_new_for_version(&mut &bytes[..], 3).is_err());
+    }
+
+    #[test]
+    fn test_netinfo_ipv4_round_trip() {
+        let netinfo = NetinfoCell::new(
+            0x5a000000,
+            OrAddress::IPv4Address([10, 0, 0, 1]),
+            vec![OrAddress::IPv4Address([192, 0, 2, 7])],
+        );
+        let mut bytes = Vec::new();
+        netinfo.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes, vec![0x5a, 0, 0, 0, 4, 4, 10, 0, 0, 1, 1, 4, 4, 192, 0, 2, 7]);
+        let read = NetinfoCell::read_new(&mut &bytes[..]).unwrap();
+        assert_eq!(read.timestamp, 0x5a000000);
+        match read.get_our_address() {
+            &OrAddress::IPv4Address(address) => assert_eq!(address, [10, 0, 0, 1]),
+            other => panic!("unexpected address {:?}", other),
+        }
+        match read.get_other_or_address() {
+            Some(OrAddress::IPv4Address(address)) => assert_eq!(address, [192, 0, 2, 7]),
+            other => panic!("unexpected address {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_netinfo_ipv6_round_trip() {
+        let mut ipv6 = [0; 16];
+        ipv6[0] = 0x20;
+        ipv6[1] = 0x01;
+        ipv6[15] = 1;
+        let netinfo = NetinfoCell::new(
+            1,
+            OrAddress::IPv6Address(ipv6),
+            vec![OrAddress::IPv6Address(ipv6), OrAddress::IPv4Address([192, 0, 2, 7])],
+        );
+        let mut bytes = Vec::new();
+        netinfo.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 4 + (2 + 16) + 1 + (2 + 16) + (2 + 4));
+        assert_eq!(&bytes[4..6], &[6, 16]);
+        assert_eq!(bytes[4 + 18], 2);
+        let read = NetinfoCell::read_new(&mut &bytes[..]).unwrap();
+        match read.get_our_address() {
+            &OrAddress::IPv6Address(address) => assert_eq!(address, ipv6),
+            other => panic!("unexpected address {:?}", other),
+        }
+        assert_eq!(read.this_or_addresses.len(), 2);
+        // The first usable address the sender listed is the one to reply to.
+        match read.get_other_or_address() {
+            Some(OrAddress::IPv6Address(address)) => assert_eq!(address, ipv6),
+            other => panic!("unexpected address {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_netinfo_skips_unknown_addresses() {
+        // An address of unknown type 9 followed by an IPv4 address.
+        let bytes = [0, 0, 0, 1, 4, 4, 10, 0, 0, 1, 2, 9, 2, 0xaa, 0xbb, 4, 4, 192, 0, 2, 7];
+        let read = NetinfoCell::read_new(&mut &bytes[..]).unwrap();
+        match read.this_or_addresses[0] {
+            OrAddress::Unknown(9) => {}
+            ref other => panic!("unexpected address {:?}", other),
+        }
+        match read.get_other_or_address() {
+            Some(OrAddress::IPv4Address(address)) => assert_eq!(address, [192, 0, 2, 7]),
+            other => panic!("unexpected address {:?}", other),
+        }
+        // An IPv4 address has to be 4 bytes.
+        let bytes = [0, 0, 0, 1, 4, 5, 10, 0, 0, 1, 0, 0];
+        assert!(NetinfoCell::read_new(&mut &bytes[..]).is_err());
+    }
+
+    #[test]
+    fn test_resolved_a_and_aaaa() {
+        let mut data = vec![4, 4, 93, 184, 216, 34, 0, 0, 0x0e, 0x10];
+        data.extend(&[6, 16, 0x26, 0x06, 0x28, 0x00, 0x02, 0x20, 0, 1, 0x02, 0x48, 0x18, 0x93]);
+        data.extend(&[0x25, 0xc8, 0x19, 0x46, 0, 0, 0, 60]);
+        let resolved = ResolvedCell::read_new(&data).unwrap();
+        assert_eq!(
+            resolved.get_answers(),
+            &[
+                ResolvedAnswer::IPv4 {
+                    address: [93, 184, 216, 34],
+                    ttl: 3600,
+                },
+                ResolvedAnswer::IPv6 {
+                    address: [
+                        0x26, 0x06, 0x28, 0x00, 0x02, 0x20, 0, 1, 0x02, 0x48, 0x18, 0x93, 0x25,
+                        0xc8, 0x19, 0x46,
+                    ],
+                    ttl: 60,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolved_bad_answers() {
+        // An A answer has to be 4 bytes, and an AAAA answer 16.
+        assert!(ResolvedCell::read_new(&[4, 5, 1, 2, 3, 4, 5, 0, 0, 0, 0]).is_err());
+        assert!(ResolvedCell::read_new(&[6, 4, 1, 2, 3, 4, 0, 0, 0, 0]).is_err());
+        // Missing the TTL.
+        assert!(ResolvedCell::read_new(&[4, 4, 1, 2, 3, 4, 0, 0]).is_err());
+        // Errors still have a TTL, and unknown types are kept.
+        let resolved = ResolvedCell::read_new(&[0xf1, 0, 0, 0, 0, 1, 9, 1, 7, 0, 0, 0, 2]).unwrap();
+        assert_eq!(
+            resolved.get_answers(),
+            &[
+                ResolvedAnswer::Error {
+                    error: ResolveError::Permanent,
+                    ttl: 1,
+                },
+                ResolvedAnswer::Unknown {
+                    answer_type: 9,
+                    value: vec![7],
+                    ttl: 2,
+                },
+            ]
+        );
+    }
+
+    use std::cmp;
+
+    /// Hands out its data in reads of the given sizes (then whatever's left).
+    struct ChunkedReader {
+        data: Vec<u8>,
+        chunk_sizes: Vec<usize>,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let chunk_size = if self.chunk_sizes.is_empty() {
+                self.data.len()
+            } else {
+                self.chunk_sizes.remove(0)
+            };
+            let len = cmp::min(cmp::min(chunk_size, buf.len()), self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data.drain(..len);
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_cell_reader_split_reads() {
+        let cells = vec![
+            Cell::new(0, Command::AuthChallenge, vec![0, 4, 0, 5]),
+            Cell::new(0x80000001, Command::CreateFast, vec![7; 20]),
+            Cell::new(0, Command::Certs, vec![1, 2, 3]),
+        ];
+        let mut data = Vec::new();
+        for cell in &cells {
+            cell.write_to_for_version(&mut data, 4).unwrap();
+        }
+        // The first cell arrives a byte at a time, the second's header comes with the end of the
+        // first, and the last read has the end of the second and all of the third.
+        let mut reader = CellReader::new(ChunkedReader {
+            data: data,
+            chunk_sizes: vec![1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 6, 300],
+        });
+        let cell = reader.next_cell().unwrap().unwrap();
+        assert_eq!(cell.command, Command::AuthChallenge);
+        assert_eq!(cell.payload, vec![0, 4, 0, 5]);
+        assert_eq!(reader.get_buffered_bytes(), &[0x80, 0, 0, 1, 5]);
+        let cell = reader.next_cell().unwrap().unwrap();
+        assert_eq!(cell.circ_id, 0x80000001);
+        assert_eq!(cell.command, Command::CreateFast);
+        assert_eq!(&cell.payload[..20], &[7; 20]);
+        let cell = reader.next_cell().unwrap().unwrap();
+        assert_eq!(cell.command, Command::Certs);
+        assert_eq!(cell.payload, vec![1, 2, 3]);
+        assert!(reader.get_buffered_bytes().is_empty());
+        assert!(reader.next_cell().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cell_reader_ends_partway() {
+        let mut data = Vec::new();
+        Cell::new(0, Command::Certs, vec![1, 2, 3]).write_to_for_version(&mut data, 4).unwrap();
+        data.pop();
+        let mut reader = CellReader::new(ChunkedReader {
+            data: data,
+            chunk_sizes: vec![2],
+        });
+        match reader.next_cell() {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::UnexpectedEof),
+            Ok(cell) => panic!("read {:?} from a truncated cell", cell.map(|cell| cell.payload)),
+        }
+    }
+
+    #[test]
+    fn test_certs_too_many() {
+        let mut bytes = vec![MAX_CERTS as u8 + 1];
+        for _ in 0..MAX_CERTS + 1 {
+            RawCert::new(CertType::Ed25519Signing, vec![0; 4])
+                .write_to(&mut bytes)
+                .unwrap();
+        }
+        let error = CertsCell::read_new(&mut &bytes[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        bytes[0] = MAX_CERTS as u8;
+        let certs = CertsCell::read_new(&mut &bytes[..]).unwrap();
+        assert_eq!(certs.certs.len(), MAX_CERTS);
+    }
+
+    #[test]
+    fn test_certs_length_past_payload() {
+        // One cert claiming 100 bytes with only 3 to back it up.
+        let bytes = [1, 4, 0, 100, 1, 2, 3];
+        let error = CertsCell::read_new(&mut &bytes[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        // The second of two certs runs off the end.
+        let bytes = [2, 4, 0, 1, 0xaa, 5, 0xff, 0xff];
+        let error = CertsCell::read_new(&mut &bytes[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_extend2_too_long() {
+        let address = "10.0.0.1".parse().unwrap();
+        let target = dir::ExtendTarget::new(address, 9001, [1; 20], [2; 32], [3; 32]);
+        // NSPEC (1 byte), IPv4 (8 bytes), legacy id (22 bytes), and Ed25519 id (34 bytes) link
+        // specifiers, then HTYPE and HLEN, leave this much of the relay payload for HDATA.
+        let max_h_data_len = RELAY_PAYLOAD_LEN - 1 - 8 - 22 - 34 - 4;
+        let extend2 = Extend2Cell::with_extend_target(
+            &target,
+            ClientHandshakeType::Ntor3,
+            vec![0; max_h_data_len],
+        );
+        let mut bytes = Vec::new();
+        extend2.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), RELAY_PAYLOAD_LEN);
+        let extend2 = Extend2Cell::with_extend_target(
+            &target,
+            ClientHandshakeType::Ntor3,
+            vec![0; max_h_data_len + 1],
+        );
+        let mut bytes = Vec::new();
+        let error = extend2.write_to(&mut bytes).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(bytes.is_empty());
+    }
 }
-*/
@@ -1,3 +1,6 @@
+use constant_time_eq::constant_time_eq;
+use std::cmp;
+
 // Ok there has to be a way to do this more generically.
 pub fn slice_to_20_byte_array(bytes: &[u8]) -> [u8; 20] {
     let mut fixed_size: [u8; 20] = [0; 20];
@@ -11,6 +14,71 @@ pub fn slice_to_32_byte_array(bytes: &[u8]) -> [u8; 32] {
     fixed_size
 }
 
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        hex.push_str(&format!("{:02x}", b));
+    }
+    hex
+}
+
+/// Compares two byte strings in constant time. Use this rather than `==` for MACs, digests,
+/// and anything else secret-dependent, where an early return would leak how much matched.
+/// `constant_time_eq` itself returns early if the lengths differ, so both strings are padded
+/// to the same length first and the length check is folded in afterwards.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    let len = cmp::max(a.len(), b.len());
+    let mut padded_a = a.to_vec();
+    padded_a.resize(len, 0);
+    let mut padded_b = b.to_vec();
+    padded_b.resize(len, 0);
+    // `&` rather than `&&` so the comparison happens either way.
+    constant_time_eq(&padded_a, &padded_b) & (a.len() == b.len())
+}
+
+/// Parses a "YYYY-MM-DD HH:MM:SS" UTC timestamp (the format used in directory documents) into
+/// seconds since the Unix epoch.
+pub fn parse_utc_timestamp(timestamp: &str) -> Option<u64> {
+    let mut parts = timestamp.split(|c| c == '-' || c == ' ' || c == ':');
+    let mut fields: [u64; 6] = [0; 6];
+    for field in fields.iter_mut() {
+        *field = match parts.next().map(|part| part.parse::<u64>()) {
+            Some(Ok(value)) => value,
+            _ => return None,
+        };
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    let (year, month, day, hour, minute, second) =
+        (fields[0], fields[1], fields[2], fields[3], fields[4], fields[5]);
+    if year < 1970 || month < 1 || month > 12 || day < 1 || hour > 23 || minute > 59 || second > 60
+    {
+        return None;
+    }
+    let leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap_year => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if day > days_in_month {
+        return None;
+    }
+    // Days since the epoch, counting from March so the leap day comes at the end of the year.
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let days_before_year = 365 * y + y / 4 - y / 100 + y / 400;
+    let days_before_month = (153 * m + 2) / 5;
+    // 719468 is days_before_year + days_before_month for 1970-01-01.
+    let days = days_before_year + days_before_month + day - 1 - 719468;
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
 #[allow(dead_code)]
 pub fn hexdump(bytes: &[u8]) {
     for b in bytes {
@@ -18,3 +86,61 @@ pub fn hexdump(bytes: &[u8]) {
     }
     println!();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ct_eq() {
+        assert!(ct_eq(b"", b""));
+        assert!(ct_eq(b"digest", b"digest"));
+        assert!(!ct_eq(b"digest", b"digesu"));
+        assert!(!ct_eq(b"digest", b"Digest"));
+    }
+
+    #[test]
+    fn test_ct_eq_length_mismatch() {
+        // A prefix isn't equal, even though the shorter string is padded with zeroes to compare.
+        assert!(!ct_eq(b"digest", b"dig"));
+        assert!(!ct_eq(b"", b"digest"));
+        assert!(!ct_eq(&[1, 0], &[1]));
+        assert!(!ct_eq(&[1], &[1, 0]));
+    }
+
+    #[test]
+    fn test_parse_utc_timestamp() {
+        assert_eq!(parse_utc_timestamp("1970-01-01 00:00:00"), Some(0));
+        assert_eq!(parse_utc_timestamp("2018-03-01 12:00:00"), Some(1519905600));
+        assert_eq!(parse_utc_timestamp("2024-02-29 23:59:59"), Some(1709251199));
+        assert_eq!(parse_utc_timestamp("2049-01-01 00:00:00"), Some(2493072000));
+    }
+
+    #[test]
+    fn test_parse_utc_timestamp_malformed() {
+        assert_eq!(parse_utc_timestamp(""), None);
+        assert_eq!(parse_utc_timestamp("2018-03-01"), None);
+        assert_eq!(parse_utc_timestamp("2018-03-01 12:00"), None);
+        assert_eq!(parse_utc_timestamp("2018-03-01 12:00:00:00"), None);
+        assert_eq!(parse_utc_timestamp("2018-03-01T12:00:00"), None);
+        assert_eq!(parse_utc_timestamp("2018-03-01  12:00:00"), None);
+        assert_eq!(parse_utc_timestamp("2018-03-01 12:00:0x"), None);
+        assert_eq!(parse_utc_timestamp("-2018-03-01 12:00:00"), None);
+    }
+
+    #[test]
+    fn test_parse_utc_timestamp_out_of_range() {
+        assert_eq!(parse_utc_timestamp("1969-12-31 23:59:59"), None);
+        assert_eq!(parse_utc_timestamp("2018-00-01 12:00:00"), None);
+        assert_eq!(parse_utc_timestamp("2018-13-01 12:00:00"), None);
+        assert_eq!(parse_utc_timestamp("2018-03-00 12:00:00"), None);
+        assert_eq!(parse_utc_timestamp("2018-03-32 12:00:00"), None);
+        assert_eq!(parse_utc_timestamp("2018-04-31 12:00:00"), None);
+        assert_eq!(parse_utc_timestamp("2018-02-29 12:00:00"), None);
+        assert_eq!(parse_utc_timestamp("2100-02-29 12:00:00"), None);
+        assert_eq!(parse_utc_timestamp("2018-03-01 24:00:00"), None);
+        assert_eq!(parse_utc_timestamp("2018-03-01 12:60:00"), None);
+        assert_eq!(parse_utc_timestamp("2018-03-01 12:00:61"), None);
+        assert_eq!(parse_utc_timestamp("99999999999999999999-03-01 12:00:00"), None);
+    }
+}